@@ -0,0 +1,230 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Support for adopting sockets passed through systemd socket activation.
+//!
+//! systemd passes `LISTEN_FDS` sockets starting at fd 3 to the process whose pid
+//! is `LISTEN_PID`, optionally naming them through the colon separated
+//! `LISTEN_FDNAMES`. Each fd is only handed out once, so two callers can't end up
+//! owning (and closing) the same fd.
+
+use std::{
+    env,
+    io::{self, Error, ErrorKind},
+    mem,
+    os::unix::io::{FromRawFd, OwnedFd, RawFd},
+    process,
+    sync::{Mutex, PoisonError},
+};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+static CLAIMED: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// The kind of Unix stream socket to adopt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Listener,
+    Stream,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ListenFd {
+    fd: RawFd,
+    name: Option<String>,
+}
+
+/// Take each passed fd that is a Unix stream socket of `kind`.
+pub fn take_listen_fds(kind: SocketKind) -> io::Result<Vec<OwnedFd>> {
+    take(kind, |_| true, usize::MAX)
+}
+
+/// Take the first passed fd named `name` that is a Unix stream socket of `kind`.
+pub fn take_listen_fd_name(kind: SocketKind, name: &str) -> io::Result<OwnedFd> {
+    take(kind, |fd| fd.name.as_deref() == Some(name), 1)?
+        .pop()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no socket activated Unix socket named {}", name),
+            )
+        })
+}
+
+fn take(
+    kind: SocketKind,
+    filter: impl Fn(&ListenFd) -> bool,
+    limit: usize,
+) -> io::Result<Vec<OwnedFd>> {
+    let listen_fds = parse(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        process::id(),
+    )?;
+
+    let mut claimed = CLAIMED.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut taken = Vec::new();
+    for listen_fd in listen_fds {
+        if taken.len() == limit {
+            break;
+        }
+        if claimed.contains(&listen_fd.fd) || !filter(&listen_fd) || !is_kind(listen_fd.fd, kind) {
+            continue;
+        }
+
+        // SAFETY: fcntl() has no memory safety requirements.
+        if unsafe { libc::fcntl(listen_fd.fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        claimed.push(listen_fd.fd);
+        // SAFETY: systemd passed us this fd and CLAIMED ensures that we only
+        // take ownership of it once.
+        taken.push(unsafe { OwnedFd::from_raw_fd(listen_fd.fd) });
+    }
+
+    Ok(taken)
+}
+
+fn parse(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> io::Result<Vec<ListenFd>> {
+    match pid.map(str::parse::<u32>) {
+        Some(Ok(pid)) if pid == own_pid => {}
+        Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        _ => return Ok(Vec::new()),
+    }
+
+    let count = match fds.map(str::parse::<RawFd>) {
+        Some(Ok(count)) if count >= 0 => count,
+        Some(Ok(count)) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid LISTEN_FDS: {}", count),
+            ))
+        }
+        Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        None => 0,
+    };
+    let names: Vec<&str> = names
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+
+    Ok((0..count)
+        .map(|i| ListenFd {
+            fd: LISTEN_FDS_START + i,
+            name: names.get(i as usize).map(|name| name.to_string()),
+        })
+        .collect())
+}
+
+/// Check that `fd` is a Unix stream socket of `kind`.
+pub fn is_kind(fd: RawFd, kind: SocketKind) -> bool {
+    // SAFETY: sockaddr_storage is a plain C struct for which all zeros is a valid value.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+
+    // SAFETY: addr and len describe a buffer large enough for any socket address.
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if result < 0 || libc::c_int::from(addr.ss_family) != libc::AF_UNIX {
+        return false;
+    }
+
+    let accepting = kind == SocketKind::Listener;
+    matches!(sockopt(fd, libc::SO_TYPE), Some(libc::SOCK_STREAM))
+        && sockopt(fd, libc::SO_ACCEPTCONN).map(|value| value != 0) == Some(accepting)
+}
+
+fn sockopt(fd: RawFd, option: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+
+    // SAFETY: value and len describe a buffer large enough for an int option.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result < 0 {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    };
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_ignores_other_pid() {
+        let fds = parse(Some("1"), Some("2"), None, 2).expect("Can't parse");
+
+        assert!(fds.is_empty());
+    }
+
+    #[test]
+    fn parse_names_fds() {
+        let fds = parse(Some("7"), Some("2"), Some("web:admin"), 7).expect("Can't parse");
+
+        assert_eq!(
+            fds,
+            vec![
+                ListenFd {
+                    fd: 3,
+                    name: Some("web".to_string())
+                },
+                ListenFd {
+                    fd: 4,
+                    name: Some("admin".to_string())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_count() {
+        let result = parse(Some("7"), Some("many"), None, 7);
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn is_kind_distinguishes_listeners_and_streams() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("socket")).expect("Can't bind");
+        let (stream, _) = UnixStream::pair().expect("Can't create pair");
+
+        assert!(is_kind(listener.as_raw_fd(), SocketKind::Listener));
+        assert!(!is_kind(listener.as_raw_fd(), SocketKind::Stream));
+        assert!(is_kind(stream.as_raw_fd(), SocketKind::Stream));
+        assert!(!is_kind(stream.as_raw_fd(), SocketKind::Listener));
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+use std::{os::unix::net::SocketAddr, path::Path};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    feature = "net-fd",
+    feature = "tokio-fd",
+    feature = "async-io-fd"
+))]
+use std::io;
+
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+use std::{
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+#[cfg(any(feature = "tokio-fd", feature = "async-io-fd"))]
+use std::os::unix::{ffi::OsStrExt, net::UnixStream};
+
+/// The kind of a Unix domain socket address.
+///
+/// Each of the socket types in this crate reports its addresses as a std
+/// [`SocketAddr`]. `AddrKind` classifies such an address as a filesystem
+/// pathname, a Linux abstract namespace name, or an unnamed address (such as
+/// either end of a socket pair or the client side of a connection that was never
+/// bound).
+///
+/// # Examples
+///
+/// ```
+/// use fd_queue::AddrKind;
+/// use std::os::unix::net::UnixStream;
+///
+/// let (sock, _) = UnixStream::pair()?;
+/// let addr = sock.local_addr()?;
+///
+/// assert_eq!(AddrKind::from(&addr), AddrKind::Unnamed);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrKind<'a> {
+    /// An address bound to a path in the filesystem.
+    Pathname(&'a Path),
+
+    /// An address in the Linux abstract namespace. The name does not include the
+    /// leading null byte.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Abstract(&'a [u8]),
+
+    /// An address with no name.
+    Unnamed,
+}
+
+impl<'a> From<&'a SocketAddr> for AddrKind<'a> {
+    fn from(addr: &'a SocketAddr) -> AddrKind<'a> {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        if let Some(path) = addr.as_pathname() {
+            return AddrKind::Pathname(path);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(name) = addr.as_abstract_name() {
+            return AddrKind::Abstract(name);
+        }
+
+        AddrKind::Unnamed
+    }
+}
+
+/// Creates a [`SocketAddr`] in the Linux abstract namespace.
+///
+/// `name` should not include the leading null byte. The resulting address can be
+/// passed to the `bind_addr()` and `connect_addr()` functions of each of the
+/// socket types in this crate. Abstract addresses do not refer to anything in the
+/// filesystem, so two processes that share a network namespace can connect
+/// through one without sharing a directory.
+///
+/// # Errors
+///
+/// This will return an error if `name` is longer than the space available in a
+/// `sockaddr_un`.
+///
+/// # Examples
+///
+/// ```
+/// use fd_queue::{abstract_addr, AddrKind};
+///
+/// let addr = abstract_addr("fd-queue-example")?;
+///
+/// assert_eq!(AddrKind::from(&addr), AddrKind::Abstract(b"fd-queue-example"));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn abstract_addr(name: impl AsRef<[u8]>) -> io::Result<SocketAddr> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    SocketAddr::from_abstract_name(name)
+}
+
+/// Starts a non-blocking connect to `addr`.
+///
+/// The returned stream is in non-blocking mode and the connect may still be in
+/// progress; the async `connect_addr()` functions wait for it to become writable
+/// and then check `take_error()`, just as tokio and async-io do for `connect()`.
+#[cfg(any(feature = "tokio-fd", feature = "async-io-fd"))]
+pub(crate) fn connect_nonblocking(addr: &SocketAddr) -> io::Result<UnixStream> {
+    let name = match AddrKind::from(addr) {
+        AddrKind::Pathname(path) => {
+            let mut name = path.as_os_str().as_bytes().to_vec();
+            name.push(0);
+            name
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        AddrKind::Abstract(name) => [&[0], name].concat(),
+        AddrKind::Unnamed => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't connect to an unnamed address",
+            ))
+        }
+    };
+    let (sockaddr, len) = sockaddr_un(&name)?;
+
+    let socket = stream_socket(true)?;
+    // SAFETY: sockaddr is a valid sockaddr_un and len is no more than its size.
+    let result = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &sockaddr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    };
+    if result < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(UnixStream::from(socket))
+}
+
+/// Creates a close-on-exec Unix stream socket, in non-blocking mode if
+/// `nonblocking` is set.
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+pub(crate) fn stream_socket(nonblocking: bool) -> io::Result<OwnedFd> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let ty = libc::SOCK_STREAM | libc::SOCK_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let ty = libc::SOCK_STREAM;
+
+    // SAFETY: socket() has no memory safety requirements.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, ty, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a newly created socket that nothing else owns.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    // SAFETY: fcntl() on an owned fd has no memory safety requirements.
+    if unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fcntl() on an owned fd has no memory safety requirements.
+    if nonblocking
+        && unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+/// Creates a `sockaddr_un` for the raw `name` and returns it with its length.
+///
+/// `name` is copied into `sun_path` as is, so it has to include the trailing
+/// null byte of a pathname or the leading null byte of an abstract name.
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+pub(crate) fn sockaddr_un(name: &[u8]) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // SAFETY: sockaddr_un is a plain C struct for which all zeros is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() > addr.sun_path.len() {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sockaddr_un>() - addr.sun_path.len() + name.len();
+
+    Ok((addr, len as libc::socklen_t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_kind_reports_pathname() {
+        let addr = SocketAddr::from_pathname("/tmp/sock").expect("Can't create address");
+
+        assert_eq!(
+            AddrKind::from(&addr),
+            AddrKind::Pathname(Path::new("/tmp/sock"))
+        );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn addr_kind_reports_abstract_name() {
+        let addr = abstract_addr(b"sock").expect("Can't create address");
+
+        assert_eq!(AddrKind::from(&addr), AddrKind::Abstract(b"sock"));
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! An implementation of [`EnqueueFd`] and [`DequeueFd`] that is integrated with
+//! async-io.
+//!
+//! The types in this module implement the `futures-io` [`AsyncRead`] and
+//! [`AsyncWrite`] traits so they can be used with any runtime that is built on
+//! `async-io` (such as `smol`) or that otherwise works with `futures-io`.
+
+use std::{
+    convert::TryFrom,
+    io::{self, ErrorKind, IoSlice, IoSliceMut},
+    net::Shutdown,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{SocketAddr, UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use ::async_io::Async;
+use futures_core::{ready, stream::Stream};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    activation::{self, SocketKind},
+    addr,
+    biqueue::BiQueue,
+    builder::{BoundPath, FromBound, ListenerBuilder},
+    path::ShortPath,
+    process::{self, FromStdStream},
+    DequeueFd, EnqueueFd, QueueFullError,
+};
+
+/// A structure representing a connected Unix socket with support for passing
+/// [`RawFd`].
+///
+/// This is the implementation of [`EnqueueFd`] and [`DequeueFd`] that is based
+/// on an `async-io` [`Async`] Unix stream. Conceptually the key interfaces on
+/// `UnixStream` interact as shown in the following diagram:
+///
+/// ```text
+/// EnqueueFd => AsyncWrite => AsyncRead => DequeueFd
+/// ```
+///
+/// That is, you first enqueue a [`RawFd`] to the `UnixStream` and then
+/// [`AsyncWrite`] at least one byte. On the other side of the `UnixStream` you
+/// then [`AsyncRead`] at least one byte and then dequeue the [`RawFd`].
+///
+/// # Examples
+///
+/// ```
+/// # use fd_queue::{EnqueueFd, DequeueFd, async_io::UnixStream};
+/// # use std::os::unix::io::FromRawFd;
+/// # use tempfile::tempfile;
+/// use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+/// use std::fs::File;
+///
+/// # async_io::block_on(async {
+/// let (mut sock1, mut sock2) = UnixStream::pair()?;
+///
+/// // sender side
+/// # let file1: File = tempfile()?;
+/// // let file1: File = ...
+/// sock1.enqueue(&file1).expect("Can't enqueue the file descriptor.");
+/// sock1.write(b"a").await?;
+/// sock1.flush().await?;
+///
+/// // receiver side
+/// let mut buf = [0u8; 1];
+/// sock2.read(&mut buf).await?;
+/// let fd = sock2.dequeue().expect("Can't dequeue the file descriptor.");
+/// let file2 = unsafe { File::from_raw_fd(fd) };
+/// #
+/// # Ok::<(), std::io::Error>(())
+/// # })?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct UnixStream {
+    inner: Async<StdUnixStream>,
+    biqueue: BiQueue,
+}
+
+/// A Unix socket which can accept connections from other Unix sockets.
+///
+/// You can accept a new connection by using the accept method. Alternatively
+/// `UnixListener` implements the [`Stream`] trait, which allows you to use the
+/// listener in places that want a stream. The stream will never return `None`
+/// and will also not yield the peer's [`SocketAddr`] structure.
+///
+/// # Examples
+///
+/// ```
+/// # use tempfile::tempdir;
+/// use fd_queue::async_io::{UnixStream, UnixListener};
+/// use futures_util::{io::{AsyncReadExt, AsyncWriteExt}, stream::StreamExt};
+///
+/// # async_io::block_on(async {
+/// # let dir = tempdir()?;
+/// # let path = dir.path().join("mysock");
+/// // let path: Path = ...
+/// let mut listener = UnixListener::bind(&path)?;
+///
+/// let mut sock1 = UnixStream::connect(path).await?;
+/// sock1.write(b"Hello World!").await?;
+///
+/// let mut sock2 = listener.next().await.expect("Listener stream unexpectedly empty")?;
+///
+/// let mut buf = [0u8; 256];
+/// sock2.read(&mut buf).await?;
+///
+/// assert!(buf.starts_with(b"Hello World!"));
+/// #
+/// # Ok::<(), std::io::Error>(())
+/// # })?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct UnixListener {
+    inner: Async<StdUnixListener>,
+    path: Option<BoundPath>,
+}
+
+// === impl UnixStream ===
+
+impl UnixStream {
+    /// The size of the bounded queue of outbound [`RawFd`].
+    pub const FD_QUEUE_SIZE: usize = BiQueue::FD_QUEUE_SIZE;
+
+    /// Connects to the socket named by `path`.
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<UnixStream> {
+        let path = ShortPath::new(path.as_ref())?;
+        Async::<StdUnixStream>::connect(&path)
+            .await
+            .map(|s| s.into())
+    }
+
+    /// Connects to the socket named by `name` relative to the directory `dir`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub async fn connect_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixStream> {
+        let path = ShortPath::at(dir.as_raw_fd(), name.as_ref())?;
+        Async::<StdUnixStream>::connect(&path)
+            .await
+            .map(|s| s.into())
+    }
+
+    /// Connects to the socket specified by `addr`.
+    ///
+    /// This can connect to an address in the Linux abstract namespace.
+    pub async fn connect_addr(addr: &SocketAddr) -> io::Result<UnixStream> {
+        let stream = Async::new(addr::connect_nonblocking(addr)?)?;
+        stream.writable().await?;
+        match stream.get_ref().take_error()? {
+            Some(e) => Err(e),
+            None => Ok(stream.into()),
+        }
+    }
+
+    /// Takes the end of the channel that the parent process created with
+    /// [`process::Command`][crate::process::Command].
+    ///
+    /// This returns a `NotFound` error if the process was not spawned with a
+    /// channel, or if the channel was already taken.
+    pub fn from_parent() -> io::Result<UnixStream> {
+        UnixStream::try_from(StdUnixStream::from(process::take_parent_channel()?))
+    }
+
+    /// Adopts the connected Unix stream sockets passed to this process through
+    /// systemd socket activation.
+    ///
+    /// Passed fds that are not connected Unix stream sockets are left alone, and
+    /// each fd is only adopted once.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixStream>> {
+        activation::take_listen_fds(SocketKind::Stream)?
+            .into_iter()
+            .map(|fd| UnixStream::try_from(StdUnixStream::from(fd)))
+            .collect()
+    }
+
+    /// Creates an unnamed pair of connected sockets.
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        Async::<StdUnixStream>::pair().map(|(s1, s2)| (s1.into(), s2.into()))
+    }
+
+    /// Returns the socket address of the local half of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().local_addr()
+    }
+
+    /// Returns the socket address of the remote half of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().peer_addr()
+    }
+
+    /// Returns the value of the `SO_ERROR` option.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.get_ref().take_error()
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    ///
+    /// This function will cause all pending and future I/O calls on the specified
+    /// portions to immediately return with an appropriate value (see the
+    /// documentation of `Shutdown`).
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.get_ref().shutdown(how)
+    }
+}
+
+impl EnqueueFd for UnixStream {
+    fn enqueue(&mut self, fd: &impl AsRawFd) -> Result<(), QueueFullError> {
+        self.biqueue.enqueue(fd)
+    }
+
+    fn clear_enqueued(&mut self) {
+        self.biqueue.clear_enqueued()
+    }
+}
+
+impl DequeueFd for UnixStream {
+    fn dequeue(&mut self) -> Option<RawFd> {
+        self.biqueue.dequeue()
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsyncRead for UnixStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_read_vectored(cx, &mut [IoSliceMut::new(buf)])
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let fd = this.inner.as_raw_fd();
+
+        loop {
+            match this.biqueue.read_vectored(fd, bufs) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            ready!(this.inner.poll_readable(cx))?;
+        }
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let fd = this.inner.as_raw_fd();
+
+        loop {
+            match this.biqueue.write_vectored(fd, bufs) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            ready!(this.inner.poll_writable(cx))?;
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown(Shutdown::Write))
+    }
+}
+
+impl From<Async<StdUnixStream>> for UnixStream {
+    fn from(inner: Async<StdUnixStream>) -> UnixStream {
+        UnixStream {
+            inner,
+            biqueue: BiQueue::new(),
+        }
+    }
+}
+
+impl TryFrom<StdUnixStream> for UnixStream {
+    type Error = io::Error;
+
+    fn try_from(inner: StdUnixStream) -> Result<Self, Self::Error> {
+        Async::new(inner).map(|stream| stream.into())
+    }
+}
+
+// === impl UnixListener ===
+
+impl UnixListener {
+    /// Creates a builder for a `UnixListener` that manages its socket file.
+    ///
+    /// See [`ListenerBuilder`] for the available options.
+    pub fn builder() -> ListenerBuilder<UnixListener> {
+        ListenerBuilder::new()
+    }
+
+    /// Keeps the socket file when this listener is dropped.
+    ///
+    /// See [`fd_queue::UnixListener::keep_socket_file()`][crate::UnixListener::keep_socket_file()].
+    pub fn keep_socket_file(&mut self) {
+        if let Some(path) = self.path.as_mut() {
+            path.keep();
+        }
+    }
+
+    /// Adopts the Unix listeners passed to this process through systemd socket
+    /// activation.
+    ///
+    /// The fds are found through the `LISTEN_PID` and `LISTEN_FDS` environment
+    /// variables. Passed fds that are not listening Unix stream sockets are left
+    /// alone, and each fd is only adopted once.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixListener>> {
+        activation::take_listen_fds(SocketKind::Listener)?
+            .into_iter()
+            .map(|fd| UnixListener::try_from(StdUnixListener::from(fd)))
+            .collect()
+    }
+
+    /// Adopts the Unix listener named `name` that was passed to this process
+    /// through systemd socket activation.
+    ///
+    /// The names come from the `LISTEN_FDNAMES` environment variable. This returns
+    /// a `NotFound` error if there is no listening Unix stream socket named `name`
+    /// left to adopt.
+    pub fn from_listen_fd_name(name: &str) -> io::Result<UnixListener> {
+        activation::take_listen_fd_name(SocketKind::Listener, name)
+            .and_then(|fd| UnixListener::try_from(StdUnixListener::from(fd)))
+    }
+
+    /// Creates a new `UnixListener` bound to the specified path.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
+        Async::<StdUnixListener>::bind(ShortPath::new(path.as_ref())?).map(|l| l.into())
+    }
+
+    /// Creates a new `UnixListener` bound to `name` relative to the directory `dir`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixListener> {
+        Async::<StdUnixListener>::bind(ShortPath::at(dir.as_raw_fd(), name.as_ref())?)
+            .map(|l| l.into())
+    }
+
+    /// Creates a new `UnixListener` bound to the specified address.
+    ///
+    /// This can bind to an address in the Linux abstract namespace.
+    pub fn bind_addr(addr: &SocketAddr) -> io::Result<UnixListener> {
+        UnixListener::try_from(StdUnixListener::bind_addr(addr)?)
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        BoundPath::local_addr(&self.path, || self.inner.get_ref().local_addr())
+    }
+
+    /// Returns the value of the `SO_ERROR` option.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.get_ref().take_error()
+    }
+
+    /// Accepts a new incoming connection on this listener.
+    pub async fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+        let (stream, addr) = self.inner.read_with(|l| l.accept()).await?;
+
+        Ok((UnixStream::try_from(stream)?, addr))
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(UnixStream, SocketAddr)>> {
+        loop {
+            match self.inner.get_ref().accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => {
+                    return Poll::Ready(
+                        result.and_then(|(stream, addr)| Ok((UnixStream::try_from(stream)?, addr))),
+                    )
+                }
+            }
+            ready!(self.inner.poll_readable(cx))?;
+        }
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Produces a continuous stream of accepted connections.
+///
+/// This is the equivalent of calling `accept()` in a loop. It will never be ready
+/// with `None`.
+impl Stream for UnixListener {
+    type Item = io::Result<UnixStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
+
+impl From<Async<StdUnixListener>> for UnixListener {
+    fn from(inner: Async<StdUnixListener>) -> UnixListener {
+        UnixListener { inner, path: None }
+    }
+}
+
+impl FromStdStream for UnixStream {
+    fn from_std_stream(stream: StdUnixStream) -> io::Result<Self> {
+        UnixStream::try_from(stream)
+    }
+}
+
+impl FromBound for UnixListener {
+    fn from_bound(inner: StdUnixListener, path: BoundPath) -> io::Result<Self> {
+        Async::new(inner).map(|inner| UnixListener {
+            inner,
+            path: Some(path),
+        })
+    }
+}
+
+impl TryFrom<StdUnixListener> for UnixListener {
+    type Error = io::Error;
+
+    fn try_from(inner: StdUnixListener) -> Result<Self, Self::Error> {
+        Async::new(inner).map(|listener| listener.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{prelude::*, SeekFrom};
+    use std::os::unix::io::FromRawFd as _;
+
+    use futures_util::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        stream::StreamExt,
+    };
+    use tempfile::{tempdir, tempfile};
+
+    #[test]
+    fn unix_stream_passes_fd() {
+        let mut file1 = tempfile().expect("Can't create temp file.");
+        file1
+            .write_all(b"Hello World!\0")
+            .expect("Can't write to temp file.");
+        file1
+            .seek(SeekFrom::Start(0))
+            .expect("Couldn't seek the file.");
+        let mut buf = [0u8];
+
+        let (mut sut, mut other) = UnixStream::pair().expect("Can't create UnixStream's");
+        ::async_io::block_on(async {
+            other.enqueue(&file1).expect("Can't enqueue fd.");
+            other
+                .write_all(b"1".as_ref())
+                .await
+                .expect("Can't write to UnixStream");
+            sut.read_exact(buf.as_mut())
+                .await
+                .expect("Can't read from UnixStream");
+        });
+        let fd = sut.dequeue().expect("Can't dequeue fd");
+
+        let mut file2 = unsafe { File::from_raw_fd(fd) };
+        let mut buf2 = Vec::new();
+        file2.read_to_end(&mut buf2).expect("Can't read from file");
+        assert_eq!(&buf2[..], b"Hello World!\0".as_ref());
+    }
+
+    #[test]
+    fn unix_stream_connects_to_listener_stream() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let sock_addr = dir.as_ref().join("socket");
+        let mut buf: [u8; 12] = [0; 12];
+
+        let mut listener = UnixListener::bind(&sock_addr).expect("Can't bind listener");
+        ::async_io::block_on(async {
+            let mut client = UnixStream::connect(sock_addr)
+                .await
+                .expect("Can't connect to listener");
+            client
+                .write_all(b"Hello World!".as_ref())
+                .await
+                .expect("Can't write to client");
+            let mut server = listener
+                .next()
+                .await
+                .expect("Listener stream unexpectedly empty")
+                .expect("Can't accept on listener");
+            server
+                .read_exact(buf.as_mut())
+                .await
+                .expect("Can't read from server");
+        });
+
+        assert_eq!(&buf, b"Hello World!");
+    }
+
+    #[test]
+    fn unix_stream_connect_addr_connects_to_pathname() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("socket")).expect("Can't bind listener");
+        let addr = listener.local_addr().expect("Can't get local address");
+
+        let client = ::async_io::block_on(UnixStream::connect_addr(&addr))
+            .expect("Can't connect to listener");
+
+        let remote = client.peer_addr().expect("Can't get peer address");
+        assert_eq!(remote.as_pathname(), addr.as_pathname());
+    }
+
+    #[test]
+    fn unix_stream_connect_addr_rejects_unnamed_addr() {
+        let (sock, _) = UnixStream::pair().expect("Can't create UnixStream's");
+        let addr = sock.local_addr().expect("Can't get local address");
+
+        let result = ::async_io::block_on(UnixStream::connect_addr(&addr));
+
+        assert_eq!(
+            result.expect_err("Connected to an unnamed address").kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -12,15 +12,18 @@
 use std::{
     collections::VecDeque,
     fmt,
-    io::{self, Error, ErrorKind, IoSlice, IoSliceMut},
+    io::{self, Error, ErrorKind, IoSlice},
     iter,
-    os::unix::io::{AsRawFd, IntoRawFd, RawFd},
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
 };
 
 use ::tracing::{trace, warn};
 
 use crate::{DequeueFd, EnqueueFd, QueueFullError};
-use iomsg::{cmsg_buffer_fds_space, Fd, MsgHdr};
+use iomsg::{cmsg_buffer_fds_space, Fd, MsgHdr, RecvBuf};
+
+#[cfg(feature = "tokio-fd")]
+pub use iomsg::IoSliceUninit;
 
 mod iomsg;
 
@@ -59,23 +62,84 @@ impl BiQueue {
     }
 
     pub fn write_vectored(&mut self, fd: impl AsRawFd, bufs: &[IoSlice]) -> io::Result<usize> {
-        let outfd = self.outfd.take();
-
-        match outfd {
-            Some(mut outfds) => send_fds(fd.as_raw_fd(), bufs, outfds.drain(..)),
+        let result = match self.outfd {
+            Some(ref outfds) => send_fds(fd.as_raw_fd(), bufs, outfds.iter().copied()),
             None => send_fds(fd.as_raw_fd(), bufs, iter::empty()),
+        };
+
+        // Keep the outbound fd's queued if nothing was sent (e.g. the write
+        // would have blocked) so that they go out with the next write.
+        if result.is_ok() {
+            self.outfd = None;
         }
+
+        result
     }
 
-    pub fn read_vectored(
+    pub fn read_vectored<B: RecvBuf>(
         &mut self,
         fd: impl AsRawFd,
-        bufs: &mut [IoSliceMut],
+        bufs: &mut [B],
     ) -> io::Result<usize> {
-        recv_fds(fd.as_raw_fd(), bufs, self)
+        recv_fds(fd.as_raw_fd(), bufs, self, 0)
+    }
+
+    /// Take all of the inbound fd's as owned fd's.
+    #[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        let mut fds = Vec::with_capacity(self.infd.len());
+        for fd in self.infd.drain(..) {
+            let _ = Push::push(&mut fds, fd);
+        }
+
+        trace!(source = "UnixStream", event = "dequeue", count = fds.len());
+
+        fds
     }
 }
 
+/// Send `bufs` together with `fds` over the unix stream `fd` in a single
+/// `sendmsg` call without going through the outbound queue of a `BiQueue`.
+#[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+pub fn send_with_fds(
+    fd: impl AsRawFd,
+    bufs: &[IoSlice],
+    fds: &[std::os::unix::io::BorrowedFd],
+) -> io::Result<usize> {
+    send_fds(fd.as_raw_fd(), bufs, fds.iter().map(|fd| fd.as_raw_fd()))
+}
+
+/// Receive into `bufs` from the unix stream `fd` in a single `recvmsg` call,
+/// pushing any fd's that arrive with the bytes onto `fds` instead of onto the
+/// inbound queue of a `BiQueue`.
+#[cfg(feature = "tokio-fd")]
+pub fn recv_with_fds(
+    fd: impl AsRawFd,
+    bufs: &mut [std::io::IoSliceMut],
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<usize> {
+    recv_fds(fd.as_raw_fd(), bufs, fds, 0)
+}
+
+/// Peek at up to `buf.len()` bytes of the unix stream `fd` without consuming
+/// them, returning how many bytes there are and whether any fd's are waiting.
+///
+/// A peek can't tell which bytes the fd's were sent with (Linux reports the
+/// waiting fd's even for a peek that stops short of those bytes), only that
+/// they are waiting. The peeked fd's are duplicates, so they are closed again.
+#[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+pub fn peek_fds_waiting(fd: impl AsRawFd, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+    let mut fds: Vec<OwnedFd> = Vec::new();
+    let len = recv_fds(
+        fd.as_raw_fd(),
+        &mut [std::io::IoSliceMut::new(buf)],
+        &mut fds,
+        libc::MSG_PEEK,
+    )?;
+
+    Ok((len, !fds.is_empty()))
+}
+
 impl DequeueFd for BiQueue {
     fn dequeue(&mut self) -> Option<RawFd> {
         let result = self.infd.pop_front().map(|fd| fd.into_raw_fd());
@@ -97,6 +161,15 @@ impl Push<Fd> for BiQueue {
     }
 }
 
+impl Push<Fd> for Vec<OwnedFd> {
+    fn push(&mut self, item: Fd) -> Result<(), Fd> {
+        // Safety: item is the owner of the contained RawFd and into_raw_fd()
+        // gives up that ownership so the new OwnedFd becomes its only owner.
+        Vec::push(self, unsafe { OwnedFd::from_raw_fd(item.into_raw_fd()) });
+        Ok(())
+    }
+}
+
 impl EnqueueFd for BiQueue {
     fn enqueue(&mut self, fd: &impl AsRawFd) -> std::result::Result<(), QueueFullError> {
         let outfd = self
@@ -111,10 +184,18 @@ impl EnqueueFd for BiQueue {
             Ok(())
         }
     }
+
+    fn clear_enqueued(&mut self) {
+        if let Some(outfd) = self.outfd.take() {
+            trace!(source = "UnixStream", event = "clear", count = outfd.len());
+        }
+    }
 }
 
 // === helper functions ===
 
+const _: () = assert!(BiQueue::FD_QUEUE_SIZE <= constants::MAX_FD_COUNT);
+
 fn send_fds(
     sockfd: RawFd,
     bufs: &[IoSlice],
@@ -124,10 +205,9 @@ fn send_fds(
         constants::CMSG_SCM_RIGHTS_SPACE as usize,
         cmsg_buffer_fds_space(constants::MAX_FD_COUNT)
     );
-    assert!(BiQueue::FD_QUEUE_SIZE <= constants::MAX_FD_COUNT);
 
     // Size the buffer to be big enough to hold MAX_FD_COUNT RawFd's.
-    // The assertions above ensure that this is the case. The buffer
+    // The assertions ensure that this is the case. The buffer
     // must be zeroed because subsequent code will not clear padding
     // bytes.
     let mut cmsg_buffer = [0u8; constants::CMSG_SCM_RIGHTS_SPACE as _];
@@ -146,10 +226,11 @@ fn send_fds(
     Ok(counts.bytes_sent())
 }
 
-fn recv_fds(
+fn recv_fds<B: RecvBuf>(
     sockfd: RawFd,
-    bufs: &mut [IoSliceMut],
+    bufs: &mut [B],
     fds_sink: &mut impl Push<Fd>,
+    flags: libc::c_int,
 ) -> io::Result<usize> {
     debug_assert_eq!(
         constants::CMSG_SCM_RIGHTS_SPACE as usize,
@@ -160,7 +241,7 @@ fn recv_fds(
     // The assertion above ensure that this is the case.
     let mut cmsg_buffer = [0u8; constants::CMSG_SCM_RIGHTS_SPACE as _];
 
-    let mut recv = MsgHdr::from_io_slice_mut(bufs, &mut cmsg_buffer).recv(sockfd)?;
+    let mut recv = MsgHdr::from_io_slice_mut(bufs, &mut cmsg_buffer).recv(sockfd, flags)?;
 
     let mut fds_count = 0;
     for fd in recv.take_fds() {
@@ -200,6 +281,7 @@ fn recv_fds(
 
 // === impl CMsgTruncatedError ===
 impl CMsgTruncatedError {
+    #[allow(clippy::new_ret_no_self)]
     fn new() -> Error {
         Error::new(ErrorKind::Other, CMsgTruncatedError {})
     }
@@ -218,6 +300,7 @@ impl std::error::Error for CMsgTruncatedError {}
 
 // === impl PushFailureError ===
 impl PushFailureError {
+    #[allow(clippy::new_ret_no_self)]
     fn new() -> Error {
         Error::new(ErrorKind::Other, PushFailureError {})
     }
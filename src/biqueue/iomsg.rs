@@ -13,16 +13,13 @@ use std::{
     error, fmt,
     io::{self, IoSlice, IoSliceMut},
     marker::PhantomData,
-    mem,
+    mem::{self, MaybeUninit},
     ops::Neg,
     os::unix::io::{IntoRawFd, RawFd},
     ptr::{self, NonNull},
     slice,
 };
 
-// needed until the MSRV is 1.43 when the associated constant becomes available
-use std::isize;
-
 use libc::{
     c_int, c_uint, close, cmsghdr, iovec, msghdr, recvmsg, sendmsg, CMSG_DATA, CMSG_FIRSTHDR,
     CMSG_LEN, CMSG_NXTHDR, CMSG_SPACE, MSG_CTRUNC, SCM_RIGHTS, SOL_SOCKET,
@@ -43,6 +40,7 @@ pub struct MsgHdr<'a, State> {
     _phantom: PhantomData<(&'a mut [iovec], &'a mut [u8])>,
 }
 
+#[allow(dead_code)]
 trait NullableControl {}
 
 // The type states for MsgHdr are used to implement the following
@@ -151,6 +149,28 @@ struct CMsgMut<'a> {
     _phantom: PhantomData<&'a mut msghdr>,
 }
 
+/// A buffer that `recvmsg` can receive bytes into.
+///
+/// # Safety
+///
+/// Implementors must have the same layout as `libc::iovec` and the buffer
+/// that the `iovec` describes must be valid for writes for its whole length.
+pub unsafe trait RecvBuf {}
+
+/// An `iovec` over a buffer whose bytes may not have been initialized.
+///
+/// `recvmsg` only writes into the buffers it is given so it is sound to
+/// receive into uninitialized memory. The bytes that `recvmsg` reports as
+/// received are initialized once it returns.
+#[repr(transparent)]
+#[cfg_attr(not(feature = "tokio-fd"), allow(dead_code))]
+pub struct IoSliceUninit<'a> {
+    // Invariant: iov describes a buffer that is valid for writes for
+    // iov_len bytes for the lifetime 'a.
+    iov: iovec,
+    _phantom: PhantomData<&'a mut [MaybeUninit<u8>]>,
+}
+
 /// A safe owner of a contained RawFd.
 #[derive(Debug)]
 pub struct Fd {
@@ -190,8 +210,8 @@ impl<'a, State: Default> MsgHdr<'a, State> {
 }
 
 impl<'a> MsgHdr<'a, RecvStart> {
-    pub fn from_io_slice_mut(bufs: &'a mut [IoSliceMut], cmsg_buffer: &'a mut [u8]) -> Self {
-        // IoSliceMut guarentees ABI compatibility with iovec.
+    pub fn from_io_slice_mut<B: RecvBuf>(bufs: &'a mut [B], cmsg_buffer: &'a mut [u8]) -> Self {
+        // RecvBuf guarentees ABI compatibility with iovec.
         let iov: *mut iovec = bufs.as_mut_ptr() as *mut iovec;
         let iov_len = bufs.len();
 
@@ -201,11 +221,11 @@ impl<'a> MsgHdr<'a, RecvStart> {
         unsafe { Self::new(iov, iov_len, cmsg_buffer) }
     }
 
-    pub fn recv(mut self, sockfd: RawFd) -> io::Result<MsgHdrRecvEnd<'a>> {
+    pub fn recv(mut self, sockfd: RawFd, flags: c_int) -> io::Result<MsgHdrRecvEnd<'a>> {
         // Safety: the invariants on self.mhdr mean that it has been properly
         // initalized for passing to recvmsg.
         let count =
-            call_res(|| unsafe { recvmsg(sockfd, &mut self.mhdr, 0) }).map(|c| c as usize)?;
+            call_res(|| unsafe { recvmsg(sockfd, &mut self.mhdr, flags) }).map(|c| c as usize)?;
 
         // Invariant: self.mhdr satified the invariant at the start of this call.
         // recvmsg can write into the buffers pointed to by the iovec's found
@@ -456,9 +476,7 @@ impl<'a> Iterator for FdsIter<'a> {
                 Some(data) => return Some(data),
                 None => {
                     self.advance_cmsg();
-                    if self.cmsg.is_none() {
-                        return None;
-                    }
+                    self.cmsg?;
                 }
             };
         }
@@ -493,7 +511,7 @@ impl FdsIterData {
             let pcmsg: *const cmsghdr = cmsg;
             // Safety: follows from pre-condition,  from the defintion of a
             // cmsg, and from the assertion above.
-            let p_end = (pcmsg.cast::<u8>()).offset(cmsg.cmsg_len as isize);
+            let p_end = (pcmsg.cast::<u8>()).add(cmsg.cmsg_len);
 
             let data_size = (p_end as usize) - (p_start as usize);
             // This may round down if the data portion is bigger than an
@@ -503,15 +521,15 @@ impl FdsIterData {
             let curr = p_start.cast::<RawFd>();
             // Safety: curr points to the first byte of the implict cmsg_data
             // member of the cmsg which is properly initalized by the precondition;
-            // curr.offset(fds_count) is <= p_end by the way fds_count is calculated
+            // curr.add(fds_count) is <= p_end by the way fds_count is calculated
             // so it is also either in the implict cmsg_data member of cmsg or is
             // one byte past the end; the assertion above guarentees that the offset
             // in bytes implied by fds_count <= isize::MAX.
-            let end = curr.offset(fds_count as isize);
+            let end = curr.add(fds_count);
 
             // Invariants:
             //      1. curr is non-null by defintion of CMSG_DATA; end is
-            //          non-null by defintion of offset.
+            //          non-null by defintion of add.
             //      2. end is offset from curr by fds_count which is non-negative
             //          so curr <= end.
             //      3. cmsg is properly initalized (by the precondition) and is
@@ -571,7 +589,7 @@ impl<'a> CMsgMut<'a> {
         // CMSG_FIRSTHDR.
         let cmsg = CMSG_FIRSTHDR(mhdr);
 
-        if cmsg == ptr::null_mut() {
+        if cmsg.is_null() {
             None
         } else {
             // Safety: from the precondition msg_control points to a byte
@@ -613,7 +631,7 @@ impl<'a> CMsgMut<'a> {
         // and has been initalized. The only pointer to the memory pointed to
         // by self.cmsg is self.cmsg so the only way to read/write this memory
         // for the rest of this method is through cmsg.
-        let mut cmsg = unsafe { self.cmsg.as_mut() };
+        let cmsg = unsafe { self.cmsg.as_mut() };
         if cmsg_len < cmsg.cmsg_len {
             // Invariant: shrinking cmsg.cmsg_len maintains the invariant
             // that the bytes buffer pointed to by cmsg is valid for reads
@@ -649,6 +667,36 @@ impl<'a> CMsgMut<'a> {
     }
 }
 
+// Safety: IoSliceMut guarentees ABI compatibility with iovec and describes
+// a buffer of initialized bytes that is valid for writes.
+unsafe impl RecvBuf for IoSliceMut<'_> {}
+
+// Safety: IoSliceUninit is a transparent wrapper around an iovec and its
+// invariant is that the buffer is valid for writes.
+unsafe impl RecvBuf for IoSliceUninit<'_> {}
+
+impl<'a> IoSliceUninit<'a> {
+    #[cfg_attr(not(feature = "tokio-fd"), allow(dead_code))]
+    pub fn new(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        // Invariant: buf is a mutable borrow for 'a of buf.len() bytes.
+        IoSliceUninit {
+            iov: iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            },
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for IoSliceUninit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoSliceUninit")
+            .field("len", &self.iov.iov_len)
+            .finish()
+    }
+}
+
 impl Fd {
     // Precondition: fd is the only retained copy of that RawFd.
     fn new(fd: RawFd) -> Self {
@@ -680,6 +728,7 @@ impl IntoRawFd for Fd {
 struct CMsgBufferTooSmallError {}
 
 impl CMsgBufferTooSmallError {
+    #[allow(clippy::new_ret_no_self)]
     fn new() -> io::Error {
         io::Error::new(io::ErrorKind::Other, CMsgBufferTooSmallError {})
     }
@@ -729,7 +778,7 @@ mod tests {
         let bufs: [IoSlice; 0] = [];
         let fds = [1, 2, 3, 4];
         let mhdr = MsgHdr::from_io_slice(&bufs, &mut control_buffer)
-            .encode_fds(fds.iter().map(|fd| *fd))
+            .encode_fds(fds.iter().copied())
             .expect("Can't encode fds");
 
         let mut sut = MsgHdrRecvEnd {
@@ -752,7 +801,7 @@ mod tests {
         let fds = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
 
         let sut = MsgHdr::from_io_slice(&bufs, &mut control_buffer);
-        let result = sut.encode_fds(fds.iter().map(|fd| *fd));
+        let result = sut.encode_fds(fds.iter().copied());
 
         assert!(result.is_err());
     }
@@ -811,13 +860,40 @@ mod tests {
         let file = tempfile::tempfile().expect("Can't get temporary file.");
 
         let sut = MsgHdr::from_io_slice_mut(&mut bufs, &mut control_buffer);
-        let result = sut.recv(file.as_raw_fd());
+        let result = sut.recv(file.as_raw_fd(), 0);
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn recv_start_recv_into_uninit_buffer() {
+        let mut control_buffer = [0u8; 0];
+        let mut bytes = [MaybeUninit::<u8>::uninit(); 16];
+        let (sock1, sock2) = std::os::unix::net::UnixStream::pair().expect("Can't create pair.");
+        let sent = [IoSlice::new(b"hello")];
+        MsgHdr::from_io_slice(&sent, &mut control_buffer)
+            .encode_fds(iter::empty())
+            .expect("Can't encode fds")
+            .send(sock1.as_raw_fd())
+            .expect("Can't send");
+
+        let mut bufs = [IoSliceUninit::new(&mut bytes)];
+        let count = MsgHdr::from_io_slice_mut(&mut bufs, &mut control_buffer)
+            .recv(sock2.as_raw_fd(), 0)
+            .expect("Can't recv")
+            .bytes_recvieved();
+
+        assert_eq!(count, 5);
+        // Safety: recvmsg initialized the first count bytes.
+        let received: Vec<u8> = bytes[..count]
+            .iter()
+            .map(|b| unsafe { b.assume_init() })
+            .collect();
+        assert_eq!(received, b"hello");
+    }
+
     unsafe fn encode_fds(cmsg: *mut cmsghdr, fds: &[RawFd]) {
-        let data_size = fds.len() * mem::size_of::<RawFd>();
+        let data_size = mem::size_of_val(fds);
         (*cmsg).cmsg_len = CMSG_LEN((data_size) as u32) as usize;
         (*cmsg).cmsg_level = SOL_SOCKET;
         (*cmsg).cmsg_type = SCM_RIGHTS;
@@ -0,0 +1,753 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A broker that opens files on behalf of sandboxed clients.
+//!
+//! A sandboxed process often can't open files itself. A [`Broker`] accepts
+//! connections from such processes, checks each request to open a file against a
+//! [`Policy`] together with the credentials of the client that made it, opens the
+//! file and passes the fd back. The broker opens files with `openat2(2)` and
+//! `RESOLVE_BENEATH` so that a path can't use `..` or a symlink to escape the
+//! directory that the policy granted. A [`Client`] makes the requests.
+//!
+//! The [`socket`] module has a broker that creates and binds network sockets in
+//! the same way.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::broker::{Access, Allowlist, Broker, Client};
+//! use fd_queue::UnixListener;
+//! use std::fs::File;
+//! use std::io::prelude::*;
+//! use std::thread;
+//! # use tempfile::tempdir;
+//! # let dir = tempdir()?;
+//! # std::fs::write(dir.path().join("motd"), "Hello World!")?;
+//! # let path = dir.path().join("broker.sock");
+//!
+//! // In the broker.
+//! let mut policy = Allowlist::new();
+//! policy.allow_dir(dir.path(), Access::ReadOnly)?;
+//! let broker = Broker::new(UnixListener::bind(&path)?, policy);
+//! thread::spawn(move || broker.run());
+//!
+//! // In the sandboxed client.
+//! let mut client = Client::connect(&path)?;
+//! let mut motd = File::from(client.open(dir.path().join("motd"), libc::O_RDONLY)?);
+//! let mut buf = String::new();
+//! motd.read_to_string(&mut buf)?;
+//! # assert_eq!(buf, "Hello World!");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    error,
+    ffi::{CString, OsString},
+    fmt, fs,
+    io::{self, prelude::*, Error, ErrorKind},
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::OpenOptionsExt,
+        io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    thread,
+};
+
+use tracing::warn;
+
+use crate::{EnqueueFd, UCred, UnixListener, UnixStream};
+
+pub mod socket;
+
+/// The `open(2)` flags that a client can use.
+///
+/// The broker always adds `O_CLOEXEC` and `O_NOCTTY` to these.
+pub const ALLOWED_FLAGS: i32 = libc::O_ACCMODE
+    | libc::O_CREAT
+    | libc::O_EXCL
+    | libc::O_TRUNC
+    | libc::O_APPEND
+    | libc::O_NONBLOCK
+    | libc::O_DIRECTORY
+    | libc::O_NOFOLLOW
+    | libc::O_CLOEXEC;
+
+// From linux/openat2.h.
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+const REQUEST_HEADER_LEN: usize = 8;
+const RESPONSE_LEN: usize = 5;
+
+/// A server that opens files for its clients as its [`Policy`] allows.
+#[derive(Debug)]
+pub struct Broker<P> {
+    listener: UnixListener,
+    policy: P,
+}
+
+/// A connection to a [`Broker`].
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+}
+
+/// A decision about which requests to open a file a [`Broker`] allows.
+pub trait Policy {
+    /// Decides whether the client with the credentials `cred` may open `path`
+    /// with the `open(2)` flags `flags`.
+    ///
+    /// `path` is the path as the client sent it and `flags` only contains
+    /// [`ALLOWED_FLAGS`]. If the request is allowed this returns the directory to
+    /// open the file beneath and the path of the file relative to it.
+    fn check<'a>(
+        &'a self,
+        cred: &UCred,
+        path: &'a Path,
+        flags: i32,
+    ) -> Result<Grant<'a>, OpenError>;
+}
+
+/// Permission from a [`Policy`] to open a file beneath a directory.
+#[derive(Debug, Clone, Copy)]
+pub struct Grant<'a> {
+    /// The directory to open the file beneath.
+    pub dir: BorrowedFd<'a>,
+
+    /// The path of the file relative to `dir`.
+    pub path: &'a Path,
+
+    /// The `RESOLVE_*` flags of `openat2(2)` to use in addition to
+    /// `RESOLVE_BENEATH`, which the broker always uses.
+    pub resolve: u64,
+}
+
+/// A [`Policy`] that allows opening the files beneath a list of directories.
+///
+/// Clients must send absolute paths. The most specific allowed directory that a
+/// path is beneath decides whether it can be opened for writing.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    dirs: Vec<AllowedDir>,
+    uids: Option<Vec<libc::uid_t>>,
+}
+
+#[derive(Debug)]
+struct AllowedDir {
+    path: PathBuf,
+    dir: OwnedFd,
+    access: Access,
+}
+
+/// How the files beneath an allowed directory can be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The files can only be opened for reading.
+    ReadOnly,
+
+    /// The files can be opened for reading and writing, created and truncated.
+    ReadWrite,
+}
+
+/// The reasons that a [`Broker`] refuses to open a file.
+///
+/// [`Client::open()`] returns these as the inner error of an `io::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// The policy doesn't allow the client to open the path.
+    Denied,
+
+    /// The policy only allows the client to open the path for reading.
+    ReadOnly,
+
+    /// The request used flags other than [`ALLOWED_FLAGS`].
+    UnsupportedFlags,
+
+    /// Opening the file failed with this `errno`.
+    ///
+    /// A path that escapes the granted directory fails with `EXDEV`.
+    Os(i32),
+}
+
+// === impl Broker ===
+
+impl<P: Policy> Broker<P> {
+    /// Creates a broker that accepts clients on `listener` and opens files as
+    /// `policy` allows.
+    pub fn new(listener: UnixListener, policy: P) -> Broker<P> {
+        Broker { listener, policy }
+    }
+
+    /// Accepts clients and serves each of them on its own thread.
+    ///
+    /// Each client can open any number of files before it disconnects, and a
+    /// client that fails is logged and dropped without affecting the others. An
+    /// error accepting a client stops the broker, but only once every connected
+    /// client has disconnected, since their threads are scoped to this call.
+    pub fn run(&self) -> io::Result<()>
+    where
+        P: Sync,
+    {
+        thread::scope(|scope| loop {
+            let (stream, _) = self.listener.accept()?;
+
+            scope.spawn(move || {
+                if let Err(e) = self.serve(stream) {
+                    warn!(
+                        source = "Broker",
+                        event = "serve",
+                        condition = "client failed",
+                        error = %e
+                    );
+                }
+            });
+        })
+    }
+
+    /// Serves the requests of the client connected to `stream` until it
+    /// disconnects.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidData` error if the client sends a malformed
+    /// request.
+    pub fn serve(&self, mut stream: UnixStream) -> io::Result<()> {
+        let cred = stream.peer_cred()?;
+
+        while let Some((path, flags, mode)) = read_request(&mut stream)? {
+            match self.open(&cred, &path, flags, mode) {
+                Ok(fd) => write_response(&mut stream, 0, 0, Some(&fd))?,
+                Err(e) => {
+                    let (status, errno) = e.to_wire();
+                    write_response(&mut stream, status, errno, None)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The listener that clients connect to.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// The policy of the broker.
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    fn open(&self, cred: &UCred, path: &Path, flags: i32, mode: u32) -> Result<OwnedFd, OpenError> {
+        let accmode = flags & libc::O_ACCMODE;
+        if flags & !ALLOWED_FLAGS != 0 || accmode == libc::O_ACCMODE {
+            return Err(OpenError::UnsupportedFlags);
+        }
+
+        let grant = self.policy.check(cred, path, flags)?;
+
+        openat2(
+            grant.dir,
+            grant.path,
+            flags | libc::O_CLOEXEC | libc::O_NOCTTY,
+            // The broker never creates setuid, setgid or sticky files for a client.
+            mode & 0o777,
+            grant.resolve | RESOLVE_BENEATH,
+        )
+        .map_err(|e| OpenError::Os(e.raw_os_error().unwrap_or(libc::EIO)))
+    }
+}
+
+// === impl Client ===
+
+impl Client {
+    /// Connects to the broker listening at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Client> {
+        UnixStream::connect(path).map(Client::from)
+    }
+
+    /// Asks the broker to open `path` with the `open(2)` flags `flags`.
+    ///
+    /// A file that `O_CREAT` creates gets the mode `0o666`, less the broker's
+    /// umask.
+    ///
+    /// # Errors
+    ///
+    /// If the broker refuses to open the file this returns an error whose inner
+    /// error is an [`OpenError`].
+    pub fn open(&mut self, path: impl AsRef<Path>, flags: i32) -> io::Result<OwnedFd> {
+        self.open_with_mode(path, flags, 0o666)
+    }
+
+    /// Asks the broker to open `path` with the `open(2)` flags `flags`, creating
+    /// it with `mode` if `flags` includes `O_CREAT`.
+    ///
+    /// The broker ignores the setuid, setgid and sticky bits of `mode`.
+    pub fn open_with_mode(
+        &mut self,
+        path: impl AsRef<Path>,
+        flags: i32,
+        mode: u32,
+    ) -> io::Result<OwnedFd> {
+        let path = path.as_ref().as_os_str().as_bytes();
+        if path.len() > libc::PATH_MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "path is too long"));
+        }
+
+        let mut request = Vec::with_capacity(4 + REQUEST_HEADER_LEN + path.len());
+        request.extend_from_slice(&((REQUEST_HEADER_LEN + path.len()) as u32).to_be_bytes());
+        request.extend_from_slice(&flags.to_be_bytes());
+        request.extend_from_slice(&mode.to_be_bytes());
+        request.extend_from_slice(path);
+        self.stream.write_all(&request)?;
+        self.stream.flush()?;
+
+        let (status, errno, fd) = read_response(&mut self.stream)?;
+
+        match OpenError::from_wire(status, errno)? {
+            Ok(()) => fd,
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl From<UnixStream> for Client {
+    fn from(stream: UnixStream) -> Client {
+        Client { stream }
+    }
+}
+
+// === impl Allowlist ===
+
+impl Allowlist {
+    /// Creates an allowlist that doesn't allow anything.
+    pub fn new() -> Allowlist {
+        Allowlist::default()
+    }
+
+    /// Allows opening the files beneath the directory `path` with `access`.
+    ///
+    /// The directory is opened now, so later changes to what `path` names don't
+    /// change what is allowed.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidInput` error if `path` isn't absolute, and any error
+    /// from opening the directory.
+    pub fn allow_dir(
+        &mut self,
+        path: impl AsRef<Path>,
+        access: Access,
+    ) -> io::Result<&mut Allowlist> {
+        let path = path.as_ref();
+        if !path.is_absolute() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "allowed directory isn't absolute",
+            ));
+        }
+
+        let dir = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(path)?;
+        self.dirs.push(AllowedDir {
+            path: path.to_owned(),
+            dir: dir.into(),
+            access,
+        });
+
+        Ok(self)
+    }
+
+    /// Only allows clients whose effective user id is `uid` or another allowed
+    /// user id.
+    ///
+    /// Clients of any user are allowed if this isn't called.
+    pub fn allow_uid(&mut self, uid: libc::uid_t) -> &mut Allowlist {
+        self.uids.get_or_insert_with(Vec::new).push(uid);
+        self
+    }
+}
+
+impl Policy for Allowlist {
+    fn check<'a>(
+        &'a self,
+        cred: &UCred,
+        path: &'a Path,
+        flags: i32,
+    ) -> Result<Grant<'a>, OpenError> {
+        if let Some(uids) = &self.uids {
+            if !uids.contains(&cred.uid) {
+                return Err(OpenError::Denied);
+            }
+        }
+
+        let (allowed, relative) = self
+            .dirs
+            .iter()
+            .filter_map(|allowed| {
+                path.strip_prefix(&allowed.path)
+                    .ok()
+                    .map(|relative| (allowed, relative))
+            })
+            .max_by_key(|(allowed, _)| allowed.path.components().count())
+            .ok_or(OpenError::Denied)?;
+
+        if allowed.access == Access::ReadOnly && is_write(flags) {
+            return Err(OpenError::ReadOnly);
+        }
+
+        Ok(Grant {
+            dir: allowed.dir.as_fd(),
+            path: if relative.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                relative
+            },
+            resolve: RESOLVE_NO_MAGICLINKS,
+        })
+    }
+}
+
+// === impl OpenError ===
+
+impl OpenError {
+    fn to_wire(self) -> (u8, i32) {
+        match self {
+            OpenError::Denied => (1, 0),
+            OpenError::ReadOnly => (2, 0),
+            OpenError::UnsupportedFlags => (3, 0),
+            OpenError::Os(errno) => (4, errno),
+        }
+    }
+
+    fn from_wire(status: u8, errno: i32) -> io::Result<Result<(), OpenError>> {
+        match status {
+            0 => Ok(Ok(())),
+            1 => Ok(Err(OpenError::Denied)),
+            2 => Ok(Err(OpenError::ReadOnly)),
+            3 => Ok(Err(OpenError::UnsupportedFlags)),
+            4 => Ok(Err(OpenError::Os(errno))),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown broker status {}", status),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::Denied => write!(f, "broker policy denied opening the path"),
+            OpenError::ReadOnly => write!(f, "broker policy only allows reading the path"),
+            OpenError::UnsupportedFlags => write!(f, "broker doesn't support the open flags"),
+            OpenError::Os(errno) => write!(
+                f,
+                "broker couldn't open the path: {}",
+                Error::from_raw_os_error(*errno)
+            ),
+        }
+    }
+}
+
+impl error::Error for OpenError {}
+
+impl From<OpenError> for Error {
+    fn from(e: OpenError) -> Error {
+        let kind = match e {
+            OpenError::Denied | OpenError::ReadOnly => ErrorKind::PermissionDenied,
+            OpenError::UnsupportedFlags => ErrorKind::InvalidInput,
+            OpenError::Os(errno) => Error::from_raw_os_error(errno).kind(),
+        };
+
+        Error::new(kind, e)
+    }
+}
+
+// === utility functions ===
+
+fn is_write(flags: i32) -> bool {
+    flags & libc::O_ACCMODE != libc::O_RDONLY
+        || flags & (libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND) != 0
+}
+
+/// Read the next request, or `None` if the client disconnected.
+fn read_request(stream: &mut UnixStream) -> io::Result<Option<(PathBuf, i32, u32)>> {
+    let mut len = [0; 4];
+    if !stream.read_or_eof(&mut len)? {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len < REQUEST_HEADER_LEN || len > REQUEST_HEADER_LEN + libc::PATH_MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "broker request has an invalid length",
+        ));
+    }
+    let mut request = vec![0; len];
+    stream.read_exact(&mut request)?;
+    // A client has no reason to send fds, so close any that it did.
+    drop(stream.take_fds());
+
+    let flags = i32::from_be_bytes([request[0], request[1], request[2], request[3]]);
+    let mode = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+    let path = PathBuf::from(OsString::from_vec(request.split_off(REQUEST_HEADER_LEN)));
+
+    Ok(Some((path, flags, mode)))
+}
+
+/// Send a response with `status` and `errno`, attaching `fd` if there is one.
+fn write_response(
+    stream: &mut UnixStream,
+    status: u8,
+    errno: i32,
+    fd: Option<&OwnedFd>,
+) -> io::Result<()> {
+    if let Some(fd) = fd {
+        stream
+            .enqueue(fd)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    let mut response = [status, 0, 0, 0, 0];
+    response[1..].copy_from_slice(&errno.to_be_bytes());
+    stream.write_all(&response)?;
+    stream.flush()
+}
+
+/// Receive a response, returning its status, its `errno` and the fd that a
+/// successful response carries.
+fn read_response(stream: &mut UnixStream) -> io::Result<(u8, i32, io::Result<OwnedFd>)> {
+    let mut response = [0; RESPONSE_LEN];
+    stream.read_exact(&mut response)?;
+    let mut fds = stream.take_fds();
+    let errno = i32::from_be_bytes([response[1], response[2], response[3], response[4]]);
+
+    let fd = if fds.len() == 1 {
+        Ok(fds.remove(0))
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "broker didn't send exactly one fd",
+        ))
+    };
+
+    Ok((response[0], errno, fd))
+}
+
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+fn openat2(
+    dir: BorrowedFd<'_>,
+    path: &Path,
+    flags: i32,
+    mode: u32,
+    resolve: u64,
+) -> io::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let how = OpenHow {
+        flags: flags as u32 as u64,
+        mode: if flags & libc::O_CREAT != 0 {
+            mode.into()
+        } else {
+            0
+        },
+        resolve,
+    };
+
+    loop {
+        // SAFETY: path is a valid C string and how is a valid open_how of the size
+        // that is passed.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dir.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const OpenHow,
+                mem::size_of::<OpenHow>(),
+            )
+        };
+
+        if fd >= 0 {
+            // SAFETY: openat2() just returned this fd so nothing else owns it.
+            return Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use tempfile::tempdir;
+
+    fn open_error(e: &Error) -> Option<OpenError> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<OpenError>())
+            .copied()
+    }
+
+    #[test]
+    fn broker_opens_allowed_files() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let data = dir.path().join("data");
+        fs::create_dir(&data).expect("Can't create dir");
+        fs::write(data.join("hello.txt"), "Hello World!").expect("Can't write file");
+        let mut policy = Allowlist::new();
+        policy
+            .allow_dir(&data, Access::ReadWrite)
+            .expect("Can't allow dir");
+        let broker = Broker::new(
+            UnixListener::bind(dir.path().join("sock")).expect("Can't bind"),
+            policy,
+        );
+        let (server, client) = UnixStream::pair().expect("Can't create pair");
+        let mut client = Client::from(client);
+        let mut buf = String::new();
+
+        thread::scope(|scope| {
+            scope.spawn(|| broker.serve(server).expect("Can't serve client"));
+            let mut hello = File::from(
+                client
+                    .open(data.join("hello.txt"), libc::O_RDONLY)
+                    .expect("Can't open file"),
+            );
+            hello.read_to_string(&mut buf).expect("Can't read file");
+            let mut new = File::from(
+                client
+                    .open_with_mode(data.join("new.txt"), libc::O_WRONLY | libc::O_CREAT, 0o600)
+                    .expect("Can't create file"),
+            );
+            new.write_all(b"created").expect("Can't write file");
+            drop(client);
+        });
+
+        assert_eq!(buf, "Hello World!");
+        assert_eq!(
+            fs::read_to_string(data.join("new.txt")).expect("Can't read file"),
+            "created"
+        );
+    }
+
+    #[test]
+    fn broker_ignores_setuid_mode_bits() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let mut policy = Allowlist::new();
+        policy
+            .allow_dir(dir.path(), Access::ReadWrite)
+            .expect("Can't allow dir");
+        let broker = Broker::new(
+            UnixListener::bind(dir.path().join("sock")).expect("Can't bind"),
+            policy,
+        );
+        let (server, client) = UnixStream::pair().expect("Can't create pair");
+        let mut client = Client::from(client);
+
+        thread::scope(|scope| {
+            scope.spawn(|| broker.serve(server).expect("Can't serve client"));
+            client
+                .open_with_mode(
+                    dir.path().join("tool"),
+                    libc::O_WRONLY | libc::O_CREAT,
+                    0o4755,
+                )
+                .expect("Can't create file");
+            drop(client);
+        });
+        let mode = fs::metadata(dir.path().join("tool"))
+            .expect("Can't stat file")
+            .permissions()
+            .mode();
+
+        assert_eq!(mode & libc::S_ISUID, 0);
+    }
+
+    #[test]
+    fn broker_refuses_disallowed_requests() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let data = dir.path().join("data");
+        fs::create_dir(&data).expect("Can't create dir");
+        fs::write(dir.path().join("secret"), "secret").expect("Can't write file");
+        fs::write(data.join("hello.txt"), "Hello World!").expect("Can't write file");
+        symlink("../secret", data.join("link")).expect("Can't create symlink");
+        let mut policy = Allowlist::new();
+        policy
+            .allow_dir(&data, Access::ReadOnly)
+            .expect("Can't allow dir");
+        let broker = Broker::new(
+            UnixListener::bind(dir.path().join("sock")).expect("Can't bind"),
+            policy,
+        );
+        let (server, client) = UnixStream::pair().expect("Can't create pair");
+        let mut client = Client::from(client);
+
+        let errors = thread::scope(|scope| {
+            scope.spawn(|| broker.serve(server).expect("Can't serve client"));
+            let mut open =
+                |path: PathBuf, flags| client.open(path, flags).err().and_then(|e| open_error(&e));
+            let errors = [
+                open(dir.path().join("secret"), libc::O_RDONLY),
+                open(data.join("hello.txt"), libc::O_RDWR),
+                open(data.join("hello.txt"), libc::O_RDONLY | libc::O_SYNC),
+                open(data.join("../secret"), libc::O_RDONLY),
+                open(data.join("link"), libc::O_RDONLY),
+            ];
+            drop(client);
+            errors
+        });
+
+        assert_eq!(
+            errors,
+            [
+                Some(OpenError::Denied),
+                Some(OpenError::ReadOnly),
+                Some(OpenError::UnsupportedFlags),
+                Some(OpenError::Os(libc::EXDEV)),
+                Some(OpenError::Os(libc::EXDEV)),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowlist_checks_peer_uid() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let mut policy = Allowlist::new();
+        policy
+            .allow_dir(dir.path(), Access::ReadOnly)
+            .expect("Can't allow dir")
+            .allow_uid(1000);
+        let path = dir.path().join("file");
+        let cred = |uid| UCred {
+            uid,
+            gid: uid,
+            pid: None,
+        };
+
+        let allowed = policy.check(&cred(1000), &path, libc::O_RDONLY);
+        let denied = policy.check(&cred(1001), &path, libc::O_RDONLY);
+
+        assert_eq!(allowed.map(|grant| grant.path), Ok(Path::new("file")));
+        assert_eq!(denied.err(), Some(OpenError::Denied));
+    }
+}
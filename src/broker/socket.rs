@@ -0,0 +1,644 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A broker that creates and binds network sockets on behalf of its clients.
+//!
+//! A service that runs without the privilege to bind its port (or without network
+//! access at all) can ask a [`Broker`] to create and bind a TCP or UDP socket for
+//! it. The broker checks each [`Request`] against a [`Policy`] together with the
+//! credentials of the client that made it, binds the socket and passes the fd
+//! back. A [`Client`] makes the requests.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::broker::socket::{Allowlist, Broker, Client, SocketType};
+//! use fd_queue::UnixListener;
+//! use std::net::{Ipv4Addr, SocketAddr};
+//! use std::thread;
+//! # use tempfile::tempdir;
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("broker.sock");
+//!
+//! // In the broker.
+//! let mut policy = Allowlist::new();
+//! policy.allow(SocketType::Stream, Ipv4Addr::LOCALHOST.into(), 0..=u16::MAX);
+//! let broker = Broker::new(UnixListener::bind(&path)?, policy);
+//! thread::spawn(move || broker.run());
+//!
+//! // In the unprivileged client.
+//! let mut client = Client::connect(&path)?;
+//! let listener = client.bind_tcp_listener(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+//! println!("Listening on {}", listener.local_addr()?);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    error, fmt,
+    io::{self, prelude::*, Error, ErrorKind},
+    mem,
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, UdpSocket,
+    },
+    ops::RangeInclusive,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    thread,
+};
+
+use tracing::warn;
+
+use super::{read_response, write_response};
+use crate::{UCred, UnixListener, UnixStream};
+
+const REQUEST_LEN: usize = 33;
+
+/// A server that creates and binds sockets for its clients as its [`Policy`]
+/// allows.
+#[derive(Debug)]
+pub struct Broker<P> {
+    listener: UnixListener,
+    policy: P,
+}
+
+/// A connection to a socket [`Broker`].
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+}
+
+/// A decision about which requests to bind a socket a [`Broker`] allows.
+pub trait Policy {
+    /// Decides whether the client with the credentials `cred` may have the socket
+    /// described by `request`.
+    fn check(&self, cred: &UCred, request: &Request) -> Result<(), BindError>;
+}
+
+/// The type of socket to create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    /// A TCP listener (`SOCK_STREAM`).
+    Stream,
+
+    /// A UDP socket (`SOCK_DGRAM`).
+    Datagram,
+}
+
+/// A description of a socket for a [`Broker`] to create and bind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    /// The type of socket.
+    pub socket_type: SocketType,
+
+    /// The address to bind the socket to, which also decides its family. Port 0
+    /// binds an ephemeral port.
+    pub addr: SocketAddr,
+
+    /// Whether to set `SO_REUSEADDR`.
+    pub reuse_addr: bool,
+
+    /// Whether to set `SO_REUSEPORT`.
+    pub reuse_port: bool,
+
+    /// The backlog to listen with, for a `Stream` socket.
+    pub backlog: i32,
+}
+
+/// A [`Policy`] that allows binding to a list of addresses and port ranges.
+///
+/// `SO_REUSEPORT` is refused unless it is allowed with
+/// [`allow_reuse_port()`][Allowlist::allow_reuse_port], since it would let a
+/// client share a port with any other socket that the broker has bound.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    rules: Vec<Rule>,
+    uids: Option<Vec<libc::uid_t>>,
+    reuse_port: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    socket_type: SocketType,
+    ip: IpAddr,
+    ports: RangeInclusive<u16>,
+}
+
+/// The reasons that a [`Broker`] refuses to bind a socket.
+///
+/// [`Client::bind()`] returns these as the inner error of an `io::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindError {
+    /// The policy doesn't allow the client to have the socket.
+    Denied,
+
+    /// Creating, configuring or binding the socket failed with this `errno`.
+    Os(i32),
+}
+
+// === impl Broker ===
+
+impl<P: Policy> Broker<P> {
+    /// Creates a broker that accepts clients on `listener` and binds sockets as
+    /// `policy` allows.
+    pub fn new(listener: UnixListener, policy: P) -> Broker<P> {
+        Broker { listener, policy }
+    }
+
+    /// Accepts clients and serves each of them on its own thread.
+    ///
+    /// Each socket that the policy allows is bound here and handed to the client
+    /// that asked for it, and a client that fails is logged and dropped. If
+    /// accepting a client fails the error is returned after the remaining
+    /// clients hang up.
+    pub fn run(&self) -> io::Result<()>
+    where
+        P: Sync,
+    {
+        thread::scope(|scope| loop {
+            let (stream, _) = self.listener.accept()?;
+
+            scope.spawn(move || {
+                if let Err(e) = self.serve(stream) {
+                    warn!(
+                        source = "socket::Broker",
+                        event = "serve",
+                        condition = "client failed",
+                        error = %e
+                    );
+                }
+            });
+        })
+    }
+
+    /// Serves the requests of the client connected to `stream` until it
+    /// disconnects.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidData` error if the client sends a malformed
+    /// request.
+    pub fn serve(&self, mut stream: UnixStream) -> io::Result<()> {
+        let cred = stream.peer_cred()?;
+        let mut request = [0; REQUEST_LEN];
+
+        while stream.read_or_eof(&mut request)? {
+            // A client has no reason to send fds, so close any that it did.
+            drop(stream.take_fds());
+            let request = Request::decode(&request)?;
+
+            let result = self
+                .policy
+                .check(&cred, &request)
+                .and_then(|()| bind(&request).map_err(BindError::from_io));
+            match result {
+                Ok(fd) => write_response(&mut stream, 0, 0, Some(&fd))?,
+                Err(BindError::Denied) => write_response(&mut stream, 1, 0, None)?,
+                Err(BindError::Os(errno)) => write_response(&mut stream, 2, errno, None)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The listener that clients connect to.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// The policy of the broker.
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+}
+
+// === impl Client ===
+
+impl Client {
+    /// Connects to the socket broker listening at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Client> {
+        UnixStream::connect(path).map(Client::from)
+    }
+
+    /// Asks the broker for the socket described by `request`.
+    ///
+    /// # Errors
+    ///
+    /// If the broker refuses to bind the socket this returns an error whose inner
+    /// error is a [`BindError`].
+    pub fn bind(&mut self, request: &Request) -> io::Result<OwnedFd> {
+        self.stream.write_all(&request.encode())?;
+        self.stream.flush()?;
+
+        let (status, errno, fd) = read_response(&mut self.stream)?;
+        match status {
+            0 => fd,
+            1 => Err(BindError::Denied.into()),
+            2 => Err(BindError::Os(errno).into()),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown broker status {}", status),
+            )),
+        }
+    }
+
+    /// Asks the broker for a TCP listener bound to `addr`.
+    pub fn bind_tcp_listener(&mut self, addr: SocketAddr) -> io::Result<TcpListener> {
+        self.bind(&Request::tcp_listener(addr))
+            .map(TcpListener::from)
+    }
+
+    /// Asks the broker for a UDP socket bound to `addr`.
+    pub fn bind_udp_socket(&mut self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        self.bind(&Request::udp_socket(addr)).map(UdpSocket::from)
+    }
+}
+
+impl From<UnixStream> for Client {
+    fn from(stream: UnixStream) -> Client {
+        Client { stream }
+    }
+}
+
+// === impl Request ===
+
+impl Request {
+    /// Describes a TCP listener bound to `addr`.
+    ///
+    /// Like `std::net::TcpListener::bind()` this sets `SO_REUSEADDR` and listens
+    /// with a backlog of 128.
+    pub fn tcp_listener(addr: SocketAddr) -> Request {
+        Request {
+            socket_type: SocketType::Stream,
+            addr,
+            reuse_addr: true,
+            reuse_port: false,
+            backlog: 128,
+        }
+    }
+
+    /// Describes a UDP socket bound to `addr`.
+    pub fn udp_socket(addr: SocketAddr) -> Request {
+        Request {
+            socket_type: SocketType::Datagram,
+            addr,
+            reuse_addr: false,
+            reuse_port: false,
+            backlog: 0,
+        }
+    }
+
+    fn encode(&self) -> [u8; REQUEST_LEN] {
+        let mut buf = [0; REQUEST_LEN];
+        buf[0] = match self.socket_type {
+            SocketType::Stream => 1,
+            SocketType::Datagram => 2,
+        };
+        buf[1] = u8::from(self.reuse_addr) | u8::from(self.reuse_port) << 1;
+        buf[2..6].copy_from_slice(&self.backlog.to_be_bytes());
+        match self.addr {
+            SocketAddr::V4(addr) => {
+                buf[6] = 4;
+                buf[7..11].copy_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                buf[6] = 6;
+                buf[7..23].copy_from_slice(&addr.ip().octets());
+                buf[25..29].copy_from_slice(&addr.flowinfo().to_be_bytes());
+                buf[29..33].copy_from_slice(&addr.scope_id().to_be_bytes());
+            }
+        }
+        buf[23..25].copy_from_slice(&self.addr.port().to_be_bytes());
+
+        buf
+    }
+
+    fn decode(buf: &[u8; REQUEST_LEN]) -> io::Result<Request> {
+        let invalid = |msg| Error::new(ErrorKind::InvalidData, msg);
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        let socket_type = match buf[0] {
+            1 => SocketType::Stream,
+            2 => SocketType::Datagram,
+            _ => return Err(invalid("unknown socket type")),
+        };
+        let port = u16::from_be_bytes([buf[23], buf[24]]);
+        let addr = match buf[6] {
+            4 => SocketAddrV4::new(Ipv4Addr::new(buf[7], buf[8], buf[9], buf[10]), port).into(),
+            6 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(&buf[7..23]);
+                SocketAddrV6::new(Ipv6Addr::from(ip), port, u32_at(25), u32_at(29)).into()
+            }
+            _ => return Err(invalid("unknown address family")),
+        };
+
+        Ok(Request {
+            socket_type,
+            addr,
+            reuse_addr: buf[1] & 1 != 0,
+            reuse_port: buf[1] & 2 != 0,
+            backlog: u32_at(2) as i32,
+        })
+    }
+}
+
+// === impl Allowlist ===
+
+impl Allowlist {
+    /// Creates an allowlist that doesn't allow anything.
+    pub fn new() -> Allowlist {
+        Allowlist::default()
+    }
+
+    /// Allows binding sockets of `socket_type` to `ip` and a port in `ports`.
+    ///
+    /// `ip` must match the requested address exactly, so allowing
+    /// `Ipv4Addr::UNSPECIFIED` only allows binding to all interfaces. Port 0 (an
+    /// ephemeral port) is only allowed if `ports` includes it.
+    pub fn allow(
+        &mut self,
+        socket_type: SocketType,
+        ip: IpAddr,
+        ports: RangeInclusive<u16>,
+    ) -> &mut Allowlist {
+        self.rules.push(Rule {
+            socket_type,
+            ip,
+            ports,
+        });
+        self
+    }
+
+    /// Only allows clients whose effective user id is `uid` or another allowed
+    /// user id.
+    ///
+    /// Clients of any user are allowed if this isn't called.
+    pub fn allow_uid(&mut self, uid: libc::uid_t) -> &mut Allowlist {
+        self.uids.get_or_insert_with(Vec::new).push(uid);
+        self
+    }
+
+    /// Sets whether clients may set `SO_REUSEPORT`.
+    pub fn allow_reuse_port(&mut self, allow: bool) -> &mut Allowlist {
+        self.reuse_port = allow;
+        self
+    }
+}
+
+impl Policy for Allowlist {
+    fn check(&self, cred: &UCred, request: &Request) -> Result<(), BindError> {
+        if let Some(uids) = &self.uids {
+            if !uids.contains(&cred.uid) {
+                return Err(BindError::Denied);
+            }
+        }
+        if request.reuse_port && !self.reuse_port {
+            return Err(BindError::Denied);
+        }
+
+        let allowed = self.rules.iter().any(|rule| {
+            rule.socket_type == request.socket_type
+                && rule.ip == request.addr.ip()
+                && rule.ports.contains(&request.addr.port())
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(BindError::Denied)
+        }
+    }
+}
+
+// === impl BindError ===
+
+impl BindError {
+    fn from_io(e: Error) -> BindError {
+        BindError::Os(e.raw_os_error().unwrap_or(libc::EIO))
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::Denied => write!(f, "broker policy denied binding the socket"),
+            BindError::Os(errno) => write!(
+                f,
+                "broker couldn't bind the socket: {}",
+                Error::from_raw_os_error(*errno)
+            ),
+        }
+    }
+}
+
+impl error::Error for BindError {}
+
+impl From<BindError> for Error {
+    fn from(e: BindError) -> Error {
+        let kind = match e {
+            BindError::Denied => ErrorKind::PermissionDenied,
+            BindError::Os(errno) => Error::from_raw_os_error(errno).kind(),
+        };
+
+        Error::new(kind, e)
+    }
+}
+
+// === utility functions ===
+
+fn bind(request: &Request) -> io::Result<OwnedFd> {
+    let (addr, len) = sockaddr(&request.addr);
+    let socket_type = match request.socket_type {
+        SocketType::Stream => libc::SOCK_STREAM,
+        SocketType::Datagram => libc::SOCK_DGRAM,
+    };
+
+    // SAFETY: socket() has no memory safety requirements.
+    let fd = unsafe {
+        libc::socket(
+            libc::c_int::from(addr.ss_family),
+            socket_type | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: socket() just returned this fd so nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    if request.reuse_addr {
+        set_option(&fd, libc::SO_REUSEADDR)?;
+    }
+    if request.reuse_port {
+        set_option(&fd, libc::SO_REUSEPORT)?;
+    }
+    // SAFETY: addr and len describe a valid socket address.
+    let result = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len,
+        )
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: listen() has no memory safety requirements.
+    if request.socket_type == SocketType::Stream
+        && unsafe { libc::listen(fd.as_raw_fd(), request.backlog) } < 0
+    {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+fn set_option(fd: &OwnedFd, option: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+
+    // SAFETY: value is an int option of the size that is passed.
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: sockaddr_storage is a plain C struct for which all zeros is a valid value.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: sockaddr_storage is large enough and suitably aligned for
+            // any socket address.
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: sockaddr_storage is large enough and suitably aligned for
+            // any socket address.
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpStream;
+
+    use tempfile::tempdir;
+
+    fn bind_error(e: &Error) -> Option<BindError> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<BindError>())
+            .copied()
+    }
+
+    fn broker(policy: Allowlist) -> (Broker<Allowlist>, Client, UnixStream) {
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("sock")).expect("Can't bind");
+        let (server, client) = UnixStream::pair().expect("Can't create pair");
+
+        (Broker::new(listener, policy), Client::from(client), server)
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let request = Request {
+            reuse_port: true,
+            ..Request::udp_socket("[fe80::1%3]:53".parse().expect("Can't parse"))
+        };
+
+        let decoded = Request::decode(&request.encode()).expect("Can't decode");
+
+        assert_eq!(decoded, request);
+    }
+
+    #[test]
+    fn socket_broker_binds_tcp_listener() {
+        let mut policy = Allowlist::new();
+        policy.allow(SocketType::Stream, Ipv4Addr::LOCALHOST.into(), 0..=u16::MAX);
+        let (broker, mut client, server) = broker(policy);
+        let mut buf = [0; 5];
+
+        thread::scope(|scope| {
+            scope.spawn(|| broker.serve(server).expect("Can't serve client"));
+            let listener = client
+                .bind_tcp_listener((Ipv4Addr::LOCALHOST, 0).into())
+                .expect("Can't bind listener");
+            let addr = listener.local_addr().expect("Can't get local addr");
+            let mut outbound = TcpStream::connect(addr).expect("Can't connect");
+            let (mut inbound, _) = listener.accept().expect("Can't accept");
+            outbound.write_all(b"hello").expect("Can't write");
+            inbound.read_exact(&mut buf).expect("Can't read");
+            drop(client);
+        });
+
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn socket_broker_refuses_disallowed_requests() {
+        let existing = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("Can't bind");
+        let taken = existing.local_addr().expect("Can't get local addr");
+        let mut policy = Allowlist::new();
+        policy.allow(SocketType::Stream, Ipv4Addr::LOCALHOST.into(), 0..=u16::MAX);
+        let (broker, mut client, server) = broker(policy);
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+
+        let errors = thread::scope(|scope| {
+            scope.spawn(|| broker.serve(server).expect("Can't serve client"));
+            let mut bind = |request| client.bind(&request).err().and_then(|e| bind_error(&e));
+            let errors = [
+                bind(Request::tcp_listener((Ipv4Addr::UNSPECIFIED, 0).into())),
+                bind(Request::udp_socket(localhost)),
+                bind(Request {
+                    reuse_port: true,
+                    ..Request::tcp_listener(localhost)
+                }),
+                bind(Request::tcp_listener(taken)),
+            ];
+            drop(client);
+            errors
+        });
+
+        assert_eq!(
+            errors,
+            [
+                Some(BindError::Denied),
+                Some(BindError::Denied),
+                Some(BindError::Denied),
+                Some(BindError::Os(libc::EADDRINUSE)),
+            ]
+        );
+    }
+}
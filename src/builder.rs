@@ -0,0 +1,404 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+use std::{
+    ffi::CString,
+    fmt, fs,
+    io::{self, Error, ErrorKind},
+    marker::PhantomData,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{SocketAddr, UnixListener as StdUnixListener},
+    },
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+use crate::{addr, path::ShortPath};
+
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// A builder for Unix domain socket listeners bound to a filesystem path.
+///
+/// `ListenerBuilder` manages the lifecycle of the socket file: it can remove a
+/// stale socket left behind by a process that crashed, remove the socket file
+/// when the listener is dropped, set the mode and owner of the socket file before
+/// any client can connect to it, and set the listen backlog. The same builder
+/// is used by each of the listener types in this crate through their `builder()`
+/// function.
+///
+/// # Examples
+///
+/// ```
+/// use fd_queue::UnixListener;
+/// # use tempfile::tempdir;
+/// # let dir = tempdir()?;
+/// # let path = dir.path().join("mysocket");
+/// // let path = ...
+///
+/// let listener = UnixListener::builder()
+///     .remove_stale(true)
+///     .unlink_on_drop(true)
+///     .mode(0o660)
+///     .backlog(16)
+///     .bind(&path)?;
+///
+/// drop(listener);
+/// assert!(!path.exists());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ListenerBuilder<L> {
+    mode: Option<u32>,
+    owner: Option<(Option<u32>, Option<u32>)>,
+    backlog: Option<i32>,
+    remove_stale: bool,
+    unlink_on_drop: bool,
+    _listener: PhantomData<fn() -> L>,
+}
+
+/// The socket file of a listener created by a `ListenerBuilder`.
+#[derive(Debug)]
+pub struct BoundPath {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    unlink: bool,
+}
+
+mod sealed {
+    use super::*;
+
+    /// A listener type that a `ListenerBuilder` can create.
+    pub trait FromBound: Sized {
+        /// Wrap a bound and listening std listener together with its socket file.
+        fn from_bound(listener: StdUnixListener, path: BoundPath) -> io::Result<Self>;
+    }
+}
+
+pub(crate) use sealed::FromBound;
+
+// === impl ListenerBuilder ===
+
+impl<L> ListenerBuilder<L> {
+    pub(crate) fn new() -> ListenerBuilder<L> {
+        ListenerBuilder {
+            mode: None,
+            owner: None,
+            backlog: None,
+            remove_stale: false,
+            unlink_on_drop: false,
+            _listener: PhantomData,
+        }
+    }
+
+    /// Set the permission bits of the socket file.
+    ///
+    /// The mode is set after the socket is bound but before it starts listening,
+    /// so a client that connects while the socket file still has the default
+    /// permissions is refused rather than accepted.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the owner and group of the socket file.
+    ///
+    /// A `None` leaves the corresponding id unchanged. As with `mode()`, the owner
+    /// is set before the socket starts listening.
+    pub fn owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// Set the backlog of pending connections passed to `listen(2)`.
+    ///
+    /// The default is 128, the same as for a std `UnixListener`.
+    pub fn backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Remove a stale socket file at the path before binding.
+    ///
+    /// A socket file is stale if a probe connect to it is refused, which means
+    /// that nothing is listening on it. A socket file with a live listener is
+    /// left alone and `bind()` will fail with `AddrInUse`.
+    pub fn remove_stale(&mut self, remove: bool) -> &mut Self {
+        self.remove_stale = remove;
+        self
+    }
+
+    /// Remove the socket file when the listener is dropped.
+    ///
+    /// The file is only removed if it is still the socket that this listener
+    /// bound, so a socket that a later process bound at the same path survives.
+    pub fn unlink_on_drop(&mut self, unlink: bool) -> &mut Self {
+        self.unlink_on_drop = unlink;
+        self
+    }
+}
+
+impl<L: FromBound> ListenerBuilder<L> {
+    /// Create a new listener bound to `path` with the options in this builder.
+    pub fn bind(&self, path: impl AsRef<Path>) -> io::Result<L> {
+        let path = path.as_ref();
+
+        if self.remove_stale {
+            remove_stale(path)?;
+        }
+
+        let listener = self.bind_listener(path)?;
+        let bound = BoundPath::new(path, self.unlink_on_drop)?;
+
+        L::from_bound(listener, bound)
+    }
+
+    // The socket only starts listening after its file has been given its mode and
+    // owner, so until then a connect to it is refused rather than accepted.
+    fn bind_listener(&self, path: &Path) -> io::Result<StdUnixListener> {
+        let short = ShortPath::new(path)?;
+        let mut name = short.as_ref().as_os_str().as_bytes().to_vec();
+        name.push(0);
+        let (addr, len) = addr::sockaddr_un(&name)?;
+        let socket = addr::stream_socket(false)?;
+
+        // SAFETY: addr is a valid sockaddr_un and len is no more than its size.
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            )
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let backlog = self.backlog.unwrap_or(DEFAULT_BACKLOG);
+        let result = self.set_attributes(path).and_then(|()| {
+            // SAFETY: listen() has no memory safety requirements.
+            if unsafe { libc::listen(socket.as_raw_fd(), backlog) } < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+
+        Ok(StdUnixListener::from(socket))
+    }
+
+    fn set_attributes(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if let Some((uid, gid)) = self.owner {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            let uid = uid.unwrap_or(libc::uid_t::MAX);
+            let gid = gid.unwrap_or(libc::gid_t::MAX);
+
+            // SAFETY: path is a valid nul terminated string.
+            if unsafe { libc::chown(path.as_ptr(), uid, gid) } < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<L> fmt::Debug for ListenerBuilder<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ListenerBuilder")
+            .field("mode", &self.mode)
+            .field("owner", &self.owner)
+            .field("backlog", &self.backlog)
+            .field("remove_stale", &self.remove_stale)
+            .field("unlink_on_drop", &self.unlink_on_drop)
+            .finish()
+    }
+}
+
+// === impl BoundPath ===
+
+impl BoundPath {
+    fn new(path: &Path, unlink: bool) -> io::Result<BoundPath> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        Ok(BoundPath {
+            path: path.to_owned(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            unlink,
+        })
+    }
+
+    /// The address of the listener in terms of its final path.
+    ///
+    /// The kernel reports the path that the socket was originally bound to, which
+    /// is a `/proc/self/fd` path for a listener created by a `ListenerBuilder` with
+    /// a path that is too long for a `sockaddr_un`.
+    pub fn local_addr(
+        path: &Option<BoundPath>,
+        bound_addr: impl FnOnce() -> io::Result<SocketAddr>,
+    ) -> io::Result<SocketAddr> {
+        match path
+            .as_ref()
+            .and_then(|path| SocketAddr::from_pathname(&path.path).ok())
+        {
+            Some(addr) => Ok(addr),
+            None => bound_addr(),
+        }
+    }
+
+    /// Keep the socket file when this `BoundPath` is dropped.
+    pub fn keep(&mut self) {
+        self.unlink = false;
+    }
+}
+
+impl Drop for BoundPath {
+    fn drop(&mut self) {
+        if !self.unlink {
+            return;
+        }
+
+        let same_socket = fs::symlink_metadata(&self.path)
+            .map(|metadata| metadata.dev() == self.dev && metadata.ino() == self.ino)
+            .unwrap_or(false);
+        if same_socket {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!(
+                    source = "BoundPath",
+                    event = "drop",
+                    condition = "can't remove socket file",
+                    error = %e
+                );
+            }
+        }
+    }
+}
+
+// === utility functions ===
+
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    if is_stale(&ShortPath::new(path)?)? {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Probe the socket with a non-blocking connect so that a live listener with a
+// full backlog can't block the probe.
+fn is_stale(path: &ShortPath) -> io::Result<bool> {
+    let mut name = path.as_ref().as_os_str().as_bytes().to_vec();
+    name.push(0);
+    let (addr, len) = addr::sockaddr_un(&name)?;
+    let socket = addr::stream_socket(true)?;
+
+    // SAFETY: addr is a valid sockaddr_un and len is no more than its size.
+    let result = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    };
+
+    Ok(result < 0 && Error::last_os_error().raw_os_error() == Some(libc::ECONNREFUSED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+
+    use tempfile::tempdir;
+
+    struct TestListener {
+        inner: StdUnixListener,
+        path: Option<BoundPath>,
+    }
+
+    impl FromBound for TestListener {
+        fn from_bound(inner: StdUnixListener, path: BoundPath) -> io::Result<Self> {
+            Ok(TestListener {
+                inner,
+                path: Some(path),
+            })
+        }
+    }
+
+    #[test]
+    fn listener_builder_removes_stale_socket() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+        drop(StdUnixListener::bind(&path).expect("Can't bind listener"));
+
+        let sut = ListenerBuilder::<TestListener>::new()
+            .remove_stale(true)
+            .bind(&path)
+            .expect("Can't bind over stale socket");
+        UnixStream::connect(&path).expect("Can't connect to listener");
+
+        assert!(sut.path.is_some());
+    }
+
+    #[test]
+    fn listener_builder_keeps_live_socket() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+        let _live = StdUnixListener::bind(&path).expect("Can't bind listener");
+
+        let result = ListenerBuilder::<TestListener>::new()
+            .remove_stale(true)
+            .bind(&path);
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::AddrInUse));
+    }
+
+    #[test]
+    fn listener_builder_sets_mode_and_unlinks_on_drop() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+
+        let sut = ListenerBuilder::<TestListener>::new()
+            .mode(0o600)
+            .unlink_on_drop(true)
+            .bind(&path)
+            .expect("Can't bind listener");
+        let mode = fs::metadata(&path).expect("Can't stat socket").mode();
+        let addr = BoundPath::local_addr(&sut.path, || sut.inner.local_addr())
+            .expect("Can't get local address");
+        UnixStream::connect(&path).expect("Can't connect to listener");
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(addr.as_pathname(), Some(path.as_path()));
+        assert_eq!(fs::read_dir(dir.path()).expect("Can't read dir").count(), 1);
+        drop(sut);
+        assert!(!path.exists());
+    }
+}
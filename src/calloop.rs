@@ -0,0 +1,475 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Event sources that integrate fd passing Unix sockets with calloop.
+//!
+//! [`UnixStreamSource`] wraps a non-blocking [`UnixStream`] and reads
+//! what is available each time the stream becomes readable. The callback
+//! receives the bytes and the fds that arrived with them together with the
+//! stream itself so that it can reply. [`UnixListenerSource`] wraps a
+//! non-blocking [`UnixListener`] and yields each accepted stream.
+
+use std::{
+    convert::TryFrom,
+    io::{self, prelude::*, ErrorKind},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+};
+
+use ::calloop::{
+    generic::{FdWrapper, Generic},
+    EventSource, Interest, Mode, Poll, PostAction, Readiness, Token, TokenFactory,
+};
+
+use crate::{DequeueFd, UnixListener, UnixStream};
+
+const READ_CHUNK_SIZE: usize = 4096;
+const MAX_READ_PER_DISPATCH: usize = 16 * READ_CHUNK_SIZE;
+
+/// A calloop event source for a non-blocking [`UnixStream`].
+///
+/// Each dispatch reads what is available on the stream, up to 64 KiB so that a
+/// peer that keeps writing can't hold up the rest of the event loop. The source
+/// is level triggered, so anything left over is read by the next dispatch. The
+/// callback receives a [`StreamEvent`] holding those bytes and the fds that
+/// were dequeued with them, along with a mutable reference to the stream.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Write;
+/// use calloop::{EventLoop, PostAction};
+/// use fd_queue::{calloop::UnixStreamSource, UnixStream};
+///
+/// let mut event_loop = EventLoop::<Vec<u8>>::try_new()?;
+/// let (sock1, mut sock2) = UnixStream::pair()?;
+///
+/// event_loop
+///     .handle()
+///     .insert_source(UnixStreamSource::new(sock1)?, |event, _stream, received| {
+///         received.extend(event.data);
+///         Ok(PostAction::Continue)
+///     })
+///     .expect("Can't insert source");
+///
+/// sock2.write_all(b"hello")?;
+///
+/// let mut received = Vec::new();
+/// event_loop.dispatch(None, &mut received)?;
+/// assert_eq!(received, b"hello");
+/// #
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct UnixStreamSource {
+    // Declared before stream so that it is dropped (removing the fd from the
+    // poller) before the stream closes the fd.
+    source: Generic<FdWrapper<RawFd>>,
+    stream: UnixStream,
+}
+
+/// The event produced by a [`UnixStreamSource`].
+#[derive(Debug)]
+pub struct StreamEvent {
+    /// The readiness reported by calloop.
+    pub readiness: Readiness,
+    /// The bytes read from the stream during this dispatch.
+    pub data: Vec<u8>,
+    /// The fds dequeued from the stream during this dispatch.
+    pub fds: Vec<OwnedFd>,
+    /// `true` if the other side of the stream has been shut down for writing.
+    pub eof: bool,
+}
+
+/// A calloop event source for a non-blocking [`UnixListener`].
+///
+/// Each dispatch accepts all pending connections and calls the callback once
+/// for each of them with a non-blocking [`UnixStream`] that can be wrapped in a
+/// [`UnixStreamSource`].
+///
+/// # Examples
+///
+/// ```
+/// # use tempfile::tempdir;
+/// use calloop::{EventLoop, PostAction};
+/// use fd_queue::{calloop::UnixListenerSource, UnixListener, UnixStream};
+///
+/// # let dir = tempdir()?;
+/// # let path = dir.path().join("mysock");
+/// // let path: Path = ...
+/// let mut event_loop = EventLoop::<Vec<UnixStream>>::try_new()?;
+/// let listener = UnixListener::bind(&path)?;
+///
+/// event_loop
+///     .handle()
+///     .insert_source(UnixListenerSource::new(listener)?, |stream, _, accepted| {
+///         accepted.push(stream);
+///         Ok(PostAction::Continue)
+///     })
+///     .expect("Can't insert source");
+///
+/// let _client = UnixStream::connect(&path)?;
+///
+/// let mut accepted = Vec::new();
+/// event_loop.dispatch(None, &mut accepted)?;
+/// assert_eq!(accepted.len(), 1);
+/// #
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct UnixListenerSource {
+    // Declared before listener for the same reason as in UnixStreamSource.
+    source: Generic<FdWrapper<RawFd>>,
+    listener: UnixListener,
+}
+
+// === impl UnixStreamSource ===
+
+impl UnixStreamSource {
+    /// Creates a new event source for `stream`, setting it to non-blocking mode.
+    pub fn new(stream: UnixStream) -> io::Result<UnixStreamSource> {
+        stream.set_nonblocking(true)?;
+
+        // Safety: the fd is owned by stream which outlives source (see the
+        // field order of UnixStreamSource).
+        let fd = unsafe { FdWrapper::new(stream.as_raw_fd()) };
+
+        Ok(UnixStreamSource {
+            source: Generic::new(fd, Interest::READ, Mode::Level),
+            stream,
+        })
+    }
+
+    /// Returns a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &UnixStream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the wrapped stream.
+    pub fn get_mut(&mut self) -> &mut UnixStream {
+        &mut self.stream
+    }
+
+    /// Consumes the event source, returning the wrapped stream.
+    ///
+    /// The stream is left in non-blocking mode.
+    pub fn into_inner(self) -> UnixStream {
+        let UnixStreamSource { source, stream } = self;
+        drop(source);
+
+        stream
+    }
+}
+
+impl EventSource for UnixStreamSource {
+    type Event = StreamEvent;
+    type Metadata = UnixStream;
+    type Ret = io::Result<PostAction>;
+    type Error = io::Error;
+
+    fn process_events<F>(
+        &mut self,
+        readiness: Readiness,
+        token: Token,
+        mut callback: F,
+    ) -> io::Result<PostAction>
+    where
+        F: FnMut(Self::Event, &mut Self::Metadata) -> Self::Ret,
+    {
+        let UnixStreamSource { source, stream } = self;
+
+        source.process_events(readiness, token, |readiness, _| {
+            let mut event = StreamEvent {
+                readiness,
+                data: Vec::new(),
+                fds: Vec::new(),
+                eof: false,
+            };
+
+            if readiness.readable {
+                read_available(stream, &mut event)?;
+            }
+
+            callback(event, stream)
+        })
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.source.register(poll, token_factory)
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.source.reregister(poll, token_factory)
+    }
+
+    fn unregister(&mut self, poll: &mut Poll) -> ::calloop::Result<()> {
+        self.source.unregister(poll)
+    }
+}
+
+impl TryFrom<StdUnixStream> for UnixStreamSource {
+    type Error = io::Error;
+
+    fn try_from(inner: StdUnixStream) -> Result<Self, Self::Error> {
+        UnixStreamSource::new(UnixStream::from_std_nonblocking(inner)?)
+    }
+}
+
+// === impl UnixListenerSource ===
+
+impl UnixListenerSource {
+    /// Creates a new event source for `listener`, setting it to non-blocking mode.
+    pub fn new(listener: UnixListener) -> io::Result<UnixListenerSource> {
+        listener.set_nonblocking(true)?;
+
+        // Safety: the fd is owned by listener which outlives source (see the
+        // field order of UnixListenerSource).
+        let fd = unsafe { FdWrapper::new(listener.as_raw_fd()) };
+
+        Ok(UnixListenerSource {
+            source: Generic::new(fd, Interest::READ, Mode::Level),
+            listener,
+        })
+    }
+
+    /// Returns a reference to the wrapped listener.
+    pub fn get_ref(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Consumes the event source, returning the wrapped listener.
+    ///
+    /// The listener is left in non-blocking mode.
+    pub fn into_inner(self) -> UnixListener {
+        let UnixListenerSource { source, listener } = self;
+        drop(source);
+
+        listener
+    }
+}
+
+impl EventSource for UnixListenerSource {
+    type Event = UnixStream;
+    type Metadata = UnixListener;
+    type Ret = io::Result<PostAction>;
+    type Error = io::Error;
+
+    fn process_events<F>(
+        &mut self,
+        readiness: Readiness,
+        token: Token,
+        mut callback: F,
+    ) -> io::Result<PostAction>
+    where
+        F: FnMut(Self::Event, &mut Self::Metadata) -> Self::Ret,
+    {
+        let UnixListenerSource { source, listener } = self;
+
+        source.process_events(readiness, token, |_, _| loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    match callback(stream, listener)? {
+                        PostAction::Continue => {}
+                        action => return Ok(action),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(PostAction::Continue),
+                Err(e) => return Err(e),
+            }
+        })
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.source.register(poll, token_factory)
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.source.reregister(poll, token_factory)
+    }
+
+    fn unregister(&mut self, poll: &mut Poll) -> ::calloop::Result<()> {
+        self.source.unregister(poll)
+    }
+}
+
+impl TryFrom<StdUnixListener> for UnixListenerSource {
+    type Error = io::Error;
+
+    fn try_from(inner: StdUnixListener) -> Result<Self, Self::Error> {
+        UnixListenerSource::new(UnixListener::from_std_nonblocking(inner)?)
+    }
+}
+
+// === utility functions ===
+
+fn read_available(stream: &mut UnixStream, event: &mut StreamEvent) -> io::Result<()> {
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+    while event.data.len() < MAX_READ_PER_DISPATCH {
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                event.eof = true;
+                break;
+            }
+            Ok(count) => event.data.extend_from_slice(&chunk[..count]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    while let Some(fd) = stream.dequeue() {
+        // Safety: DequeueFd transfers ownership of the dequeued RawFd to the
+        // caller.
+        event.fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use ::calloop::EventLoop;
+    use tempfile::{tempdir, tempfile};
+
+    use crate::EnqueueFd;
+
+    #[test]
+    fn stream_source_delivers_bytes_and_fds() {
+        let file = tempfile().expect("Can't create temp file");
+        let mut event_loop = EventLoop::<Vec<StreamEvent>>::try_new().expect("Can't create loop");
+
+        let (sut, mut other) = UnixStream::pair().expect("Can't create pair");
+        event_loop
+            .handle()
+            .insert_source(
+                UnixStreamSource::new(sut).expect("Can't create source"),
+                |event, _, events| {
+                    events.push(event);
+                    Ok(PostAction::Continue)
+                },
+            )
+            .expect("Can't insert source");
+        other.enqueue(&file).expect("Can't enqueue fd");
+        other.write_all(b"hello").expect("Can't write");
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Duration::from_secs(5), &mut events)
+            .expect("Can't dispatch");
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, b"hello");
+        assert_eq!(events[0].fds.len(), 1);
+        assert!(!events[0].eof);
+    }
+
+    #[test]
+    fn stream_source_limits_bytes_read_per_dispatch() {
+        let mut event_loop = EventLoop::<Vec<StreamEvent>>::try_new().expect("Can't create loop");
+
+        let (sut, mut other) = UnixStream::pair().expect("Can't create pair");
+        event_loop
+            .handle()
+            .insert_source(
+                UnixStreamSource::new(sut).expect("Can't create source"),
+                |event, _, events| {
+                    events.push(event);
+                    Ok(PostAction::Continue)
+                },
+            )
+            .expect("Can't insert source");
+        other
+            .write_all(&[7; MAX_READ_PER_DISPATCH + 10])
+            .expect("Can't write");
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            event_loop
+                .dispatch(Duration::from_secs(5), &mut events)
+                .expect("Can't dispatch");
+        }
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data.len(), MAX_READ_PER_DISPATCH);
+        assert_eq!(events[1].data.len(), 10);
+    }
+
+    #[test]
+    fn stream_source_reports_eof() {
+        let mut event_loop = EventLoop::<Vec<StreamEvent>>::try_new().expect("Can't create loop");
+
+        let (sut, other) = UnixStream::pair().expect("Can't create pair");
+        event_loop
+            .handle()
+            .insert_source(
+                UnixStreamSource::new(sut).expect("Can't create source"),
+                |event, _, events| {
+                    events.push(event);
+                    Ok(PostAction::Remove)
+                },
+            )
+            .expect("Can't insert source");
+        drop(other);
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Duration::from_secs(5), &mut events)
+            .expect("Can't dispatch");
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].eof);
+    }
+
+    #[test]
+    fn listener_source_accepts_streams() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+        let mut event_loop = EventLoop::<Vec<UnixStream>>::try_new().expect("Can't create loop");
+
+        let listener = UnixListener::bind(&path).expect("Can't bind listener");
+        event_loop
+            .handle()
+            .insert_source(
+                UnixListenerSource::new(listener).expect("Can't create source"),
+                |stream, _, accepted| {
+                    accepted.push(stream);
+                    Ok(PostAction::Continue)
+                },
+            )
+            .expect("Can't insert source");
+        let _client1 = UnixStream::connect(&path).expect("Can't connect");
+        let _client2 = UnixStream::connect(&path).expect("Can't connect");
+        let mut accepted = Vec::new();
+        event_loop
+            .dispatch(Duration::from_secs(5), &mut accepted)
+            .expect("Can't dispatch");
+
+        assert_eq!(accepted.len(), 2);
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Object capabilities that are live connections to a served object.
+//!
+//! [`serve()`] creates a capability for an [`Object`]: it makes a
+//! [`UnixStream::pair()`], serves the object on one end on a thread of the local
+//! process, and returns the other end wrapped in a [`Proxy`]. The proxy is typed by
+//! the [`Interface`] of the object and makes calls to it with [`Proxy::call()`].
+//!
+//! A proxy is an fd like any other, so it can be sent to another process in a
+//! [`Message`] (or with any of the other ways of passing fds) and wrapped back
+//! into a proxy there with [`Proxy::from_fd()`]. A process that receives a proxy
+//! can forward it to a third process in the same way.
+//!
+//! Forwarding a proxy moves it: [`Message::with_fd()`] takes the proxy by value
+//! and its fd is closed in the sending process once the message is sent. There is
+//! only ever one proxy for each object, since calls made through two copies of
+//! the same connection would interleave, so a proxy is not `Clone` and its fd
+//! must not be duplicated. The object is dropped, and the capability revoked,
+//! once the proxy is dropped.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::cap::{self, Interface, Message, Proxy};
+//! use std::os::unix::io::OwnedFd;
+//!
+//! struct Counter;
+//!
+//! impl Interface for Counter {
+//!     const NAME: &'static str = "org.example.Counter";
+//!     type Request = Message;
+//!     type Response = Message;
+//! }
+//!
+//! let mut count = 0;
+//! let proxy = cap::serve::<Counter>(move |_request: Message| {
+//!     count += 1;
+//!     Ok(Message::new(format!("{}", count)))
+//! })?;
+//!
+//! // The proxy can be passed to another process, which wraps it back up.
+//! let fd = OwnedFd::from(proxy);
+//! let mut proxy = Proxy::<Counter>::from_fd(fd)?;
+//!
+//! let reply = proxy.call(Message::new("increment"))?;
+//! assert_eq!(reply.data(), b"1");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    fmt,
+    io::{self, prelude::*, Error, ErrorKind},
+    marker::PhantomData,
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    thread,
+};
+
+use tracing::warn;
+
+use crate::{
+    activation::{self, SocketKind},
+    EnqueueFd, UnixStream,
+};
+
+/// The longest data of a [`Message`].
+pub const MAX_DATA_LEN: usize = 1 << 24;
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_WRONG_INTERFACE: u8 = 2;
+
+const HEADER_LEN: usize = 7;
+
+/// The protocol of a capability.
+///
+/// The name of the interface is sent with each call so that a proxy of one
+/// interface can't make calls to an object of another.
+pub trait Interface: 'static {
+    /// The name of the interface.
+    const NAME: &'static str;
+
+    /// The requests that a proxy sends.
+    type Request: Payload;
+
+    /// The responses that an object returns.
+    type Response: Payload;
+}
+
+/// A type that can be sent as a [`Message`].
+pub trait Payload: Sized {
+    /// Converts this value into the message to send.
+    fn into_message(self) -> io::Result<Message>;
+
+    /// Converts a received message back into a value.
+    fn from_message(message: Message) -> io::Result<Self>;
+}
+
+/// An object that can be served as a capability with [`serve()`].
+///
+/// This is implemented for closures that take a request and return a response.
+pub trait Object<I: Interface>: Send + 'static {
+    /// Handles a call from a proxy.
+    ///
+    /// An error is returned to the proxy that made the call as an error of kind
+    /// `Other` with the same message.
+    fn call(&mut self, request: I::Request) -> io::Result<I::Response>;
+}
+
+/// The bytes and fds of a request or response.
+///
+/// Each of the fds can be a [`Proxy`], which passes the capability on.
+#[derive(Debug, Default)]
+pub struct Message {
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+/// A typed connection to an object served as a capability.
+///
+/// Dropping the proxy revokes the capability.
+pub struct Proxy<I> {
+    stream: UnixStream,
+    interface: PhantomData<fn() -> I>,
+}
+
+#[derive(Debug)]
+struct Frame {
+    code: u8,
+    name: Vec<u8>,
+    message: Message,
+}
+
+/// Serves `object` as a new capability and returns a proxy for it.
+///
+/// The object is served on its own thread until the proxy, wherever it has been
+/// forwarded to, is dropped.
+pub fn serve<I: Interface>(object: impl Object<I>) -> io::Result<Proxy<I>> {
+    let (local, remote) = UnixStream::pair()?;
+    thread::spawn(move || {
+        if let Err(e) = serve_object::<I>(local, object) {
+            warn!(
+                source = "cap",
+                event = "serve",
+                condition = "capability failed",
+                interface = I::NAME,
+                error = %e
+            );
+        }
+    });
+
+    Ok(Proxy::from_stream(remote))
+}
+
+// === impl Object ===
+
+impl<I, F> Object<I> for F
+where
+    I: Interface,
+    F: FnMut(I::Request) -> io::Result<I::Response> + Send + 'static,
+{
+    fn call(&mut self, request: I::Request) -> io::Result<I::Response> {
+        self(request)
+    }
+}
+
+// === impl Message ===
+
+impl Message {
+    /// Creates a message with `data` and no fds.
+    pub fn new(data: impl Into<Vec<u8>>) -> Message {
+        Message {
+            data: data.into(),
+            fds: Vec::new(),
+        }
+    }
+
+    /// Adds `fd` to the fds of this message.
+    ///
+    /// A [`Proxy`] converts into an `OwnedFd`, so this also passes on a
+    /// capability.
+    pub fn with_fd(mut self, fd: impl Into<OwnedFd>) -> Message {
+        self.fds.push(fd.into());
+        self
+    }
+
+    /// Gets the data of this message.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Gets the fds of this message.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Splits this message into its data and its fds.
+    pub fn into_parts(self) -> (Vec<u8>, Vec<OwnedFd>) {
+        (self.data, self.fds)
+    }
+}
+
+impl Payload for Message {
+    fn into_message(self) -> io::Result<Message> {
+        Ok(self)
+    }
+
+    fn from_message(message: Message) -> io::Result<Message> {
+        Ok(message)
+    }
+}
+
+// === impl Proxy ===
+
+impl<I: Interface> Proxy<I> {
+    /// Wraps a received capability into a proxy.
+    ///
+    /// Whether `fd` is a capability for an object of interface `I` is checked on
+    /// each call.
+    ///
+    /// # Errors
+    ///
+    /// This returns an error of kind `InvalidInput` if `fd` is not a connected
+    /// Unix stream socket.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Proxy<I>> {
+        if !activation::is_kind(fd.as_raw_fd(), SocketKind::Stream) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "capability fd is not a connected Unix stream socket",
+            ));
+        }
+
+        // Safety: the OwnedFd transfers its ownership of the fd to the UnixStream.
+        Ok(Proxy::from_stream(unsafe {
+            UnixStream::from_raw_fd(fd.into_raw_fd())
+        }))
+    }
+
+    fn from_stream(stream: UnixStream) -> Proxy<I> {
+        Proxy {
+            stream,
+            interface: PhantomData,
+        }
+    }
+
+    /// Calls the object of this capability with `request` and waits for its
+    /// response.
+    ///
+    /// # Errors
+    ///
+    /// This returns an error of kind `Other` if the object returned an error, of
+    /// kind `InvalidInput` if the object isn't of interface `I`, and of kind
+    /// `UnexpectedEof` if the object is no longer served.
+    pub fn call(&mut self, request: I::Request) -> io::Result<I::Response> {
+        let message = request.into_message()?;
+        write_frame(&mut self.stream, 0, I::NAME.as_bytes(), message)?;
+
+        let frame = read_frame(&mut self.stream)?.ok_or_else(|| {
+            Error::new(ErrorKind::UnexpectedEof, "capability is no longer served")
+        })?;
+        match frame.code {
+            STATUS_OK => I::Response::from_message(frame.message),
+            STATUS_FAILED => Err(Error::new(
+                ErrorKind::Other,
+                String::from_utf8_lossy(&frame.message.data).into_owned(),
+            )),
+            STATUS_WRONG_INTERFACE => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "capability is for {} not {}",
+                    String::from_utf8_lossy(&frame.message.data),
+                    I::NAME
+                ),
+            )),
+            status => Err(invalid_data(&format!(
+                "unknown capability status {}",
+                status
+            ))),
+        }
+    }
+}
+
+impl<I> fmt::Debug for Proxy<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<I> AsRawFd for Proxy<I> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl<I> From<Proxy<I>> for OwnedFd {
+    fn from(proxy: Proxy<I>) -> OwnedFd {
+        // Safety: the UnixStream transfers its ownership of the fd to the OwnedFd.
+        unsafe { OwnedFd::from_raw_fd(proxy.stream.into_raw_fd()) }
+    }
+}
+
+// === utility functions ===
+
+fn serve_object<I: Interface>(
+    mut stream: UnixStream,
+    mut object: impl Object<I>,
+) -> io::Result<()> {
+    while let Some(frame) = read_frame(&mut stream)? {
+        let (status, message) = if frame.name != I::NAME.as_bytes() {
+            (STATUS_WRONG_INTERFACE, Message::new(I::NAME))
+        } else {
+            let response = I::Request::from_message(frame.message)
+                .and_then(|request| object.call(request))
+                .and_then(Payload::into_message);
+            match response {
+                Ok(message) => (STATUS_OK, message),
+                Err(e) => (STATUS_FAILED, Message::new(e.to_string())),
+            }
+        };
+
+        write_frame(&mut stream, status, &[], message)?;
+    }
+
+    Ok(())
+}
+
+fn write_frame(stream: &mut UnixStream, code: u8, name: &[u8], message: Message) -> io::Result<()> {
+    if message.data.len() > MAX_DATA_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "capability message too long",
+        ));
+    }
+    if name.len() > usize::from(u8::MAX) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "capability interface name too long",
+        ));
+    }
+    if message.fds.len() > UnixStream::FD_QUEUE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "too many fds for a capability message",
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + name.len() + message.data.len());
+    frame.push(code);
+    frame.push(name.len() as u8);
+    frame.extend_from_slice(&(message.data.len() as u32).to_be_bytes());
+    frame.push(message.fds.len() as u8);
+    frame.extend_from_slice(name);
+    frame.extend_from_slice(&message.data);
+
+    for fd in message.fds.iter() {
+        stream
+            .enqueue(fd)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Read the next frame, or `None` if the other end is closed.
+fn read_frame(stream: &mut UnixStream) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_LEN];
+    if !stream.read_or_eof(&mut header)? {
+        return Ok(None);
+    }
+
+    let data_len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if data_len > MAX_DATA_LEN {
+        return Err(invalid_data("capability message too long"));
+    }
+    let mut name = vec![0; usize::from(header[1])];
+    stream.read_exact(&mut name)?;
+    let mut data = vec![0; data_len];
+    stream.read_exact(&mut data)?;
+
+    let fds = stream.take_fds();
+    if fds.len() != usize::from(header[6]) {
+        return Err(invalid_data(
+            "capability message has the wrong number of fds",
+        ));
+    }
+
+    Ok(Some(Frame {
+        code: header[0],
+        name,
+        message: Message { data, fds },
+    }))
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::sync::mpsc;
+
+    use assert_matches::assert_matches;
+
+    use crate::test_util::hello_file;
+
+    struct Files;
+
+    impl Interface for Files {
+        const NAME: &'static str = "org.example.Files";
+        type Request = Message;
+        type Response = Message;
+    }
+
+    struct Greeter;
+
+    impl Interface for Greeter {
+        const NAME: &'static str = "org.example.Greeter";
+        type Request = Name;
+        type Response = Message;
+    }
+
+    struct Name(String);
+
+    impl Payload for Name {
+        fn into_message(self) -> io::Result<Message> {
+            Ok(Message::new(self.0))
+        }
+
+        fn from_message(message: Message) -> io::Result<Name> {
+            String::from_utf8(message.data)
+                .map(Name)
+                .map_err(|e| invalid_data(&e.to_string()))
+        }
+    }
+
+    fn files() -> Proxy<Files> {
+        serve::<Files>(|_request: Message| Ok(Message::new("file").with_fd(hello_file())))
+            .expect("Can't serve capability")
+    }
+
+    #[test]
+    fn proxy_calls_object_and_receives_fds() {
+        let mut sut = files();
+
+        let (data, mut fds) = sut
+            .call(Message::new("open"))
+            .expect("Can't call capability")
+            .into_parts();
+
+        let mut buf = String::new();
+        File::from(fds.pop().expect("No fd in response"))
+            .read_to_string(&mut buf)
+            .expect("Can't read from file");
+        assert_eq!(data, b"file");
+        assert_eq!(buf, "Hello World!");
+    }
+
+    #[test]
+    fn proxy_forwarded_through_another_capability_reaches_object() {
+        let files = files();
+        let (sender, receiver) = mpsc::channel();
+        let mut broker = serve::<Files>(move |request: Message| {
+            let (_, mut fds) = request.into_parts();
+            let forwarded = Proxy::<Files>::from_fd(fds.pop().expect("No capability"))?;
+            sender.send(forwarded).expect("Can't hand over capability");
+            Ok(Message::default())
+        })
+        .expect("Can't serve capability");
+
+        broker
+            .call(Message::new("take").with_fd(files))
+            .expect("Can't forward capability");
+        let mut sut = receiver.recv().expect("No forwarded capability");
+        let response = sut
+            .call(Message::new("open"))
+            .expect("Can't call capability");
+
+        assert_eq!(response.data(), b"file");
+        assert_eq!(response.fds().len(), 1);
+    }
+
+    #[test]
+    fn dropping_proxy_revokes_capability() {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let sut = serve::<Files>(move |request: Message| {
+            let _keep = &sender;
+            Ok(request)
+        })
+        .expect("Can't serve capability");
+
+        drop(sut);
+
+        // The object, and so the sender, is dropped once the capability is revoked.
+        assert_matches!(receiver.recv(), Err(mpsc::RecvError));
+    }
+
+    #[test]
+    fn proxy_of_wrong_interface_is_refused() {
+        let files = files();
+        let mut sut =
+            Proxy::<Greeter>::from_fd(OwnedFd::from(files)).expect("Can't wrap capability");
+
+        let result = sut.call(Name("world".to_owned()));
+
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn proxy_from_fd_refuses_non_socket() {
+        let result = Proxy::<Files>::from_fd(OwnedFd::from(hello_file()));
+
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn object_error_is_returned_to_caller() {
+        let mut sut = serve::<Greeter>(|name: Name| {
+            if name.0.is_empty() {
+                Err(Error::new(ErrorKind::InvalidInput, "no name"))
+            } else {
+                Ok(Message::new(format!("Hello {}!", name.0)))
+            }
+        })
+        .expect("Can't serve capability");
+
+        let hello = sut.call(Name("world".to_owned())).expect("Can't call");
+        let result = sut.call(Name(String::new()));
+
+        assert_eq!(hello.data(), b"Hello world!");
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::Other && e.to_string() == "no name");
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+use std::{
+    io::{self, Error},
+    os::unix::io::RawFd,
+};
+
+/// The credentials of the process on the other end of a connected Unix socket.
+///
+/// The credentials are those of the peer at the time that the connection was
+/// established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UCred {
+    /// The effective user id of the peer.
+    pub uid: libc::uid_t,
+
+    /// The effective group id of the peer.
+    pub gid: libc::gid_t,
+
+    /// The process id of the peer, on the platforms that report it.
+    pub pid: Option<libc::pid_t>,
+}
+
+/// Get the credentials of the peer of the connected Unix socket `fd`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_cred(fd: RawFd) -> io::Result<UCred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of_val(&cred) as libc::socklen_t;
+
+    // SAFETY: cred and len describe a buffer large enough for SO_PEERCRED.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(UCred {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+/// Get the credentials of the peer of the connected Unix socket `fd`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn peer_cred(fd: RawFd) -> io::Result<UCred> {
+    let mut uid = 0;
+    let mut gid = 0;
+
+    // SAFETY: uid and gid are valid for writes.
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(UCred {
+        uid,
+        gid,
+        pid: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+
+    #[test]
+    fn peer_cred_reports_own_process() {
+        let (stream, _other) = UnixStream::pair().expect("Can't create pair");
+
+        let cred = peer_cred(stream.as_raw_fd()).expect("Can't get peer credentials");
+
+        // SAFETY: geteuid() and getegid() have no memory safety requirements.
+        assert_eq!(cred.uid, unsafe { libc::geteuid() });
+        assert_eq!(cred.gid, unsafe { libc::getegid() });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(cred.pid, Some(std::process::id() as libc::pid_t));
+    }
+}
@@ -0,0 +1,1351 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! The D-Bus wire format, including `UNIX_FD` arguments.
+//!
+//! A [`Message`] holds the header fields and the body of a D-Bus message. Its body
+//! is a list of [`Value`]s of the basic and container types of D-Bus, including
+//! [`Value::UnixFd`]. Encoding a message replaces each fd with its index among
+//! the fds of the message and sets the `UNIX_FDS` header field to their count;
+//! decoding a message checks that exactly that many fds arrived with it and
+//! resolves the indices against them.
+//!
+//! [`write_message`] and [`read_message`] send and receive messages on a blocking
+//! stream such as [`UnixStream`][crate::UnixStream], with the fds of each message
+//! traveling with its first byte through [`EnqueueFd`] and [`DequeueFd`]. With
+//! the `codec-fd` feature [`DbusCodec`] does the same for an
+//! [`FdFramed`][crate::tokio::codec::FdFramed] over a tokio
+//! [`UnixStream`][crate::tokio::UnixStream].
+//!
+//! Messages are encoded little endian and decoded in either byte order. The
+//! authentication handshake that precedes the messages on a connection to a bus
+//! is outside the scope of this module.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::dbus::{self, Message, Value};
+//! use fd_queue::UnixStream;
+//! # use tempfile::tempfile;
+//!
+//! let (mut sock1, mut sock2) = UnixStream::pair()?;
+//! # let log = tempfile()?;
+//! // let log: File = ...
+//!
+//! let mut call = Message::method_call(1, "/org/example/Logs", "Open");
+//! call.interface = Some("org.example.Logs".to_owned());
+//! call.body = vec![Value::String("app".to_owned()), Value::UnixFd(log.into())];
+//! dbus::write_message(&mut sock1, &call)?;
+//!
+//! let received = dbus::read_message(&mut sock2)?;
+//! assert_eq!(received.signature(), "sh");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    io::{self, prelude::*, Error, ErrorKind},
+    os::unix::io::{AsFd, BorrowedFd, FromRawFd, OwnedFd},
+};
+
+use crate::{DequeueFd, EnqueueFd, QueueFullError, UnixStream};
+
+/// The longest message.
+pub const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+/// The message flag that says that the sender doesn't expect a reply.
+pub const NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// The message flag that says that the bus shouldn't start the destination.
+pub const NO_AUTO_START: u8 = 0x2;
+
+/// The message flag that says that the sender is prepared to wait for
+/// interactive authorization.
+pub const ALLOW_INTERACTIVE_AUTHORIZATION: u8 = 0x4;
+
+const FIXED_HEADER_LEN: usize = 16;
+const MAX_ARRAY_LEN: usize = 1 << 26;
+const MAX_SIGNATURE_LEN: usize = 255;
+const MAX_DEPTH: usize = 64;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+/// A D-Bus message.
+///
+/// The `SIGNATURE` and `UNIX_FDS` header fields are derived from the body.
+#[derive(Debug)]
+pub struct Message {
+    /// The type of the message.
+    pub message_type: MessageType,
+
+    /// The flags of the message, such as [`NO_REPLY_EXPECTED`].
+    pub flags: u8,
+
+    /// The serial number of the message, which must not be 0.
+    pub serial: u32,
+
+    /// The object that a call is to, or that a signal is from.
+    pub path: Option<String>,
+
+    /// The interface of the method or signal.
+    pub interface: Option<String>,
+
+    /// The name of the method or signal.
+    pub member: Option<String>,
+
+    /// The name of the error of an error reply.
+    pub error_name: Option<String>,
+
+    /// The serial number of the call that this message replies to.
+    pub reply_serial: Option<u32>,
+
+    /// The connection that the message is for.
+    pub destination: Option<String>,
+
+    /// The connection that sent the message.
+    pub sender: Option<String>,
+
+    /// The arguments of the message.
+    pub body: Vec<Value>,
+}
+
+/// The types of D-Bus messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// A method call, which requires a path and a member.
+    MethodCall,
+
+    /// A reply with the results of a method call, which requires a reply serial.
+    MethodReturn,
+
+    /// An error reply to a method call, which requires an error name and a reply
+    /// serial.
+    Error,
+
+    /// A signal, which requires a path, an interface and a member.
+    Signal,
+}
+
+/// A D-Bus value.
+#[derive(Debug)]
+pub enum Value {
+    /// A `y` value.
+    Byte(u8),
+
+    /// A `b` value.
+    Boolean(bool),
+
+    /// An `n` value.
+    Int16(i16),
+
+    /// A `q` value.
+    Uint16(u16),
+
+    /// An `i` value.
+    Int32(i32),
+
+    /// A `u` value.
+    Uint32(u32),
+
+    /// An `x` value.
+    Int64(i64),
+
+    /// A `t` value.
+    Uint64(u64),
+
+    /// A `d` value.
+    Double(f64),
+
+    /// An `s` value.
+    String(String),
+
+    /// An `o` value.
+    ObjectPath(String),
+
+    /// A `g` value.
+    Signature(String),
+
+    /// An `h` value.
+    UnixFd(OwnedFd),
+
+    /// An `a` value whose items all have the signature `element`.
+    ///
+    /// A dictionary is an array of [`DictEntry`][Value::DictEntry] items.
+    Array {
+        /// The signature of the items.
+        element: String,
+        /// The items.
+        items: Vec<Value>,
+    },
+
+    /// A `(...)` value.
+    Struct(Vec<Value>),
+
+    /// A `{..}` value, which can only be an item of an array.
+    DictEntry(Box<Value>, Box<Value>),
+
+    /// A `v` value.
+    Variant(Box<Value>),
+}
+
+/// A codec for D-Bus messages for use with
+/// [`FdFramed`][crate::tokio::codec::FdFramed].
+#[cfg(feature = "codec-fd")]
+#[derive(Debug, Default)]
+pub struct DbusCodec;
+
+// A single complete type of a signature.
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Byte,
+    Boolean,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Int64,
+    Uint64,
+    Double,
+    String,
+    ObjectPath,
+    Signature,
+    UnixFd,
+    Variant,
+    Array(Box<Type>),
+    Struct(Vec<Type>),
+    DictEntry(Box<Type>, Box<Type>),
+}
+
+struct Writer<'a> {
+    buf: Vec<u8>,
+    fds: Vec<BorrowedFd<'a>>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+    fds: &'a [OwnedFd],
+}
+
+/// Writes `message` to `writer`, queuing its fds with its first byte.
+///
+/// If this fails the fds that are enqueued on `writer` are discarded, including
+/// any that were enqueued before the call.
+pub fn write_message<W>(writer: &mut W, message: &Message) -> io::Result<()>
+where
+    W: Write + EnqueueFd,
+{
+    let (bytes, fds) = message.encode()?;
+    if fds.len() > UnixStream::FD_QUEUE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, QueueFullError::new()));
+    }
+
+    // The queue only holds the numbers of the fds, which are borrowed from
+    // `message`, so they are taken back off the queue if the write fails.
+    let result = write_with_fds(writer, &bytes, &fds);
+    if result.is_err() {
+        writer.clear_enqueued();
+    }
+
+    result
+}
+
+/// Reads the next message from `reader` together with the fds that arrived with
+/// it.
+pub fn read_message<R>(reader: &mut R) -> io::Result<Message>
+where
+    R: Read + DequeueFd,
+{
+    let mut bytes = vec![0; FIXED_HEADER_LEN];
+    reader.read_exact(&mut bytes)?;
+    let len = message_len(&bytes)?.expect("fixed header is complete");
+    bytes.resize(len, 0);
+    reader.read_exact(&mut bytes[FIXED_HEADER_LEN..])?;
+
+    // The fds of the message arrived with its first byte, and reading exactly
+    // the message's bytes leaves the fds of the next message on the stream.
+    let mut fds = Vec::new();
+    while let Some(fd) = reader.dequeue() {
+        // Safety: DequeueFd transfers ownership of the dequeued RawFd to the
+        // caller.
+        fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
+    Message::decode(&bytes, fds)
+}
+
+// === impl Message ===
+
+impl Message {
+    /// Creates a call of the method `member` on the object at `path`.
+    pub fn method_call(serial: u32, path: impl Into<String>, member: impl Into<String>) -> Message {
+        Message {
+            path: Some(path.into()),
+            member: Some(member.into()),
+            ..Message::new(MessageType::MethodCall, serial)
+        }
+    }
+
+    /// Creates a reply with the results of `call`.
+    pub fn method_return(serial: u32, call: &Message) -> Message {
+        Message {
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            ..Message::new(MessageType::MethodReturn, serial)
+        }
+    }
+
+    /// Creates an error reply to `call`.
+    pub fn error(serial: u32, call: &Message, error_name: impl Into<String>) -> Message {
+        Message {
+            error_name: Some(error_name.into()),
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            ..Message::new(MessageType::Error, serial)
+        }
+    }
+
+    /// Creates the signal `interface.member` from the object at `path`.
+    pub fn signal(
+        serial: u32,
+        path: impl Into<String>,
+        interface: impl Into<String>,
+        member: impl Into<String>,
+    ) -> Message {
+        Message {
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            ..Message::new(MessageType::Signal, serial)
+        }
+    }
+
+    fn new(message_type: MessageType, serial: u32) -> Message {
+        Message {
+            message_type,
+            flags: 0,
+            serial,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body: Vec::new(),
+        }
+    }
+
+    /// Gets the signature of the body.
+    pub fn signature(&self) -> String {
+        self.body.iter().map(Value::signature).collect()
+    }
+
+    /// Encodes this message into its bytes and the fds that have to be sent with
+    /// them.
+    pub fn encode(&self) -> io::Result<(Vec<u8>, Vec<BorrowedFd<'_>>)> {
+        self.check_fields()?;
+        let signature = self.signature();
+        parse_signature(&signature)?;
+
+        let mut body = Writer::new();
+        for value in self.body.iter() {
+            body.put_value(value, 0)?;
+        }
+
+        let mut fields = Vec::new();
+        let mut field = |code, value| {
+            fields.push(Value::Struct(vec![
+                Value::Byte(code),
+                Value::Variant(Box::new(value)),
+            ]))
+        };
+        let strings = vec![
+            (FIELD_PATH, &self.path),
+            (FIELD_INTERFACE, &self.interface),
+            (FIELD_MEMBER, &self.member),
+            (FIELD_ERROR_NAME, &self.error_name),
+            (FIELD_DESTINATION, &self.destination),
+            (FIELD_SENDER, &self.sender),
+        ];
+        for (code, value) in strings {
+            if let Some(value) = value {
+                let value = match code {
+                    FIELD_PATH => Value::ObjectPath(value.clone()),
+                    _ => Value::String(value.clone()),
+                };
+                field(code, value);
+            }
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            field(FIELD_REPLY_SERIAL, Value::Uint32(reply_serial));
+        }
+        if !signature.is_empty() {
+            field(FIELD_SIGNATURE, Value::Signature(signature));
+        }
+        if !body.fds.is_empty() {
+            field(FIELD_UNIX_FDS, Value::Uint32(body.fds.len() as u32));
+        }
+        let fields = Value::Array {
+            element: "(yv)".to_owned(),
+            items: fields,
+        };
+
+        let mut header = Writer::new();
+        header
+            .buf
+            .extend_from_slice(&[b'l', self.message_type.to_wire(), self.flags, 1]);
+        header.put_u32(body.buf.len() as u32);
+        header.put_u32(self.serial);
+        header.put_value(&fields, 0)?;
+        header.pad(8);
+
+        let mut bytes = header.buf;
+        bytes.extend_from_slice(&body.buf);
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "D-Bus message too long",
+            ));
+        }
+
+        Ok((bytes, body.fds))
+    }
+
+    /// Decodes a message from its bytes and the fds that arrived with them.
+    ///
+    /// It is an error if the number of fds isn't the one in the `UNIX_FDS` header
+    /// field.
+    pub fn decode(bytes: &[u8], fds: Vec<OwnedFd>) -> io::Result<Message> {
+        Message::decode_with(bytes, |unix_fds| {
+            if unix_fds == fds.len() {
+                Ok(fds)
+            } else {
+                Err(invalid_data(&format!(
+                    "D-Bus message has {} fds but its header says {}",
+                    fds.len(),
+                    unix_fds
+                )))
+            }
+        })
+    }
+
+    // Decodes a message, getting its fds from `take_fds` once the count in its
+    // `UNIX_FDS` header field is known.
+    fn decode_with<F>(bytes: &[u8], take_fds: F) -> io::Result<Message>
+    where
+        F: FnOnce(usize) -> io::Result<Vec<OwnedFd>>,
+    {
+        if message_len(bytes)? != Some(bytes.len()) {
+            return Err(invalid_data(
+                "D-Bus message length doesn't match its header",
+            ));
+        }
+        let big_endian = bytes[0] == b'B';
+        let message_type = MessageType::from_wire(bytes[1])?;
+        if bytes[3] != 1 {
+            return Err(invalid_data("unsupported D-Bus protocol version"));
+        }
+
+        let mut header = Reader {
+            buf: bytes,
+            pos: 4,
+            big_endian,
+            fds: &[],
+        };
+        header.get_u32()?;
+        let mut message = Message::new(message_type, header.get_u32()?);
+        message.flags = bytes[2];
+        let fields = header.read_value(
+            &Type::Array(Box::new(Type::Struct(vec![Type::Byte, Type::Variant]))),
+            0,
+        )?;
+        header.align(8)?;
+        let body_start = header.pos;
+
+        let mut signature = String::new();
+        let mut unix_fds = 0;
+        for field in into_items(fields) {
+            let (code, value) = match into_fields(field).as_mut_slice() {
+                [Value::Byte(code), Value::Variant(value)] => {
+                    (*code, std::mem::replace(&mut **value, Value::Byte(0)))
+                }
+                _ => return Err(invalid_data("invalid D-Bus header field")),
+            };
+            match (code, value) {
+                (FIELD_PATH, Value::ObjectPath(path)) => message.path = Some(path),
+                (FIELD_INTERFACE, Value::String(s)) => message.interface = Some(s),
+                (FIELD_MEMBER, Value::String(s)) => message.member = Some(s),
+                (FIELD_ERROR_NAME, Value::String(s)) => message.error_name = Some(s),
+                (FIELD_REPLY_SERIAL, Value::Uint32(n)) => message.reply_serial = Some(n),
+                (FIELD_DESTINATION, Value::String(s)) => message.destination = Some(s),
+                (FIELD_SENDER, Value::String(s)) => message.sender = Some(s),
+                (FIELD_SIGNATURE, Value::Signature(s)) => signature = s,
+                (FIELD_UNIX_FDS, Value::Uint32(n)) => unix_fds = n as usize,
+                (FIELD_PATH..=FIELD_UNIX_FDS, _) => {
+                    return Err(invalid_data("D-Bus header field has the wrong type"))
+                }
+                // Unknown header fields are ignored.
+                _ => {}
+            }
+        }
+        message
+            .check_fields()
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        let fds = take_fds(unix_fds)?;
+
+        let mut body = Reader {
+            buf: &bytes[body_start..],
+            pos: 0,
+            big_endian,
+            fds: &fds,
+        };
+        for ty in parse_signature(&signature)? {
+            let value = body.read_value(&ty, 0)?;
+            message.body.push(value);
+        }
+        if body.pos != body.buf.len() {
+            return Err(invalid_data(
+                "D-Bus message body is longer than its signature",
+            ));
+        }
+
+        Ok(message)
+    }
+
+    fn check_fields(&self) -> io::Result<()> {
+        let required = match self.message_type {
+            MessageType::MethodCall => self.path.is_some() && self.member.is_some(),
+            MessageType::MethodReturn => self.reply_serial.is_some(),
+            MessageType::Error => self.error_name.is_some() && self.reply_serial.is_some(),
+            MessageType::Signal => {
+                self.path.is_some() && self.interface.is_some() && self.member.is_some()
+            }
+        };
+        if !required {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "D-Bus message is missing a required header field",
+            ));
+        }
+        if self.serial == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "D-Bus message serial is 0",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// === impl MessageType ===
+
+impl MessageType {
+    fn to_wire(self) -> u8 {
+        match self {
+            MessageType::MethodCall => 1,
+            MessageType::MethodReturn => 2,
+            MessageType::Error => 3,
+            MessageType::Signal => 4,
+        }
+    }
+
+    fn from_wire(value: u8) -> io::Result<MessageType> {
+        match value {
+            1 => Ok(MessageType::MethodCall),
+            2 => Ok(MessageType::MethodReturn),
+            3 => Ok(MessageType::Error),
+            4 => Ok(MessageType::Signal),
+            _ => Err(invalid_data(&format!(
+                "unknown D-Bus message type {}",
+                value
+            ))),
+        }
+    }
+}
+
+// === impl Value ===
+
+impl Value {
+    /// Gets the signature of this value.
+    pub fn signature(&self) -> String {
+        match self {
+            Value::Byte(_) => "y".to_owned(),
+            Value::Boolean(_) => "b".to_owned(),
+            Value::Int16(_) => "n".to_owned(),
+            Value::Uint16(_) => "q".to_owned(),
+            Value::Int32(_) => "i".to_owned(),
+            Value::Uint32(_) => "u".to_owned(),
+            Value::Int64(_) => "x".to_owned(),
+            Value::Uint64(_) => "t".to_owned(),
+            Value::Double(_) => "d".to_owned(),
+            Value::String(_) => "s".to_owned(),
+            Value::ObjectPath(_) => "o".to_owned(),
+            Value::Signature(_) => "g".to_owned(),
+            Value::UnixFd(_) => "h".to_owned(),
+            Value::Array { element, .. } => format!("a{}", element),
+            Value::Struct(fields) => {
+                let fields: String = fields.iter().map(Value::signature).collect();
+                format!("({})", fields)
+            }
+            Value::DictEntry(key, value) => format!("{{{}{}}}", key.signature(), value.signature()),
+            Value::Variant(_) => "v".to_owned(),
+        }
+    }
+}
+
+// === impl DbusCodec ===
+
+#[cfg(feature = "codec-fd")]
+impl crate::tokio::codec::FdEncoder<Message> for DbusCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        message: Message,
+        dst: &mut bytes::BytesMut,
+        fds: &mut Vec<OwnedFd>,
+    ) -> io::Result<()> {
+        let (bytes, message_fds) = message.encode()?;
+        for fd in message_fds {
+            fds.push(fd.try_clone_to_owned()?);
+        }
+        dst.extend_from_slice(&bytes);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "codec-fd")]
+impl crate::tokio::codec::FdDecoder for DbusCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut bytes::BytesMut,
+        fds: &mut crate::tokio::codec::RecvFds,
+    ) -> io::Result<Option<Message>> {
+        let len = match message_len(src)? {
+            Some(len) if src.len() >= len => len,
+            Some(len) => {
+                src.reserve(len - src.len());
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        let bytes = src.split_to(len);
+        Message::decode_with(&bytes, |count| fds.take(count)).map(Some)
+    }
+}
+
+// === impl Type ===
+
+impl Type {
+    fn alignment(&self) -> usize {
+        match self {
+            Type::Byte | Type::Signature | Type::Variant => 1,
+            Type::Int16 | Type::Uint16 => 2,
+            Type::Boolean
+            | Type::Int32
+            | Type::Uint32
+            | Type::String
+            | Type::ObjectPath
+            | Type::UnixFd
+            | Type::Array(_) => 4,
+            Type::Int64 | Type::Uint64 | Type::Double | Type::Struct(_) | Type::DictEntry(..) => 8,
+        }
+    }
+
+    fn signature(&self) -> String {
+        match self {
+            Type::Byte => "y".to_owned(),
+            Type::Boolean => "b".to_owned(),
+            Type::Int16 => "n".to_owned(),
+            Type::Uint16 => "q".to_owned(),
+            Type::Int32 => "i".to_owned(),
+            Type::Uint32 => "u".to_owned(),
+            Type::Int64 => "x".to_owned(),
+            Type::Uint64 => "t".to_owned(),
+            Type::Double => "d".to_owned(),
+            Type::String => "s".to_owned(),
+            Type::ObjectPath => "o".to_owned(),
+            Type::Signature => "g".to_owned(),
+            Type::UnixFd => "h".to_owned(),
+            Type::Variant => "v".to_owned(),
+            Type::Array(element) => format!("a{}", element.signature()),
+            Type::Struct(fields) => {
+                let fields: String = fields.iter().map(Type::signature).collect();
+                format!("({})", fields)
+            }
+            Type::DictEntry(key, value) => format!("{{{}{}}}", key.signature(), value.signature()),
+        }
+    }
+
+    fn is_basic(&self) -> bool {
+        !matches!(
+            self,
+            Type::Variant | Type::Array(_) | Type::Struct(_) | Type::DictEntry(..)
+        )
+    }
+}
+
+// === impl Writer ===
+
+impl<'a> Writer<'a> {
+    fn new() -> Writer<'a> {
+        Writer {
+            buf: Vec::new(),
+            fds: Vec::new(),
+        }
+    }
+
+    fn pad(&mut self, alignment: usize) {
+        let len = self.buf.len();
+        self.buf
+            .resize(len + (alignment - len % alignment) % alignment, 0);
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.pad(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_string(&mut self, value: &str) -> io::Result<()> {
+        if value.contains('\0') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "D-Bus string contains a NUL",
+            ));
+        }
+        self.put_u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+
+        Ok(())
+    }
+
+    fn put_signature(&mut self, value: &str) -> io::Result<()> {
+        parse_signature(value)?;
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+
+        Ok(())
+    }
+
+    fn put_value(&mut self, value: &'a Value, depth: usize) -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "D-Bus value nested too deeply",
+            ));
+        }
+
+        match value {
+            Value::Byte(v) => self.buf.push(*v),
+            Value::Boolean(v) => self.put_u32(*v as u32),
+            Value::Int16(v) => {
+                self.pad(2);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Uint16(v) => {
+                self.pad(2);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Int32(v) => self.put_u32(*v as u32),
+            Value::Uint32(v) => self.put_u32(*v),
+            Value::Int64(v) => {
+                self.pad(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Uint64(v) => {
+                self.pad(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Double(v) => {
+                self.pad(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::String(v) | Value::ObjectPath(v) => self.put_string(v)?,
+            Value::Signature(v) => self.put_signature(v)?,
+            Value::UnixFd(fd) => {
+                let index = self.fds.len() as u32;
+                self.fds.push(fd.as_fd());
+                self.put_u32(index);
+            }
+            Value::Array { element, items } => {
+                let element_type = match parse_single_type(&value.signature())? {
+                    Type::Array(element_type) => element_type,
+                    _ => unreachable!("array signature parses to an array type"),
+                };
+                self.put_u32(0);
+                let len_pos = self.buf.len() - 4;
+                self.pad(element_type.alignment());
+                let start = self.buf.len();
+                for item in items {
+                    if item.signature() != *element {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "D-Bus array item doesn't match the array's signature",
+                        ));
+                    }
+                    self.put_value(item, depth + 1)?;
+                }
+                let len = self.buf.len() - start;
+                if len > MAX_ARRAY_LEN {
+                    return Err(Error::new(ErrorKind::InvalidInput, "D-Bus array too long"));
+                }
+                self.buf[len_pos..len_pos + 4].copy_from_slice(&(len as u32).to_le_bytes());
+            }
+            Value::Struct(fields) => {
+                self.pad(8);
+                for field in fields {
+                    self.put_value(field, depth + 1)?;
+                }
+            }
+            Value::DictEntry(key, value) => {
+                self.pad(8);
+                self.put_value(key, depth + 1)?;
+                self.put_value(value, depth + 1)?;
+            }
+            Value::Variant(value) => {
+                self.put_signature(&value.signature())?;
+                self.put_value(value, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// === impl Reader ===
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(invalid_data("D-Bus message is truncated"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn align(&mut self, alignment: usize) -> io::Result<()> {
+        let padding = (alignment - self.pos % alignment) % alignment;
+        if self.take(padding)?.iter().any(|&b| b != 0) {
+            return Err(invalid_data("D-Bus message has non-zero padding"));
+        }
+
+        Ok(())
+    }
+
+    fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> io::Result<u16> {
+        self.align(2)?;
+        let bytes = [self.get_u8()?, self.get_u8()?];
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn get_u32(&mut self) -> io::Result<u32> {
+        self.align(4)?;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn get_u64(&mut self) -> io::Result<u64> {
+        self.align(8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn get_string(&mut self, len: usize) -> io::Result<String> {
+        let bytes = self.take(len + 1)?;
+        if bytes[len] != 0 || bytes[..len].contains(&0) {
+            return Err(invalid_data("invalid D-Bus string"));
+        }
+        String::from_utf8(bytes[..len].to_vec())
+            .map_err(|_| invalid_data("D-Bus string isn't utf-8"))
+    }
+
+    fn get_signature(&mut self) -> io::Result<String> {
+        let len = self.get_u8()? as usize;
+        let signature = self.get_string(len)?;
+        parse_signature(&signature)?;
+
+        Ok(signature)
+    }
+
+    fn read_value(&mut self, ty: &Type, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("D-Bus value nested too deeply"));
+        }
+
+        let value = match ty {
+            Type::Byte => Value::Byte(self.get_u8()?),
+            Type::Boolean => match self.get_u32()? {
+                0 => Value::Boolean(false),
+                1 => Value::Boolean(true),
+                _ => return Err(invalid_data("invalid D-Bus boolean")),
+            },
+            Type::Int16 => Value::Int16(self.get_u16()? as i16),
+            Type::Uint16 => Value::Uint16(self.get_u16()?),
+            Type::Int32 => Value::Int32(self.get_u32()? as i32),
+            Type::Uint32 => Value::Uint32(self.get_u32()?),
+            Type::Int64 => Value::Int64(self.get_u64()? as i64),
+            Type::Uint64 => Value::Uint64(self.get_u64()?),
+            Type::Double => Value::Double(f64::from_bits(self.get_u64()?)),
+            Type::String => {
+                let len = self.get_u32()? as usize;
+                Value::String(self.get_string(len)?)
+            }
+            Type::ObjectPath => {
+                let len = self.get_u32()? as usize;
+                Value::ObjectPath(self.get_string(len)?)
+            }
+            Type::Signature => Value::Signature(self.get_signature()?),
+            Type::UnixFd => {
+                let index = self.get_u32()? as usize;
+                let fd = self
+                    .fds
+                    .get(index)
+                    .ok_or_else(|| invalid_data("D-Bus fd index is out of range"))?;
+                Value::UnixFd(fd.try_clone()?)
+            }
+            Type::Variant => {
+                let signature = self.get_signature()?;
+                let ty = parse_single_type(&signature).map_err(|e| invalid_data(&e.to_string()))?;
+                Value::Variant(Box::new(self.read_value(&ty, depth + 1)?))
+            }
+            Type::Array(element) => {
+                let len = self.get_u32()? as usize;
+                if len > MAX_ARRAY_LEN {
+                    return Err(invalid_data("D-Bus array too long"));
+                }
+                self.align(element.alignment())?;
+                let end = self.pos + len;
+                if end > self.buf.len() {
+                    return Err(invalid_data("D-Bus message is truncated"));
+                }
+                let mut items = Vec::new();
+                while self.pos < end {
+                    items.push(self.read_value(element, depth + 1)?);
+                }
+                if self.pos != end {
+                    return Err(invalid_data("D-Bus array item crosses the array's end"));
+                }
+                Value::Array {
+                    element: element.signature(),
+                    items,
+                }
+            }
+            Type::Struct(fields) => {
+                self.align(8)?;
+                let mut values = Vec::with_capacity(fields.len());
+                for field in fields {
+                    values.push(self.read_value(field, depth + 1)?);
+                }
+                Value::Struct(values)
+            }
+            Type::DictEntry(key, value) => {
+                self.align(8)?;
+                let key = self.read_value(key, depth + 1)?;
+                let value = self.read_value(value, depth + 1)?;
+                Value::DictEntry(Box::new(key), Box::new(value))
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+// === utility functions ===
+
+fn write_with_fds<W>(writer: &mut W, bytes: &[u8], fds: &[BorrowedFd]) -> io::Result<()>
+where
+    W: Write + EnqueueFd,
+{
+    for fd in fds {
+        writer
+            .enqueue(fd)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    writer.write_all(bytes)?;
+    writer.flush()
+}
+
+// Gets the length of the message that starts with `bytes` once its fixed header
+// has arrived.
+fn message_len(bytes: &[u8]) -> io::Result<Option<usize>> {
+    if bytes.len() < FIXED_HEADER_LEN {
+        return Ok(None);
+    }
+    let big_endian = match bytes[0] {
+        b'l' => false,
+        b'B' => true,
+        _ => return Err(invalid_data("invalid D-Bus byte order")),
+    };
+    let get_u32 = |pos: usize| {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[pos..pos + 4]);
+        if big_endian {
+            u32::from_be_bytes(word) as usize
+        } else {
+            u32::from_le_bytes(word) as usize
+        }
+    };
+
+    let body_len = get_u32(4);
+    let fields_len = get_u32(12);
+    let len = (FIXED_HEADER_LEN + fields_len + 7) / 8 * 8 + body_len;
+    if body_len > MAX_MESSAGE_LEN || fields_len > MAX_MESSAGE_LEN || len > MAX_MESSAGE_LEN {
+        return Err(invalid_data("D-Bus message too long"));
+    }
+
+    Ok(Some(len))
+}
+
+fn parse_signature(signature: &str) -> io::Result<Vec<Type>> {
+    if signature.len() > MAX_SIGNATURE_LEN {
+        return Err(invalid_signature());
+    }
+
+    let bytes = signature.as_bytes();
+    let mut pos = 0;
+    let mut types = Vec::new();
+    while pos < bytes.len() {
+        types.push(parse_type(bytes, &mut pos, 0)?);
+    }
+
+    Ok(types)
+}
+
+fn parse_single_type(signature: &str) -> io::Result<Type> {
+    let mut types = parse_signature(signature)?;
+    match types.len() {
+        1 => Ok(types.remove(0)),
+        _ => Err(invalid_signature()),
+    }
+}
+
+fn parse_type(signature: &[u8], pos: &mut usize, depth: usize) -> io::Result<Type> {
+    if depth > MAX_DEPTH {
+        return Err(invalid_signature());
+    }
+    let code = *signature.get(*pos).ok_or_else(invalid_signature)?;
+    *pos += 1;
+
+    let ty = match code {
+        b'y' => Type::Byte,
+        b'b' => Type::Boolean,
+        b'n' => Type::Int16,
+        b'q' => Type::Uint16,
+        b'i' => Type::Int32,
+        b'u' => Type::Uint32,
+        b'x' => Type::Int64,
+        b't' => Type::Uint64,
+        b'd' => Type::Double,
+        b's' => Type::String,
+        b'o' => Type::ObjectPath,
+        b'g' => Type::Signature,
+        b'h' => Type::UnixFd,
+        b'v' => Type::Variant,
+        b'a' if signature.get(*pos) == Some(&b'{') => {
+            *pos += 1;
+            let key = parse_type(signature, pos, depth + 1)?;
+            let value = parse_type(signature, pos, depth + 1)?;
+            if !key.is_basic() || signature.get(*pos) != Some(&b'}') {
+                return Err(invalid_signature());
+            }
+            *pos += 1;
+            Type::Array(Box::new(Type::DictEntry(Box::new(key), Box::new(value))))
+        }
+        b'a' => Type::Array(Box::new(parse_type(signature, pos, depth + 1)?)),
+        b'(' => {
+            let mut fields = Vec::new();
+            while signature.get(*pos) != Some(&b')') {
+                fields.push(parse_type(signature, pos, depth + 1)?);
+            }
+            *pos += 1;
+            if fields.is_empty() {
+                return Err(invalid_signature());
+            }
+            Type::Struct(fields)
+        }
+        _ => return Err(invalid_signature()),
+    };
+
+    Ok(ty)
+}
+
+fn into_items(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array { items, .. } => items,
+        _ => Vec::new(),
+    }
+}
+
+fn into_fields(value: Value) -> Vec<Value> {
+    match value {
+        Value::Struct(fields) => fields,
+        _ => Vec::new(),
+    }
+}
+
+fn invalid_signature() -> Error {
+    invalid_data("invalid D-Bus signature")
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    use assert_matches::assert_matches;
+
+    use crate::test_util::hello_file;
+
+    fn assert_hello(value: Value) {
+        let fd = match value {
+            Value::UnixFd(fd) => fd,
+            value => panic!("Not an fd: {:?}", value),
+        };
+        let mut buf = String::new();
+        File::from(fd)
+            .read_to_string(&mut buf)
+            .expect("Can't read from file");
+        assert_eq!(buf, "Hello World!");
+    }
+
+    fn open_call() -> Message {
+        let mut call = Message::method_call(7, "/org/example/Logs", "Open");
+        call.interface = Some("org.example.Logs".to_owned());
+        call.destination = Some("org.example".to_owned());
+        call.body = vec![
+            Value::String("app".to_owned()),
+            Value::UnixFd(hello_file().into()),
+            Value::Array {
+                element: "h".to_owned(),
+                items: vec![
+                    Value::UnixFd(hello_file().into()),
+                    Value::UnixFd(hello_file().into()),
+                ],
+            },
+            Value::Array {
+                element: "{sv}".to_owned(),
+                items: vec![Value::DictEntry(
+                    Box::new(Value::String("mode".to_owned())),
+                    Box::new(Value::Variant(Box::new(Value::Uint32(0o640)))),
+                )],
+            },
+            Value::Struct(vec![Value::Byte(1), Value::Int64(-2), Value::Double(0.5)]),
+        ];
+        call
+    }
+
+    #[test]
+    fn message_round_trip_keeps_fds_with_their_arguments() {
+        let (mut sock1, mut sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+
+        write_message(&mut sock1, &open_call()).expect("Can't write message");
+        let sut = read_message(&mut sock2).expect("Can't read message");
+
+        assert_eq!(sut.message_type, MessageType::MethodCall);
+        assert_eq!(sut.serial, 7);
+        assert_eq!(sut.path.as_deref(), Some("/org/example/Logs"));
+        assert_eq!(sut.interface.as_deref(), Some("org.example.Logs"));
+        assert_eq!(sut.member.as_deref(), Some("Open"));
+        assert_eq!(sut.destination.as_deref(), Some("org.example"));
+        assert_eq!(sut.signature(), "shaha{sv}(yxd)");
+        let mut body = sut.body.into_iter();
+        assert_matches!(body.next(), Some(Value::String(s)) if s == "app");
+        assert_hello(body.next().expect("No fd argument"));
+        for fd in into_items(body.next().expect("No fd array")) {
+            assert_hello(fd);
+        }
+        assert_matches!(
+            into_items(body.next().expect("No dict")).as_slice(),
+            [Value::DictEntry(key, value)] if matches!(
+                (&**key, &**value),
+                (Value::String(k), Value::Variant(v)) if k == "mode" && matches!(**v, Value::Uint32(0o640))
+            )
+        );
+    }
+
+    #[test]
+    fn write_message_with_too_many_fds_queues_none() {
+        let (mut sock1, mut sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut call = Message::method_call(1, "/", "Method");
+        call.body = (0..=UnixStream::FD_QUEUE_SIZE)
+            .map(|_| Value::UnixFd(hello_file().into()))
+            .collect();
+
+        let result = write_message(&mut sock1, &call);
+        write_message(&mut sock1, &Message::method_call(2, "/", "Method"))
+            .expect("Can't write message");
+        let sut = read_message(&mut sock2).expect("Can't read message");
+
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::InvalidInput);
+        assert_eq!(sut.serial, 2);
+    }
+
+    #[test]
+    fn write_message_failure_clears_queued_fds() {
+        let (mut sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        drop(sock2);
+        let file = hello_file();
+
+        let result = write_message(&mut sock1, &open_call());
+        for _ in 0..UnixStream::FD_QUEUE_SIZE {
+            sock1.enqueue(&file).expect("Failed write left fds queued");
+        }
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decode_with_wrong_fd_count_is_error() {
+        let call = open_call();
+        let (bytes, fds) = call.encode().expect("Can't encode message");
+        let mut fds: Vec<OwnedFd> = fds
+            .into_iter()
+            .map(|fd| fd.try_clone_to_owned().expect("Can't dup fd"))
+            .collect();
+        fds.pop();
+
+        let result = Message::decode(&bytes, fds);
+
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_big_endian_reply() {
+        // A method return with reply serial 7 and the body "u" 42, big endian.
+        let bytes = [
+            b'B', 2, 0, 1, 0, 0, 0, 4, 0, 0, 0, 9, 0, 0, 0, 15, // fixed header
+            5, 1, b'u', 0, 0, 0, 0, 7, // REPLY_SERIAL
+            8, 1, b'g', 0, 1, b'u', 0, 0, // SIGNATURE
+            0, 0, 0, 42, // body
+        ];
+
+        let sut = Message::decode(&bytes, Vec::new()).expect("Can't decode message");
+
+        assert_eq!(sut.message_type, MessageType::MethodReturn);
+        assert_eq!(sut.reply_serial, Some(7));
+        assert_matches!(sut.body.as_slice(), [Value::Uint32(42)]);
+    }
+
+    #[test]
+    fn encode_array_with_mismatched_item_is_error() {
+        let mut call = Message::method_call(1, "/", "Method");
+        call.body = vec![Value::Array {
+            element: "s".to_owned(),
+            items: vec![Value::Uint32(1)],
+        }];
+
+        let result = call.encode();
+
+        assert_matches!(result, Err(e) if e.kind() == ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "codec-fd")]
+    #[tokio::test]
+    async fn codec_keeps_fds_with_messages_from_separate_writes() {
+        use crate::tokio::{codec::FdFramed, UnixStream};
+        use futures_util::{SinkExt, StreamExt};
+
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sender = FdFramed::new(sock1, DbusCodec);
+        let mut sut = FdFramed::new(sock2, DbusCodec);
+
+        let plain = Message::signal(1, "/org/example/Logs", "org.example.Logs", "Plain");
+        let mut withfd = Message::signal(2, "/org/example/Logs", "org.example.Logs", "Rotated");
+        withfd.body = vec![Value::UnixFd(hello_file().into())];
+        sender.send(plain).await.expect("Can't send message");
+        sender.send(withfd).await.expect("Can't send message");
+
+        let plain = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode message");
+        let withfd = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode message");
+
+        assert!(plain.body.is_empty());
+        assert_hello(withfd.body.into_iter().next().expect("No fd argument"));
+    }
+
+    #[cfg(feature = "codec-fd")]
+    #[tokio::test]
+    async fn codec_keeps_fds_with_their_messages() {
+        use crate::tokio::{codec::FdFramed, UnixStream};
+        use futures_util::{SinkExt, StreamExt};
+
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sender = FdFramed::new(sock1, DbusCodec);
+        let mut sut = FdFramed::new(sock2, DbusCodec);
+
+        let mut signal = Message::signal(8, "/org/example/Logs", "org.example.Logs", "Rotated");
+        signal.body = vec![Value::UnixFd(hello_file().into())];
+        sender.feed(open_call()).await.expect("Can't feed message");
+        sender.feed(signal).await.expect("Can't feed message");
+        sender.flush().await.expect("Can't flush messages");
+
+        let call = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode message");
+        let signal = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode message");
+
+        assert_eq!(call.serial, 7);
+        assert_eq!(signal.member.as_deref(), Some("Rotated"));
+        assert_hello(signal.body.into_iter().next().expect("No fd argument"));
+    }
+}
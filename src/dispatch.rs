@@ -0,0 +1,593 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Hand accepted TCP connections to a pool of prefork worker processes.
+//!
+//! A [`Dispatcher`] owns a `TcpListener` and a pool of worker processes, each of
+//! which is connected to it by a [`UnixStream`] created with
+//! [`process::Command`][crate::process::Command]. The dispatcher accepts each
+//! connection and passes its fd to the worker that its [`Balance`] picks, and it
+//! replaces any worker that dies. A worker receives the connections through a
+//! [`Worker`] and can report its load back to the dispatcher for the
+//! [`LeastLoaded`] balance to use.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use fd_queue::dispatch::{Dispatcher, RoundRobin, Worker};
+//! use fd_queue::process::Command;
+//! use std::io::prelude::*;
+//! use std::net::TcpListener;
+//!
+//! // In the front process.
+//! let listener = TcpListener::bind("127.0.0.1:8080")?;
+//! let mut dispatcher = Dispatcher::new(listener, Command::new("worker"), 4, RoundRobin::new())?;
+//! dispatcher.run()?;
+//!
+//! // In each worker process ("worker").
+//! for stream in Worker::from_parent()? {
+//!     stream?.write_all(b"Hello from a worker!")?;
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    io::{self, prelude::*, Error, ErrorKind},
+    net::{TcpListener, TcpStream},
+    process::Child,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{process::Command, EnqueueFd, UnixStream};
+
+const FRAME_CONNECTION: u8 = b'C';
+const FRAME_LOAD: u8 = b'L';
+const LOAD_FRAME_LEN: usize = 5;
+
+/// How long a worker has to finish a load report once it has started sending
+/// it before it is replaced.
+pub const PARTIAL_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Accepts TCP connections and hands them to a pool of worker processes.
+#[derive(Debug)]
+pub struct Dispatcher<B> {
+    listener: TcpListener,
+    command: Command,
+    workers: Vec<WorkerProcess>,
+    loads: Vec<WorkerLoad>,
+    balance: B,
+}
+
+#[derive(Debug)]
+struct WorkerProcess {
+    child: Child,
+    channel: UnixStream,
+    // the part of a load report that has arrived so far
+    frame: [u8; LOAD_FRAME_LEN],
+    filled: usize,
+    // when a partial load report was first seen
+    partial_since: Option<Instant>,
+}
+
+/// The receiving end of the connections that a [`Dispatcher`] hands to a worker
+/// process.
+#[derive(Debug)]
+pub struct Worker {
+    channel: UnixStream,
+}
+
+/// The load of a worker as a [`Dispatcher`] knows it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerLoad {
+    /// The load that the worker last reported with [`Worker::report_load()`], or 0
+    /// if it hasn't reported one.
+    pub reported: u32,
+
+    /// The number of connections handed to the worker since it last reported its
+    /// load.
+    pub dispatched: u32,
+}
+
+/// A strategy for picking the worker to hand the next connection to.
+pub trait Balance {
+    /// Picks the index in `workers` of the worker to hand the next connection to.
+    ///
+    /// `workers` is never empty.
+    fn pick(&mut self, workers: &[WorkerLoad]) -> usize;
+}
+
+/// A [`Balance`] that hands connections to each worker in turn.
+#[derive(Debug, Clone, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+/// A [`Balance`] that hands each connection to the worker with the lowest load.
+///
+/// The load of a worker is the load it last reported plus the connections that
+/// it has been handed since.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeastLoaded;
+
+// === impl Dispatcher ===
+
+impl<B: Balance> Dispatcher<B> {
+    /// Creates a dispatcher that hands the connections that `listener` accepts to
+    /// `count` worker processes spawned from `command`.
+    ///
+    /// Any worker that dies is replaced by spawning `command` again.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidInput` error if `count` is 0, and any error from
+    /// spawning the workers.
+    pub fn new(
+        listener: TcpListener,
+        mut command: Command,
+        count: usize,
+        balance: B,
+    ) -> io::Result<Dispatcher<B>> {
+        if count == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "dispatcher needs at least one worker",
+            ));
+        }
+
+        let workers = (0..count)
+            .map(|_| WorkerProcess::spawn(&mut command))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Dispatcher {
+            listener,
+            command,
+            workers,
+            loads: vec![WorkerLoad::default(); count],
+            balance,
+        })
+    }
+
+    /// Accepts connections and hands them to the workers.
+    ///
+    /// This only returns if accepting a connection or replacing a worker fails.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            self.dispatch(stream)?;
+        }
+    }
+
+    /// Hands `stream` to the worker that the balance picks.
+    ///
+    /// This first checks on the workers as [`check_workers()`] does. If the
+    /// picked worker turns out to have died then it is replaced and another
+    /// worker is picked.
+    ///
+    /// [`check_workers()`]: Dispatcher::check_workers
+    pub fn dispatch(&mut self, stream: TcpStream) -> io::Result<()> {
+        self.check_workers()?;
+
+        for _ in 0..=self.workers.len() {
+            let index = self.balance.pick(&self.loads);
+            match self.workers[index].send(&stream) {
+                Ok(()) => {
+                    self.loads[index].dispatched = self.loads[index].dispatched.saturating_add(1);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        source = "Dispatcher",
+                        event = "dispatch",
+                        condition = "worker failed",
+                        error = %e
+                    );
+                    self.respawn(index)?;
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::BrokenPipe,
+            "no worker accepted the connection",
+        ))
+    }
+
+    /// Reads the load reports that the workers have sent and replaces any worker
+    /// that has died.
+    ///
+    /// This doesn't block. A worker that leaves a load report unfinished for
+    /// longer than [`PARTIAL_FRAME_TIMEOUT`] is replaced as well.
+    pub fn check_workers(&mut self) -> io::Result<()> {
+        for index in 0..self.workers.len() {
+            loop {
+                match self.workers[index].try_recv_load() {
+                    Ok(Some(load)) => {
+                        self.loads[index] = WorkerLoad {
+                            reported: load,
+                            dispatched: 0,
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!(
+                            source = "Dispatcher",
+                            event = "check",
+                            condition = "worker failed",
+                            error = %e
+                        );
+                        self.respawn(index)?;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The loads of the workers as the balance sees them.
+    pub fn loads(&self) -> &[WorkerLoad] {
+        &self.loads
+    }
+
+    /// The process ids of the workers, in the same order as [`loads()`].
+    ///
+    /// [`loads()`]: Dispatcher::loads
+    pub fn pids(&self) -> Vec<u32> {
+        self.workers
+            .iter()
+            .map(|worker| worker.child.id())
+            .collect()
+    }
+
+    /// The listener that connections are accepted from.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Closes the channels to the workers and waits for them to exit.
+    ///
+    /// A worker sees the end of its connections once its channel is closed.
+    pub fn shutdown(self) -> io::Result<()> {
+        let children: Vec<_> = self
+            .workers
+            .into_iter()
+            .map(|WorkerProcess { child, channel, .. }| {
+                drop(channel);
+                child
+            })
+            .collect();
+
+        for mut child in children {
+            child.wait()?;
+        }
+
+        Ok(())
+    }
+
+    fn respawn(&mut self, index: usize) -> io::Result<()> {
+        let old = &mut self.workers[index];
+        // The worker may still be running if it broke the protocol.
+        let _ = old.child.kill();
+        old.child.wait()?;
+
+        self.workers[index] = WorkerProcess::spawn(&mut self.command)?;
+        self.loads[index] = WorkerLoad::default();
+
+        Ok(())
+    }
+}
+
+// === impl WorkerProcess ===
+
+impl WorkerProcess {
+    fn spawn(command: &mut Command) -> io::Result<WorkerProcess> {
+        let (child, channel) = command.spawn::<UnixStream>()?;
+
+        Ok(WorkerProcess {
+            child,
+            channel,
+            frame: [0; LOAD_FRAME_LEN],
+            filled: 0,
+            partial_since: None,
+        })
+    }
+
+    fn send(&mut self, stream: &TcpStream) -> io::Result<()> {
+        self.channel
+            .enqueue(stream)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        self.channel.write_all(&[FRAME_CONNECTION])?;
+        self.channel.flush()
+    }
+
+    /// Receive the next load report if all of it has arrived.
+    fn try_recv_load(&mut self) -> io::Result<Option<u32>> {
+        self.channel.set_nonblocking(true)?;
+        let result = self.read_frame();
+        self.channel.set_nonblocking(false)?;
+
+        if !result? {
+            if self.filled == 0 {
+                return Ok(None);
+            }
+            let since = *self.partial_since.get_or_insert_with(Instant::now);
+            if since.elapsed() < PARTIAL_FRAME_TIMEOUT {
+                return Ok(None);
+            }
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "worker left a load report unfinished",
+            ));
+        }
+
+        self.filled = 0;
+        self.partial_since = None;
+        let frame = self.frame;
+        if frame[0] == FRAME_LOAD {
+            Ok(Some(u32::from_be_bytes([
+                frame[1], frame[2], frame[3], frame[4],
+            ])))
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown worker frame {}", frame[0]),
+            ))
+        }
+    }
+
+    // Reads as much of a load report as has arrived without blocking, returning
+    // `true` once all of it has.
+    fn read_frame(&mut self) -> io::Result<bool> {
+        while self.filled < LOAD_FRAME_LEN {
+            match self.channel.read(&mut self.frame[self.filled..]) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "worker closed its channel",
+                    ))
+                }
+                Ok(count) => self.filled += count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            // A worker has no reason to send fds, so close any that it did.
+            drop(self.channel.take_fds());
+        }
+
+        Ok(true)
+    }
+}
+
+// === impl Worker ===
+
+impl Worker {
+    /// Connects to the dispatcher that spawned this process.
+    pub fn from_parent() -> io::Result<Worker> {
+        UnixStream::from_parent().map(Worker::from)
+    }
+
+    /// Receives the next connection, or `None` once the dispatcher has closed
+    /// the channel.
+    pub fn recv(&mut self) -> io::Result<Option<TcpStream>> {
+        let mut frame = [0; 1];
+        let count = loop {
+            match self.channel.read(&mut frame) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // The dispatcher closed the channel without reading our last load
+                // report.
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => break 0,
+                result => break result?,
+            }
+        };
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let fd = self.channel.take_fds().pop();
+        match (frame[0], fd) {
+            (FRAME_CONNECTION, Some(fd)) => Ok(Some(TcpStream::from(fd))),
+            (FRAME_CONNECTION, None) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "connection frame without an fd",
+            )),
+            (frame, _) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown dispatcher frame {}", frame),
+            )),
+        }
+    }
+
+    /// Reports the current load of this worker (for instance its number of open
+    /// connections) to the dispatcher.
+    pub fn report_load(&mut self, load: u32) -> io::Result<()> {
+        let mut frame = [FRAME_LOAD, 0, 0, 0, 0];
+        frame[1..].copy_from_slice(&load.to_be_bytes());
+        self.channel.write_all(&frame)?;
+        self.channel.flush()
+    }
+}
+
+impl From<UnixStream> for Worker {
+    fn from(channel: UnixStream) -> Worker {
+        Worker { channel }
+    }
+}
+
+impl Iterator for Worker {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().transpose()
+    }
+}
+
+// === impl RoundRobin ===
+
+impl RoundRobin {
+    /// Creates a `RoundRobin` that starts with the first worker.
+    pub fn new() -> RoundRobin {
+        RoundRobin::default()
+    }
+}
+
+impl Balance for RoundRobin {
+    fn pick(&mut self, workers: &[WorkerLoad]) -> usize {
+        let index = self.next % workers.len();
+        self.next = index + 1;
+
+        index
+    }
+}
+
+// === impl LeastLoaded ===
+
+impl Balance for LeastLoaded {
+    fn pick(&mut self, workers: &[WorkerLoad]) -> usize {
+        workers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, load)| load.reported.saturating_add(load.dispatched))
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        collections::HashSet,
+        env, process,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn least_loaded_picks_lowest_load() {
+        let loads = [
+            WorkerLoad {
+                reported: 3,
+                dispatched: 0,
+            },
+            WorkerLoad {
+                reported: 1,
+                dispatched: 1,
+            },
+            WorkerLoad {
+                reported: 0,
+                dispatched: 4,
+            },
+        ];
+
+        assert_eq!(LeastLoaded.pick(&loads), 1);
+        assert_eq!(RoundRobin { next: 2 }.pick(&loads), 2);
+        assert_eq!(RoundRobin { next: 3 }.pick(&loads), 0);
+    }
+
+    #[test]
+    fn dispatcher_respawns_dead_workers() {
+        const CHILD_ENV: &str = "FD_QUEUE_DISPATCH_CHILD";
+
+        if env::var_os(CHILD_ENV).is_some() {
+            let mut worker = Worker::from_parent().expect("Can't connect to dispatcher");
+            while let Some(mut stream) = worker.recv().expect("Can't receive connection") {
+                worker.report_load(1).expect("Can't report load");
+                stream
+                    .write_all(&process::id().to_be_bytes())
+                    .expect("Can't write");
+            }
+            return;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Can't bind");
+        let addr = listener.local_addr().expect("Can't get local addr");
+        let mut command = Command::new(env::current_exe().expect("Can't find test executable"));
+        command
+            .as_std_mut()
+            .args([
+                "--exact",
+                "dispatch::tests::dispatcher_respawns_dead_workers",
+                "--quiet",
+            ])
+            .env(CHILD_ENV, "1");
+        let mut sut =
+            Dispatcher::new(listener, command, 2, RoundRobin::new()).expect("Can't start workers");
+        let connect = |sut: &mut Dispatcher<RoundRobin>| {
+            let mut client = TcpStream::connect(addr).expect("Can't connect");
+            let (stream, _) = sut.listener().accept().expect("Can't accept");
+            sut.dispatch(stream).expect("Can't dispatch");
+            let mut pid = [0; 4];
+            client.read_exact(&mut pid).expect("Can't read pid");
+            u32::from_be_bytes(pid)
+        };
+
+        let first: HashSet<_> = (0..2).map(|_| connect(&mut sut)).collect();
+        let pids = sut.pids();
+        // SAFETY: kill() has no memory safety requirements.
+        unsafe { libc::kill(pids[0] as libc::pid_t, libc::SIGKILL) };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sut.pids() == pids && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+            sut.check_workers().expect("Can't check workers");
+        }
+        let second: HashSet<_> = (0..2).map(|_| connect(&mut sut)).collect();
+        let respawned = sut.pids();
+        sut.shutdown().expect("Can't shut down");
+
+        assert_eq!(first, pids.iter().copied().collect());
+        assert_ne!(respawned[0], pids[0]);
+        assert_eq!(respawned[1], pids[1]);
+        assert_eq!(second, respawned.iter().copied().collect());
+    }
+
+    #[test]
+    fn dispatcher_respawns_workers_with_partial_load_reports() {
+        const CHILD_ENV: &str = "FD_QUEUE_DISPATCH_PARTIAL_CHILD";
+
+        if env::var_os(CHILD_ENV).is_some() {
+            let mut worker = Worker::from_parent().expect("Can't connect to dispatcher");
+            // The dispatcher may already have replaced this worker.
+            let _ = worker.channel.write_all(&[FRAME_LOAD]);
+            let _ = worker.recv();
+            return;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Can't bind");
+        let mut command = Command::new(env::current_exe().expect("Can't find test executable"));
+        command
+            .as_std_mut()
+            .args([
+                "--exact",
+                "dispatch::tests::dispatcher_respawns_workers_with_partial_load_reports",
+                "--quiet",
+            ])
+            .env(CHILD_ENV, "1");
+        let mut sut =
+            Dispatcher::new(listener, command, 1, RoundRobin::new()).expect("Can't start workers");
+        let pids = sut.pids();
+        let mut slowest = Duration::ZERO;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sut.pids() == pids && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+            let start = Instant::now();
+            sut.check_workers().expect("Can't check workers");
+            slowest = slowest.max(start.elapsed());
+        }
+        let respawned = sut.pids();
+        sut.shutdown().expect("Can't shut down");
+
+        assert_ne!(respawned, pids);
+        assert!(slowest < PARTIAL_FRAME_TIMEOUT);
+    }
+}
@@ -0,0 +1,513 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Hand listeners and live connections from one process to another.
+//!
+//! A hot restart upgrades a daemon without dropping its clients: the new process
+//! listens on a handoff socket, the old process connects to it with a
+//! [`Sender`] and transfers each of its listening sockets and accepted
+//! connections, and the new process takes them over with a [`Receiver`]. Each
+//! transferred fd carries a name and an opaque blob of application state (for
+//! instance a session id or a partially parsed request).
+//!
+//! The new process acknowledges each fd once it has received it, and
+//! [`Sender::send_listener()`] and [`Sender::send_stream()`] only return after that
+//! acknowledgement, so the old process can keep serving on an fd until the
+//! moment that the new process owns a copy of it.
+//!
+//! The old and new processes share a handed off listener's socket file, so the
+//! old process has to call [`UnixListener::keep_socket_file()`] on a listener
+//! that it created with
+//! [`ListenerBuilder::unlink_on_drop()`][crate::ListenerBuilder::unlink_on_drop()]
+//! before it drops the listener.
+//!
+//! # Protocol
+//!
+//! Both sides start by sending the 4 byte magic `FDQH` and the big endian `u16`
+//! protocol [`VERSION`], and each side fails if the other's version differs from
+//! its own. Each handoff is then a frame of a kind byte, a `u8` name length, a
+//! big endian `u32` state length, the name and the state, with the fd attached to
+//! the frame. The receiver answers each frame with a single acknowledgement byte.
+//! A frame with the kind byte `0` and no fd ends the handoff.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::{handoff::{Receiver, Sender}, UnixListener, UnixStream};
+//! # use std::thread;
+//! # use tempfile::tempdir;
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("service");
+//!
+//! // In the old process.
+//! let mut listener = UnixListener::builder().unlink_on_drop(true).bind(&path)?;
+//! let (old, new) = UnixStream::pair()?;
+//! let old = thread::spawn(move || {
+//!     let mut sender = Sender::new(old)?;
+//!     sender.send_listener("main", b"", &listener)?;
+//!     listener.keep_socket_file();
+//!     sender.finish()
+//! });
+//!
+//! // In the new process.
+//! let mut receiver = Receiver::new(new)?;
+//! while let Some(handoff) = receiver.recv()? {
+//!     assert_eq!(handoff.name(), "main");
+//!     let listener = handoff.into_listener()?;
+//! }
+//! # old.join().unwrap()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    convert::TryFrom,
+    io::{self, prelude::*, Error, ErrorKind},
+    os::unix::{
+        io::{AsRawFd, OwnedFd},
+        net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::Path,
+};
+
+use crate::{
+    activation::{self, SocketKind},
+    EnqueueFd, UnixListener, UnixStream,
+};
+
+/// The version of the handoff protocol spoken by this crate.
+pub const VERSION: u16 = 1;
+
+/// The largest application state that can accompany a single fd.
+pub const MAX_STATE_LEN: usize = 1 << 24;
+
+const MAGIC: &[u8; 4] = b"FDQH";
+const KIND_END: u8 = 0;
+const KIND_LISTENER: u8 = 1;
+const KIND_STREAM: u8 = 2;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+/// The sending (old process) side of a handoff.
+#[derive(Debug)]
+pub struct Sender {
+    stream: UnixStream,
+}
+
+/// The receiving (new process) side of a handoff.
+#[derive(Debug)]
+pub struct Receiver {
+    stream: UnixStream,
+    done: bool,
+}
+
+/// A listener or connection received through a handoff.
+#[derive(Debug)]
+pub struct Handoff {
+    kind: HandoffKind,
+    name: String,
+    state: Vec<u8>,
+    fd: OwnedFd,
+}
+
+/// The kind of fd in a [`Handoff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffKind {
+    /// A listening Unix stream socket.
+    Listener,
+
+    /// A connected Unix stream socket.
+    Stream,
+}
+
+// === impl Sender ===
+
+impl Sender {
+    /// Starts a handoff over `stream` by exchanging protocol versions.
+    pub fn new(mut stream: UnixStream) -> io::Result<Sender> {
+        handshake(&mut stream)?;
+
+        Ok(Sender { stream })
+    }
+
+    /// Connects to the handoff socket of the new process at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Sender> {
+        Sender::new(UnixStream::connect(path)?)
+    }
+
+    /// Hands `listener` off to the new process under `name`.
+    ///
+    /// This blocks until the new process has acknowledged the listener. The
+    /// caller still owns `listener` and can go on accepting connections on it
+    /// until this returns.
+    ///
+    /// A listener created with
+    /// [`ListenerBuilder::unlink_on_drop()`][crate::ListenerBuilder::unlink_on_drop()]
+    /// removes its socket file when the old process drops it, even though the new
+    /// process is now listening on that file. Call
+    /// [`UnixListener::keep_socket_file()`] on such a listener once it has been
+    /// handed off.
+    pub fn send_listener(
+        &mut self,
+        name: &str,
+        state: &[u8],
+        listener: &impl AsRawFd,
+    ) -> io::Result<()> {
+        self.send(KIND_LISTENER, name, state, Some(listener))
+    }
+
+    /// Hands the connection `stream` off to the new process under `name`.
+    ///
+    /// This blocks until the new process has acknowledged the connection. Any
+    /// bytes that the caller has read from `stream` but not yet processed should
+    /// be passed in `state`.
+    pub fn send_stream(
+        &mut self,
+        name: &str,
+        state: &[u8],
+        stream: &impl AsRawFd,
+    ) -> io::Result<()> {
+        self.send(KIND_STREAM, name, state, Some(stream))
+    }
+
+    /// Ends the handoff.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send::<OwnedFd>(KIND_END, "", &[], None)
+    }
+
+    fn send<F: AsRawFd>(
+        &mut self,
+        kind: u8,
+        name: &str,
+        state: &[u8],
+        fd: Option<&F>,
+    ) -> io::Result<()> {
+        let name_len = u8::try_from(name.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "handoff name is too long"))?;
+        if state.len() > MAX_STATE_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "handoff state is too long",
+            ));
+        }
+
+        let mut frame = Vec::with_capacity(6 + name.len() + state.len());
+        frame.push(kind);
+        frame.push(name_len);
+        frame.extend_from_slice(&(state.len() as u32).to_be_bytes());
+        frame.extend_from_slice(name.as_bytes());
+        frame.extend_from_slice(state);
+
+        if let Some(fd) = fd {
+            self.stream
+                .enqueue(fd)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        }
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+
+        let mut ack = [0];
+        self.stream.read_exact(&mut ack)?;
+        match ack[0] {
+            ACK => Ok(()),
+            NAK => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("new process rejected handoff {:?}", name),
+            )),
+            _ => Err(invalid_data("unexpected handoff acknowledgement")),
+        }
+    }
+}
+
+// === impl Receiver ===
+
+impl Receiver {
+    /// Starts a handoff over `stream` by exchanging protocol versions.
+    pub fn new(mut stream: UnixStream) -> io::Result<Receiver> {
+        handshake(&mut stream)?;
+
+        Ok(Receiver {
+            stream,
+            done: false,
+        })
+    }
+
+    /// Accepts a connection from the old process on `listener` and starts a
+    /// handoff over it.
+    pub fn accept(listener: &UnixListener) -> io::Result<Receiver> {
+        let (stream, _) = listener.accept()?;
+
+        Receiver::new(stream)
+    }
+
+    /// Receives the next listener or connection from the old process.
+    ///
+    /// This returns `Ok(None)` once the old process has finished the handoff.
+    /// It is an error for the old process to send an fd whose socket type
+    /// doesn't match the kind that it was sent as.
+    pub fn recv(&mut self) -> io::Result<Option<Handoff>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut header = [0; 6];
+        self.stream.read_exact(&mut header)?;
+        let kind = header[0];
+        let name_len = usize::from(header[1]);
+        let state_len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if state_len > MAX_STATE_LEN {
+            return Err(invalid_data("handoff state is too long"));
+        }
+
+        let mut name = vec![0; name_len];
+        self.stream.read_exact(&mut name)?;
+        let mut state = vec![0; state_len];
+        self.stream.read_exact(&mut state)?;
+        let fd = self.stream.take_fds().pop();
+
+        let (kind, fd) = match (kind, fd) {
+            (KIND_END, None) => {
+                self.done = true;
+                self.stream.write_all(&[ACK])?;
+                return Ok(None);
+            }
+            (KIND_LISTENER, Some(fd)) => (HandoffKind::Listener, fd),
+            (KIND_STREAM, Some(fd)) => (HandoffKind::Stream, fd),
+            _ => {
+                self.stream.write_all(&[NAK])?;
+                return Err(invalid_data("malformed handoff frame"));
+            }
+        };
+
+        let socket_kind = match kind {
+            HandoffKind::Listener => SocketKind::Listener,
+            HandoffKind::Stream => SocketKind::Stream,
+        };
+        let name = match String::from_utf8(name) {
+            Ok(name) if activation::is_kind(fd.as_raw_fd(), socket_kind) => name,
+            _ => {
+                self.stream.write_all(&[NAK])?;
+                return Err(invalid_data("handoff fd doesn't match its kind"));
+            }
+        };
+
+        self.stream.write_all(&[ACK])?;
+
+        Ok(Some(Handoff {
+            kind,
+            name,
+            state,
+            fd,
+        }))
+    }
+}
+
+impl Iterator for Receiver {
+    type Item = io::Result<Handoff>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().transpose()
+    }
+}
+
+// === impl Handoff ===
+
+impl Handoff {
+    /// The kind of fd that was handed off.
+    pub fn kind(&self) -> HandoffKind {
+        self.kind
+    }
+
+    /// The name that the old process gave this fd.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The application state that the old process sent with this fd.
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// Converts this handoff into the underlying fd.
+    pub fn into_fd(self) -> OwnedFd {
+        self.fd
+    }
+
+    /// Converts this handoff into a blocking `UnixListener`.
+    pub fn into_listener(self) -> io::Result<UnixListener> {
+        self.expect_kind(HandoffKind::Listener)
+            .map(|fd| StdUnixListener::from(fd).into())
+    }
+
+    /// Converts this handoff into a blocking `UnixStream`.
+    pub fn into_stream(self) -> io::Result<UnixStream> {
+        self.expect_kind(HandoffKind::Stream)
+            .map(|fd| StdUnixStream::from(fd).into())
+    }
+
+    /// Converts this handoff into a tokio `UnixListener`.
+    ///
+    /// This has the same runtime requirements as
+    /// [`crate::tokio::UnixListener::bind()`].
+    #[cfg(feature = "tokio-fd")]
+    pub fn into_tokio_listener(self) -> io::Result<crate::tokio::UnixListener> {
+        self.expect_kind(HandoffKind::Listener)
+            .and_then(|fd| crate::tokio::UnixListener::try_from(StdUnixListener::from(fd)))
+    }
+
+    /// Converts this handoff into a tokio `UnixStream`.
+    #[cfg(feature = "tokio-fd")]
+    pub fn into_tokio_stream(self) -> io::Result<crate::tokio::UnixStream> {
+        self.expect_kind(HandoffKind::Stream)
+            .and_then(|fd| crate::tokio::UnixStream::try_from(StdUnixStream::from(fd)))
+    }
+
+    fn expect_kind(self, kind: HandoffKind) -> io::Result<OwnedFd> {
+        if self.kind == kind {
+            Ok(self.fd)
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("handoff {:?} is a {:?}", self.name, self.kind),
+            ))
+        }
+    }
+}
+
+// === utility functions ===
+
+fn handshake(stream: &mut UnixStream) -> io::Result<()> {
+    let mut hello = [0; 6];
+    hello[..4].copy_from_slice(MAGIC);
+    hello[4..].copy_from_slice(&VERSION.to_be_bytes());
+    stream.write_all(&hello)?;
+
+    let mut peer = [0; 6];
+    stream.read_exact(&mut peer)?;
+    if &peer[..4] != MAGIC {
+        return Err(invalid_data("peer doesn't speak the handoff protocol"));
+    }
+
+    let version = u16::from_be_bytes([peer[4], peer[5]]);
+    if version != VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported handoff protocol version {}",
+            version
+        )));
+    }
+
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn handoff_passes_listener_and_stream() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("service");
+        let listener = UnixListener::bind(&path).expect("Can't bind listener");
+        let (conn, mut client) = UnixStream::pair().expect("Can't create pair");
+        let (old, new) = UnixStream::pair().expect("Can't create pair");
+        let mut buf = [0; 3];
+
+        let sender = thread::spawn(move || {
+            let mut sender = Sender::new(old).expect("Can't start sender");
+            sender
+                .send_listener("main", b"", &listener)
+                .expect("Can't send listener");
+            sender
+                .send_stream("client-1", b"session=7", &conn)
+                .expect("Can't send stream");
+            sender.finish().expect("Can't finish handoff");
+        });
+        let handoffs: Vec<Handoff> = Receiver::new(new)
+            .expect("Can't start receiver")
+            .collect::<io::Result<_>>()
+            .expect("Can't receive handoffs");
+        sender.join().expect("Sender panicked");
+
+        let mut handoffs = handoffs.into_iter();
+        let main = handoffs.next().expect("Missing listener");
+        let stream = handoffs.next().expect("Missing stream");
+        assert!(handoffs.next().is_none());
+        assert_eq!(main.name(), "main");
+        assert_eq!(stream.name(), "client-1");
+        assert_eq!(stream.state(), b"session=7");
+
+        let listener = main.into_listener().expect("Can't convert listener");
+        let _other = UnixStream::connect(&path).expect("Can't connect to listener");
+        listener.accept().expect("Can't accept on listener");
+        let mut stream = stream.into_stream().expect("Can't convert stream");
+        client.write_all(b"abc").expect("Can't write");
+        stream.read_exact(&mut buf).expect("Can't read");
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn handoff_rejects_mismatched_kind() {
+        let (conn, _client) = UnixStream::pair().expect("Can't create pair");
+        let (old, new) = UnixStream::pair().expect("Can't create pair");
+
+        let sender = thread::spawn(move || {
+            let mut sender = Sender::new(old).expect("Can't start sender");
+            sender.send_listener("main", b"", &conn)
+        });
+        let result = Receiver::new(new).expect("Can't start receiver").recv();
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+        assert_eq!(
+            sender
+                .join()
+                .expect("Sender panicked")
+                .err()
+                .map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn handoff_keeps_socket_file_of_handed_off_listener() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("service");
+        let mut listener = UnixListener::builder()
+            .unlink_on_drop(true)
+            .bind(&path)
+            .expect("Can't bind listener");
+        let (old, new) = UnixStream::pair().expect("Can't create pair");
+
+        let sender = thread::spawn(move || {
+            let mut sender = Sender::new(old).expect("Can't start sender");
+            sender
+                .send_listener("main", b"", &listener)
+                .expect("Can't send listener");
+            listener.keep_socket_file();
+            drop(listener);
+            sender.finish().expect("Can't finish handoff");
+        });
+        let mut receiver = Receiver::new(new).expect("Can't start receiver");
+        let main = receiver
+            .recv()
+            .expect("Can't receive handoff")
+            .expect("Missing listener");
+        assert!(receiver.recv().expect("Can't finish handoff").is_none());
+        sender.join().expect("Sender panicked");
+
+        let listener = main.into_listener().expect("Can't convert listener");
+        let _client = UnixStream::connect(&path).expect("Can't connect to listener");
+        listener.accept().expect("Can't accept on listener");
+    }
+}
@@ -18,21 +18,101 @@
 
 #![deny(missing_docs, warnings)]
 
+mod addr;
 mod queue;
 
-#[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+mod activation;
+
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
 mod biqueue;
 
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+mod builder;
+
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+mod path;
+
+#[cfg(feature = "net-fd")]
+mod cred;
+
 #[cfg(feature = "net-fd")]
 mod net;
 
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+pub mod process;
+
+#[cfg(all(feature = "net-fd", target_os = "linux"))]
+pub mod broker;
+
+#[cfg(feature = "net-fd")]
+pub mod cap;
+
+#[cfg(feature = "net-fd")]
+pub mod dispatch;
+
+#[cfg(feature = "net-fd")]
+pub mod handoff;
+
+#[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+pub mod mux;
+
+#[cfg(feature = "net-fd")]
+pub mod notify;
+
+#[cfg(feature = "net-fd")]
+pub mod pubsub;
+
+#[cfg(feature = "net-fd")]
+pub mod registry;
+
+#[cfg(any(feature = "net-fd", feature = "tokio-fd"))]
+pub mod relay;
+
+#[cfg(feature = "net-fd")]
+pub mod single_instance;
+
+#[cfg(feature = "dbus-fd")]
+pub mod dbus;
+
+#[cfg(feature = "rpc-fd")]
+pub mod rpc;
+
+#[cfg(feature = "serde-fd")]
+pub mod serde;
+
+#[cfg(feature = "varlink-fd")]
+pub mod varlink;
+
 #[cfg(feature = "mio-fd")]
 pub mod mio;
 
 #[cfg(feature = "tokio-fd")]
 pub mod tokio;
 
+#[cfg(feature = "async-io-fd")]
+pub mod async_io;
+
+#[cfg(feature = "calloop-fd")]
+pub mod calloop;
+
+#[cfg(all(test, any(feature = "net-fd", feature = "tokio-fd")))]
+mod test_util;
+
 #[cfg(feature = "net-fd")]
 pub use net::{Incoming, UnixListener, UnixStream};
 
+#[cfg(feature = "net-fd")]
+pub use cred::UCred;
+
+#[cfg(feature = "net-fd")]
+pub use relay::{relay, relay_with_filter};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use addr::abstract_addr;
+#[cfg(any(feature = "net-fd", feature = "tokio-fd", feature = "async-io-fd"))]
+pub use builder::ListenerBuilder;
+
+pub use addr::AddrKind;
+
 pub use queue::{DequeueFd, EnqueueFd, QueueFullError};
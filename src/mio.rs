@@ -8,7 +8,12 @@
 
 //! An implementation of `EnqueueFd` and `DequeueFd` that is integrated with mio.
 
-use crate::{DequeueFd, EnqueueFd, QueueFullError};
+use crate::{
+    builder::{BoundPath, FromBound, ListenerBuilder},
+    path::ShortPath,
+    process::{self, FromStdStream},
+    DequeueFd, EnqueueFd, QueueFullError,
+};
 
 use std::convert::{TryFrom, TryInto};
 use std::io::{self, prelude::*, IoSlice, IoSliceMut};
@@ -41,7 +46,47 @@ impl UnixStream {
     ///
     /// Note that this is synchronous.
     pub fn connect(path: impl AsRef<Path>) -> io::Result<UnixStream> {
-        StdUnixStream::connect(path)?.try_into()
+        StdUnixStream::connect(ShortPath::new(path.as_ref())?)?.try_into()
+    }
+
+    /// Connects to the socket named by `name` relative to the directory `dir`.
+    ///
+    /// Note that this is synchronous.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn connect_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixStream> {
+        StdUnixStream::connect(ShortPath::at(dir.as_raw_fd(), name.as_ref())?)?.try_into()
+    }
+
+    /// Connects to the socket specified by `addr`.
+    ///
+    /// This can connect to an address in the Linux abstract namespace. Note that
+    /// this is synchronous.
+    pub fn connect_addr(addr: &SocketAddr) -> io::Result<UnixStream> {
+        StdUnixStream::connect_addr(addr)?.try_into()
+    }
+
+    /// Takes the end of the channel that the parent process created with
+    /// [`process::Command`][crate::process::Command].
+    ///
+    /// This returns a `NotFound` error if the process was not spawned with a
+    /// channel, or if the channel was already taken.
+    pub fn from_parent() -> io::Result<UnixStream> {
+        UnixStream::try_from(StdUnixStream::from(process::take_parent_channel()?))
+    }
+
+    /// Adopts the connected Unix stream sockets passed to this process through
+    /// systemd socket activation.
+    ///
+    /// See [`crate::UnixStream::from_listen_fds()`]. The streams will be set to
+    /// non-blocking mode.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixStream>> {
+        crate::UnixStream::from_listen_fds()?
+            .into_iter()
+            .map(|inner| {
+                inner.set_nonblocking(true)?;
+                Ok(UnixStream { inner })
+            })
+            .collect()
     }
 
     /// Creates an unnamed pair of connected sockets.
@@ -79,6 +124,10 @@ impl EnqueueFd for UnixStream {
     fn enqueue(&mut self, fd: &impl AsRawFd) -> Result<(), QueueFullError> {
         self.inner.enqueue(fd)
     }
+
+    fn clear_enqueued(&mut self) {
+        self.inner.clear_enqueued()
+    }
 }
 
 impl DequeueFd for UnixStream {
@@ -166,22 +215,75 @@ impl TryFrom<StdUnixStream> for UnixStream {
     type Error = io::Error;
 
     fn try_from(inner: StdUnixStream) -> io::Result<UnixStream> {
-        inner.set_nonblocking(true)?;
-
-        Ok(UnixStream {
-            inner: inner.into(),
-        })
+        crate::UnixStream::from_std_nonblocking(inner).map(|inner| UnixStream { inner })
     }
 }
 
 // === impl UnixListener ===
 
 impl UnixListener {
+    /// Creates a builder for a `UnixListener` that manages its socket file.
+    ///
+    /// The listener will be set to non-blocking mode. See [`ListenerBuilder`]
+    /// for the available options.
+    pub fn builder() -> ListenerBuilder<UnixListener> {
+        ListenerBuilder::new()
+    }
+
+    /// Keeps the socket file when this listener is dropped.
+    ///
+    /// See [`fd_queue::UnixListener::keep_socket_file()`][crate::UnixListener::keep_socket_file()].
+    pub fn keep_socket_file(&mut self) {
+        self.inner.keep_socket_file();
+    }
+
+    /// Adopts the Unix listeners passed to this process through systemd socket
+    /// activation.
+    ///
+    /// See [`crate::UnixListener::from_listen_fds()`]. The listeners will be set
+    /// to non-blocking mode.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixListener>> {
+        crate::UnixListener::from_listen_fds()?
+            .into_iter()
+            .map(|inner| {
+                inner.set_nonblocking(true)?;
+                Ok(UnixListener { inner })
+            })
+            .collect()
+    }
+
+    /// Adopts the Unix listener named `name` that was passed to this process
+    /// through systemd socket activation.
+    ///
+    /// See [`crate::UnixListener::from_listen_fd_name()`]. The listener will be
+    /// set to non-blocking mode.
+    pub fn from_listen_fd_name(name: &str) -> io::Result<UnixListener> {
+        let inner = crate::UnixListener::from_listen_fd_name(name)?;
+        inner.set_nonblocking(true)?;
+        Ok(UnixListener { inner })
+    }
+
     /// Creates a new `UnixListener` bound to the specific path.
     ///
     /// The listener will be set to non-blocking mode.
     pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
-        StdUnixListner::bind(path)?.try_into()
+        StdUnixListner::bind(ShortPath::new(path.as_ref())?)?.try_into()
+    }
+
+    /// Creates a new `UnixListener` bound to `name` relative to the directory `dir`.
+    ///
+    /// The listener will be set to non-blocking mode.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixListener> {
+        StdUnixListner::bind(ShortPath::at(dir.as_raw_fd(), name.as_ref())?)?.try_into()
+    }
+
+    /// Creates a new `UnixListener` bound to the specific address.
+    ///
+    /// This can bind to an address in the Linux abstract namespace. The listener
+    /// will be set to non-blocking mode.
+    pub fn bind_addr(addr: &SocketAddr) -> io::Result<UnixListener> {
+        StdUnixListner::bind_addr(addr)?.try_into()
     }
 
     /// Accepts a new incoming connection to this listener.
@@ -256,15 +358,25 @@ impl Evented for UnixListener {
     }
 }
 
+impl FromStdStream for UnixStream {
+    fn from_std_stream(stream: StdUnixStream) -> io::Result<Self> {
+        UnixStream::try_from(stream)
+    }
+}
+
+impl FromBound for UnixListener {
+    fn from_bound(inner: StdUnixListner, path: BoundPath) -> io::Result<Self> {
+        let inner = crate::UnixListener::from_bound(inner, path)?;
+        inner.set_nonblocking(true)?;
+        Ok(UnixListener { inner })
+    }
+}
+
 impl TryFrom<StdUnixListner> for UnixListener {
     type Error = io::Error;
 
     fn try_from(inner: StdUnixListner) -> Result<Self, Self::Error> {
-        inner.set_nonblocking(true)?;
-
-        Ok(UnixListener {
-            inner: inner.into(),
-        })
+        crate::UnixListener::from_std_nonblocking(inner).map(|inner| UnixListener { inner })
     }
 }
 
@@ -293,8 +405,8 @@ mod tests {
         let poll = Poll::new().expect("Can't create poll.");
         let mut events = Events::with_capacity(5);
 
-        let (mut sut, mut other) = UnixStream::pair().expect("Unable to create pair.");
-        poll.register(&mut sut, Token(0), Ready::readable(), PollOpt::edge())
+        let (sut, mut other) = UnixStream::pair().expect("Unable to create pair.");
+        poll.register(&sut, Token(0), Ready::readable(), PollOpt::edge())
             .unwrap();
         write_to_steam(&mut other);
 
@@ -0,0 +1,1048 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Numbered channels multiplexed over a single `UnixStream`.
+//!
+//! A [`Mux`] carries any number of [`Channel`]s over one stream. Each channel
+//! implements `Read`, `Write`, [`EnqueueFd`] and [`DequeueFd`] (and, with the
+//! `tokio-fd` feature, `AsyncRead` and `AsyncWrite`) as if it were a stream of its
+//! own. The fds that are enqueued on a channel travel in a frame of that channel,
+//! and on the other side they are dequeued only from the channel whose frame
+//! carried them, so the fds of different channels never get mixed up.
+//!
+//! Each channel has its own flow control: a channel can have at most [`WINDOW`]
+//! bytes in flight that the other side hasn't read yet, after which writes to it
+//! wait. A channel that isn't read from only holds up its own writers.
+//!
+//! Both sides open a channel by its number with [`Mux::channel()`]. Frames that
+//! arrive before a side opens the channel are kept for it, for up to
+//! [`MAX_PENDING_CHANNELS`] such channels at a time; a peer that sends to more
+//! channels than that before they are opened breaks the mux with an
+//! `InvalidData` error, which bounds the memory that it can make this side hold.
+//! Dropping a channel closes it, after which reads on the other side return 0
+//! once its data has been read.
+//!
+//! [`Mux::new()`] drives a blocking [`UnixStream`][crate::UnixStream] on two
+//! threads of its own; [`Mux::new_async()`] drives a tokio
+//! [`UnixStream`][crate::tokio::UnixStream] with a [`Driver`] future that has to be
+//! spawned or polled.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "net-fd")]
+//! # {
+//! use fd_queue::mux::Mux;
+//! use fd_queue::{DequeueFd, EnqueueFd, UnixStream};
+//! use std::io::prelude::*;
+//! # use tempfile::tempfile;
+//!
+//! let (sock1, sock2) = UnixStream::pair()?;
+//! let (mux1, mux2) = (Mux::new(sock1)?, Mux::new(sock2)?);
+//! let mut logs = mux1.channel(1)?;
+//! let mut control = mux1.channel(2)?;
+//!
+//! # let file = tempfile()?;
+//! // let file: File = ...
+//! logs.enqueue(&file).expect("Can't enqueue the file");
+//! logs.write_all(b"log")?;
+//! control.write_all(b"ping")?;
+//!
+//! let mut control = mux2.channel(2)?;
+//! let mut buf = [0; 4];
+//! control.read_exact(&mut buf)?;
+//! assert!(control.dequeue().is_none());
+//!
+//! let mut logs = mux2.channel(1)?;
+//! let mut buf = [0; 3];
+//! logs.read_exact(&mut buf)?;
+//! assert!(logs.dequeue().is_some());
+//! # }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, prelude::*, Error, ErrorKind},
+    os::unix::io::{AsRawFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    task::Waker,
+};
+
+#[cfg(feature = "net-fd")]
+use std::{net::Shutdown, thread};
+
+#[cfg(feature = "tokio-fd")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "tokio-fd")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{biqueue::BiQueue, DequeueFd, EnqueueFd, QueueFullError};
+
+/// The number of bytes that can be in flight on a channel before the other side
+/// reads them.
+pub const WINDOW: usize = 1 << 18;
+
+/// The number of channels that the other side can send to before this side opens
+/// them.
+pub const MAX_PENDING_CHANNELS: usize = 16;
+
+const MAX_FRAME_DATA: usize = 1 << 16;
+const HEADER_LEN: usize = 10;
+
+const KIND_DATA: u8 = 0;
+const KIND_CREDIT: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+
+/// Multiplexes numbered [`Channel`]s over a single `UnixStream`.
+#[derive(Debug)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+/// One of the channels of a [`Mux`].
+pub struct Channel {
+    shared: Arc<Shared>,
+    id: u32,
+}
+
+/// The future that drives the stream of a [`Mux`] created with
+/// [`Mux::new_async()`].
+///
+/// It completes when the other side closes the stream, or once the mux and all of
+/// its channels have been dropped and everything written to them has been sent.
+#[cfg(feature = "tokio-fd")]
+#[derive(Debug)]
+pub struct Driver {
+    shared: Arc<Shared>,
+    stream: crate::tokio::UnixStream,
+    read_buf: Vec<u8>,
+    read_len: usize,
+    write_buf: Vec<u8>,
+    write_fds: Vec<OwnedFd>,
+    written: usize,
+}
+
+#[derive(Debug)]
+struct Shared {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    channels: HashMap<u32, ChannelState>,
+    outbound: VecDeque<Frame>,
+    writing: bool,
+    handles: usize,
+    broken: Option<(ErrorKind, String)>,
+    driver: Option<Waker>,
+    flushers: Vec<Waker>,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    open: bool,
+    sent_close: bool,
+    peer_closed: bool,
+    inbound: VecDeque<u8>,
+    inbound_fds: VecDeque<OwnedFd>,
+    outbound_fds: Vec<RawFd>,
+    credit: usize,
+    consumed: usize,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Frame {
+    channel: u32,
+    kind: u8,
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+// === impl Mux ===
+
+impl Mux {
+    /// Creates a mux over `stream`, which it drives on two threads of its own.
+    #[cfg(feature = "net-fd")]
+    pub fn new(stream: crate::UnixStream) -> io::Result<Mux> {
+        let mut reader = stream.try_clone()?;
+        let mux = Mux::with_shared();
+
+        let shared = mux.shared.clone();
+        thread::spawn(move || loop {
+            match read_frame(&mut reader) {
+                Ok(Some(frame)) => {
+                    let mut inner = shared.lock();
+                    if let Err(e) = inner.route(frame) {
+                        inner.fail(e.kind(), e.to_string());
+                    }
+                }
+                Ok(None) => {
+                    shared
+                        .lock()
+                        .fail(ErrorKind::BrokenPipe, "multiplexed stream is closed");
+                }
+                Err(e) => shared.lock().fail(e.kind(), e.to_string()),
+            }
+            shared.changed.notify_all();
+            if shared.lock().broken.is_some() {
+                break;
+            }
+        });
+
+        let shared = mux.shared.clone();
+        thread::spawn(move || {
+            let mut stream = stream;
+            while let Some(frame) = shared.next_frame() {
+                let result = write_frame(&mut stream, frame);
+                let mut inner = shared.lock();
+                inner.finish_write();
+                if let Err(e) = result {
+                    inner.fail(e.kind(), e.to_string());
+                }
+                drop(inner);
+                shared.changed.notify_all();
+            }
+            // This also ends the reading thread.
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+
+        Ok(mux)
+    }
+
+    /// Creates a mux over `stream` and the [`Driver`] that drives it.
+    ///
+    /// The channels of the mux make no progress unless the driver is polled.
+    #[cfg(feature = "tokio-fd")]
+    pub fn new_async(stream: crate::tokio::UnixStream) -> (Mux, Driver) {
+        let mux = Mux::with_shared();
+        let driver = Driver {
+            shared: mux.shared.clone(),
+            stream,
+            read_buf: Vec::new(),
+            read_len: 0,
+            write_buf: Vec::new(),
+            write_fds: Vec::new(),
+            written: 0,
+        };
+
+        (mux, driver)
+    }
+
+    fn with_shared() -> Mux {
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                handles: 1,
+                ..Inner::default()
+            }),
+            changed: Condvar::new(),
+        });
+
+        Mux { shared }
+    }
+
+    /// Opens the channel numbered `id`.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `AlreadyExists` error if the channel is already open, or if
+    /// it has been closed on this side but not yet on the other.
+    pub fn channel(&self, id: u32) -> io::Result<Channel> {
+        let mut inner = self.shared.lock();
+        let state = inner.channel(id);
+        if state.open || state.sent_close {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("channel {} is in use", id),
+            ));
+        }
+        state.open = true;
+        inner.handles += 1;
+
+        Ok(Channel {
+            shared: self.shared.clone(),
+            id,
+        })
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        self.shared.release();
+    }
+}
+
+// === impl Channel ===
+
+impl Channel {
+    /// Gets the number of this channel.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn try_read(&self, inner: &mut Inner, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .expect("open channel has a state");
+        if !state.inbound.is_empty() {
+            let len = buf.len().min(state.inbound.len());
+            for (dst, src) in buf.iter_mut().zip(state.inbound.drain(..len)) {
+                *dst = src;
+            }
+            state.consumed += len;
+            if state.consumed >= WINDOW / 2 {
+                let credit = std::mem::take(&mut state.consumed) as u32;
+                inner.push(Frame::new(
+                    self.id,
+                    KIND_CREDIT,
+                    credit.to_be_bytes().to_vec(),
+                ));
+            }
+            return Some(Ok(len));
+        }
+        if state.peer_closed || buf.is_empty() {
+            return Some(Ok(0));
+        }
+
+        inner.check_broken().err().map(Err)
+    }
+
+    fn try_write(&self, inner: &mut Inner, buf: &[u8]) -> Option<io::Result<usize>> {
+        if let Err(e) = inner.check_broken() {
+            return Some(Err(e));
+        }
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .expect("open channel has a state");
+        if state.sent_close {
+            return Some(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "channel has been shut down",
+            )));
+        }
+        if buf.is_empty() {
+            return Some(Ok(0));
+        }
+        if state.credit == 0 {
+            return None;
+        }
+
+        let len = buf.len().min(state.credit).min(MAX_FRAME_DATA);
+        let fds = state
+            .outbound_fds
+            .iter()
+            // Safety: the caller of enqueue() keeps the fd open until it is written.
+            .map(|&fd| unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned())
+            .collect::<io::Result<Vec<_>>>();
+        let fds = match fds {
+            Ok(fds) => fds,
+            Err(e) => return Some(Err(e)),
+        };
+        state.outbound_fds.clear();
+        state.credit -= len;
+        inner.push(Frame {
+            fds,
+            ..Frame::new(self.id, KIND_DATA, buf[..len].to_vec())
+        });
+
+        Some(Ok(len))
+    }
+
+    fn shut_down(&self, inner: &mut Inner) {
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .expect("open channel has a state");
+        if !state.sent_close {
+            state.sent_close = true;
+            inner.push(Frame::new(self.id, KIND_CLOSE, Vec::new()));
+        }
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel").field("id", &self.id).finish()
+    }
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.shared.lock();
+        loop {
+            if let Some(result) = self.try_read(&mut inner, buf) {
+                drop(inner);
+                self.shared.changed.notify_all();
+                return result;
+            }
+            inner = self.shared.wait(inner);
+        }
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.shared.lock();
+        loop {
+            if let Some(result) = self.try_write(&mut inner, buf) {
+                drop(inner);
+                self.shared.changed.notify_all();
+                return result;
+            }
+            inner = self.shared.wait(inner);
+        }
+    }
+
+    /// Waits until everything written to the mux has been sent on its stream.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut inner = self.shared.lock();
+        while !inner.is_flushed() {
+            inner.check_broken()?;
+            inner = self.shared.wait(inner);
+        }
+
+        Ok(())
+    }
+}
+
+/// Enqueue a [`RawFd`] to be sent with the next write to the channel.
+///
+/// The fd has to stay open until then. The number of fds that can be enqueued
+/// before being sent is bounded by `FD_QUEUE_SIZE` of the underlying stream.
+impl EnqueueFd for Channel {
+    fn enqueue(&mut self, fd: &impl AsRawFd) -> std::result::Result<(), QueueFullError> {
+        let mut inner = self.shared.lock();
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .expect("open channel has a state");
+        if state.outbound_fds.len() >= BiQueue::FD_QUEUE_SIZE {
+            return Err(QueueFullError::new());
+        }
+        state.outbound_fds.push(fd.as_raw_fd());
+
+        Ok(())
+    }
+
+    fn clear_enqueued(&mut self) {
+        if let Some(state) = self.shared.lock().channels.get_mut(&self.id) {
+            state.outbound_fds.clear();
+        }
+    }
+}
+
+/// Dequeue a [`RawFd`] that arrived in a frame of this channel.
+impl DequeueFd for Channel {
+    fn dequeue(&mut self) -> Option<RawFd> {
+        let mut inner = self.shared.lock();
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .expect("open channel has a state");
+        state.inbound_fds.pop_front().map(IntoRawFd::into_raw_fd)
+    }
+}
+
+#[cfg(feature = "tokio-fd")]
+impl AsyncRead for Channel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+        match self.try_read(&mut inner, buf.initialize_unfilled()) {
+            Some(result) => {
+                drop(inner);
+                self.shared.changed.notify_all();
+                Poll::Ready(result.map(|len| buf.advance(len)))
+            }
+            None => {
+                inner.channel(self.id).reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio-fd")]
+impl AsyncWrite for Channel {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.lock();
+        match self.try_write(&mut inner, buf) {
+            Some(result) => {
+                drop(inner);
+                self.shared.changed.notify_all();
+                Poll::Ready(result)
+            }
+            None => {
+                inner.channel(self.id).writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+        if inner.is_flushed() {
+            return Poll::Ready(Ok(()));
+        }
+        inner.check_broken()?;
+        inner.flushers.push(cx.waker().clone());
+
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        {
+            let mut inner = self.shared.lock();
+            self.shut_down(&mut inner);
+        }
+        self.shared.changed.notify_all();
+
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        if inner.broken.is_none() {
+            self.shut_down(&mut inner);
+        }
+        let state = inner.channel(self.id);
+        state.open = false;
+        state.inbound.clear();
+        state.inbound_fds.clear();
+        if state.peer_closed {
+            inner.channels.remove(&self.id);
+        }
+        drop(inner);
+
+        self.shared.release();
+    }
+}
+
+// === impl Driver ===
+
+#[cfg(feature = "tokio-fd")]
+impl Driver {
+    // Reads and routes frames until the stream would block or is closed.
+    fn poll_read_frames(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let want = if self.read_len < HEADER_LEN {
+                HEADER_LEN
+            } else {
+                HEADER_LEN + frame_data_len(&self.read_buf)?
+            };
+
+            if self.read_len == want {
+                let frame = Frame::decode(&self.read_buf[..want], self.stream.take_fds())?;
+                self.read_len = 0;
+
+                self.shared.lock().route(frame)?;
+                self.shared.changed.notify_all();
+                continue;
+            }
+
+            self.read_buf.resize(want, 0);
+            let mut buf = ReadBuf::new(&mut self.read_buf[self.read_len..want]);
+            futures_core::ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            let len = buf.filled().len();
+            if len == 0 {
+                return match self.read_len {
+                    0 => Poll::Ready(Ok(())),
+                    _ => Poll::Ready(Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "multiplexed stream closed in the middle of a frame",
+                    ))),
+                };
+            }
+            self.read_len += len;
+        }
+    }
+
+    // Writes frames until the stream would block, or until the mux and its channels
+    // are gone and everything has been written.
+    fn poll_write_frames(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            if self.written < self.write_buf.len() {
+                let len = futures_core::ready!(
+                    Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.written..])
+                )?;
+                if len == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.written += len;
+                continue;
+            }
+            self.write_fds.clear();
+
+            let mut inner = self.shared.lock();
+            inner.finish_write();
+            match inner.outbound.pop_front() {
+                Some(frame) => {
+                    inner.writing = true;
+                    drop(inner);
+                    for fd in frame.fds.iter() {
+                        self.stream
+                            .enqueue(fd)
+                            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+                    }
+                    self.write_buf = frame.encode();
+                    self.write_fds = frame.fds;
+                    self.written = 0;
+                }
+                None if inner.handles == 0 => {
+                    drop(inner);
+                    return Pin::new(&mut self.stream).poll_shutdown(cx);
+                }
+                None => {
+                    inner.driver = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(feature = "tokio-fd")]
+impl Future for Driver {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let result = match this.poll_read_frames(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => this.poll_write_frames(cx),
+        };
+        match &result {
+            Poll::Ready(Ok(())) => {
+                this.shared
+                    .lock()
+                    .fail(ErrorKind::BrokenPipe, "multiplexed stream is closed");
+            }
+            Poll::Ready(Err(e)) => this.shared.lock().fail(e.kind(), e.to_string()),
+            Poll::Pending => return Poll::Pending,
+        }
+        this.shared.changed.notify_all();
+
+        result
+    }
+}
+
+// === impl Shared ===
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, guard: MutexGuard<'a, Inner>) -> MutexGuard<'a, Inner> {
+        self.changed
+            .wait(guard)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn release(&self) {
+        let mut inner = self.lock();
+        inner.handles -= 1;
+        inner.wake_driver();
+        drop(inner);
+        self.changed.notify_all();
+    }
+
+    // Waits for the next frame to write, or None once there won't be any more.
+    #[cfg(feature = "net-fd")]
+    fn next_frame(&self) -> Option<Frame> {
+        let mut inner = self.lock();
+        loop {
+            if inner.broken.is_some() {
+                return None;
+            }
+            if let Some(frame) = inner.outbound.pop_front() {
+                inner.writing = true;
+                return Some(frame);
+            }
+            if inner.handles == 0 {
+                return None;
+            }
+            inner = self.wait(inner);
+        }
+    }
+}
+
+// === impl Inner ===
+
+impl Inner {
+    fn channel(&mut self, id: u32) -> &mut ChannelState {
+        self.channels.entry(id).or_insert_with(|| ChannelState {
+            credit: WINDOW,
+            ..ChannelState::default()
+        })
+    }
+
+    // The channels that the peer has sent to but that haven't been opened here.
+    fn pending_channels(&self) -> usize {
+        self.channels
+            .values()
+            .filter(|state| !state.open && !state.sent_close)
+            .count()
+    }
+
+    fn push(&mut self, frame: Frame) {
+        self.outbound.push_back(frame);
+        self.wake_driver();
+    }
+
+    fn wake_driver(&mut self) {
+        if let Some(waker) = self.driver.take() {
+            waker.wake();
+        }
+    }
+
+    fn finish_write(&mut self) {
+        if std::mem::take(&mut self.writing) && self.outbound.is_empty() {
+            self.flushers.drain(..).for_each(Waker::wake);
+        }
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.outbound.is_empty() && !self.writing
+    }
+
+    fn check_broken(&self) -> io::Result<()> {
+        match &self.broken {
+            Some((kind, msg)) => Err(Error::new(*kind, msg.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn route(&mut self, frame: Frame) -> io::Result<()> {
+        let id = frame.channel;
+        if !self.channels.contains_key(&id) && self.pending_channels() >= MAX_PENDING_CHANNELS {
+            return Err(invalid_data(
+                "peer sent to too many channels that aren't open",
+            ));
+        }
+        let state = self.channel(id);
+        match frame.kind {
+            KIND_DATA if state.peer_closed => {
+                return Err(invalid_data("data on a channel that the peer closed"));
+            }
+            KIND_DATA if !state.open && state.sent_close => {
+                // The channel is closed on this side, so its data is dropped and the
+                // credit for it handed straight back.
+                let credit = frame.data.len() as u32;
+                self.push(Frame::new(id, KIND_CREDIT, credit.to_be_bytes().to_vec()));
+            }
+            KIND_DATA => {
+                if state.inbound.len() + frame.data.len() > WINDOW {
+                    return Err(invalid_data("peer overran the window of a channel"));
+                }
+                state.inbound.extend(frame.data);
+                state.inbound_fds.extend(frame.fds);
+            }
+            KIND_CREDIT => {
+                let credit = match frame.data[..] {
+                    [a, b, c, d] => u32::from_be_bytes([a, b, c, d]) as usize,
+                    _ => return Err(invalid_data("invalid channel credit")),
+                };
+                state.credit += credit;
+            }
+            KIND_CLOSE => {
+                state.peer_closed = true;
+                if !state.open && state.sent_close {
+                    self.channels.remove(&id);
+                    return Ok(());
+                }
+            }
+            kind => return Err(invalid_data(&format!("unknown frame kind {}", kind))),
+        }
+
+        self.channel(id).wake();
+
+        Ok(())
+    }
+
+    fn fail(&mut self, kind: ErrorKind, msg: impl Into<String>) {
+        if self.broken.is_none() {
+            self.broken = Some((kind, msg.into()));
+        }
+        self.channels.values_mut().for_each(ChannelState::wake);
+        self.flushers.drain(..).for_each(Waker::wake);
+        self.wake_driver();
+    }
+}
+
+// === impl ChannelState ===
+
+impl ChannelState {
+    fn wake(&mut self) {
+        self.reader.take().into_iter().for_each(Waker::wake);
+        self.writer.take().into_iter().for_each(Waker::wake);
+    }
+}
+
+// === impl Frame ===
+
+impl Frame {
+    fn new(channel: u32, kind: u8, data: Vec<u8>) -> Frame {
+        Frame {
+            channel,
+            kind,
+            data,
+            fds: Vec::new(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.data.len());
+        bytes.extend_from_slice(&self.channel.to_be_bytes());
+        bytes.push(self.kind);
+        bytes.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        bytes.push(self.fds.len() as u8);
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8], fds: Vec<OwnedFd>) -> io::Result<Frame> {
+        if fds.len() != usize::from(bytes[9]) {
+            return Err(invalid_data("frame has the wrong number of fds"));
+        }
+
+        Ok(Frame {
+            channel: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            kind: bytes[4],
+            data: bytes[HEADER_LEN..].to_vec(),
+            fds,
+        })
+    }
+}
+
+// === utility functions ===
+
+fn frame_data_len(header: &[u8]) -> io::Result<usize> {
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > MAX_FRAME_DATA {
+        return Err(invalid_data("frame too long"));
+    }
+
+    Ok(len)
+}
+
+#[cfg(feature = "net-fd")]
+fn write_frame(stream: &mut crate::UnixStream, frame: Frame) -> io::Result<()> {
+    for fd in frame.fds.iter() {
+        stream
+            .enqueue(fd)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    stream.write_all(&frame.encode())?;
+    stream.flush()
+}
+
+/// Read the next frame, or `None` if the other side closed the stream.
+#[cfg(feature = "net-fd")]
+fn read_frame(stream: &mut crate::UnixStream) -> io::Result<Option<Frame>> {
+    let mut bytes = vec![0; HEADER_LEN];
+    if !stream.read_or_eof(&mut bytes)? {
+        return Ok(None);
+    }
+    bytes.resize(HEADER_LEN + frame_data_len(&bytes)?, 0);
+    stream.read_exact(&mut bytes[HEADER_LEN..])?;
+
+    Frame::decode(&bytes, stream.take_fds()).map(Some)
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::test_util::hello_file;
+
+    fn assert_hello(fd: RawFd) {
+        // Safety: the dequeued fd is owned by the test.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).expect("Can't read from file");
+        assert_eq!(buf, "Hello World!");
+    }
+
+    #[cfg(feature = "net-fd")]
+    fn mux_pair() -> (Mux, Mux) {
+        let (sock1, sock2) = crate::UnixStream::pair().expect("Can't create UnixStream's");
+        (
+            Mux::new(sock1).expect("Can't create mux"),
+            Mux::new(sock2).expect("Can't create mux"),
+        )
+    }
+
+    #[cfg(feature = "net-fd")]
+    #[test]
+    fn fds_are_routed_to_their_channel() {
+        let (mux1, mux2) = mux_pair();
+        let mut logs = mux1.channel(1).expect("Can't open channel");
+        let mut control = mux1.channel(2).expect("Can't open channel");
+        let file = hello_file();
+
+        control.write_all(b"first").expect("Can't write");
+        logs.enqueue(&file).expect("Can't enqueue fd");
+        logs.write_all(b"log").expect("Can't write");
+        control.write_all(b"second").expect("Can't write");
+        logs.flush().expect("Can't flush");
+        drop(file);
+
+        let mut control = mux2.channel(2).expect("Can't open channel");
+        let mut buf = [0; 11];
+        control.read_exact(&mut buf).expect("Can't read");
+        let mut logs = mux2.channel(1).expect("Can't open channel");
+        let mut log = [0; 3];
+        logs.read_exact(&mut log).expect("Can't read");
+
+        assert_eq!(&buf, b"firstsecond");
+        assert!(control.dequeue().is_none());
+        assert_eq!(&log, b"log");
+        assert_hello(logs.dequeue().expect("No fd on channel"));
+    }
+
+    #[cfg(feature = "net-fd")]
+    #[test]
+    fn full_channel_does_not_block_other_channels() {
+        let (mux1, mux2) = mux_pair();
+        let mut bulk = mux1.channel(1).expect("Can't open channel");
+        let mut control = mux1.channel(2).expect("Can't open channel");
+        let peer_bulk = mux2.channel(1).expect("Can't open channel");
+        let mut peer_control = mux2.channel(2).expect("Can't open channel");
+
+        let data = vec![7; WINDOW * 2];
+        let writer = thread::spawn(move || {
+            bulk.write_all(&data).expect("Can't write");
+            bulk
+        });
+        control.write_all(b"ping").expect("Can't write");
+        let mut buf = [0; 4];
+        peer_control.read_exact(&mut buf).expect("Can't read");
+        assert_eq!(&buf, b"ping");
+        assert!(!writer.is_finished());
+
+        let mut peer_bulk = peer_bulk;
+        let mut received = vec![0; WINDOW * 2];
+        peer_bulk.read_exact(&mut received).expect("Can't read");
+        writer.join().expect("Writer panicked");
+        assert!(received.iter().all(|&b| b == 7));
+    }
+
+    #[cfg(feature = "net-fd")]
+    #[test]
+    fn dropped_channel_reads_as_eof() {
+        let (mux1, mux2) = mux_pair();
+        let mut sender = mux1.channel(5).expect("Can't open channel");
+        sender.write_all(b"bye").expect("Can't write");
+        drop(sender);
+
+        let mut sut = mux2.channel(5).expect("Can't open channel");
+        let mut buf = Vec::new();
+        sut.read_to_end(&mut buf).expect("Can't read");
+
+        assert_eq!(buf, b"bye");
+        assert_eq!(
+            mux2.channel(5).map_err(|e| e.kind()).err(),
+            Some(ErrorKind::AlreadyExists)
+        );
+        drop(sut);
+        assert!(mux2.channel(5).is_ok());
+    }
+
+    #[cfg(feature = "net-fd")]
+    #[test]
+    fn too_many_pending_channels_break_the_mux() {
+        let (mux1, mux2) = mux_pair();
+        let mut senders = Vec::new();
+        for id in 0..=MAX_PENDING_CHANNELS as u32 {
+            let mut sender = mux1.channel(id).expect("Can't open channel");
+            sender.write_all(b"x").expect("Can't write");
+            sender.flush().expect("Can't flush");
+            senders.push(sender);
+        }
+
+        let mut sut = mux2
+            .channel(MAX_PENDING_CHANNELS as u32 + 1)
+            .expect("Can't open channel");
+        let result = sut.read(&mut [0; 1]);
+
+        assert_eq!(
+            result.map_err(|e| e.kind()).err(),
+            Some(ErrorKind::InvalidData)
+        );
+    }
+
+    #[cfg(feature = "tokio-fd")]
+    #[tokio::test]
+    async fn async_channels_route_fds() {
+        use crate::tokio::UnixStream;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mux1, driver1) = Mux::new_async(sock1);
+        let (mux2, driver2) = Mux::new_async(sock2);
+        tokio::spawn(driver1);
+        tokio::spawn(driver2);
+
+        let mut sender = mux1.channel(3).expect("Can't open channel");
+        let mut other = mux1.channel(4).expect("Can't open channel");
+        let file = hello_file();
+        sender.enqueue(&file).expect("Can't enqueue fd");
+        AsyncWriteExt::write_all(&mut sender, b"file")
+            .await
+            .expect("Can't write");
+        AsyncWriteExt::write_all(&mut other, b"none")
+            .await
+            .expect("Can't write");
+        AsyncWriteExt::flush(&mut sender)
+            .await
+            .expect("Can't flush");
+        drop(file);
+
+        let mut receiver = mux2.channel(3).expect("Can't open channel");
+        let mut peer_other = mux2.channel(4).expect("Can't open channel");
+        let mut buf = [0; 4];
+        AsyncReadExt::read_exact(&mut peer_other, &mut buf)
+            .await
+            .expect("Can't read");
+        assert!(peer_other.dequeue().is_none());
+        AsyncReadExt::read_exact(&mut receiver, &mut buf)
+            .await
+            .expect("Can't read");
+
+        assert_eq!(&buf, b"file");
+        assert_hello(receiver.dequeue().expect("No fd on channel"));
+    }
+}
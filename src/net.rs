@@ -7,19 +7,24 @@
 // except according to those terms
 
 use std::{
-    io::{self, prelude::*, Error, IoSlice, IoSliceMut},
+    io::{self, prelude::*, Error, ErrorKind, IoSlice, IoSliceMut},
     net::Shutdown,
     os::unix::{
-        io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+        io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
         net::{SocketAddr, UnixListener as StdUnixListner, UnixStream as StdUnixStream},
     },
     path::Path,
+    time::Duration,
 };
 
-// needed until the MSRV is 1.43 when the associated constant becomes available
-use std::usize;
-
-use crate::biqueue::BiQueue;
+use crate::{
+    activation::{self, SocketKind},
+    biqueue::{self, BiQueue},
+    builder::{BoundPath, FromBound, ListenerBuilder},
+    cred::{self, UCred},
+    path::ShortPath,
+    process::{self, FromStdStream},
+};
 
 use crate::{DequeueFd, EnqueueFd, QueueFullError};
 
@@ -79,6 +84,7 @@ pub struct UnixStream {
 #[derive(Debug)]
 pub struct UnixListener {
     inner: StdUnixListner,
+    path: Option<BoundPath>,
 }
 
 /// An iterator over incoming connections to a `UnixListener`.
@@ -123,7 +129,58 @@ impl UnixStream {
     /// # Ok::<(), std::io::Error>(())
     /// ```
     pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
-        StdUnixStream::connect(path).map(|s| s.into())
+        StdUnixStream::connect(ShortPath::new(path.as_ref())?).map(|s| s.into())
+    }
+
+    /// Connects to the socket named by `name` relative to the directory `dir`.
+    ///
+    /// An absolute `name` ignores `dir`, as with `openat(2)`. The connection is
+    /// made through `/proc/self/fd`, so the peer address of the returned stream
+    /// names the socket through that path rather than through `dir`'s own path.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::thread;
+    /// # use fd_queue::UnixListener;
+    /// # use tempfile::tempdir;
+    /// use fd_queue::UnixStream;
+    /// use std::fs::File;
+    ///
+    /// # let tmp = tempdir()?;
+    /// # let dir_path = tmp.path();
+    /// // let dir_path = ...
+    /// let dir = File::open(dir_path)?;
+    /// # let listener = UnixListener::bind_at(&dir, "mysock")?;
+    /// # thread::spawn(move || listener.accept());
+    /// let sock = UnixStream::connect_at(&dir, "mysock")?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn connect_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixStream> {
+        StdUnixStream::connect(ShortPath::at(dir.as_raw_fd(), name.as_ref())?).map(|s| s.into())
+    }
+
+    /// Connects to the socket specified by `addr`.
+    ///
+    /// Unlike `connect()` this can connect to an address in the Linux abstract
+    /// namespace.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use fd_queue::UnixListener;
+    /// use fd_queue::{abstract_addr, UnixStream};
+    ///
+    /// let addr = abstract_addr("fd-queue-net-connect-addr")?;
+    /// # let listener = UnixListener::bind_addr(&addr)?;
+    /// let sock = UnixStream::connect_addr(&addr)?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn connect_addr(addr: &SocketAddr) -> io::Result<UnixStream> {
+        StdUnixStream::connect_addr(addr).map(|s| s.into())
     }
 
     /// Creates an unnamed pair of connected sockets.
@@ -147,6 +204,38 @@ impl UnixStream {
         StdUnixStream::pair().map(|(s1, s2)| (s1.into(), s2.into()))
     }
 
+    /// Takes the end of the channel that the parent process created with
+    /// [`process::Command`][crate::process::Command].
+    ///
+    /// This returns a `NotFound` error if the process was not spawned with a
+    /// channel, or if the channel was already taken.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use fd_queue::UnixStream;
+    ///
+    /// let channel = UnixStream::from_parent()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn from_parent() -> io::Result<UnixStream> {
+        process::take_parent_channel().map(|fd| StdUnixStream::from(fd).into())
+    }
+
+    /// Adopts the connected Unix stream sockets passed to this process through
+    /// systemd socket activation.
+    ///
+    /// This is the counterpart of `UnixListener::from_listen_fds()` for services
+    /// with `Accept=yes`. Passed fds that are not connected Unix stream sockets
+    /// are left alone, and each fd is only adopted once.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixStream>> {
+        activation::take_listen_fds(SocketKind::Stream).map(|fds| {
+            fds.into_iter()
+                .map(|fd| StdUnixStream::from(fd).into())
+                .collect()
+        })
+    }
+
     /// Creates a new independently owned handle to the underlying socket.
     ///
     /// The returned `UnixStream` is a reference to the same stream that this object references.
@@ -238,6 +327,24 @@ impl UnixStream {
         self.inner.peer_addr()
     }
 
+    /// Returns the credentials of the process on the other end of this connection.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::UnixStream;
+    ///
+    /// let (sock, _) = UnixStream::pair()?;
+    ///
+    /// let cred = sock.peer_cred()?;
+    /// println!("Connected to uid {}", cred.uid);
+    ///
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        cred::peer_cred(self.as_raw_fd())
+    }
+
     /// Returns the value of the `SO_ERROR` option.
     ///
     /// # Examples
@@ -265,6 +372,33 @@ impl UnixStream {
         self.inner.take_error()
     }
 
+    /// Sets the read timeout for the socket.
+    ///
+    /// A read that times out returns an error of kind `WouldBlock` or `TimedOut`.
+    /// A `None` timeout blocks indefinitely.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::UnixStream;
+    /// use std::time::Duration;
+    ///
+    /// let (sock, _) = UnixStream::pair()?;
+    ///
+    /// sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+    /// assert_eq!(sock.read_timeout()?, Some(Duration::from_secs(1)));
+    /// #
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    /// Returns the read timeout of the socket.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
     /// Shuts down the read, write, or both halves of this connection.
     ///
     /// This function will cause all pending and future I/O calls on the specified portions to
@@ -297,19 +431,65 @@ impl UnixStream {
     pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
         self.inner.set_nonblocking(nonblocking)
     }
+
+    // Takes the fds that have arrived but have not been dequeued yet.
+    pub(crate) fn take_fds(&mut self) -> Vec<OwnedFd> {
+        self.biqueue.take_fds()
+    }
+
+    // Peeks at the waiting bytes, returning how many there are and whether any
+    // fds are waiting.
+    pub(crate) fn peek_fds_waiting(&self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+        loop {
+            match biqueue::peek_fds_waiting(self.inner.as_raw_fd(), buf) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        }
+    }
+
+    // Fills `buf` (usually a frame header), or returns `false` if the peer closed
+    // the stream before sending any of it.
+    pub(crate) fn read_or_eof(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let first = loop {
+            match self.read(buf) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if first == 0 && !buf.is_empty() {
+            return Ok(false);
+        }
+        self.read_exact(&mut buf[first..])?;
+
+        Ok(true)
+    }
+
+    // Shared setup for the non-blocking wrappers of UnixStream.
+    #[allow(dead_code)]
+    pub(crate) fn from_std_nonblocking(inner: StdUnixStream) -> io::Result<UnixStream> {
+        inner.set_nonblocking(true)?;
+        Ok(inner.into())
+    }
 }
 
 /// Enqueue a [`RawFd`][RawFd] for later transmission across the `UnixStream`.
 ///
 /// The [`RawFd`][RawFd] will be transmitted on a later call to a method of `Write`.
 /// The number of [`RawFd`][RawFd] that can be enqueued before being transmitted is
-/// bounded by `FD_QUEUE_SIZE`.
+/// bounded by `FD_QUEUE_SIZE`. If that call fails the [`RawFd`][RawFd] stay enqueued
+/// and are transmitted by the next call that succeeds, so they must be kept open
+/// until then or until they are discarded with `clear_enqueued()`.
 ///
 /// [RawFd]: https://doc.rust-lang.org/stable/std/os/unix/io/type.RawFd.html
 impl EnqueueFd for UnixStream {
     fn enqueue(&mut self, fd: &impl AsRawFd) -> std::result::Result<(), QueueFullError> {
         self.biqueue.enqueue(fd)
     }
+
+    fn clear_enqueued(&mut self) {
+        self.biqueue.clear_enqueued()
+    }
 }
 
 /// Dequeue a [`RawFd`][RawFd] that was previously transmitted across the
@@ -395,6 +575,90 @@ impl From<StdUnixStream> for UnixStream {
 
 // === impl UnixListener ===
 impl UnixListener {
+    /// Creates a builder for a `UnixListener` that manages its socket file.
+    ///
+    /// See [`ListenerBuilder`] for the available options.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::UnixListener;
+    /// # use tempfile::tempdir;
+    /// # let dir = tempdir()?;
+    /// # let path = dir.path().join("mysocket");
+    /// // let path = ...
+    ///
+    /// let listener = UnixListener::builder()
+    ///     .remove_stale(true)
+    ///     .unlink_on_drop(true)
+    ///     .bind(&path)?;
+    /// #
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    pub fn builder() -> ListenerBuilder<UnixListener> {
+        ListenerBuilder::new()
+    }
+
+    /// Keeps the socket file when this listener is dropped.
+    ///
+    /// This undoes [`ListenerBuilder::unlink_on_drop()`] for a listener that has
+    /// been handed to another process (for instance with
+    /// [`handoff::Sender::send_listener()`][crate::handoff::Sender::send_listener()]),
+    /// which goes on listening on the same socket file after this process drops
+    /// its copy of the listener.
+    pub fn keep_socket_file(&mut self) {
+        if let Some(path) = self.path.as_mut() {
+            path.keep();
+        }
+    }
+
+    /// Adopts the Unix listeners passed to this process through systemd socket
+    /// activation.
+    ///
+    /// The fds are found through the `LISTEN_PID` and `LISTEN_FDS` environment
+    /// variables. Passed fds that are not listening Unix stream sockets (as
+    /// reported by `SO_TYPE` and `SO_ACCEPTCONN`) are left alone, and each fd is
+    /// only adopted once, so a second call returns an empty `Vec`. If this process
+    /// was not socket activated this also returns an empty `Vec`.
+    ///
+    /// # Errors
+    ///
+    /// This returns an error if `LISTEN_PID` or `LISTEN_FDS` can't be parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::UnixListener;
+    ///
+    /// for listener in UnixListener::from_listen_fds()? {
+    ///     println!("Listening on {:?}", listener.local_addr()?);
+    /// }
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    pub fn from_listen_fds() -> io::Result<Vec<UnixListener>> {
+        activation::take_listen_fds(SocketKind::Listener).map(|fds| {
+            fds.into_iter()
+                .map(|fd| StdUnixListner::from(fd).into())
+                .collect()
+        })
+    }
+
+    /// Adopts the Unix listener named `name` that was passed to this process
+    /// through systemd socket activation.
+    ///
+    /// The names come from the `LISTEN_FDNAMES` environment variable, which
+    /// systemd sets from the `FileDescriptorName=` of each socket unit. If
+    /// several listeners share a name this adopts the first one not yet adopted.
+    ///
+    /// # Errors
+    ///
+    /// This returns a `NotFound` error if there is no listening Unix stream
+    /// socket named `name` left to adopt.
+    pub fn from_listen_fd_name(name: &str) -> io::Result<UnixListener> {
+        activation::take_listen_fd_name(SocketKind::Listener, name)
+            .map(|fd| StdUnixListner::from(fd).into())
+    }
+
     /// Create a new `UnixListener` bound to the specified socket.
     ///
     /// # Examples
@@ -416,7 +680,56 @@ impl UnixListener {
     /// # Ok::<(),std::io::Error>(())
     /// ```
     pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
-        StdUnixListner::bind(path).map(|s| s.into())
+        StdUnixListner::bind(ShortPath::new(path.as_ref())?).map(|s| s.into())
+    }
+
+    /// Create a new `UnixListener` bound to `name` relative to the directory `dir`.
+    ///
+    /// An absolute `name` ignores `dir`, as with `openat(2)`. The socket is bound
+    /// through `/proc/self/fd`, so `local_addr()` reports that path rather than
+    /// one through `dir`'s own path.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::UnixListener;
+    /// use std::fs::File;
+    /// # use tempfile::tempdir;
+    /// # let tmp = tempdir()?;
+    /// # let dir_path = tmp.path();
+    /// // let dir_path = ...
+    ///
+    /// let dir = File::open(dir_path)?;
+    /// let listener = UnixListener::bind_at(&dir, "mysocket")?;
+    ///
+    /// # assert!(dir_path.join("mysocket").exists());
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixListener> {
+        StdUnixListner::bind(ShortPath::at(dir.as_raw_fd(), name.as_ref())?).map(|s| s.into())
+    }
+
+    /// Create a new `UnixListener` bound to the specified address.
+    ///
+    /// Unlike `bind()` this can bind to an address in the Linux abstract
+    /// namespace.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::{abstract_addr, AddrKind, UnixListener};
+    ///
+    /// let addr = abstract_addr("fd-queue-net-bind-addr")?;
+    /// let listener = UnixListener::bind_addr(&addr)?;
+    ///
+    /// let local = listener.local_addr()?;
+    /// assert_eq!(AddrKind::from(&local), AddrKind::Abstract(b"fd-queue-net-bind-addr"));
+    /// #
+    /// # Ok::<(),std::io::Error>(())
+    /// ```
+    pub fn bind_addr(addr: &SocketAddr) -> io::Result<UnixListener> {
+        StdUnixListner::bind_addr(addr).map(|s| s.into())
     }
 
     /// Accepts a new incoming connection to this server.
@@ -508,7 +821,7 @@ impl UnixListener {
     /// # Ok::<(),std::io::Error>(())
     /// ```
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.inner.local_addr()
+        BoundPath::local_addr(&self.path, || self.inner.local_addr())
     }
 
     /// Return the value of the `SO_ERROR` option.
@@ -576,9 +889,21 @@ impl UnixListener {
     /// ```
     ///
     /// [SocketAddr]: https://doc.rust-lang.org/stable/std/os/unix/net/struct.SocketAddr.html
-    pub fn incoming(&self) -> Incoming {
+    pub fn incoming(&self) -> Incoming<'_> {
         Incoming { listener: self }
     }
+
+    #[allow(dead_code)]
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    // Shared setup for the non-blocking wrappers of UnixListener.
+    #[allow(dead_code)]
+    pub(crate) fn from_std_nonblocking(inner: StdUnixListner) -> io::Result<UnixListener> {
+        inner.set_nonblocking(true)?;
+        Ok(inner.into())
+    }
 }
 
 impl AsRawFd for UnixListener {
@@ -595,6 +920,9 @@ impl FromRawFd for UnixListener {
 
 impl IntoRawFd for UnixListener {
     fn into_raw_fd(self) -> RawFd {
+        if let Some(mut path) = self.path {
+            path.keep();
+        }
         self.inner.into_raw_fd()
     }
 }
@@ -610,7 +938,22 @@ impl<'a> IntoIterator for &'a UnixListener {
 
 impl From<StdUnixListner> for UnixListener {
     fn from(inner: StdUnixListner) -> Self {
-        UnixListener { inner }
+        UnixListener { inner, path: None }
+    }
+}
+
+impl FromStdStream for UnixStream {
+    fn from_std_stream(stream: StdUnixStream) -> io::Result<Self> {
+        Ok(stream.into())
+    }
+}
+
+impl FromBound for UnixListener {
+    fn from_bound(inner: StdUnixListner, path: BoundPath) -> io::Result<Self> {
+        Ok(UnixListener {
+            inner,
+            path: Some(path),
+        })
     }
 }
 
@@ -732,16 +1075,141 @@ mod test {
     #[test]
     fn unix_stream_passes_fd() {
         let shm = make_hello("/unix_stream_passes_fd");
-        let mut buf = vec![0; 20];
+        let mut buf = [0; 20];
 
         let (mut sut1, mut sut2) = UnixStream::pair().expect("Can't make pair");
         sut1.enqueue(&shm).expect("Can't enqueue");
-        sut1.write(b"abc").expect("Can't write");
+        sut1.write_all(b"abc").expect("Can't write");
         sut1.flush().expect("Can't flush");
-        sut2.read(&mut buf).expect("Can't read");
+        sut2.read_exact(&mut buf[..3]).expect("Can't read");
         let fd = sut2.dequeue().expect("Empty fd queue");
 
         assert!(fd != shm.fd, "fd's unexpectedly equal");
         assert!(compare_hello(fd), "fd didn't contain expect contents");
     }
+
+    #[test]
+    fn unix_stream_keeps_fd_queued_after_write_would_block() {
+        let shm = make_hello("/unix_stream_keeps_fd_queued_after_write_would_block");
+        let mut buf = [0; 4096];
+
+        let (mut sut1, mut sut2) = UnixStream::pair().expect("Can't make pair");
+        sut1.set_nonblocking(true).expect("Can't set nonblocking");
+        let mut filled = 0;
+        loop {
+            match sut1.write(&buf) {
+                Ok(len) => filled += len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("Can't fill stream: {}", e),
+            }
+        }
+        sut1.enqueue(&shm).expect("Can't enqueue");
+        let err = sut1.write(b"abc").expect_err("Write didn't block");
+        while filled > 0 {
+            filled -= sut2.read(&mut buf[..filled.min(4096)]).expect("Can't read");
+        }
+        assert!(sut2.dequeue().is_none(), "fd sent by a failed write");
+        sut1.write_all(b"abc").expect("Can't write");
+        sut2.read_exact(&mut buf[..3]).expect("Can't read");
+        let fd = sut2.dequeue().expect("Empty fd queue");
+
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(compare_hello(fd), "fd didn't contain expect contents");
+    }
+
+    #[test]
+    fn unix_stream_keeps_fd_queued_after_write_fails() {
+        let shm = make_hello("/unix_stream_keeps_fd_queued_after_write_fails");
+
+        let (mut sut1, sut2) = UnixStream::pair().expect("Can't make pair");
+        drop(sut2);
+        sut1.enqueue(&shm).expect("Can't enqueue");
+        sut1.write(b"abc")
+            .expect_err("Write to closed stream succeeded");
+        for _ in 1..UnixStream::FD_QUEUE_SIZE {
+            sut1.enqueue(&shm).expect("Can't enqueue");
+        }
+
+        assert!(sut1.enqueue(&shm).is_err(), "fd dropped by a failed write");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unix_stream_connects_to_long_path() {
+        let dir = tempfile::tempdir().expect("Can't create temp dir");
+        let deep = dir.path().join("a".repeat(60)).join("b".repeat(60));
+        std::fs::create_dir_all(&deep).expect("Can't create deep dir");
+        let path = deep.join("socket");
+        let mut buf = [0; 3];
+
+        let listener = UnixListener::bind(&path).expect("Can't bind to long path");
+        let mut client = UnixStream::connect(&path).expect("Can't connect to long path");
+        let (mut server, _) = listener.accept().expect("Can't accept");
+        client.write_all(b"abc").expect("Can't write");
+        server.read_exact(&mut buf).expect("Can't read");
+
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn unix_listener_adopts_socket_activated_listener() {
+        use std::os::unix::process::CommandExt;
+        use std::process::Command;
+
+        const CHILD_ENV: &str = "FD_QUEUE_SOCKET_ACTIVATION_CHILD";
+
+        if std::env::var_os(CHILD_ENV).is_some() {
+            let listener = UnixListener::from_listen_fd_name("test").expect("Can't adopt listener");
+            let (mut stream, _) = listener.accept().expect("Can't accept");
+            stream.write_all(b"abc").expect("Can't write");
+            assert!(UnixListener::from_listen_fds()
+                .expect("Can't adopt listeners")
+                .is_empty());
+            return;
+        }
+
+        let dir = tempfile::tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+        let listener = StdUnixListner::bind(&path).expect("Can't bind listener");
+        let fd = listener.as_raw_fd();
+        let mut buf = [0; 3];
+
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg("export LISTEN_PID=$$; exec \"$0\" \"$@\"")
+            .arg(std::env::current_exe().expect("Can't find test executable"))
+            .args([
+                "--exact",
+                "net::test::unix_listener_adopts_socket_activated_listener",
+                "--quiet",
+            ])
+            .env(CHILD_ENV, "1")
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDNAMES", "test");
+        // SAFETY: dup2() and fcntl() are async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                let result = if fd == 3 {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, 3)
+                };
+                if result < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+        }
+        let mut child = command.spawn().expect("Can't spawn child");
+        drop(listener);
+
+        let mut client = UnixStream::connect(&path).expect("Can't connect to child");
+        client.read_exact(&mut buf).expect("Can't read");
+        let status = child.wait().expect("Can't wait for child");
+
+        assert!(status.success());
+        assert_eq!(&buf, b"abc");
+    }
 }
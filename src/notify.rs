@@ -0,0 +1,306 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A client for the systemd service notification protocol.
+//!
+//! A service started by systemd with `Type=notify` (or with `FileDescriptorStoreMax=`
+//! set) finds the notification socket through the `NOTIFY_SOCKET` environment
+//! variable and sends it newline separated `KEY=VALUE` assignments in single
+//! datagrams. [`Notifier`] speaks this protocol and can attach fds to a
+//! notification so that systemd keeps them in its fd store across a restart of
+//! the service. The stored fds are passed back to the restarted service through
+//! socket activation, where [`UnixListener::from_listen_fd_name()`] can adopt
+//! them.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::notify::Notifier;
+//! # use std::os::unix::net::UnixDatagram;
+//! # use tempfile::tempdir;
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("notify");
+//! # let systemd = UnixDatagram::bind(&path)?;
+//! # std::env::set_var("NOTIFY_SOCKET", &path);
+//!
+//! if let Some(notifier) = Notifier::from_env()? {
+//!     notifier.status("Starting up")?;
+//!     notifier.ready()?;
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! [`UnixListener::from_listen_fd_name()`]: crate::UnixListener::from_listen_fd_name
+
+use std::{
+    env,
+    io::{self, Error, ErrorKind, IoSlice},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, BorrowedFd, RawFd},
+        net::{SocketAddr, UnixDatagram},
+    },
+    path::Path,
+};
+
+use crate::biqueue;
+
+/// The longest name that systemd accepts for an entry in its fd store.
+pub const FDNAME_MAX: usize = 255;
+
+/// A connection to the systemd notification socket.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+}
+
+// === impl Notifier ===
+
+impl Notifier {
+    /// The largest number of fds that can be sent in one notification.
+    pub const FD_QUEUE_SIZE: usize = biqueue::BiQueue::FD_QUEUE_SIZE;
+
+    /// Connects to the notification socket named by `NOTIFY_SOCKET`.
+    ///
+    /// This returns `Ok(None)` if `NOTIFY_SOCKET` is not set, which is the case
+    /// when the process was not started by systemd. A `NOTIFY_SOCKET` that starts
+    /// with `@` names a socket in the Linux abstract namespace.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `Unsupported` error for a `NOTIFY_SOCKET` that is neither a
+    /// path nor an abstract name (such as an `AF_VSOCK` address), and any error
+    /// from connecting to the socket.
+    pub fn from_env() -> io::Result<Option<Notifier>> {
+        let addr = match env::var_os("NOTIFY_SOCKET") {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+
+        match addr.as_bytes() {
+            [b'/', ..] => Notifier::connect(Path::new(&addr)).map(Some),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            [b'@', name @ ..] => Notifier::connect_addr(&crate::abstract_addr(name)?).map(Some),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported NOTIFY_SOCKET address {:?}", addr),
+            )),
+        }
+    }
+
+    /// Connects to the notification socket at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Notifier> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+
+        Ok(Notifier { socket })
+    }
+
+    /// Connects to the notification socket at `addr`.
+    pub fn connect_addr(addr: &SocketAddr) -> io::Result<Notifier> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(addr)?;
+
+        Ok(Notifier { socket })
+    }
+
+    /// Sends `state`, which is one or more newline separated assignments, together
+    /// with `fds` in a single notification.
+    ///
+    /// This is the primitive that the other functions of `Notifier` are built on.
+    /// At most [`FD_QUEUE_SIZE`][Notifier::FD_QUEUE_SIZE] fds can be sent in one
+    /// notification.
+    pub fn notify(&self, state: &str, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        let count = biqueue::send_with_fds(
+            self.socket.as_raw_fd(),
+            &[IoSlice::new(state.as_bytes())],
+            fds,
+        )?;
+
+        if count == state.len() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::WriteZero,
+                "notification was truncated",
+            ))
+        }
+    }
+
+    /// Tells systemd that the service has finished starting up (`READY=1`).
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1\n", &[])
+    }
+
+    /// Tells systemd that the service is reloading its configuration (`RELOADING=1`).
+    pub fn reloading(&self) -> io::Result<()> {
+        self.notify("RELOADING=1\n", &[])
+    }
+
+    /// Tells systemd that the service is shutting down (`STOPPING=1`).
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1\n", &[])
+    }
+
+    /// Sets the free form status of the service (`STATUS=`).
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidInput` error if `status` contains a newline.
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        if status.contains('\n') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "status can't contain a newline",
+            ));
+        }
+
+        self.notify(&format!("STATUS={}\n", status), &[])
+    }
+
+    /// Stores `fds` in the systemd fd store under `name` (`FDSTORE=1`).
+    ///
+    /// Any fds can be stored, including those of fd_queue streams and listeners. They are
+    /// passed back to the service through socket activation the next time it is
+    /// started, as long as the unit sets `FileDescriptorStoreMax=`.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidInput` error if `name` is not a valid fd name: it
+    /// must be at most [`FDNAME_MAX`] printable ASCII characters other than `:`.
+    pub fn store_fds(&self, name: &str, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        check_fdname(name)?;
+
+        self.notify(&format!("FDSTORE=1\nFDNAME={}\n", name), fds)
+    }
+
+    /// Removes the fds stored under `name` from the systemd fd store
+    /// (`FDSTOREREMOVE=1`).
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidInput` error if `name` is not a valid fd name.
+    pub fn remove_fds(&self, name: &str) -> io::Result<()> {
+        check_fdname(name)?;
+
+        self.notify(&format!("FDSTOREREMOVE=1\nFDNAME={}\n", name), &[])
+    }
+}
+
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+// === utility functions ===
+
+fn check_fdname(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= FDNAME_MAX
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid fd store name {:?}", name),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{prelude::*, IoSliceMut, SeekFrom};
+    use std::os::unix::io::{AsFd, FromRawFd};
+
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+    use tempfile::{tempdir, tempfile};
+
+    fn recv_notification(systemd: &UnixDatagram) -> (String, Vec<File>) {
+        let mut buf = [0u8; 256];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 10]);
+        let mut iov = [IoSliceMut::new(&mut buf)];
+
+        let msg = recvmsg::<()>(
+            systemd.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::empty(),
+        )
+        .expect("Can't receive notification");
+        let files = msg
+            .cmsgs()
+            .flat_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds,
+                _ => Vec::new(),
+            })
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        let len = msg.bytes;
+
+        (String::from_utf8_lossy(&buf[..len]).into_owned(), files)
+    }
+
+    #[test]
+    fn notifier_sends_ready() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).expect("Can't bind systemd stand-in");
+
+        let sut = Notifier::connect(&path).expect("Can't connect notifier");
+        sut.ready().expect("Can't notify");
+        let (state, files) = recv_notification(&systemd);
+
+        assert_eq!(state, "READY=1\n");
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn notifier_stores_fds() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).expect("Can't bind systemd stand-in");
+        let mut file = tempfile().expect("Can't create temp file");
+        file.write_all(b"Hello World!")
+            .expect("Can't write temp file");
+        let mut buf = String::new();
+
+        let sut = Notifier::connect(&path).expect("Can't connect notifier");
+        sut.store_fds("conn-1", &[file.as_fd()])
+            .expect("Can't store fds");
+        let (state, mut files) = recv_notification(&systemd);
+        files[0]
+            .seek(SeekFrom::Start(0))
+            .expect("Can't seek stored file");
+        files[0]
+            .read_to_string(&mut buf)
+            .expect("Can't read stored file");
+
+        assert_eq!(state, "FDSTORE=1\nFDNAME=conn-1\n");
+        assert_eq!(files.len(), 1);
+        assert_eq!(buf, "Hello World!");
+    }
+
+    #[test]
+    fn notifier_rejects_bad_fdname() {
+        let (socket, _other) = UnixDatagram::pair().expect("Can't create pair");
+        let sut = Notifier { socket };
+
+        let result = sut.remove_fds("a:b");
+
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+use std::{
+    fs::File,
+    io,
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+/// A socket path that fits in the `sun_path` field of a `sockaddr_un`.
+///
+/// A path that is too long is rewritten relative to an open file descriptor for
+/// its parent directory through `/proc/self/fd`. The directory stays open for as
+/// long as the `ShortPath` does, so a `ShortPath` must outlive the `bind()` or
+/// `connect()` that uses it.
+#[derive(Debug)]
+pub struct ShortPath {
+    path: PathBuf,
+    _dir: Option<File>,
+}
+
+impl ShortPath {
+    /// Create a `ShortPath` for `path`, rewriting it only if it is too long.
+    pub fn new(path: &Path) -> io::Result<ShortPath> {
+        if path.as_os_str().len() < sun_path_len() {
+            return Ok(ShortPath {
+                path: path.to_owned(),
+                _dir: None,
+            });
+        }
+
+        Self::shorten(path)
+    }
+
+    /// Create a `ShortPath` for `name` relative to the open directory `dir`.
+    ///
+    /// As with `openat(2)` an absolute `name` ignores `dir`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn at(dir: RawFd, name: &Path) -> io::Result<ShortPath> {
+        Self::new(&proc_fd_path(dir).join(name))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn shorten(path: &Path) -> io::Result<ShortPath> {
+        use std::os::unix::io::AsRawFd;
+
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(too_long()),
+        };
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(parent)?;
+        let path = proc_fd_path(dir.as_raw_fd()).join(name);
+        if path.as_os_str().len() >= sun_path_len() {
+            return Err(too_long());
+        }
+
+        Ok(ShortPath {
+            path,
+            _dir: Some(dir),
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn shorten(_path: &Path) -> io::Result<ShortPath> {
+        Err(too_long())
+    }
+}
+
+impl AsRef<Path> for ShortPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn proc_fd_path(fd: RawFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd))
+}
+
+fn sun_path_len() -> usize {
+    // SAFETY: sockaddr_un is a plain C struct for which all zeros is a valid value.
+    let addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_path.len()
+}
+
+fn too_long() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENAMETOOLONG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd, net::UnixListener};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn short_path_keeps_short_path() {
+        let sut = ShortPath::new(Path::new("/tmp/sock")).expect("Can't create ShortPath");
+
+        assert_eq!(sut.as_ref(), Path::new("/tmp/sock"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn short_path_binds_long_path() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let deep = dir.path().join("a".repeat(60)).join("b".repeat(60));
+        std::fs::create_dir_all(&deep).expect("Can't create deep dir");
+        let path = deep.join("socket");
+
+        let sut = ShortPath::new(&path).expect("Can't create ShortPath");
+        UnixListener::bind(&sut).expect("Can't bind to long path");
+
+        assert!(path.as_os_str().as_bytes().len() >= sun_path_len());
+        assert!(path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn short_path_at_is_relative_to_dir() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let dirfd = File::open(dir.path()).expect("Can't open temp dir");
+
+        let sut =
+            ShortPath::at(dirfd.as_raw_fd(), Path::new("socket")).expect("Can't create ShortPath");
+        UnixListener::bind(&sut).expect("Can't bind relative to dir");
+
+        assert!(dir.path().join("socket").exists());
+    }
+}
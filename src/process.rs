@@ -0,0 +1,228 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Spawn child processes with a connected fd passing channel.
+//!
+//! [`Command`] wraps a [`std::process::Command`] and spawns the child with one end
+//! of a connected Unix stream socket pair. The child finds its end through the
+//! [`CHANNEL_ENV`] environment variable with `UnixStream::from_parent()`, which is
+//! available on each of the stream types in this crate.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use fd_queue::{process::Command, UnixStream};
+//! use std::io::prelude::*;
+//!
+//! // In the parent.
+//! let (mut child, mut channel) = Command::new("worker").spawn::<UnixStream>()?;
+//! channel.write_all(b"Hello child!")?;
+//! child.wait()?;
+//!
+//! // In the child ("worker").
+//! let mut channel = UnixStream::from_parent()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    env,
+    ffi::OsStr,
+    fmt,
+    io::{self, Error, ErrorKind},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixStream as StdUnixStream,
+        process::CommandExt,
+    },
+    process::{self, Child},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use crate::activation::{self, SocketKind};
+
+/// The environment variable that passes the child's end of the channel.
+///
+/// The variable stays set after the child takes the channel, but the channel
+/// is close-on-exec so the child's own children don't inherit it.
+pub const CHANNEL_ENV: &str = "FD_QUEUE_CHANNEL_FD";
+
+static TAKEN: Mutex<Option<RawFd>> = Mutex::new(None);
+
+/// A process builder that connects the child to the parent with a Unix stream.
+pub struct Command {
+    inner: process::Command,
+    channel_fd: Arc<AtomicI32>,
+}
+
+mod sealed {
+    use super::*;
+
+    /// A stream type that the parent's end of the channel can be converted to.
+    pub trait FromStdStream: Sized {
+        /// Convert the parent's end of the channel.
+        fn from_std_stream(stream: StdUnixStream) -> io::Result<Self>;
+    }
+}
+
+pub(crate) use sealed::FromStdStream;
+
+// === impl Command ===
+
+impl Command {
+    /// Constructs a new `Command` for launching `program`.
+    ///
+    /// This is the same as [`std::process::Command::new()`].
+    pub fn new(program: impl AsRef<OsStr>) -> Command {
+        process::Command::new(program).into()
+    }
+
+    /// Borrows the underlying `std::process::Command`.
+    pub fn as_std(&self) -> &process::Command {
+        &self.inner
+    }
+
+    /// Mutably borrows the underlying `std::process::Command` to set its
+    /// arguments, environment and so on.
+    pub fn as_std_mut(&mut self) -> &mut process::Command {
+        &mut self.inner
+    }
+
+    /// Spawns the child with a new connected channel and returns the parent's
+    /// end of the channel as `S`.
+    ///
+    /// `S` can be any of the stream types in this crate. Each call creates a new
+    /// channel.
+    pub fn spawn<S: FromStdStream>(&mut self) -> io::Result<(Child, S)> {
+        let (parent, child) = StdUnixStream::pair()?;
+
+        self.inner.env(CHANNEL_ENV, child.as_raw_fd().to_string());
+        self.channel_fd.store(child.as_raw_fd(), Ordering::SeqCst);
+        let result = self.inner.spawn();
+        self.channel_fd.store(-1, Ordering::SeqCst);
+        self.inner.env_remove(CHANNEL_ENV);
+        drop(child);
+
+        Ok((result?, S::from_std_stream(parent)?))
+    }
+}
+
+impl From<process::Command> for Command {
+    fn from(mut inner: process::Command) -> Command {
+        let channel_fd = Arc::new(AtomicI32::new(-1));
+        let child_fd = channel_fd.clone();
+
+        // SAFETY: the closure only loads an atomic and calls fcntl(), both of
+        // which are async-signal-safe.
+        unsafe {
+            inner.pre_exec(move || {
+                let fd = child_fd.load(Ordering::SeqCst);
+                if fd >= 0 && libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        Command { inner, channel_fd }
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+// === utility functions ===
+
+/// Take ownership of the child's end of the channel that the parent passed.
+///
+/// This leaves [`CHANNEL_ENV`] in the environment, since changing it isn't safe
+/// while other threads may read it, and records the fd in `TAKEN` instead so
+/// that the channel is taken once. The fd is marked close-on-exec so that it
+/// isn't inherited further.
+pub(crate) fn take_parent_channel() -> io::Result<OwnedFd> {
+    let value = env::var(CHANNEL_ENV).map_err(|_| {
+        Error::new(
+            ErrorKind::NotFound,
+            "process was not spawned with an fd_queue channel",
+        )
+    })?;
+    let fd: RawFd = value
+        .parse()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    let mut taken = TAKEN.lock().unwrap_or_else(PoisonError::into_inner);
+    if taken.is_some() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "the fd_queue channel was already taken",
+        ));
+    }
+    if !activation::is_kind(fd, SocketKind::Stream) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("fd {} is not a connected Unix stream", fd),
+        ));
+    }
+    // SAFETY: fcntl() has no memory safety requirements.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    *taken = Some(fd);
+    // SAFETY: the parent passed us this fd and TAKEN ensures that we only take
+    // ownership of it once.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(all(test, feature = "net-fd"))]
+mod tests {
+    use super::*;
+
+    use std::io::prelude::*;
+
+    use crate::UnixStream;
+
+    #[test]
+    fn command_connects_child_channel() {
+        const CHILD_ENV: &str = "FD_QUEUE_PROCESS_CHILD";
+
+        if env::var_os(CHILD_ENV).is_some() {
+            let mut channel = UnixStream::from_parent().expect("Can't find parent channel");
+            let mut buf = [0; 4];
+            channel.read_exact(&mut buf).expect("Can't read");
+            assert_eq!(&buf, b"ping");
+            channel.write_all(b"pong").expect("Can't write");
+            assert!(UnixStream::from_parent().is_err());
+            return;
+        }
+
+        let mut command = Command::new(env::current_exe().expect("Can't find test executable"));
+        command
+            .as_std_mut()
+            .args([
+                "--exact",
+                "process::tests::command_connects_child_channel",
+                "--quiet",
+            ])
+            .env(CHILD_ENV, "1");
+        let (mut child, mut channel) = command.spawn::<UnixStream>().expect("Can't spawn child");
+        let mut buf = [0; 4];
+
+        channel.write_all(b"ping").expect("Can't write");
+        channel.read_exact(&mut buf).expect("Can't read");
+        let status = child.wait().expect("Can't wait for child");
+
+        assert!(status.success());
+        assert_eq!(&buf, b"pong");
+    }
+}
@@ -0,0 +1,772 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A publish/subscribe hub that fans messages and their fds out to subscribers.
+//!
+//! Clients connect to a [`Hub`], subscribe to topics and publish messages, each of
+//! which can carry fds (such as a freshly rotated log pipe or a new shared memory
+//! segment). The hub sends every message that is published to a topic, together
+//! with its fds, to each subscriber of the topic.
+//!
+//! Every subscriber has its own bounded outbox, so a slow subscriber doesn't hold
+//! up the others; what happens when its outbox is full is decided by the hub's
+//! [`Overflow`]. A message can be published as the retained value of its topic,
+//! which the hub sends to each new subscriber of the topic as soon as it
+//! subscribes.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::pubsub::{Client, Hub};
+//! use fd_queue::UnixListener;
+//! use std::os::unix::io::AsFd;
+//! use std::thread;
+//! # use tempfile::{tempdir, tempfile};
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("hub.sock");
+//!
+//! // In the hub.
+//! let hub = Hub::new(UnixListener::bind(&path)?);
+//! thread::spawn(move || hub.run());
+//!
+//! // In the publisher.
+//! # let log = tempfile()?;
+//! let mut publisher = Client::connect(&path)?;
+//! publisher.retain("log", b"rotated", &[log.as_fd()])?;
+//!
+//! // In a subscriber.
+//! let mut subscriber = Client::connect(&path)?;
+//! subscriber.subscribe("log")?;
+//! if let Some(message) = subscriber.recv()? {
+//!     println!("{} on {}", String::from_utf8_lossy(message.payload()), message.topic());
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, prelude::*, Error, ErrorKind},
+    net::Shutdown,
+    os::unix::io::{AsFd, BorrowedFd, OwnedFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+use tracing::warn;
+
+use crate::{EnqueueFd, UnixListener, UnixStream};
+
+/// The longest topic name.
+pub const MAX_TOPIC_LEN: usize = 255;
+
+/// The longest message payload.
+pub const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+const OP_SUBSCRIBE: u8 = 1;
+const OP_UNSUBSCRIBE: u8 = 2;
+const OP_PUBLISH: u8 = 3;
+const OP_MESSAGE: u8 = 4;
+const OP_SUBSCRIBED: u8 = 5;
+
+const FLAG_RETAIN: u8 = 1;
+
+const HEADER_LEN: usize = 7;
+
+/// A server that passes the messages that its clients publish on to the
+/// subscribers of their topics.
+#[derive(Debug)]
+pub struct Hub {
+    listener: UnixListener,
+    topics: Mutex<HashMap<String, Topic>>,
+    next_id: AtomicU64,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+#[derive(Debug, Default)]
+struct Topic {
+    subscribers: Vec<(u64, Arc<Outbox>)>,
+    retained: Option<Arc<Shared>>,
+}
+
+/// What a [`Hub`] does with a message for a subscriber whose outbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the subscriber to make room, which holds up the publisher.
+    Block,
+
+    /// Drop the oldest message in the subscriber's outbox.
+    DropOldest,
+
+    /// Disconnect the subscriber.
+    Disconnect,
+}
+
+/// A connection to a [`Hub`].
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+    pending: VecDeque<Message>,
+}
+
+/// A message received from a [`Hub`].
+#[derive(Debug)]
+pub struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    retained: bool,
+}
+
+/// A published message as it is shared between the outboxes of the subscribers.
+#[derive(Debug)]
+struct Shared {
+    topic: String,
+    payload: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    Message {
+        message: Arc<Shared>,
+        retained: bool,
+    },
+    Subscribed(String),
+}
+
+/// The bounded queue of frames waiting to be written to one subscriber.
+#[derive(Debug, Default)]
+struct Outbox {
+    state: Mutex<OutboxState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    items: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Frame {
+    op: u8,
+    flags: u8,
+    topic: String,
+    payload: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+// === impl Hub ===
+
+impl Hub {
+    /// Creates a hub that accepts clients on `listener`.
+    ///
+    /// Each subscriber's outbox holds up to 64 messages and the hub waits for a
+    /// subscriber whose outbox is full.
+    pub fn new(listener: UnixListener) -> Hub {
+        Hub {
+            listener,
+            topics: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            capacity: 64,
+            overflow: Overflow::Block,
+        }
+    }
+
+    /// Sets the number of messages that each subscriber's outbox holds.
+    pub fn capacity(&mut self, capacity: usize) -> &mut Hub {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets what to do with a message for a subscriber whose outbox is full.
+    pub fn overflow(&mut self, overflow: Overflow) -> &mut Hub {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Accepts clients and serves each of them on its own thread.
+    ///
+    /// A client's subscriptions are dropped when it disconnects. This returns an
+    /// error from accepting a new client only after every existing subscriber
+    /// and publisher has disconnected.
+    pub fn run(&self) -> io::Result<()> {
+        thread::scope(|scope| loop {
+            let (stream, _) = self.listener.accept()?;
+
+            scope.spawn(move || {
+                if let Err(e) = self.serve(stream) {
+                    warn!(
+                        source = "Hub",
+                        event = "serve",
+                        condition = "client failed",
+                        error = %e
+                    );
+                }
+            });
+        })
+    }
+
+    /// Serves the client connected to `stream` until it disconnects.
+    ///
+    /// This reads the client's requests on the calling thread and writes its
+    /// messages on a second thread.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidData` error if the client sends a malformed frame.
+    pub fn serve(&self, mut stream: UnixStream) -> io::Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let outbox = Arc::new(Outbox::default());
+        let mut writer = stream.try_clone()?;
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                outbox.write_to(&mut writer);
+                let _ = writer.shutdown(Shutdown::Both);
+            });
+
+            let result = self.read_from(&mut stream, id, &outbox);
+            self.topics().retain(|_, topic| topic.remove_subscriber(id));
+            outbox.close();
+
+            result
+        })
+    }
+
+    /// The listener that clients connect to.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    fn read_from(&self, stream: &mut UnixStream, id: u64, outbox: &Arc<Outbox>) -> io::Result<()> {
+        while let Some(frame) = read_frame(stream)? {
+            match frame.op {
+                OP_SUBSCRIBE => self.subscribe(frame.topic, id, outbox),
+                OP_UNSUBSCRIBE => {
+                    let mut topics = self.topics();
+                    if let Some(topic) = topics.get_mut(&frame.topic) {
+                        if !topic.remove_subscriber(id) {
+                            topics.remove(&frame.topic);
+                        }
+                    }
+                }
+                OP_PUBLISH => self.publish(frame),
+                op => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown pubsub op {}", op),
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn subscribe(&self, name: String, id: u64, outbox: &Arc<Outbox>) {
+        let mut topics = self.topics();
+        let topic = topics.entry(name.clone()).or_default();
+        if !topic.subscribers.iter().any(|(other, _)| *other == id) {
+            topic.subscribers.push((id, outbox.clone()));
+        }
+
+        // Queue these while holding the lock so that a message published to the
+        // topic from now on can't overtake its retained value. They bypass the
+        // capacity of the outbox so that this doesn't block.
+        if let Some(message) = &topic.retained {
+            outbox.push_control(Outgoing::Message {
+                message: message.clone(),
+                retained: true,
+            });
+        }
+        outbox.push_control(Outgoing::Subscribed(name));
+    }
+
+    fn publish(&self, frame: Frame) {
+        let message = Arc::new(Shared {
+            topic: frame.topic,
+            payload: frame.payload,
+            fds: frame.fds,
+        });
+
+        let outboxes: Vec<_> = {
+            let mut topics = self.topics();
+            let topic = topics.entry(message.topic.clone()).or_default();
+            if frame.flags & FLAG_RETAIN != 0 {
+                let clear = message.payload.is_empty() && message.fds.is_empty();
+                topic.retained = if clear { None } else { Some(message.clone()) };
+            }
+            let outboxes = topic
+                .subscribers
+                .iter()
+                .map(|(_, outbox)| outbox.clone())
+                .collect();
+            if topic.is_unused() {
+                topics.remove(&message.topic);
+            }
+            outboxes
+        };
+
+        for outbox in outboxes {
+            outbox.push(
+                Outgoing::Message {
+                    message: message.clone(),
+                    retained: false,
+                },
+                self.capacity,
+                self.overflow,
+            );
+        }
+    }
+
+    fn topics(&self) -> MutexGuard<'_, HashMap<String, Topic>> {
+        self.topics.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// === impl Topic ===
+
+impl Topic {
+    /// Remove the subscriber `id`, returning whether the topic is still in use.
+    fn remove_subscriber(&mut self, id: u64) -> bool {
+        self.subscribers.retain(|(other, _)| *other != id);
+
+        !self.is_unused()
+    }
+
+    fn is_unused(&self) -> bool {
+        self.subscribers.is_empty() && self.retained.is_none()
+    }
+}
+
+// === impl Outbox ===
+
+impl Outbox {
+    /// Queue `item`, applying `overflow` if the outbox already holds `capacity`
+    /// items.
+    fn push(&self, item: Outgoing, capacity: usize, overflow: Overflow) {
+        let mut state = self.lock();
+
+        while !state.closed && state.items.len() >= capacity {
+            match overflow {
+                Overflow::Block => {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+                Overflow::DropOldest => {
+                    let oldest = state
+                        .items
+                        .iter()
+                        .position(|item| matches!(item, Outgoing::Message { .. }));
+                    match oldest {
+                        Some(index) => drop(state.items.remove(index)),
+                        None => break,
+                    }
+                }
+                Overflow::Disconnect => {
+                    warn!(
+                        source = "Hub",
+                        event = "publish",
+                        condition = "subscriber outbox full"
+                    );
+                    state.closed = true;
+                }
+            }
+        }
+
+        if !state.closed {
+            state.items.push_back(item);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Queue `item` regardless of the capacity of the outbox.
+    fn push_control(&self, item: Outgoing) {
+        let mut state = self.lock();
+        if !state.closed {
+            state.items.push_back(item);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Take the next item, waiting for one if the outbox is empty, or `None` once
+    /// the outbox is closed.
+    fn pop(&self) -> Option<Outgoing> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(item) = state.items.pop_front() {
+                self.changed.notify_all();
+                return Some(item);
+            }
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.items.clear();
+        self.changed.notify_all();
+    }
+
+    /// Write the queued items to `stream` until the outbox is closed or writing
+    /// fails.
+    fn write_to(&self, stream: &mut UnixStream) {
+        while let Some(item) = self.pop() {
+            let result = match item {
+                Outgoing::Message { message, retained } => {
+                    let fds: Vec<_> = message.fds.iter().map(AsFd::as_fd).collect();
+                    let flags = if retained { FLAG_RETAIN } else { 0 };
+                    write_frame(
+                        stream,
+                        OP_MESSAGE,
+                        flags,
+                        &message.topic,
+                        &message.payload,
+                        &fds,
+                    )
+                }
+                Outgoing::Subscribed(topic) => {
+                    write_frame(stream, OP_SUBSCRIBED, 0, &topic, &[], &[])
+                }
+            };
+
+            if let Err(e) = result {
+                warn!(
+                    source = "Hub",
+                    event = "write",
+                    condition = "subscriber failed",
+                    error = %e
+                );
+                self.close();
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OutboxState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// === impl Client ===
+
+impl Client {
+    /// Connects to the hub listening at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Client> {
+        UnixStream::connect(path).map(Client::from)
+    }
+
+    /// Subscribes to `topic`.
+    ///
+    /// This waits for the hub to confirm the subscription, so any message
+    /// published to `topic` after this returns will be received. If `topic` has a
+    /// retained value it is the next message received on `topic`.
+    pub fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        write_frame(&mut self.stream, OP_SUBSCRIBE, 0, topic, &[], &[])?;
+
+        loop {
+            let frame = read_frame(&mut self.stream)?
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "hub disconnected"))?;
+            match frame.op {
+                OP_SUBSCRIBED if frame.topic == topic => return Ok(()),
+                OP_SUBSCRIBED => {}
+                _ => self.pending.push_back(Message::try_from_frame(frame)?),
+            }
+        }
+    }
+
+    /// Unsubscribes from `topic`.
+    ///
+    /// Messages that the hub has already sent on `topic` can still be received
+    /// after this returns.
+    pub fn unsubscribe(&mut self, topic: &str) -> io::Result<()> {
+        write_frame(&mut self.stream, OP_UNSUBSCRIBE, 0, topic, &[], &[])
+    }
+
+    /// Publishes `payload` and `fds` to the subscribers of `topic`.
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> io::Result<()> {
+        write_frame(&mut self.stream, OP_PUBLISH, 0, topic, payload, fds)
+    }
+
+    /// Publishes `payload` and `fds` to the subscribers of `topic` and makes them
+    /// the retained value of `topic`.
+    ///
+    /// An empty payload without fds clears the retained value.
+    pub fn retain(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> io::Result<()> {
+        write_frame(
+            &mut self.stream,
+            OP_PUBLISH,
+            FLAG_RETAIN,
+            topic,
+            payload,
+            fds,
+        )
+    }
+
+    /// Receives the next message on any subscribed topic, or `None` once the hub
+    /// has disconnected.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message));
+        }
+
+        loop {
+            match read_frame(&mut self.stream)? {
+                Some(frame) if frame.op == OP_SUBSCRIBED => {}
+                Some(frame) => return Message::try_from_frame(frame).map(Some),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl From<UnixStream> for Client {
+    fn from(stream: UnixStream) -> Client {
+        Client {
+            stream,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+// === impl Message ===
+
+impl Message {
+    fn try_from_frame(frame: Frame) -> io::Result<Message> {
+        if frame.op != OP_MESSAGE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected pubsub op {}", frame.op),
+            ));
+        }
+
+        Ok(Message {
+            topic: frame.topic,
+            payload: frame.payload,
+            fds: frame.fds,
+            retained: frame.flags & FLAG_RETAIN != 0,
+        })
+    }
+
+    /// The topic that the message was published to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// The payload of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The fds that were published with the message.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Takes the fds that were published with the message.
+    pub fn into_fds(self) -> Vec<OwnedFd> {
+        self.fds
+    }
+
+    /// Whether this is the retained value of the topic, sent because the client
+    /// just subscribed, rather than a newly published message.
+    pub fn is_retained(&self) -> bool {
+        self.retained
+    }
+}
+
+// === utility functions ===
+
+fn write_frame(
+    stream: &mut UnixStream,
+    op: u8,
+    flags: u8,
+    topic: &str,
+    payload: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> io::Result<()> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid topic {:?}", topic),
+        ));
+    }
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, "payload is too long"));
+    }
+    if fds.len() > UnixStream::FD_QUEUE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "message has too many fds",
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + topic.len() + payload.len());
+    frame.push(op);
+    frame.push(flags);
+    frame.push(topic.len() as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(topic.as_bytes());
+    frame.extend_from_slice(payload);
+
+    for fd in fds {
+        stream
+            .enqueue(fd)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Read the next frame, or `None` if the peer disconnected.
+fn read_frame(stream: &mut UnixStream) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_LEN];
+    if !stream.read_or_eof(&mut header)? {
+        return Ok(None);
+    }
+
+    let payload_len = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "payload is too long"));
+    }
+    let mut topic = vec![0; usize::from(header[2])];
+    let mut payload = vec![0; payload_len];
+    stream.read_exact(&mut topic)?;
+    stream.read_exact(&mut payload)?;
+
+    let fds = stream.take_fds();
+    let topic = String::from_utf8(topic).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(Some(Frame {
+        op: header[0],
+        flags: header[1],
+        topic,
+        payload,
+        fds,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::SeekFrom;
+
+    use tempfile::{tempdir, tempfile};
+
+    fn read_fd(fd: OwnedFd) -> String {
+        let mut file = File::from(fd);
+        let mut buf = String::new();
+        file.seek(SeekFrom::Start(0)).expect("Can't seek");
+        file.read_to_string(&mut buf).expect("Can't read");
+        buf
+    }
+
+    fn message(payload: &[u8]) -> Outgoing {
+        Outgoing::Message {
+            message: Arc::new(Shared {
+                topic: "topic".to_string(),
+                payload: payload.to_vec(),
+                fds: Vec::new(),
+            }),
+            retained: false,
+        }
+    }
+
+    #[test]
+    fn hub_fans_out_messages_and_retained_values() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("hub.sock");
+        let hub = Hub::new(UnixListener::bind(&path).expect("Can't bind"));
+        thread::spawn(move || hub.run());
+        let connect = || Client::connect(&path).expect("Can't connect");
+        let (mut publisher, mut first, mut second) = (connect(), connect(), connect());
+        let mut file = tempfile().expect("Can't create temp file");
+        file.write_all(b"Hello World!")
+            .expect("Can't write temp file");
+
+        first.subscribe("log").expect("Can't subscribe");
+        second.subscribe("log").expect("Can't subscribe");
+        publisher.subscribe("log").expect("Can't subscribe");
+        publisher
+            .retain("log", b"rotated", &[file.as_fd()])
+            .expect("Can't publish");
+        // Once the publisher sees its own message the hub has retained it.
+        publisher.recv().expect("Can't receive");
+        let mut late = connect();
+        late.subscribe("log").expect("Can't subscribe");
+        let messages: Vec<_> = vec![&mut first, &mut second, &mut late]
+            .into_iter()
+            .map(|client| {
+                client
+                    .recv()
+                    .expect("Can't receive")
+                    .expect("Hub disconnected")
+            })
+            .collect();
+
+        for (message, retained) in messages.into_iter().zip([false, false, true]) {
+            assert_eq!(message.topic(), "log");
+            assert_eq!(message.payload(), b"rotated");
+            assert_eq!(message.is_retained(), retained);
+            let mut fds = message.into_fds();
+            assert_eq!(fds.len(), 1);
+            assert_eq!(read_fd(fds.remove(0)), "Hello World!");
+        }
+    }
+
+    #[test]
+    fn outbox_drops_oldest_message_when_full() {
+        let sut = Outbox::default();
+
+        sut.push_control(Outgoing::Subscribed("topic".to_string()));
+        for payload in [b"1", b"2", b"3"] {
+            sut.push(message(payload), 2, Overflow::DropOldest);
+        }
+        let items: Vec<_> = sut.lock().items.drain(..).collect();
+
+        assert!(matches!(&items[0], Outgoing::Subscribed(_)));
+        assert!(matches!(&items[1], Outgoing::Message { message, .. } if message.payload == b"3"));
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn outbox_disconnects_slow_subscriber() {
+        let sut = Outbox::default();
+
+        sut.push(message(b"1"), 1, Overflow::Disconnect);
+        sut.push(message(b"2"), 1, Overflow::Disconnect);
+
+        assert!(sut.pop().is_none());
+    }
+}
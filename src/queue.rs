@@ -25,8 +25,19 @@ pub trait EnqueueFd {
     /// Enqueue `fd` for later transmission to a different process.
     ///
     /// The caller is responsible for keeping `fd` open until after the `write()` and
-    /// `flush()` calls for actually transmitting the `fd` have been completed.
+    /// `flush()` calls for actually transmitting the `fd` have been completed. A
+    /// `write()` that fails (including one that would block) leaves `fd` queued for
+    /// the next `write()`, so `fd` has to stay open until a `write()` succeeds.
     fn enqueue(&mut self, fd: &impl AsRawFd) -> Result<(), QueueFullError>;
+
+    /// Discard the fds that have been enqueued but not yet transmitted.
+    ///
+    /// A caller that gives up on transmitting its enqueued fds, for example after
+    /// a `write()` failed, calls this before closing them so that they aren't sent
+    /// with a later `write()`. The default implementation does nothing, which is
+    /// only correct for an implementation that never keeps fds enqueued after a
+    /// failed `write()`.
+    fn clear_enqueued(&mut self) {}
 }
 
 /// An interface to dequeue a [`RawFd`][RawFd] that was previously transmitted from a
@@ -0,0 +1,644 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A registry that keeps fds alive under a name for other processes to fetch.
+//!
+//! A [`Registry`] holds on to the fds (memfds, eventfds, listeners and so on) that
+//! its clients publish with [`Client::put()`] so that they stay open after the
+//! publishing process exits, and hands a copy of one to any client that asks for
+//! it by name with [`Client::get()`].
+//!
+//! Access is controlled by the user id of the peer of each connection. The user
+//! that puts an fd owns its entry and is the only one that can replace or remove
+//! it. Other users can only get the fd (or see its name in [`Client::list()`]) if
+//! the owner has shared it with them through [`Client::share()`]. Each user can
+//! own at most [`DEFAULT_MAX_ENTRIES`] entries (or the number set with
+//! [`Registry::set_max_entries()`]) so that one client can't use up the fds of
+//! the registry.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::registry::{Client, Registry};
+//! use fd_queue::UnixListener;
+//! use std::thread;
+//! # use tempfile::{tempdir, tempfile};
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("registry.sock");
+//!
+//! // In the registry.
+//! let registry = Registry::new(UnixListener::bind(&path)?);
+//! thread::spawn(move || registry.run());
+//!
+//! // In the publisher.
+//! # let segment = tempfile()?;
+//! let mut client = Client::connect(&path)?;
+//! client.put("shared-segment", &segment)?;
+//!
+//! // In another process.
+//! let mut client = Client::connect(&path)?;
+//! let segment = client.get("shared-segment")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    error, fmt,
+    io::{self, prelude::*, Error, ErrorKind},
+    os::unix::io::{AsRawFd, OwnedFd},
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+use tracing::warn;
+
+use crate::{EnqueueFd, UCred, UnixListener, UnixStream};
+
+/// The longest name of an entry in the registry.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// The number of entries that each user can own unless
+/// [`Registry::set_max_entries()`] changes it.
+pub const DEFAULT_MAX_ENTRIES: usize = 64;
+
+const OP_PUT: u8 = 1;
+const OP_GET: u8 = 2;
+const OP_REMOVE: u8 = 3;
+const OP_LIST: u8 = 4;
+const OP_SHARE: u8 = 5;
+
+const REQUEST_HEADER_LEN: usize = 6;
+const RESPONSE_HEADER_LEN: usize = 5;
+const MAX_RESPONSE_LEN: usize = 1 << 20;
+
+/// A server that keeps fds alive under a name for its clients.
+#[derive(Debug)]
+pub struct Registry {
+    listener: UnixListener,
+    entries: Mutex<BTreeMap<String, Entry>>,
+    uids: Option<Vec<libc::uid_t>>,
+    max_entries: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    fd: OwnedFd,
+    owner: libc::uid_t,
+    readers: Vec<libc::uid_t>,
+}
+
+/// A connection to a [`Registry`].
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+}
+
+/// The reasons that a [`Registry`] refuses a request.
+///
+/// The functions of [`Client`] return these as the inner error of an `io::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// There is no entry with the name that the client can see.
+    NotFound,
+
+    /// The client isn't allowed to change the entry, or to use the registry at
+    /// all.
+    Denied,
+
+    /// The name is empty or longer than [`MAX_NAME_LEN`].
+    InvalidName,
+
+    /// The client already owns as many entries as the registry allows.
+    LimitReached,
+}
+
+#[derive(Debug)]
+struct Request {
+    op: u8,
+    name: String,
+    uid: libc::uid_t,
+    fd: Option<OwnedFd>,
+}
+
+#[derive(Debug)]
+enum Reply {
+    Done,
+    Fd(OwnedFd),
+    Names(Vec<String>),
+}
+
+// === impl Registry ===
+
+impl Registry {
+    /// Creates a registry that accepts clients on `listener`.
+    pub fn new(listener: UnixListener) -> Registry {
+        Registry {
+            listener,
+            entries: Mutex::new(BTreeMap::new()),
+            uids: None,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Only allows clients whose effective user id is `uid` or another allowed
+    /// user id to use the registry.
+    ///
+    /// Clients of any user are allowed if this isn't called.
+    pub fn allow_uid(&mut self, uid: libc::uid_t) -> &mut Registry {
+        self.uids.get_or_insert_with(Vec::new).push(uid);
+        self
+    }
+
+    /// Limits the number of entries that each user can own to `max`.
+    ///
+    /// Replacing an entry that a user already owns doesn't count against the
+    /// limit.
+    pub fn set_max_entries(&mut self, max: usize) -> &mut Registry {
+        self.max_entries = max;
+        self
+    }
+
+    /// Accepts clients and serves each of them on its own thread.
+    ///
+    /// The fds that a client registers stay in the registry after it
+    /// disconnects. An `accept()` error ends the loop, and is returned once the
+    /// threads of the clients that are still connected have finished.
+    pub fn run(&self) -> io::Result<()> {
+        thread::scope(|scope| loop {
+            let (stream, _) = self.listener.accept()?;
+
+            scope.spawn(move || {
+                if let Err(e) = self.serve(stream) {
+                    warn!(
+                        source = "Registry",
+                        event = "serve",
+                        condition = "client failed",
+                        error = %e
+                    );
+                }
+            });
+        })
+    }
+
+    /// Serves the requests of the client connected to `stream` until it
+    /// disconnects.
+    ///
+    /// # Errors
+    ///
+    /// This returns an `InvalidData` error if the client sends a malformed
+    /// request.
+    pub fn serve(&self, mut stream: UnixStream) -> io::Result<()> {
+        let cred = stream.peer_cred()?;
+
+        while let Some(request) = read_request(&mut stream)? {
+            let (status, payload, fd) = match self.handle(&cred, request) {
+                Ok(Reply::Done) => (0, Vec::new(), None),
+                Ok(Reply::Fd(fd)) => (0, Vec::new(), Some(fd)),
+                Ok(Reply::Names(names)) => {
+                    let mut payload = Vec::new();
+                    for name in names {
+                        payload.push(name.len() as u8);
+                        payload.extend_from_slice(name.as_bytes());
+                    }
+                    (0, payload, None)
+                }
+                Err(e) => (e.to_wire(), Vec::new(), None),
+            };
+
+            if let Some(fd) = &fd {
+                stream
+                    .enqueue(fd)
+                    .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            }
+            let mut response = Vec::with_capacity(RESPONSE_HEADER_LEN + payload.len());
+            response.push(status);
+            response.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            response.extend_from_slice(&payload);
+            stream.write_all(&response)?;
+            stream.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of all of the entries in the registry.
+    pub fn names(&self) -> Vec<String> {
+        self.entries().keys().cloned().collect()
+    }
+
+    /// The listener that clients connect to.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    fn handle(&self, cred: &UCred, request: Request) -> Result<Reply, RegistryError> {
+        if let Some(uids) = &self.uids {
+            if !uids.contains(&cred.uid) {
+                return Err(RegistryError::Denied);
+            }
+        }
+
+        let mut entries = self.entries();
+        if request.op == OP_LIST {
+            return Ok(Reply::Names(
+                entries
+                    .iter()
+                    .filter(|(_, entry)| entry.can_read(cred))
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+            ));
+        }
+
+        check_name(&request.name).map_err(|_| RegistryError::InvalidName)?;
+        if request.op == OP_GET {
+            return match entries.get(&request.name) {
+                Some(entry) if entry.can_read(cred) => entry
+                    .fd
+                    .try_clone()
+                    .map(Reply::Fd)
+                    .map_err(|_| RegistryError::Denied),
+                _ => Err(RegistryError::NotFound),
+            };
+        }
+
+        let owner = entries.get(&request.name).map(|entry| entry.owner);
+        match (request.op, owner) {
+            (_, Some(owner)) if owner != cred.uid => Err(RegistryError::Denied),
+            (OP_PUT, None) if owned_by(&entries, cred.uid) >= self.max_entries => {
+                Err(RegistryError::LimitReached)
+            }
+            (OP_PUT, _) => {
+                let fd = request.fd.ok_or(RegistryError::Denied)?;
+                match entries.get_mut(&request.name) {
+                    Some(entry) => entry.fd = fd,
+                    None => {
+                        entries.insert(
+                            request.name,
+                            Entry {
+                                fd,
+                                owner: cred.uid,
+                                readers: Vec::new(),
+                            },
+                        );
+                    }
+                }
+                Ok(Reply::Done)
+            }
+            (OP_REMOVE, Some(_)) => {
+                entries.remove(&request.name);
+                Ok(Reply::Done)
+            }
+            (OP_SHARE, Some(_)) => {
+                if let Some(entry) = entries.get_mut(&request.name) {
+                    if !entry.readers.contains(&request.uid) {
+                        entry.readers.push(request.uid);
+                    }
+                }
+                Ok(Reply::Done)
+            }
+            (OP_REMOVE | OP_SHARE, None) => Err(RegistryError::NotFound),
+            _ => Err(RegistryError::Denied),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, BTreeMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// === impl Entry ===
+
+impl Entry {
+    fn can_read(&self, cred: &UCred) -> bool {
+        self.owner == cred.uid || self.readers.contains(&cred.uid)
+    }
+}
+
+// === impl Client ===
+
+impl Client {
+    /// Connects to the registry listening at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Client> {
+        UnixStream::connect(path).map(Client::from)
+    }
+
+    /// Publishes `fd` under `name`, replacing the fd that this user put under
+    /// `name` before (and keeping the users it was shared with).
+    ///
+    /// The registry keeps its own copy of `fd` open, so the caller can close its
+    /// copy once this returns.
+    pub fn put(&mut self, name: &str, fd: &impl AsRawFd) -> io::Result<()> {
+        self.request(OP_PUT, name, 0, Some(fd)).map(drop)
+    }
+
+    /// Gets a copy of the fd published under `name`.
+    pub fn get(&mut self, name: &str) -> io::Result<OwnedFd> {
+        self.request::<OwnedFd>(OP_GET, name, 0, None)?
+            .1
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "registry didn't send an fd"))
+    }
+
+    /// Removes the entry that this user put under `name`, closing the registry's
+    /// copy of its fd.
+    pub fn remove(&mut self, name: &str) -> io::Result<()> {
+        self.request::<OwnedFd>(OP_REMOVE, name, 0, None).map(drop)
+    }
+
+    /// Lists the names of the entries that this user can get.
+    pub fn list(&mut self) -> io::Result<Vec<String>> {
+        let (payload, _) = self.request::<OwnedFd>(OP_LIST, "", 0, None)?;
+
+        let mut names = Vec::new();
+        let mut payload = payload.as_slice();
+        while let [len, rest @ ..] = payload {
+            let len = usize::from(*len);
+            if rest.len() < len {
+                return Err(invalid_data("truncated registry response"));
+            }
+            let name = String::from_utf8(rest[..len].to_vec())
+                .map_err(|e| invalid_data(&e.to_string()))?;
+            names.push(name);
+            payload = &rest[len..];
+        }
+
+        Ok(names)
+    }
+
+    /// Allows the user `uid` to get the entry that this user put under `name`.
+    pub fn share(&mut self, name: &str, uid: libc::uid_t) -> io::Result<()> {
+        self.request::<OwnedFd>(OP_SHARE, name, uid, None).map(drop)
+    }
+
+    fn request<F: AsRawFd>(
+        &mut self,
+        op: u8,
+        name: &str,
+        uid: libc::uid_t,
+        fd: Option<&F>,
+    ) -> io::Result<(Vec<u8>, Option<OwnedFd>)> {
+        if op != OP_LIST {
+            check_name(name)?;
+        }
+
+        let mut request = Vec::with_capacity(REQUEST_HEADER_LEN + name.len());
+        request.push(op);
+        request.push(name.len() as u8);
+        request.extend_from_slice(&uid.to_be_bytes());
+        request.extend_from_slice(name.as_bytes());
+        if let Some(fd) = fd {
+            self.stream
+                .enqueue(fd)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        }
+        self.stream.write_all(&request)?;
+        self.stream.flush()?;
+
+        let mut header = [0; RESPONSE_HEADER_LEN];
+        self.stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_RESPONSE_LEN {
+            return Err(invalid_data("registry response is too long"));
+        }
+        let mut payload = vec![0; len];
+        self.stream.read_exact(&mut payload)?;
+        let mut fds = self.stream.take_fds();
+
+        match RegistryError::from_wire(header[0])? {
+            Some(e) => Err(e.into()),
+            None => Ok((payload, fds.pop())),
+        }
+    }
+}
+
+impl From<UnixStream> for Client {
+    fn from(stream: UnixStream) -> Client {
+        Client { stream }
+    }
+}
+
+// === impl RegistryError ===
+
+impl RegistryError {
+    fn to_wire(self) -> u8 {
+        match self {
+            RegistryError::NotFound => 1,
+            RegistryError::Denied => 2,
+            RegistryError::InvalidName => 3,
+            RegistryError::LimitReached => 4,
+        }
+    }
+
+    fn from_wire(status: u8) -> io::Result<Option<RegistryError>> {
+        match status {
+            0 => Ok(None),
+            1 => Ok(Some(RegistryError::NotFound)),
+            2 => Ok(Some(RegistryError::Denied)),
+            3 => Ok(Some(RegistryError::InvalidName)),
+            4 => Ok(Some(RegistryError::LimitReached)),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown registry status {}", status),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::NotFound => write!(f, "no registry entry with that name"),
+            RegistryError::Denied => write!(f, "registry denied the request"),
+            RegistryError::InvalidName => write!(f, "invalid registry entry name"),
+            RegistryError::LimitReached => write!(f, "too many registry entries"),
+        }
+    }
+}
+
+impl error::Error for RegistryError {}
+
+impl From<RegistryError> for Error {
+    fn from(e: RegistryError) -> Error {
+        let kind = match e {
+            RegistryError::NotFound => ErrorKind::NotFound,
+            RegistryError::Denied => ErrorKind::PermissionDenied,
+            RegistryError::InvalidName => ErrorKind::InvalidInput,
+            RegistryError::LimitReached => ErrorKind::Other,
+        };
+
+        Error::new(kind, e)
+    }
+}
+
+// === utility functions ===
+
+fn owned_by(entries: &BTreeMap<String, Entry>, uid: libc::uid_t) -> usize {
+    entries.values().filter(|entry| entry.owner == uid).count()
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid registry entry name {:?}", name),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Read the next request, or `None` if the client disconnected.
+fn read_request(stream: &mut UnixStream) -> io::Result<Option<Request>> {
+    let mut header = [0; REQUEST_HEADER_LEN];
+    if !stream.read_or_eof(&mut header)? {
+        return Ok(None);
+    }
+
+    let mut name = vec![0; usize::from(header[1])];
+    stream.read_exact(&mut name)?;
+    let mut fds = stream.take_fds();
+    let name = String::from_utf8(name).map_err(|e| invalid_data(&e.to_string()))?;
+
+    Ok(Some(Request {
+        op: header[0],
+        name,
+        uid: u32::from_be_bytes([header[2], header[3], header[4], header[5]]),
+        fd: fds.pop(),
+    }))
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::SeekFrom;
+
+    use tempfile::{tempdir, tempfile};
+
+    fn registry() -> Registry {
+        let dir = tempdir().expect("Can't create temp dir");
+
+        Registry::new(UnixListener::bind(dir.path().join("sock")).expect("Can't bind"))
+    }
+
+    fn registry_error(e: &Error) -> Option<RegistryError> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<RegistryError>())
+            .copied()
+    }
+
+    #[test]
+    fn registry_stores_and_returns_fds() {
+        let sut = registry();
+        let (server, client) = UnixStream::pair().expect("Can't create pair");
+        let mut client = Client::from(client);
+        let mut file = tempfile().expect("Can't create temp file");
+        file.write_all(b"Hello World!")
+            .expect("Can't write temp file");
+        let mut buf = String::new();
+
+        let (names, missing) = thread::scope(|scope| {
+            scope.spawn(|| sut.serve(server).expect("Can't serve client"));
+            client.put("greeting", &file).expect("Can't put fd");
+            drop(file);
+            let mut copy = File::from(client.get("greeting").expect("Can't get fd"));
+            copy.seek(SeekFrom::Start(0)).expect("Can't seek");
+            copy.read_to_string(&mut buf).expect("Can't read");
+            let names = client.list().expect("Can't list");
+            client.remove("greeting").expect("Can't remove");
+            let missing = client
+                .get("greeting")
+                .err()
+                .and_then(|e| registry_error(&e));
+            drop(client);
+            (names, missing)
+        });
+
+        assert_eq!(buf, "Hello World!");
+        assert_eq!(names, vec!["greeting".to_string()]);
+        assert_eq!(missing, Some(RegistryError::NotFound));
+        assert!(sut.names().is_empty());
+    }
+
+    #[test]
+    fn registry_enforces_owner_uid() {
+        let sut = registry();
+        let owner = UCred {
+            uid: 1000,
+            gid: 1000,
+            pid: None,
+        };
+        let other = UCred { uid: 1001, ..owner };
+        let request = |op, uid, fd| Request {
+            op,
+            name: "segment".to_string(),
+            uid,
+            fd,
+        };
+        let fd = || Some(tempfile().expect("Can't create temp file").into());
+        let error = |result: Result<Reply, RegistryError>| result.err();
+
+        sut.handle(&owner, request(OP_PUT, 0, fd()))
+            .expect("Can't put fd");
+        let hidden = error(sut.handle(&other, request(OP_GET, 0, None)));
+        let replace = error(sut.handle(&other, request(OP_PUT, 0, fd())));
+        let remove = error(sut.handle(&other, request(OP_REMOVE, 0, None)));
+        let listed = sut.handle(&other, request(OP_LIST, 0, None));
+        sut.handle(&owner, request(OP_SHARE, other.uid, None))
+            .expect("Can't share fd");
+        let shared = sut.handle(&other, request(OP_GET, 0, None));
+
+        assert_eq!(hidden, Some(RegistryError::NotFound));
+        assert_eq!(replace, Some(RegistryError::Denied));
+        assert_eq!(remove, Some(RegistryError::Denied));
+        assert!(matches!(listed, Ok(Reply::Names(names)) if names.is_empty()));
+        assert!(matches!(shared, Ok(Reply::Fd(_))));
+    }
+
+    #[test]
+    fn registry_bounds_entries_and_readers() {
+        let mut sut = registry();
+        sut.set_max_entries(2);
+        let owner = UCred {
+            uid: 1000,
+            gid: 1000,
+            pid: None,
+        };
+        let other = UCred { uid: 1001, ..owner };
+        let request = |op, name: &str, uid, fd| Request {
+            op,
+            name: name.to_string(),
+            uid,
+            fd,
+        };
+        let fd = || Some(tempfile().expect("Can't create temp file").into());
+
+        for name in ["one", "two"] {
+            sut.handle(&owner, request(OP_PUT, name, 0, fd()))
+                .expect("Can't put fd");
+        }
+        let over = sut.handle(&owner, request(OP_PUT, "three", 0, fd())).err();
+        let replace = sut.handle(&owner, request(OP_PUT, "two", 0, fd()));
+        let others = sut.handle(&other, request(OP_PUT, "three", 0, fd()));
+        for _ in 0..3 {
+            sut.handle(&owner, request(OP_SHARE, "one", other.uid, None))
+                .expect("Can't share fd");
+        }
+        let readers = sut.entries()["one"].readers.clone();
+
+        assert_eq!(over, Some(RegistryError::LimitReached));
+        assert!(replace.is_ok());
+        assert!(others.is_ok());
+        assert_eq!(readers, vec![other.uid]);
+    }
+}
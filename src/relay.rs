@@ -0,0 +1,300 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Relay bytes and fds between two streams.
+//!
+//! [`relay()`] forwards everything that arrives on either of two
+//! [`UnixStream`][crate::UnixStream]s to the other one until both of them reach
+//! end of file. Each fd is forwarded with the bytes that it arrived with, so a
+//! protocol on top of the streams that relies on where its fds are sees them in
+//! the same place on both sides of the relay. To find those bytes the relay
+//! reads a byte at a time while fds are waiting, so it is slower for streams
+//! that pass many fds.
+//!
+//! [`relay_with_filter()`] also passes each fd to a filter before forwarding it.
+//! The filter can inspect the fd and veto it, in which case the fd is closed
+//! instead of being forwarded but the bytes that it arrived with are still
+//! forwarded.
+//!
+//! With the `tokio-fd` feature [`tokio::relay()`][crate::tokio::relay()] and
+//! [`tokio::relay_with_filter()`][crate::tokio::relay_with_filter()] do the same
+//! for two tokio [`UnixStream`][crate::tokio::UnixStream]s.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "net-fd")]
+//! # {
+//! use fd_queue::relay::{self, Direction};
+//! use fd_queue::UnixStream;
+//! use std::thread;
+//!
+//! let (mut client, mut a) = UnixStream::pair()?;
+//! let (mut b, mut server) = UnixStream::pair()?;
+//!
+//! // Forward fds from the client to the server but not from the server back.
+//! let relay = thread::spawn(move || {
+//!     relay::relay_with_filter(&mut a, &mut b, |direction, _fd| {
+//!         direction == Direction::AToB
+//!     })
+//! });
+//! # drop((client, server));
+//! # relay.join().expect("Relay panicked")?;
+//! # }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+#[cfg(feature = "net-fd")]
+use std::{
+    io::{self, prelude::*, Error, ErrorKind},
+    net::Shutdown,
+    os::unix::io::{AsFd, BorrowedFd},
+    panic,
+    sync::{Mutex, PoisonError},
+    thread,
+};
+
+#[cfg(feature = "net-fd")]
+use crate::{EnqueueFd, UnixStream};
+
+#[cfg(feature = "net-fd")]
+const BUF_LEN: usize = 1 << 16;
+
+/// The direction in which a relay forwards an fd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the first stream passed to the relay to the second.
+    AToB,
+
+    /// From the second stream passed to the relay to the first.
+    BToA,
+}
+
+/// Forwards bytes and fds between `a` and `b` until both reach end of file.
+///
+/// When one of the streams reaches end of file the other one is shut down for
+/// writing. This returns the number of bytes forwarded from `a` to `b` and from
+/// `b` to `a`.
+///
+/// # Errors
+///
+/// If forwarding in either direction fails then both streams are shut down and
+/// the error is returned.
+#[cfg(feature = "net-fd")]
+pub fn relay(a: &mut UnixStream, b: &mut UnixStream) -> io::Result<(u64, u64)> {
+    relay_with_filter(a, b, |_, _| true)
+}
+
+/// Forwards bytes and fds between `a` and `b`, passing each fd through `filter`.
+///
+/// An fd is forwarded only if `filter` returns `true` for it; otherwise it is
+/// closed. See [`relay()`] for the rest.
+#[cfg(feature = "net-fd")]
+pub fn relay_with_filter<F>(
+    a: &mut UnixStream,
+    b: &mut UnixStream,
+    filter: F,
+) -> io::Result<(u64, u64)>
+where
+    F: FnMut(Direction, BorrowedFd<'_>) -> bool + Send,
+{
+    let filter = Mutex::new(filter);
+    let mut a_reader = a.try_clone()?;
+    let mut b_reader = b.try_clone()?;
+
+    thread::scope(|scope| {
+        let b_to_a = scope.spawn(|| transfer(&mut b_reader, a, Direction::BToA, &filter));
+        let a_to_b = transfer(&mut a_reader, b, Direction::AToB, &filter);
+        let b_to_a = b_to_a.join().unwrap_or_else(|e| panic::resume_unwind(e));
+
+        Ok((a_to_b?, b_to_a?))
+    })
+}
+
+// === utility functions ===
+
+#[cfg(feature = "net-fd")]
+fn transfer<F>(
+    src: &mut UnixStream,
+    dst: &mut UnixStream,
+    direction: Direction,
+    filter: &Mutex<F>,
+) -> io::Result<u64>
+where
+    F: FnMut(Direction, BorrowedFd<'_>) -> bool,
+{
+    let result = forward(src, dst, direction, filter);
+    if result.is_err() {
+        // Unblock the other direction so that the relay can return the error.
+        let _ = src.shutdown(Shutdown::Both);
+        let _ = dst.shutdown(Shutdown::Both);
+    }
+
+    result
+}
+
+#[cfg(feature = "net-fd")]
+fn forward<F>(
+    src: &mut UnixStream,
+    dst: &mut UnixStream,
+    direction: Direction,
+    filter: &Mutex<F>,
+) -> io::Result<u64>
+where
+    F: FnMut(Direction, BorrowedFd<'_>) -> bool,
+{
+    let mut buf = vec![0; BUF_LEN];
+    let mut total = 0;
+
+    loop {
+        let (len, fds_waiting) = src.peek_fds_waiting(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        // A read returns fds together with any bytes queued ahead of the ones
+        // that they were sent with, so while fds are waiting read a byte at a
+        // time until they arrive.
+        let (plain, len, mut fds) = if fds_waiting {
+            let mut count = 0;
+            let mut fds = Vec::new();
+            while count < len && fds.is_empty() {
+                src.read_exact(&mut buf[count..count + 1])?;
+                fds = src.take_fds();
+                count += 1;
+            }
+            (if fds.is_empty() { count } else { count - 1 }, count, fds)
+        } else {
+            src.read_exact(&mut buf[..len])?;
+            (len, len, Vec::new())
+        };
+
+        let mut filter = filter.lock().unwrap_or_else(PoisonError::into_inner);
+        fds.retain(|fd| filter(direction, fd.as_fd()));
+        drop(filter);
+
+        dst.write_all(&buf[..plain])?;
+        // The fds are sent with the first byte written, which is the byte that
+        // they arrived with.
+        for fd in fds.iter() {
+            dst.enqueue(fd)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        }
+        dst.write_all(&buf[plain..len])?;
+        total += len as u64;
+    }
+
+    dst.shutdown(Shutdown::Write)?;
+    Ok(total)
+}
+
+#[cfg(all(test, feature = "net-fd"))]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::{test_util::hello_file, DequeueFd};
+
+    #[test]
+    fn relay_forwards_bytes_and_filtered_fds() {
+        let (mut client, mut a) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mut b, mut server) = UnixStream::pair().expect("Can't create UnixStream's");
+        let relay = thread::spawn(move || {
+            relay_with_filter(&mut a, &mut b, |direction, _| direction == Direction::AToB)
+        });
+
+        let file = hello_file();
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client.write_all(b"request").expect("Can't write");
+        let mut request = [0; 7];
+        server.read_exact(&mut request).expect("Can't read");
+        let fd = server.dequeue().expect("No fd forwarded");
+
+        server.enqueue(&file).expect("Can't enqueue fd");
+        server.write_all(b"reply").expect("Can't write");
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).expect("Can't read");
+        let vetoed = client.dequeue();
+
+        drop((client, server));
+        let counts = relay.join().expect("Relay panicked").expect("Relay failed");
+
+        // SAFETY: the dequeued fd is owned by the test.
+        let mut forwarded = unsafe { File::from_raw_fd(fd) };
+        let mut buf = String::new();
+        forwarded
+            .read_to_string(&mut buf)
+            .expect("Can't read from file");
+        assert_eq!(&request, b"request");
+        assert_eq!(buf, "Hello World!");
+        assert_eq!(&reply, b"reply");
+        assert!(vetoed.is_none());
+        assert_eq!(counts, (7, 5));
+    }
+
+    #[test]
+    fn relay_keeps_fds_with_their_bytes() {
+        let (mut client, mut a) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mut b, mut server) = UnixStream::pair().expect("Can't create UnixStream's");
+        let relay = thread::spawn(move || relay(&mut a, &mut b));
+
+        let file = hello_file();
+        client.write_all(b"plain").expect("Can't write");
+        client.flush().expect("Can't flush");
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).expect("Can't read");
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client.write_all(b"withfd").expect("Can't write");
+        drop(client);
+
+        let plain_fd = server.dequeue();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).expect("Can't read");
+        let fd = server.dequeue();
+        drop(server);
+        relay.join().expect("Relay panicked").expect("Relay failed");
+
+        assert!(plain_fd.is_none());
+        assert_eq!(rest, b"withfd");
+        // SAFETY: the dequeued fd is owned by the test.
+        drop(unsafe { File::from_raw_fd(fd.expect("No fd with its bytes")) });
+    }
+
+    #[test]
+    fn relay_keeps_fds_with_their_bytes_when_both_are_waiting() {
+        let (mut client, mut a) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mut b, mut server) = UnixStream::pair().expect("Can't create UnixStream's");
+
+        // Both writes are waiting before the relay starts so that a single read
+        // would return them together.
+        let file = hello_file();
+        client.write_all(b"plain").expect("Can't write");
+        client.flush().expect("Can't flush");
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client.write_all(b"withfd").expect("Can't write");
+        drop(client);
+        let relay = thread::spawn(move || relay(&mut a, &mut b));
+
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).expect("Can't read");
+        let plain_fd = server.dequeue();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).expect("Can't read");
+        let fd = server.dequeue();
+        drop(server);
+        relay.join().expect("Relay panicked").expect("Relay failed");
+
+        assert_eq!(&buf, b"plain");
+        assert!(plain_fd.is_none());
+        assert_eq!(rest, b"withfd");
+        // SAFETY: the dequeued fd is owned by the test.
+        drop(unsafe { File::from_raw_fd(fd.expect("No fd with its bytes")) });
+    }
+}
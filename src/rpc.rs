@@ -0,0 +1,928 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Bidirectional remote procedure calls whose arguments and results can carry fds.
+//!
+//! Both ends of a [`UnixStream`] are equal peers: each can call methods on the
+//! other, send it notifications and answer the calls that it receives.
+//! [`Connection::new`] splits a stream into the [`Connection`] future that drives
+//! it, a [`Peer`] for making calls and an [`Incoming`] stream of the [`Request`]s
+//! sent by the other end.
+//!
+//! Every call has its own id so that any number of calls can be in flight at once
+//! and their results can arrive in any order. Dropping the future returned by
+//! [`Peer::call`] cancels the call, which the other end sees through
+//! [`Request::cancelled`].
+//!
+//! Each argument and each result is an [`Arg`] of bytes and fds. The fds of a
+//! message travel with the message's first byte through the [`EnqueueFd`] and
+//! [`DequeueFd`] implementations of [`UnixStream`], and are given back to the
+//! [`Arg`] that they were attached to.
+//!
+//! [`EnqueueFd`]: crate::EnqueueFd
+//! [`DequeueFd`]: crate::DequeueFd
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::rpc::{Arg, Connection};
+//! use fd_queue::tokio::UnixStream;
+//! # use tempfile::tempfile;
+//! # tokio_test::block_on(async {
+//! let (sock1, sock2) = UnixStream::pair()?;
+//!
+//! // In the server.
+//! let (connection, _, mut incoming) = Connection::new(sock2);
+//! tokio::spawn(connection);
+//! tokio::spawn(async move {
+//!     while let Some(mut request) = incoming.recv().await {
+//!         match request.method() {
+//!             "open-log" => {
+//! #               let log = tempfile().unwrap();
+//!                 // let log: File = ...
+//!                 let result = Arg::new("log").with_fd(log);
+//!                 let _ = request.reply(vec![result]);
+//!             }
+//!             _ => {
+//!                 let _ = request.fail(1, "unknown method");
+//!             }
+//!         }
+//!     }
+//! });
+//!
+//! // In the client.
+//! let (connection, peer, _) = Connection::new(sock1);
+//! tokio::spawn(connection);
+//! let mut results = peer.call("open-log", vec![]).await?;
+//! let log = results.remove(0).into_fds().remove(0);
+//! # Ok::<(), std::io::Error>(())
+//! # });
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error, fmt,
+    future::Future,
+    io::{self, Error, ErrorKind},
+    mem,
+    os::unix::io::OwnedFd,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures_core::stream::Stream;
+use futures_sink::Sink;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::tokio::{
+    codec::{FdDecoder, FdEncoder, FdFramed, RecvFds},
+    UnixStream,
+};
+
+/// The longest message, not counting its length prefix.
+pub const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+const KIND_REQUEST: u8 = 1;
+const KIND_RESPONSE: u8 = 2;
+const KIND_ERROR: u8 = 3;
+const KIND_NOTIFY: u8 = 4;
+const KIND_CANCEL: u8 = 5;
+const KIND_CANCELLED: u8 = 6;
+
+// kind, id, code, text length and argument count
+const HEADER_LEN: usize = 1 + 8 + 4 + 2 + 2;
+// data length and fd count
+const ARG_HEADER_LEN: usize = 4 + 1;
+
+/// An argument or result of a call: some bytes and the fds attached to them.
+#[derive(Debug, Default)]
+pub struct Arg {
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+/// The future that drives an rpc connection.
+///
+/// A `Connection` sends the calls, notifications and replies of its [`Peer`] and
+/// [`Request`]s, and dispatches the messages that arrive from the other end. It
+/// has to be polled (usually by spawning it) for anything to happen on the
+/// connection. It completes when the other end closes the stream.
+#[derive(Debug)]
+pub struct Connection {
+    framed: FdFramed<UnixStream, RpcCodec>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    commands_tx: mpsc::UnboundedSender<Command>,
+    requests: mpsc::UnboundedSender<Request>,
+    // the calls made by this end that are waiting for a result
+    pending: HashMap<u64, oneshot::Sender<Result<Vec<Arg>, RpcError>>>,
+    // the calls made by the other end that haven't been answered
+    active: HashMap<u64, oneshot::Sender<()>>,
+    outbox: VecDeque<Message>,
+}
+
+/// The handle for making calls and sending notifications to the other end of a
+/// [`Connection`].
+#[derive(Debug, Clone)]
+pub struct Peer {
+    commands: mpsc::UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// The stream of [`Request`]s sent by the other end of a [`Connection`].
+#[derive(Debug)]
+pub struct Incoming {
+    requests: mpsc::UnboundedReceiver<Request>,
+}
+
+/// A call or notification from the other end of a [`Connection`].
+///
+/// A call is answered with [`reply`][Request::reply] or [`fail`][Request::fail].
+/// Dropping a call without answering it answers it with
+/// [`RpcError::Cancelled`].
+#[derive(Debug)]
+pub struct Request {
+    // None for notifications and for calls that have been answered
+    id: Option<u64>,
+    method: String,
+    args: Vec<Arg>,
+    cancel: Option<oneshot::Receiver<()>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// The reasons that a call doesn't produce a result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// The other end answered the call with [`Request::fail`].
+    Failed {
+        /// The code passed to [`Request::fail`].
+        code: u32,
+        /// The message passed to [`Request::fail`].
+        message: String,
+    },
+
+    /// The other end dropped the call without answering it.
+    Cancelled,
+
+    /// The connection has closed.
+    Disconnected,
+
+    /// The arguments have more than
+    /// [`FD_QUEUE_SIZE`][crate::tokio::UnixStream::FD_QUEUE_SIZE] fds between
+    /// them.
+    TooManyFds,
+
+    /// The message would be longer than [`MAX_MESSAGE_LEN`].
+    TooLarge,
+}
+
+#[derive(Debug)]
+enum Command {
+    Call {
+        id: u64,
+        method: String,
+        args: Vec<Arg>,
+        reply: oneshot::Sender<Result<Vec<Arg>, RpcError>>,
+    },
+    Notify {
+        method: String,
+        args: Vec<Arg>,
+    },
+    Cancel(u64),
+    Respond {
+        id: u64,
+        outcome: Outcome,
+    },
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Reply(Vec<Arg>),
+    Fail(u32, String),
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct Message {
+    kind: u8,
+    id: u64,
+    code: u32,
+    text: String,
+    args: Vec<Arg>,
+}
+
+#[derive(Debug)]
+struct RpcCodec;
+
+// Sends a Cancel for a call unless it is disarmed.
+struct CancelGuard<'a> {
+    id: u64,
+    commands: &'a mpsc::UnboundedSender<Command>,
+}
+
+// === impl Arg ===
+
+impl Arg {
+    /// Creates an argument with the given bytes and no fds.
+    pub fn new(data: impl Into<Vec<u8>>) -> Arg {
+        Arg {
+            data: data.into(),
+            fds: Vec::new(),
+        }
+    }
+
+    /// Attaches `fd` to this argument.
+    pub fn with_fd(mut self, fd: impl Into<OwnedFd>) -> Arg {
+        self.fds.push(fd.into());
+        self
+    }
+
+    /// Gets the bytes of this argument.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Gets the fds attached to this argument.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Takes the fds attached to this argument.
+    pub fn into_fds(self) -> Vec<OwnedFd> {
+        self.fds
+    }
+
+    /// Takes the bytes of this argument and the fds attached to it.
+    pub fn into_parts(self) -> (Vec<u8>, Vec<OwnedFd>) {
+        (self.data, self.fds)
+    }
+}
+
+// === impl Connection ===
+
+impl Connection {
+    /// Splits `stream` into the future that drives it, the [`Peer`] for making
+    /// calls on the other end and the [`Incoming`] calls from the other end.
+    pub fn new(stream: UnixStream) -> (Connection, Peer, Incoming) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+
+        let connection = Connection {
+            framed: FdFramed::new(stream, RpcCodec),
+            commands,
+            commands_tx: commands_tx.clone(),
+            requests: requests_tx,
+            pending: HashMap::new(),
+            active: HashMap::new(),
+            outbox: VecDeque::new(),
+        };
+        let peer = Peer {
+            commands: commands_tx,
+            next_id: Arc::new(AtomicU64::new(1)),
+        };
+
+        (connection, peer, Incoming { requests })
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Call {
+                id,
+                method,
+                args,
+                reply,
+            } => {
+                self.pending.insert(id, reply);
+                self.outbox
+                    .push_back(Message::new(KIND_REQUEST, id, method, args));
+            }
+            Command::Notify { method, args } => {
+                self.outbox
+                    .push_back(Message::new(KIND_NOTIFY, 0, method, args));
+            }
+            Command::Cancel(id) => {
+                if self.pending.remove(&id).is_some() {
+                    self.outbox
+                        .push_back(Message::new(KIND_CANCEL, id, String::new(), Vec::new()));
+                }
+            }
+            Command::Respond { id, outcome } => {
+                // A call that the other end has cancelled gets no answer.
+                if self.active.remove(&id).is_none() {
+                    return;
+                }
+                let message = match outcome {
+                    Outcome::Reply(args) => Message::new(KIND_RESPONSE, id, String::new(), args),
+                    Outcome::Fail(code, text) => Message {
+                        code,
+                        ..Message::new(KIND_ERROR, id, text, Vec::new())
+                    },
+                    Outcome::Cancelled => {
+                        Message::new(KIND_CANCELLED, id, String::new(), Vec::new())
+                    }
+                };
+                self.outbox.push_back(message);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        let id = message.id;
+        match message.kind {
+            KIND_REQUEST | KIND_NOTIFY => {
+                let (id, cancel) = if message.kind == KIND_REQUEST {
+                    let (cancel_tx, cancel) = oneshot::channel();
+                    self.active.insert(id, cancel_tx);
+                    (Some(id), Some(cancel))
+                } else {
+                    (None, None)
+                };
+                // If nobody is listening for requests then dropping the
+                // request answers it.
+                let _ = self.requests.send(Request {
+                    id,
+                    method: message.text,
+                    args: message.args,
+                    cancel,
+                    commands: self.commands_tx.clone(),
+                });
+            }
+            KIND_RESPONSE => self.complete(id, Ok(message.args)),
+            KIND_ERROR => self.complete(
+                id,
+                Err(RpcError::Failed {
+                    code: message.code,
+                    message: message.text,
+                }),
+            ),
+            KIND_CANCELLED => self.complete(id, Err(RpcError::Cancelled)),
+            KIND_CANCEL => {
+                if let Some(cancel) = self.active.remove(&id) {
+                    let _ = cancel.send(());
+                }
+            }
+            _ => unreachable!("RpcCodec only decodes known kinds"),
+        }
+    }
+
+    fn complete(&mut self, id: u64, result: Result<Vec<Arg>, RpcError>) {
+        // A call that this end has cancelled may still get an answer.
+        if let Some(reply) = self.pending.remove(&id) {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+impl Future for Connection {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        loop {
+            let mut progress = false;
+
+            while let Poll::Ready(Some(command)) = this.commands.poll_recv(cx) {
+                this.handle_command(command);
+                progress = true;
+            }
+
+            while !this.outbox.is_empty() {
+                if Pin::new(&mut this.framed).poll_ready(cx)?.is_pending() {
+                    break;
+                }
+                let message = this.outbox.pop_front().expect("outbox is not empty");
+                Pin::new(&mut this.framed).start_send(message)?;
+                progress = true;
+            }
+            let _ = Pin::new(&mut this.framed).poll_flush(cx)?;
+
+            match Pin::new(&mut this.framed).poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => {
+                    this.handle_message(message);
+                    progress = true;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => {}
+            }
+
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+// === impl Peer ===
+
+impl Peer {
+    /// Calls `method` on the other end with `args` and waits for its results.
+    ///
+    /// Dropping the returned future before it completes cancels the call.
+    pub async fn call(&self, method: &str, args: Vec<Arg>) -> Result<Vec<Arg>, RpcError> {
+        check_message(method, &args)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Call {
+                id,
+                method: method.to_owned(),
+                args,
+                reply,
+            })
+            .map_err(|_| RpcError::Disconnected)?;
+
+        let guard = CancelGuard {
+            id,
+            commands: &self.commands,
+        };
+        let result = result.await;
+        guard.disarm();
+
+        result.unwrap_or(Err(RpcError::Disconnected))
+    }
+
+    /// Sends `method` with `args` to the other end without waiting for, or
+    /// getting, an answer.
+    pub fn notify(&self, method: &str, args: Vec<Arg>) -> Result<(), RpcError> {
+        check_message(method, &args)?;
+
+        self.commands
+            .send(Command::Notify {
+                method: method.to_owned(),
+                args,
+            })
+            .map_err(|_| RpcError::Disconnected)
+    }
+}
+
+// === impl Incoming ===
+
+impl Incoming {
+    /// Receives the next request from the other end.
+    ///
+    /// Returns `None` once the [`Connection`] has completed.
+    pub async fn recv(&mut self) -> Option<Request> {
+        self.requests.recv().await
+    }
+}
+
+impl Stream for Incoming {
+    type Item = Request;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        self.requests.poll_recv(cx)
+    }
+}
+
+// === impl Request ===
+
+impl Request {
+    /// Gets the name of the method.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Gets the arguments.
+    pub fn args(&self) -> &[Arg] {
+        &self.args
+    }
+
+    /// Takes the arguments, leaving none in their place.
+    pub fn take_args(&mut self) -> Vec<Arg> {
+        mem::take(&mut self.args)
+    }
+
+    /// Returns `true` if this is a notification, which has no answer.
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
+    /// Waits until the caller cancels this call or the connection closes.
+    ///
+    /// This never completes for a notification.
+    pub async fn cancelled(&mut self) {
+        match self.cancel.as_mut() {
+            Some(cancel) => {
+                let _ = cancel.await;
+                self.cancel = None;
+            }
+            None if self.id.is_none() => futures_util::future::pending().await,
+            None => {}
+        }
+    }
+
+    /// Answers this call with `results`.
+    ///
+    /// Answering a notification, or a call that the caller has cancelled, does
+    /// nothing.
+    pub fn reply(mut self, results: Vec<Arg>) -> Result<(), RpcError> {
+        check_message("", &results)?;
+        self.respond(Outcome::Reply(results))
+    }
+
+    /// Answers this call with a failure that the caller gets as
+    /// [`RpcError::Failed`].
+    ///
+    /// Answering a notification, or a call that the caller has cancelled, does
+    /// nothing.
+    pub fn fail(mut self, code: u32, message: impl Into<String>) -> Result<(), RpcError> {
+        let message = message.into();
+        check_message(&message, &[])?;
+        self.respond(Outcome::Fail(code, message))
+    }
+
+    fn respond(&mut self, outcome: Outcome) -> Result<(), RpcError> {
+        match self.id.take() {
+            Some(id) => self
+                .commands
+                .send(Command::Respond { id, outcome })
+                .map_err(|_| RpcError::Disconnected),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        let _ = self.respond(Outcome::Cancelled);
+    }
+}
+
+// === impl RpcError ===
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::Failed { code, message } => {
+                write!(f, "call failed with code {}: {}", code, message)
+            }
+            RpcError::Cancelled => write!(f, "call was cancelled"),
+            RpcError::Disconnected => write!(f, "rpc connection closed"),
+            RpcError::TooManyFds => write!(f, "too many fds attached to the arguments"),
+            RpcError::TooLarge => write!(f, "rpc message too large"),
+        }
+    }
+}
+
+impl error::Error for RpcError {}
+
+impl From<RpcError> for Error {
+    fn from(e: RpcError) -> Error {
+        let kind = match e {
+            RpcError::Failed { .. } => ErrorKind::Other,
+            RpcError::Cancelled => ErrorKind::Interrupted,
+            RpcError::Disconnected => ErrorKind::NotConnected,
+            RpcError::TooManyFds | RpcError::TooLarge => ErrorKind::InvalidInput,
+        };
+
+        Error::new(kind, e)
+    }
+}
+
+// === impl Message ===
+
+impl Message {
+    fn new(kind: u8, id: u64, text: String, args: Vec<Arg>) -> Message {
+        Message {
+            kind,
+            id,
+            code: 0,
+            text,
+            args,
+        }
+    }
+}
+
+// === impl RpcCodec ===
+
+impl FdEncoder<Message> for RpcCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        message: Message,
+        dst: &mut BytesMut,
+        fds: &mut Vec<OwnedFd>,
+    ) -> io::Result<()> {
+        let len = message_len(&message.text, &message.args);
+        dst.reserve(4 + len);
+
+        dst.put_u32(len as u32);
+        dst.put_u8(message.kind);
+        dst.put_u64(message.id);
+        dst.put_u32(message.code);
+        dst.put_u16(message.text.len() as u16);
+        dst.put_u16(message.args.len() as u16);
+        dst.put_slice(message.text.as_bytes());
+        for arg in message.args {
+            dst.put_u32(arg.data.len() as u32);
+            dst.put_u8(arg.fds.len() as u8);
+            dst.put_slice(&arg.data);
+            fds.extend(arg.fds);
+        }
+
+        Ok(())
+    }
+}
+
+impl FdDecoder for RpcCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut, fds: &mut RecvFds) -> io::Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(invalid_data("rpc message too long"));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        let mut buf = src.split_to(4 + len);
+        buf.advance(4);
+
+        need(&buf, HEADER_LEN)?;
+        let kind = buf.get_u8();
+        let id = buf.get_u64();
+        let code = buf.get_u32();
+        let text_len = buf.get_u16() as usize;
+        let arg_count = buf.get_u16() as usize;
+        if !(KIND_REQUEST..=KIND_CANCELLED).contains(&kind) {
+            return Err(invalid_data(format!("unknown rpc message kind {}", kind)));
+        }
+
+        need(&buf, text_len)?;
+        let text = String::from_utf8(buf.split_to(text_len).to_vec())
+            .map_err(|_| invalid_data("rpc method name isn't utf-8"))?;
+
+        let mut args = Vec::with_capacity(arg_count.min(buf.len() / ARG_HEADER_LEN));
+        for _ in 0..arg_count {
+            need(&buf, ARG_HEADER_LEN)?;
+            let data_len = buf.get_u32() as usize;
+            let fd_count = buf.get_u8() as usize;
+            need(&buf, data_len)?;
+            let data = buf.split_to(data_len).to_vec();
+            let fds = fds.take(fd_count)?;
+            args.push(Arg { data, fds });
+        }
+        if buf.has_remaining() {
+            return Err(invalid_data("rpc message has trailing bytes"));
+        }
+
+        Ok(Some(Message {
+            kind,
+            id,
+            code,
+            text,
+            args,
+        }))
+    }
+}
+
+// === impl CancelGuard ===
+
+impl CancelGuard<'_> {
+    fn disarm(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Cancel(self.id));
+    }
+}
+
+// === utility functions ===
+
+fn message_len(text: &str, args: &[Arg]) -> usize {
+    HEADER_LEN
+        + text.len()
+        + args
+            .iter()
+            .map(|arg| ARG_HEADER_LEN + arg.data.len())
+            .sum::<usize>()
+}
+
+fn check_message(text: &str, args: &[Arg]) -> Result<(), RpcError> {
+    let fds: usize = args.iter().map(|arg| arg.fds.len()).sum();
+    if fds > UnixStream::FD_QUEUE_SIZE {
+        return Err(RpcError::TooManyFds);
+    }
+    if text.len() > u16::MAX as usize
+        || args.len() > u16::MAX as usize
+        || message_len(text, args) > MAX_MESSAGE_LEN
+    {
+        return Err(RpcError::TooLarge);
+    }
+
+    Ok(())
+}
+
+fn need(buf: &BytesMut, len: usize) -> io::Result<()> {
+    if buf.len() < len {
+        Err(invalid_data("truncated rpc message"))
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use futures_util::{
+        future::{self, Either},
+        SinkExt,
+    };
+
+    use crate::test_util::hello_file;
+
+    fn connect() -> ((Peer, Incoming), (Peer, Incoming)) {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (connection1, peer1, incoming1) = Connection::new(sock1);
+        let (connection2, peer2, incoming2) = Connection::new(sock2);
+        tokio::spawn(connection1);
+        tokio::spawn(connection2);
+
+        ((peer1, incoming1), (peer2, incoming2))
+    }
+
+    #[tokio::test]
+    async fn call_keeps_fds_with_their_args() {
+        let ((client, _), (_, mut incoming)) = connect();
+        tokio::spawn(async move {
+            while let Some(mut request) = incoming.recv().await {
+                let mut args = request.take_args();
+                args.reverse();
+                request.reply(args).expect("Can't reply");
+            }
+        });
+
+        let args = vec![
+            Arg::new("none"),
+            Arg::new("one").with_fd(hello_file()),
+            Arg::new("two").with_fd(hello_file()).with_fd(hello_file()),
+        ];
+        let results = client.call("reverse", args).await.expect("Can't call");
+
+        let counts: Vec<_> = results
+            .iter()
+            .map(|arg| (arg.data().to_vec(), arg.fds().len()))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (b"two".to_vec(), 2),
+                (b"one".to_vec(), 1),
+                (b"none".to_vec(), 0)
+            ]
+        );
+        for fd in results.into_iter().flat_map(Arg::into_fds) {
+            let mut buf = String::new();
+            File::from(fd)
+                .read_to_string(&mut buf)
+                .expect("Can't read from file");
+            assert_eq!(buf, "Hello World!");
+        }
+    }
+
+    #[tokio::test]
+    async fn call_results_arrive_out_of_order() {
+        let ((client, _), (_, mut incoming)) = connect();
+        let (first_tx, first_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let slow = incoming.recv().await.expect("No first request");
+            let fast = incoming.recv().await.expect("No second request");
+            fast.fail(7, "fast").expect("Can't fail");
+            let _ = first_rx.await;
+            slow.reply(vec![Arg::new("slow")]).expect("Can't reply");
+        });
+
+        let slow = client.call("slow", vec![]);
+        let fast = async {
+            let result = client.call("fast", vec![]).await;
+            let _ = first_tx.send(());
+            result
+        };
+        let (slow, fast) = tokio::join!(slow, fast);
+
+        assert_eq!(
+            fast.expect_err("Fast call didn't fail"),
+            RpcError::Failed {
+                code: 7,
+                message: "fast".to_owned()
+            }
+        );
+        assert_eq!(slow.expect("Slow call failed")[0].data(), b"slow");
+    }
+
+    #[tokio::test]
+    async fn notify_reaches_other_end() {
+        let ((_, mut incoming), (server, _)) = connect();
+
+        server
+            .notify("rotated", vec![Arg::new("log").with_fd(hello_file())])
+            .expect("Can't notify");
+        let request = incoming.recv().await.expect("No notification");
+
+        assert!(request.is_notification());
+        assert_eq!(request.method(), "rotated");
+        assert_eq!(request.args()[0].fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn notifications_from_separate_writes_keep_their_fds() {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sender = FdFramed::new(sock1, RpcCodec);
+        let plain = Message::new(KIND_NOTIFY, 0, "plain".to_owned(), vec![Arg::new("x")]);
+        let withfd = Message::new(
+            KIND_NOTIFY,
+            0,
+            "withfd".to_owned(),
+            vec![Arg::new("x").with_fd(hello_file())],
+        );
+        sender.send(plain).await.expect("Can't send notification");
+        sender.send(withfd).await.expect("Can't send notification");
+
+        // Both notifications are waiting on the socket before the first read.
+        let (connection, _, mut incoming) = Connection::new(sock2);
+        tokio::spawn(connection);
+        let plain = incoming.recv().await.expect("No notification");
+        let withfd = incoming.recv().await.expect("No notification");
+
+        assert_eq!(plain.method(), "plain");
+        assert_eq!(plain.args()[0].fds().len(), 0);
+        assert_eq!(withfd.method(), "withfd");
+        assert_eq!(withfd.args()[0].fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_call_is_cancelled() {
+        let ((client, _), (_, mut incoming)) = connect();
+        let (started_tx, started_rx) = oneshot::channel();
+        let (cancelled_tx, cancelled_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut request = incoming.recv().await.expect("No request");
+            let _ = started_tx.send(());
+            request.cancelled().await;
+            let _ = cancelled_tx.send(());
+        });
+
+        let call = Box::pin(client.call("wait", vec![]));
+        match future::select(call, started_rx).await {
+            Either::Left(_) => panic!("Call completed before being cancelled"),
+            Either::Right((_, call)) => drop(call),
+        }
+
+        cancelled_rx.await.expect("Request wasn't cancelled");
+    }
+
+    #[tokio::test]
+    async fn dropped_request_answers_cancelled() {
+        let ((client, _), (_, mut incoming)) = connect();
+        tokio::spawn(async move {
+            drop(incoming.recv().await);
+        });
+
+        let result = client.call("ignored", vec![]).await;
+
+        assert_eq!(result.expect_err("Call succeeded"), RpcError::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn call_with_too_many_fds_is_error() {
+        let (sock, _) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (_, peer, _) = Connection::new(sock);
+        let args = (0..=UnixStream::FD_QUEUE_SIZE)
+            .map(|_| Arg::new("x").with_fd(hello_file()))
+            .collect();
+
+        let result = peer.notify("many", args);
+
+        assert_eq!(result, Err(RpcError::TooManyFds));
+    }
+}
@@ -0,0 +1,1217 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! A serde data format that carries fds out of band.
+//!
+//! The format is a compact binary format in the style of bincode: integers are
+//! fixed width and little endian, and strings, byte arrays, sequences and maps are
+//! prefixed with their length. It isn't self describing, so it doesn't support
+//! `deserialize_any` (which rules out `#[serde(flatten)]` and untagged enums).
+//!
+//! Fields that hold fds are marked with `#[serde(with = "fd_queue::serde::fd")]`
+//! (for any type that is [`AsFd`] and `From<OwnedFd>`, such as [`OwnedFd`] and
+//! [`File`][std::fs::File]), or use the [`Fd`] wrapper. The serializer replaces
+//! each fd with its index among the fds of the message and [`to_writer`] queues
+//! the fds themselves on the stream through [`EnqueueFd`], so that they travel
+//! with the first byte of the message. [`from_reader`] resolves the indices
+//! against the fds that arrived with the message, and rejects a message that
+//! refers to an fd that didn't arrive, refers to the same fd twice, or leaves
+//! some of its fds unused.
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::UnixStream;
+//! use serde::{Deserialize, Serialize};
+//! use std::fs::File;
+//! # use tempfile::tempfile;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Rotated {
+//!     name: String,
+//!     #[serde(with = "fd_queue::serde::fd")]
+//!     log: File,
+//! }
+//!
+//! let (mut sock1, mut sock2) = UnixStream::pair()?;
+//! # let log = tempfile()?;
+//! // let log: File = ...
+//!
+//! fd_queue::serde::to_writer(&mut sock1, &Rotated { name: "app".into(), log })?;
+//! let rotated: Rotated = fd_queue::serde::from_reader(&mut sock2)?;
+//!
+//! assert_eq!(rotated.name, "app");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    error, fmt,
+    io::{self, prelude::*, ErrorKind},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use serde::{
+    de::{
+        self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+        SeqAccess, VariantAccess, Visitor,
+    },
+    ser::{self, Serialize},
+    Deserialize,
+};
+
+use crate::{DequeueFd, EnqueueFd, UnixStream};
+
+/// The longest message, not counting its length prefix.
+pub const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+// The name of the newtype struct that stands in for an fd. The fd itself is
+// handed between the fd module and the serializer or deserializer of this
+// format through FD_SLOT.
+const FD_TOKEN: &str = "$fd_queue::serde::Fd";
+
+thread_local! {
+    static FD_SLOT: RefCell<Option<OwnedFd>> = const { RefCell::new(None) };
+}
+
+/// An fd that can be a field of a `Serialize` and `Deserialize` type.
+///
+/// This is useful where `#[serde(with = "fd_queue::serde::fd")]` doesn't fit,
+/// such as for an `Option` or `Vec` of fds.
+#[derive(Debug)]
+pub struct Fd(pub OwnedFd);
+
+/// The errors from serializing and deserializing values in this format.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the stream failed.
+    Io(io::Error),
+
+    /// A `Serialize` or `Deserialize` implementation reported an error.
+    Message(String),
+
+    /// The message ended before the value did.
+    Eof,
+
+    /// The message has bytes left over after the value.
+    TrailingBytes,
+
+    /// The message isn't a valid encoding of the value.
+    Invalid(&'static str),
+
+    /// The value needs a serde feature that this format doesn't support.
+    Unsupported(&'static str),
+
+    /// The message refers to an fd index that didn't arrive with it.
+    MissingFd(u32),
+
+    /// The message refers to the same fd index more than once.
+    DuplicateFd(u32),
+
+    /// The given number of fds arrived with the message but it didn't refer to
+    /// them.
+    ExtraFds(usize),
+
+    /// The stream couldn't queue all of the fds of the value.
+    TooManyFds,
+
+    /// The message would be longer than [`MAX_MESSAGE_LEN`].
+    TooLarge,
+
+    /// An fd was serialized or deserialized by a different format.
+    ForeignFormat,
+}
+
+struct Serializer {
+    output: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+// The state of a sequence, tuple, map or struct being serialized.
+struct Compound<'a> {
+    ser: &'a mut Serializer,
+    // the position of the length prefix for sequences and maps
+    len_pos: Option<usize>,
+    count: u64,
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+    // None for the fds that have already been taken by the value
+    fds: Vec<Option<OwnedFd>>,
+}
+
+// The elements of a sequence, tuple, map or struct being deserialized.
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+struct FdVisitor;
+
+/// Serializes and deserializes an fd field in this format.
+///
+/// Use it as `#[serde(with = "fd_queue::serde::fd")]` on a field whose type is
+/// [`AsFd`] and `From<OwnedFd>`. Other serde formats see the field as a unit
+/// newtype struct, and can't deserialize it.
+pub mod fd {
+    use super::*;
+
+    /// Serializes the fd of `value`.
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsFd,
+        S: ser::Serializer,
+    {
+        let fd = value
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(ser::Error::custom)?;
+        FD_SLOT.with(|slot| *slot.borrow_mut() = Some(fd));
+        let result = serializer.serialize_newtype_struct(FD_TOKEN, &());
+        FD_SLOT.with(|slot| slot.borrow_mut().take());
+
+        result
+    }
+
+    /// Deserializes an fd into a `T`.
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: From<OwnedFd>,
+        D: de::Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_newtype_struct(FD_TOKEN, FdVisitor)
+            .map(T::from)
+    }
+}
+
+/// Serializes `value` as a message on `writer`, queuing its fds with the message.
+///
+/// If this fails the fds that are enqueued on `writer` are discarded, including
+/// any that were enqueued before the call.
+pub fn to_writer<W, T>(writer: &mut W, value: &T) -> Result<(), Error>
+where
+    W: Write + EnqueueFd,
+    T: Serialize + ?Sized,
+{
+    let (body, fds) = to_vec(value)?;
+    if fds.len() > UnixStream::FD_QUEUE_SIZE {
+        return Err(Error::TooManyFds);
+    }
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    message.extend_from_slice(&body);
+
+    // The queue only holds the numbers of the fds, so they stay open until the
+    // write has sent them and are taken back off the queue if it doesn't.
+    let result = write_with_fds(writer, &message, &fds);
+    if result.is_err() {
+        writer.clear_enqueued();
+    }
+    drop(fds);
+
+    result
+}
+
+/// Deserializes a `T` from the next message on `reader` and the fds that arrived
+/// with it.
+pub fn from_reader<R, T>(reader: &mut R) -> Result<T, Error>
+where
+    R: Read + DequeueFd,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::TooLarge);
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    // The fds of the message arrived with its first byte, and reading exactly
+    // the message's bytes leaves the fds of the next message on the stream.
+    let mut fds = Vec::new();
+    while let Some(fd) = reader.dequeue() {
+        // Safety: DequeueFd transfers ownership of the dequeued RawFd to the
+        // caller.
+        fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
+    from_slice(&body, fds)
+}
+
+/// Serializes `value` into the bytes of a message (without its length prefix)
+/// and the fds that go with it.
+pub fn to_vec<T>(value: &T) -> Result<(Vec<u8>, Vec<OwnedFd>), Error>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer {
+        output: Vec::new(),
+        fds: Vec::new(),
+    };
+    value.serialize(&mut serializer)?;
+    if serializer.output.len() > MAX_MESSAGE_LEN {
+        return Err(Error::TooLarge);
+    }
+
+    Ok((serializer.output, serializer.fds))
+}
+
+/// Deserializes a `T` from the bytes of a message (without its length prefix)
+/// and the fds that go with it.
+pub fn from_slice<'de, T>(data: &'de [u8], fds: Vec<OwnedFd>) -> Result<T, Error>
+where
+    T: Deserialize<'de>,
+{
+    let mut deserializer = Deserializer {
+        input: data,
+        fds: fds.into_iter().map(Some).collect(),
+    };
+    let value = T::deserialize(&mut deserializer)?;
+
+    if !deserializer.input.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    let extra = deserializer.fds.iter().filter(|fd| fd.is_some()).count();
+    if extra > 0 {
+        return Err(Error::ExtraFds(extra));
+    }
+
+    Ok(value)
+}
+
+// === impl Fd ===
+
+impl Serialize for Fd {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        fd::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fd {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Fd, D::Error> {
+        fd::deserialize(deserializer).map(Fd)
+    }
+}
+
+impl AsFd for Fd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl From<OwnedFd> for Fd {
+    fn from(fd: OwnedFd) -> Fd {
+        Fd(fd)
+    }
+}
+
+impl From<Fd> for OwnedFd {
+    fn from(fd: Fd) -> OwnedFd {
+        fd.0
+    }
+}
+
+// === impl Error ===
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Message(msg) => write!(f, "{}", msg),
+            Error::Eof => write!(f, "message ended before the value"),
+            Error::TrailingBytes => write!(f, "message has bytes after the value"),
+            Error::Invalid(what) => write!(f, "invalid {} in message", what),
+            Error::Unsupported(what) => write!(f, "{} isn't supported", what),
+            Error::MissingFd(index) => write!(f, "fd {} didn't arrive with the message", index),
+            Error::DuplicateFd(index) => write!(f, "fd {} is used more than once", index),
+            Error::ExtraFds(count) => write!(f, "{} fds arrived but weren't used", count),
+            Error::TooManyFds => write!(f, "too many fds in the value"),
+            Error::TooLarge => write!(f, "message too large"),
+            Error::ForeignFormat => write!(f, "fds need the fd_queue serde format"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e {
+            Error::Io(e) => return e,
+            Error::Eof => ErrorKind::UnexpectedEof,
+            Error::Unsupported(_) | Error::TooManyFds | Error::TooLarge | Error::ForeignFormat => {
+                ErrorKind::InvalidInput
+            }
+            _ => ErrorKind::InvalidData,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+// === impl Serializer ===
+
+impl Serializer {
+    fn put(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn put_len(&mut self, len: usize) {
+        self.put(&(len as u64).to_le_bytes());
+    }
+
+    fn compound(&mut self, len: bool) -> Compound<'_> {
+        let len_pos = if len {
+            self.put_len(0);
+            Some(self.output.len() - 8)
+        } else {
+            None
+        };
+
+        Compound {
+            ser: self,
+            len_pos,
+            count: 0,
+        }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.put(&[v as u8]);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.put(&[v]);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.put(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.put_len(v.len());
+        self.put(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.put(&[0]);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.put(&[1]);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        if name != FD_TOKEN {
+            return value.serialize(self);
+        }
+
+        let fd = FD_SLOT
+            .with(|slot| slot.borrow_mut().take())
+            .ok_or(Error::ForeignFormat)?;
+        let index = u32::try_from(self.fds.len()).map_err(|_| Error::TooManyFds)?;
+        self.fds.push(fd);
+        self.serialize_u32(index)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(true))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.put(&variant_index.to_le_bytes());
+        Ok(self.compound(false))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(true))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.put(&variant_index.to_le_bytes());
+        Ok(self.compound(false))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// === impl Compound ===
+
+impl Compound<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some(pos) = self.len_pos {
+            self.ser.output[pos..pos + 8].copy_from_slice(&self.count.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+// === impl Deserializer ===
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < len {
+            return Err(Error::Eof);
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn take_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take_u32(&mut self) -> Result<u32, Error> {
+        self.take_array().map(u32::from_le_bytes)
+    }
+
+    fn take_len(&mut self) -> Result<usize, Error> {
+        let len = self.take_array().map(u64::from_le_bytes)?;
+        usize::try_from(len).map_err(|_| Error::TooLarge)
+    }
+
+    fn take_bytes(&mut self) -> Result<&'de [u8], Error> {
+        let len = self.take_len()?;
+        self.take(len)
+    }
+
+    fn take_str(&mut self) -> Result<&'de str, Error> {
+        std::str::from_utf8(self.take_bytes()?).map_err(|_| Error::Invalid("utf-8 string"))
+    }
+
+    fn take_fd(&mut self) -> Result<OwnedFd, Error> {
+        let index = self.take_u32()?;
+        match self.fds.get_mut(index as usize) {
+            Some(fd) => fd.take().ok_or(Error::DuplicateFd(index)),
+            None => Err(Error::MissingFd(index)),
+        }
+    }
+
+    fn access(&mut self, remaining: usize) -> Access<'_, 'de> {
+        Access {
+            de: self,
+            remaining,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Unsupported("deserialize_any"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.take_u8()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(Error::Invalid("bool")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i8(i8::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i16(i16::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i32(i32::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(i64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i128(i128::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u8(self.take_u8()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u16(u16::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u64(u64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u128(u128::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f32(f32::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f64(f64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let c = char::from_u32(self.take_u32()?).ok_or(Error::Invalid("char"))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.take_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.take_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.take_u8()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(Error::Invalid("option tag")),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if name != FD_TOKEN {
+            return visitor.visit_newtype_struct(self);
+        }
+
+        let fd = self.take_fd()?;
+        FD_SLOT.with(|slot| *slot.borrow_mut() = Some(fd));
+        let result = visitor.visit_unit();
+        FD_SLOT.with(|slot| slot.borrow_mut().take());
+
+        result
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.take_len()?;
+        visitor.visit_seq(self.access(len))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(self.access(len))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_seq(self.access(len))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.take_len()?;
+        visitor.visit_map(self.access(len))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_seq(self.access(fields.len()))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Unsupported("deserialize_identifier"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Unsupported("deserialize_ignored_any"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'de> EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self), Error> {
+        let index = self.take_u32()?;
+        let value = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(self.access(len))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_seq(self.access(fields.len()))
+    }
+}
+
+// === impl Access ===
+
+impl<'de> SeqAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> MapAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        self.next_element_seed(seed)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+// === impl FdVisitor ===
+
+impl<'de> Visitor<'de> for FdVisitor {
+    type Value = OwnedFd;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an fd")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<OwnedFd, E> {
+        FD_SLOT
+            .with(|slot| slot.borrow_mut().take())
+            .ok_or_else(|| E::custom(Error::ForeignFormat))
+    }
+}
+
+// === utility functions ===
+
+fn write_with_fds<W>(writer: &mut W, message: &[u8], fds: &[OwnedFd]) -> Result<(), Error>
+where
+    W: Write + EnqueueFd,
+{
+    for fd in fds {
+        writer.enqueue(fd).map_err(|_| Error::TooManyFds)?;
+    }
+    writer.write_all(message)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    use assert_matches::assert_matches;
+    use serde::{Deserialize, Serialize};
+
+    use crate::test_util::hello_file;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Handles {
+        name: String,
+        #[serde(with = "fd")]
+        first: File,
+        count: u32,
+        rest: Vec<Fd>,
+        maybe: Option<Fd>,
+        kind: Kind,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Plain,
+        Tagged(u8, String),
+    }
+
+    #[derive(Serialize)]
+    struct TwoFds {
+        #[serde(with = "fd")]
+        a: File,
+        #[serde(with = "fd")]
+        b: File,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct OneFd {
+        #[serde(with = "fd")]
+        _a: OwnedFd,
+    }
+
+    fn assert_hello(fd: impl Into<OwnedFd>) {
+        let mut buf = String::new();
+        File::from(fd.into())
+            .read_to_string(&mut buf)
+            .expect("Can't read from file");
+        assert_eq!(buf, "Hello World!");
+    }
+
+    #[test]
+    fn round_trip_keeps_fds_with_their_fields() {
+        let (mut sock1, mut sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let value = Handles {
+            name: "logs".to_owned(),
+            first: hello_file(),
+            count: 3,
+            rest: vec![Fd(hello_file().into()), Fd(hello_file().into())],
+            maybe: None,
+            kind: Kind::Tagged(7, "seven".to_owned()),
+        };
+
+        to_writer(&mut sock1, &value).expect("Can't write value");
+        let sut: Handles = from_reader(&mut sock2).expect("Can't read value");
+
+        assert_eq!(sut.name, "logs");
+        assert_eq!(sut.count, 3);
+        assert_eq!(sut.rest.len(), 2);
+        assert!(sut.maybe.is_none());
+        assert_eq!(sut.kind, Kind::Tagged(7, "seven".to_owned()));
+        assert_hello(sut.first);
+        for fd in sut.rest {
+            assert_hello(fd);
+        }
+    }
+
+    #[test]
+    fn to_writer_with_too_many_fds_queues_none() {
+        let (mut sock1, mut sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let value: Vec<Fd> = (0..=UnixStream::FD_QUEUE_SIZE)
+            .map(|_| Fd(hello_file().into()))
+            .collect();
+
+        let result = to_writer(&mut sock1, &value);
+        to_writer(&mut sock1, &7u32).expect("Can't write value");
+        let sut: u32 = from_reader(&mut sock2).expect("Can't read value");
+
+        assert_matches!(result, Err(Error::TooManyFds));
+        assert_eq!(sut, 7);
+    }
+
+    #[test]
+    fn to_writer_failure_clears_queued_fds() {
+        let (mut sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        drop(sock2);
+        let file = hello_file();
+
+        let result = to_writer(&mut sock1, &Fd(hello_file().into()));
+        for _ in 0..UnixStream::FD_QUEUE_SIZE {
+            sock1.enqueue(&file).expect("Failed write left fds queued");
+        }
+
+        assert_matches!(result, Err(Error::Io(_)));
+    }
+
+    #[test]
+    fn message_with_extra_fds_is_error() {
+        let (data, fds) = to_vec(&TwoFds {
+            a: hello_file(),
+            b: hello_file(),
+        })
+        .expect("Can't serialize value");
+
+        let result = from_slice::<OneFd>(&data[..4], fds);
+
+        assert_matches!(result, Err(Error::ExtraFds(1)));
+    }
+
+    #[test]
+    fn message_with_missing_fd_is_error() {
+        let (data, mut fds) = to_vec(&TwoFds {
+            a: hello_file(),
+            b: hello_file(),
+        })
+        .expect("Can't serialize value");
+        fds.pop();
+
+        let result = from_slice::<(Fd, Fd)>(&data, fds);
+
+        assert_matches!(result, Err(Error::MissingFd(1)));
+    }
+
+    #[test]
+    fn message_with_duplicate_fd_is_error() {
+        let (_, fds) = to_vec(&TwoFds {
+            a: hello_file(),
+            b: hello_file(),
+        })
+        .expect("Can't serialize value");
+        let data = [0, 0, 0, 0, 0, 0, 0, 0];
+
+        let result = from_slice::<(Fd, Fd)>(&data, fds);
+
+        assert_matches!(result, Err(Error::DuplicateFd(0)));
+    }
+}
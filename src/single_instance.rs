@@ -0,0 +1,475 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Run a single instance of an application and forward later invocations to it.
+//!
+//! The first invocation of an application to call [`acquire()`] becomes the
+//! [`Primary`] instance and listens on a socket. Each later invocation becomes a
+//! [`Secondary`] that forwards its arguments, working directory and stdin,
+//! stdout and stderr fds to the primary instance, waits for the primary to handle
+//! them, and exits with the status that the primary sends back (in the style of
+//! `emacsclient`).
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use fd_queue::single_instance::{self, Instance};
+//! use std::io::prelude::*;
+//! use std::process;
+//!
+//! match single_instance::acquire("/run/user/1000/mytool.sock")? {
+//!     Instance::Primary(primary) => loop {
+//!         let mut request = match primary.accept() {
+//!             Ok(request) => request,
+//!             Err(e) => {
+//!                 eprintln!("Skipping request: {}", e);
+//!                 continue;
+//!             }
+//!         };
+//!         let [_, mut stdout, _] = request.take_stdio()?;
+//!         writeln!(stdout, "Handling {:?}", request.args())?;
+//!         drop(stdout);
+//!         request.finish(0)?;
+//!     },
+//!     Instance::Secondary(secondary) => process::exit(secondary.forward_current()?),
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    convert::TryInto,
+    env,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, prelude::*, Error, ErrorKind},
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{EnqueueFd, UnixListener, UnixStream};
+
+/// The largest request, in bytes of arguments and working directory, that a
+/// secondary instance can forward.
+pub const MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// The default time that the primary instance waits for a secondary instance to
+/// send its request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of [`acquire()`].
+#[derive(Debug)]
+pub enum Instance {
+    /// This is the first running instance.
+    Primary(Primary),
+
+    /// Another instance is already running.
+    Secondary(Secondary),
+}
+
+/// The running instance, which handles the requests forwarded by later ones.
+#[derive(Debug)]
+pub struct Primary {
+    listener: UnixListener,
+    timeout: Option<Duration>,
+}
+
+/// A later invocation connected to the running instance.
+#[derive(Debug)]
+pub struct Secondary {
+    stream: UnixStream,
+}
+
+/// An invocation forwarded by a secondary instance.
+#[derive(Debug)]
+pub struct Request {
+    args: Vec<OsString>,
+    cwd: PathBuf,
+    stdio: [OwnedFd; 3],
+    stream: UnixStream,
+}
+
+/// Become the primary instance listening on `path`, or connect to the running
+/// primary instance.
+///
+/// Deciding which instance is first is serialized through an `flock(2)` on the
+/// directory that contains `path`, so two invocations that start at the same
+/// time can't both become primary, and a socket file left behind by a primary
+/// instance that crashed is replaced. No lock file is created, and the primary
+/// instance removes its socket file when it is dropped.
+pub fn acquire(path: impl AsRef<Path>) -> io::Result<Instance> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let lock = File::open(dir)?;
+    let _guard = Flock::lock(&lock)?;
+
+    match UnixStream::connect(path) {
+        Ok(stream) => return Ok(Instance::Secondary(Secondary { stream })),
+        Err(e) if is_not_running(&e) => {}
+        Err(e) => return Err(e),
+    }
+
+    UnixListener::builder()
+        .remove_stale(true)
+        .unlink_on_drop(true)
+        .bind(path)
+        .map(|listener| {
+            Instance::Primary(Primary {
+                listener,
+                timeout: Some(REQUEST_TIMEOUT),
+            })
+        })
+}
+
+// === impl Primary ===
+
+impl Primary {
+    /// Accepts the next request forwarded by a secondary instance.
+    ///
+    /// A secondary instance that doesn't send its whole request within the
+    /// request timeout, or that sends a malformed request, makes this return an
+    /// error. Such an error only affects that one request, so the primary
+    /// instance can skip it and go on accepting.
+    pub fn accept(&self) -> io::Result<Request> {
+        let (stream, _) = self.listener.accept()?;
+
+        Request::recv(stream, self.timeout)
+    }
+
+    /// Sets the time to wait for a secondary instance to send its request.
+    ///
+    /// The default is [`REQUEST_TIMEOUT`]; `None` waits indefinitely.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// The listener that secondary instances connect to.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+}
+
+// === impl Secondary ===
+
+impl Secondary {
+    /// Forwards `args`, `cwd` and the `stdio` fds (stdin, stdout and stderr in
+    /// that order) to the primary instance and returns the exit status that it
+    /// sends back.
+    pub fn forward<A: AsRef<OsStr>>(
+        mut self,
+        args: impl IntoIterator<Item = A>,
+        cwd: &Path,
+        stdio: [BorrowedFd<'_>; 3],
+    ) -> io::Result<i32> {
+        let args: Vec<OsString> = args.into_iter().map(|a| a.as_ref().to_owned()).collect();
+
+        let mut request = Vec::new();
+        put_bytes(&mut request, cwd.as_os_str().as_bytes());
+        request.extend_from_slice(&(args.len() as u32).to_be_bytes());
+        for arg in &args {
+            put_bytes(&mut request, arg.as_bytes());
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "request is too long"));
+        }
+
+        let mut message = (request.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&request);
+        for fd in &stdio {
+            self.stream
+                .enqueue(fd)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        }
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+
+        let mut status = [0; 4];
+        self.stream.read_exact(&mut status)?;
+
+        Ok(i32::from_be_bytes(status))
+    }
+
+    /// Forwards the arguments, working directory and stdio of this process.
+    ///
+    /// The first argument (the program name) is not forwarded.
+    pub fn forward_current(self) -> io::Result<i32> {
+        let cwd = env::current_dir()?;
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let stderr = io::stderr();
+
+        self.forward(
+            env::args_os().skip(1),
+            &cwd,
+            [stdin.as_fd(), stdout.as_fd(), stderr.as_fd()],
+        )
+    }
+}
+
+// === impl Request ===
+
+impl Request {
+    fn recv(mut stream: UnixStream, timeout: Option<Duration>) -> io::Result<Request> {
+        stream.set_read_timeout(timeout)?;
+        let len = read_u32(&mut stream)? as usize;
+        if len > MAX_REQUEST_LEN {
+            return Err(invalid_data("request is too long"));
+        }
+        let mut request = vec![0; len];
+        stream.read_exact(&mut request)?;
+
+        let stdio: [OwnedFd; 3] = stream
+            .take_fds()
+            .try_into()
+            .map_err(|_| invalid_data("request doesn't have 3 stdio fds"))?;
+        stream.set_read_timeout(None)?;
+
+        let mut request = request.as_slice();
+        let cwd = PathBuf::from(OsString::from_vec(take_bytes(&mut request)?));
+        let argc = take_u32(&mut request)?;
+        let args = (0..argc)
+            .map(|_| take_bytes(&mut request).map(OsString::from_vec))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Request {
+            args,
+            cwd,
+            stdio,
+            stream,
+        })
+    }
+
+    /// The arguments forwarded by the secondary instance.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// The working directory of the secondary instance.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// The stdin of the secondary instance.
+    pub fn stdin(&self) -> BorrowedFd<'_> {
+        self.stdio[0].as_fd()
+    }
+
+    /// The stdout of the secondary instance.
+    pub fn stdout(&self) -> BorrowedFd<'_> {
+        self.stdio[1].as_fd()
+    }
+
+    /// The stderr of the secondary instance.
+    pub fn stderr(&self) -> BorrowedFd<'_> {
+        self.stdio[2].as_fd()
+    }
+
+    /// Takes the stdin, stdout and stderr of the secondary instance, leaving
+    /// `/dev/null` in their place.
+    pub fn take_stdio(&mut self) -> io::Result<[File; 3]> {
+        let devnull = [dev_null()?, dev_null()?, dev_null()?];
+
+        Ok(mem::replace(&mut self.stdio, devnull).map(File::from))
+    }
+
+    /// Finishes the request, sending `status` back for the secondary instance
+    /// to exit with.
+    ///
+    /// The primary instance's copies of the secondary's stdio are closed before
+    /// the status is sent so that, for instance, a pipe reading the secondary's
+    /// stdout sees the end of the output. Any stdio taken with `take_stdio()`
+    /// should be dropped before calling this for the same reason.
+    pub fn finish(self, status: i32) -> io::Result<()> {
+        let Request {
+            stdio, mut stream, ..
+        } = self;
+        drop(stdio);
+
+        stream.write_all(&status.to_be_bytes())
+    }
+}
+
+// === impl Flock ===
+
+struct Flock<'a> {
+    file: &'a File,
+}
+
+impl<'a> Flock<'a> {
+    fn lock(file: &'a File) -> io::Result<Flock<'a>> {
+        // SAFETY: flock() has no memory safety requirements.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Flock { file })
+    }
+}
+
+impl Drop for Flock<'_> {
+    fn drop(&mut self) {
+        // SAFETY: flock() has no memory safety requirements.
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+// === utility functions ===
+
+fn is_not_running(e: &Error) -> bool {
+    e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ECONNREFUSED)
+}
+
+fn dev_null() -> io::Result<OwnedFd> {
+    File::open("/dev/null").map(OwnedFd::from)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn read_u32(stream: &mut UnixStream) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn take_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    if buf.len() < 4 {
+        return Err(invalid_data("truncated request"));
+    }
+    let (value, rest) = buf.split_at(4);
+    *buf = rest;
+
+    Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+fn take_bytes(buf: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = take_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(invalid_data("truncated request"));
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+
+    Ok(value.to_vec())
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::SeekFrom;
+    use std::thread;
+
+    use tempfile::{tempdir, tempfile};
+
+    #[test]
+    fn single_instance_forwards_to_primary() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("app.sock");
+        let mut stdout = tempfile().expect("Can't create temp file");
+        let stdin = tempfile().expect("Can't create temp file");
+        let mut output = String::new();
+
+        let primary = match acquire(&path).expect("Can't acquire instance") {
+            Instance::Primary(primary) => primary,
+            Instance::Secondary(_) => panic!("First instance isn't primary"),
+        };
+        let client_path = path.clone();
+        let client_stdout = stdout.try_clone().expect("Can't clone temp file");
+        let client = thread::spawn(move || {
+            let secondary = match acquire(&client_path).expect("Can't acquire instance") {
+                Instance::Secondary(secondary) => secondary,
+                Instance::Primary(_) => panic!("Second instance isn't secondary"),
+            };
+            secondary
+                .forward(
+                    ["open", "notes.txt"],
+                    Path::new("/work"),
+                    [stdin.as_fd(), client_stdout.as_fd(), client_stdout.as_fd()],
+                )
+                .expect("Can't forward request")
+        });
+        let mut request = primary.accept().expect("Can't accept request");
+        let [_, mut out, _] = request.take_stdio().expect("Can't take stdio");
+        out.write_all(b"opened").expect("Can't write to stdout");
+        drop(out);
+        let args = request.args().to_vec();
+        let cwd = request.cwd().to_owned();
+        request.finish(3).expect("Can't finish request");
+        let status = client.join().expect("Client panicked");
+        stdout.seek(SeekFrom::Start(0)).expect("Can't seek");
+        stdout.read_to_string(&mut output).expect("Can't read");
+
+        assert_eq!(status, 3);
+        assert_eq!(
+            args,
+            vec![OsString::from("open"), OsString::from("notes.txt")]
+        );
+        assert_eq!(cwd, Path::new("/work"));
+        assert_eq!(output, "opened");
+    }
+
+    #[test]
+    fn single_instance_replaces_stale_socket() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("app.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).expect("Can't bind"));
+
+        let instance = acquire(&path).expect("Can't acquire instance");
+
+        assert!(matches!(instance, Instance::Primary(_)));
+        drop(instance);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn single_instance_times_out_silent_secondary() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("app.sock");
+        let mut primary = match acquire(&path).expect("Can't acquire instance") {
+            Instance::Primary(primary) => primary,
+            Instance::Secondary(_) => panic!("First instance isn't primary"),
+        };
+        primary.set_request_timeout(Some(Duration::from_millis(50)));
+
+        let _silent = UnixStream::connect(&path).expect("Can't connect to primary");
+        let result = primary.accept();
+
+        assert!(matches!(
+            result.err().map(|e| e.kind()),
+            Some(ErrorKind::WouldBlock) | Some(ErrorKind::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn single_instance_leaves_no_lock_file() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("app.sock");
+
+        let instance = acquire(&path).expect("Can't acquire instance");
+
+        assert!(matches!(instance, Instance::Primary(_)));
+        assert_eq!(fs::read_dir(dir.path()).expect("Can't read dir").count(), 1);
+    }
+}
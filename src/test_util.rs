@@ -0,0 +1,24 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Fixtures shared by the unit tests of the fd passing modules.
+
+use std::fs::File;
+use std::io::{prelude::*, SeekFrom};
+
+use tempfile::tempfile;
+
+/// A temporary file containing "Hello World!", positioned at its start.
+pub fn hello_file() -> File {
+    let mut file = tempfile().expect("Can't create temp file.");
+    file.write_all(b"Hello World!")
+        .expect("Can't write to temp file.");
+    file.seek(SeekFrom::Start(0))
+        .expect("Couldn't seek the file.");
+    file
+}
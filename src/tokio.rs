@@ -13,8 +13,8 @@ use std::{
     io::{ErrorKind, IoSlice, IoSliceMut},
     net::Shutdown,
     os::unix::{
-        io::{AsRawFd, RawFd},
-        net::{SocketAddr, UnixStream as StdUnixStream},
+        io::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+        net::{SocketAddr, UnixListener as StdUnixListener, UnixStream as StdUnixStream},
     },
     path::Path,
     pin::Pin,
@@ -33,7 +33,22 @@ use tokio::{
     },
 };
 
-use crate::{biqueue::BiQueue, DequeueFd, EnqueueFd, QueueFullError};
+use crate::{
+    activation::{self, SocketKind},
+    addr,
+    biqueue::{self, BiQueue, IoSliceUninit},
+    builder::{BoundPath, FromBound, ListenerBuilder},
+    path::ShortPath,
+    process::{self, FromStdStream},
+    DequeueFd, EnqueueFd, QueueFullError,
+};
+
+#[cfg(feature = "codec-fd")]
+pub mod codec;
+
+mod relay;
+
+pub use self::relay::{relay, relay_with_filter};
 
 /// A structure representing a connected Unix socket with support for passing
 /// [`RawFd`].
@@ -134,11 +149,15 @@ pub struct UnixStream {
 #[derive(Debug)]
 pub struct UnixListener {
     inner: TokioUnixListener,
+    path: Option<BoundPath>,
 }
 
 // === impl UnixStream ===
 
 impl UnixStream {
+    /// The size of the bounded queue of outbound [`RawFd`].
+    pub const FD_QUEUE_SIZE: usize = BiQueue::FD_QUEUE_SIZE;
+
     /// Connects to the socket named by path.
     ///
     /// This function will create a new socket and connect the the path specified,
@@ -163,7 +182,88 @@ impl UnixStream {
     /// # });
     /// ```
     pub async fn connect(path: impl AsRef<Path>) -> io::Result<UnixStream> {
-        TokioUnixStream::connect(path).await.map(|s| s.into())
+        let path = ShortPath::new(path.as_ref())?;
+        TokioUnixStream::connect(&path).await.map(|s| s.into())
+    }
+
+    /// Connects to the socket named by `name` relative to the directory `dir`.
+    ///
+    /// An absolute `name` ignores `dir`, as with `openat(2)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tempfile::tempdir;
+    /// # use fd_queue::tokio::UnixListener;
+    /// use fd_queue::tokio::UnixStream;
+    /// use std::fs::File;
+    /// # tokio_test::block_on(async {
+    /// # let tmp = tempdir()?;
+    /// # let dir_path = tmp.path();
+    /// // let dir_path: Path = ...
+    /// let dir = File::open(dir_path)?;
+    /// # let mut listener = UnixListener::bind_at(&dir, "mysock")?;
+    /// # tokio::spawn(async move { listener.accept().await.expect("Can't accept")});
+    ///
+    /// UnixStream::connect_at(&dir, "mysock").await?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub async fn connect_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixStream> {
+        let path = ShortPath::at(dir.as_raw_fd(), name.as_ref())?;
+        TokioUnixStream::connect(&path).await.map(|s| s.into())
+    }
+
+    /// Connects to the socket specified by `addr`.
+    ///
+    /// Unlike `connect()` this can connect to an address in the Linux abstract
+    /// namespace.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use fd_queue::tokio::UnixListener;
+    /// use fd_queue::{abstract_addr, tokio::UnixStream};
+    /// # tokio_test::block_on(async {
+    /// let addr = abstract_addr("fd-queue-tokio-connect-addr")?;
+    /// # let mut listener = UnixListener::bind_addr(&addr)?;
+    /// # tokio::spawn(async move { listener.accept().await.expect("Can't accept")});
+    ///
+    /// UnixStream::connect_addr(&addr).await?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    pub async fn connect_addr(addr: &SocketAddr) -> io::Result<UnixStream> {
+        let stream = TokioUnixStream::from_std(addr::connect_nonblocking(addr)?)?;
+        stream.writable().await?;
+        match stream.take_error()? {
+            Some(e) => Err(e),
+            None => Ok(stream.into()),
+        }
+    }
+
+    /// Takes the end of the channel that the parent process created with
+    /// [`process::Command`][crate::process::Command].
+    ///
+    /// This returns a `NotFound` error if the process was not spawned with a
+    /// channel, or if the channel was already taken.
+    pub fn from_parent() -> io::Result<UnixStream> {
+        UnixStream::try_from(StdUnixStream::from(process::take_parent_channel()?))
+    }
+
+    /// Adopts the connected Unix stream sockets passed to this process through
+    /// systemd socket activation.
+    ///
+    /// Passed fds that are not connected Unix stream sockets are left alone, and
+    /// each fd is only adopted once.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixStream>> {
+        activation::take_listen_fds(SocketKind::Stream)?
+            .into_iter()
+            .map(|fd| UnixStream::try_from(StdUnixStream::from(fd)))
+            .collect()
     }
 
     /// Creates an unnamed pair of connected sockets.
@@ -210,7 +310,7 @@ impl UnixStream {
     /// # });
     /// ```
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        to_addr(self.inner.local_addr()?)
+        self.inner.local_addr().map(to_addr)
     }
 
     /// Returns the socket address of the remote half of this connection.
@@ -237,7 +337,7 @@ impl UnixStream {
     /// # });
     /// ```
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        to_addr(self.inner.peer_addr()?)
+        self.inner.peer_addr().map(to_addr)
     }
 
     /// Returns the value of the SO_ERROR option.
@@ -307,12 +407,147 @@ impl UnixStream {
     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
         shutdown(self, how)
     }
+
+    /// Sends the bytes in `bufs` together with `fds` in a single message.
+    ///
+    /// Unlike enqueuing the fds with [`EnqueueFd`] and then writing, the fds
+    /// are guaranteed to be transmitted with the bytes written by this call.
+    /// At most [`UnixStream::FD_QUEUE_SIZE`] fds can be sent at once. As with
+    /// any write to a stream socket, fewer than all of the bytes in `bufs` may
+    /// be sent; the fds are sent with the first byte that is.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the future is dropped before it
+    /// completes then neither the bytes nor the fds have been sent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tempfile::tempfile;
+    /// use std::{io::IoSlice, os::unix::io::AsFd};
+    /// use fd_queue::tokio::UnixStream;
+    ///
+    /// # tokio_test::block_on(async {
+    /// let (sock1, sock2) = UnixStream::pair()?;
+    /// # let file = tempfile()?;
+    /// // let file: File = ...
+    ///
+    /// sock1.send_with_fds(&[IoSlice::new(b"a")], &[file.as_fd()]).await?;
+    ///
+    /// let mut buf = [0u8; 1];
+    /// let (count, fds) = sock2.recv_with_fds(&mut buf).await?;
+    /// assert_eq!(count, 1);
+    /// assert_eq!(fds.len(), 1);
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    pub async fn send_with_fds(
+        &self,
+        bufs: &[IoSlice<'_>],
+        fds: &[BorrowedFd<'_>],
+    ) -> io::Result<usize> {
+        let fd = self.inner.as_raw_fd();
+
+        loop {
+            self.inner.writable().await?;
+
+            match self
+                .inner
+                .try_io(Interest::WRITABLE, || biqueue::send_with_fds(fd, bufs, fds))
+            {
+                Ok(count) => return Ok(count),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receives bytes into `buf` together with the fds that were sent with them.
+    ///
+    /// The returned fds are the ones that arrived with the bytes read by this
+    /// call. They are not added to the queue used by [`DequeueFd`].
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the future is dropped before it
+    /// completes then no bytes and no fds have been received.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::IoSlice;
+    /// use fd_queue::tokio::UnixStream;
+    ///
+    /// # tokio_test::block_on(async {
+    /// let (sock1, sock2) = UnixStream::pair()?;
+    /// sock1.send_with_fds(&[IoSlice::new(b"hello")], &[]).await?;
+    ///
+    /// let mut buf = [0u8; 5];
+    /// let (count, fds) = sock2.recv_with_fds(&mut buf).await?;
+    ///
+    /// assert_eq!(&buf[..count], b"hello");
+    /// assert!(fds.is_empty());
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    pub async fn recv_with_fds(&self, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        let fd = self.inner.as_raw_fd();
+
+        loop {
+            self.inner.readable().await?;
+
+            match self.inner.try_io(Interest::READABLE, || {
+                let mut fds = Vec::new();
+                biqueue::recv_with_fds(fd, &mut [IoSliceMut::new(buf)], &mut fds)
+                    .map(|count| (count, fds))
+            }) {
+                Ok(result) => return Ok(result),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Takes the fds that have arrived but have not been dequeued yet.
+    pub(crate) fn take_fds(&mut self) -> Vec<OwnedFd> {
+        self.biqueue.take_fds()
+    }
+
+    // Peeks at the waiting bytes, returning how many there are and whether any
+    // fds are waiting.
+    pub(crate) fn poll_peek_fds_waiting(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, bool)>> {
+        let fd = self.inner.as_raw_fd();
+
+        loop {
+            ready!(self.inner.poll_read_ready(cx))?;
+
+            match self
+                .inner
+                .try_io(Interest::READABLE, || biqueue::peek_fds_waiting(fd, buf))
+            {
+                Ok(result) => return Poll::Ready(Ok(result)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
 }
 
 impl EnqueueFd for UnixStream {
     fn enqueue(&mut self, fd: &impl AsRawFd) -> Result<(), QueueFullError> {
         self.biqueue.enqueue(fd)
     }
+
+    fn clear_enqueued(&mut self) {
+        self.biqueue.clear_enqueued()
+    }
 }
 
 impl DequeueFd for UnixStream {
@@ -342,10 +577,15 @@ impl AsyncRead for UnixStream {
             ready!(inner.poll_read_ready(cx))?;
 
             match inner.try_io(Interest::READABLE, || {
-                // TODO: find a way to handle uninitialized memory on buf
-                biqueue.read_vectored(fd, &mut [IoSliceMut::new(buf.initialize_unfilled())])
+                // Safety: recvmsg only writes to the unfilled part of buf and
+                // never de-initializes it.
+                let unfilled = unsafe { buf.unfilled_mut() };
+                biqueue.read_vectored(fd, &mut [IoSliceUninit::new(unfilled)])
             }) {
                 Ok(count) => {
+                    // Safety: recvmsg initialized the first count bytes of the
+                    // unfilled part of buf.
+                    unsafe { buf.assume_init(count) };
                     buf.advance(count);
                     return Poll::Ready(Ok(()));
                 }
@@ -416,6 +656,67 @@ impl TryFrom<StdUnixStream> for UnixStream {
 // === impl UnixListener ===
 
 impl UnixListener {
+    /// Creates a builder for a `UnixListener` that manages its socket file.
+    ///
+    /// See [`ListenerBuilder`] for the available options. The `bind()` function
+    /// of the builder has the same runtime requirements as `UnixListener::bind()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tempfile::tempdir;
+    /// use fd_queue::tokio::UnixListener;
+    ///
+    /// # tokio_test::block_on(async {
+    /// # let dir = tempdir()?;
+    /// # let path = dir.path().join("mysock");
+    /// // let path: Path = ...
+    /// let listener = UnixListener::builder()
+    ///     .remove_stale(true)
+    ///     .unlink_on_drop(true)
+    ///     .bind(&path)?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    pub fn builder() -> ListenerBuilder<UnixListener> {
+        ListenerBuilder::new()
+    }
+
+    /// Keeps the socket file when this listener is dropped.
+    ///
+    /// See [`fd_queue::UnixListener::keep_socket_file()`][crate::UnixListener::keep_socket_file()].
+    pub fn keep_socket_file(&mut self) {
+        if let Some(path) = self.path.as_mut() {
+            path.keep();
+        }
+    }
+
+    /// Adopts the Unix listeners passed to this process through systemd socket
+    /// activation.
+    ///
+    /// The fds are found through the `LISTEN_PID` and `LISTEN_FDS` environment
+    /// variables. Passed fds that are not listening Unix stream sockets are left
+    /// alone, and each fd is only adopted once. These functions have the same runtime
+    /// requirements as `bind()`.
+    pub fn from_listen_fds() -> io::Result<Vec<UnixListener>> {
+        activation::take_listen_fds(SocketKind::Listener)?
+            .into_iter()
+            .map(|fd| UnixListener::try_from(StdUnixListener::from(fd)))
+            .collect()
+    }
+
+    /// Adopts the Unix listener named `name` that was passed to this process
+    /// through systemd socket activation.
+    ///
+    /// The names come from the `LISTEN_FDNAMES` environment variable. This returns
+    /// a `NotFound` error if there is no listening Unix stream socket named `name`
+    /// left to adopt.
+    pub fn from_listen_fd_name(name: &str) -> io::Result<UnixListener> {
+        activation::take_listen_fd_name(SocketKind::Listener, name)
+            .and_then(|fd| UnixListener::try_from(StdUnixListener::from(fd)))
+    }
+
     /// Creates a new UnixListener bound to the specified path.
     ///
     /// This function will bind a UnixListener to the specified path and associate it
@@ -444,7 +745,55 @@ impl UnixListener {
     /// # Ok::<(), std::io::Error>(())
     /// # });
     pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
-        TokioUnixListener::bind(path).map(|l| l.into())
+        TokioUnixListener::bind(ShortPath::new(path.as_ref())?).map(|l| l.into())
+    }
+
+    /// Creates a new `UnixListener` bound to `name` relative to the directory `dir`.
+    ///
+    /// An absolute `name` ignores `dir`, as with `openat(2)`. This function has
+    /// the same runtime requirements as `bind()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tempfile::tempdir;
+    /// use fd_queue::tokio::UnixListener;
+    /// use std::fs::File;
+    ///
+    /// # tokio_test::block_on(async {
+    /// # let tmp = tempdir()?;
+    /// # let dir_path = tmp.path();
+    /// // let dir_path: Path = ...
+    /// let dir = File::open(dir_path)?;
+    /// let listener = UnixListener::bind_at(&dir, "mysock")?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> io::Result<UnixListener> {
+        TokioUnixListener::bind(ShortPath::at(dir.as_raw_fd(), name.as_ref())?).map(|l| l.into())
+    }
+
+    /// Creates a new `UnixListener` bound to the specified address.
+    ///
+    /// Unlike `bind()` this can bind to an address in the Linux abstract
+    /// namespace. This function has the same runtime requirements as `bind()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fd_queue::{abstract_addr, tokio::UnixListener};
+    ///
+    /// # tokio_test::block_on(async {
+    /// let addr = abstract_addr("fd-queue-tokio-bind-addr")?;
+    /// let listener = UnixListener::bind_addr(&addr)?;
+    /// #
+    /// # Ok::<(), std::io::Error>(())
+    /// # });
+    /// ```
+    pub fn bind_addr(addr: &SocketAddr) -> io::Result<UnixListener> {
+        UnixListener::try_from(StdUnixListener::bind_addr(addr)?)
     }
 
     /// Returns the local socket address of this listener.
@@ -471,7 +820,7 @@ impl UnixListener {
     /// # Ok::<(), std::io::Error>(())
     /// # });
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        to_addr(self.inner.local_addr()?)
+        BoundPath::local_addr(&self.path, || self.inner.local_addr().map(to_addr))
     }
 
     /// Returns the value of the `SO_ERROR` option.
@@ -533,13 +882,13 @@ impl UnixListener {
         self.inner
             .accept()
             .await
-            .and_then(|(stream, addr)| to_addr(addr).map(|addr| (stream.into(), addr)))
+            .map(|(stream, addr)| (stream.into(), to_addr(addr)))
     }
 
     fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<(UnixStream, SocketAddr)>> {
-        self.inner.poll_accept(cx).map(|result| {
-            result.and_then(|(stream, addr)| to_addr(addr).map(|addr| (stream.into(), addr)))
-        })
+        self.inner
+            .poll_accept(cx)
+            .map(|result| result.map(|(stream, addr)| (stream.into(), to_addr(addr))))
     }
 }
 
@@ -597,17 +946,39 @@ impl Stream for UnixListener {
 
 impl From<TokioUnixListener> for UnixListener {
     fn from(inner: TokioUnixListener) -> UnixListener {
-        UnixListener { inner }
+        UnixListener { inner, path: None }
     }
 }
 
-// === utility functions ===
+impl TryFrom<StdUnixListener> for UnixListener {
+    type Error = io::Error;
+
+    fn try_from(inner: StdUnixListener) -> Result<Self, Self::Error> {
+        inner.set_nonblocking(true)?;
+        TokioUnixListener::from_std(inner).map(|listener| listener.into())
+    }
+}
 
-fn to_addr(addr: TokioSocketAddr) -> io::Result<SocketAddr> {
-    addr.as_pathname()
-        .map_or(SocketAddr::from_pathname(""), |path| {
-            SocketAddr::from_pathname(path)
+impl FromStdStream for UnixStream {
+    fn from_std_stream(stream: StdUnixStream) -> io::Result<Self> {
+        UnixStream::try_from(stream)
+    }
+}
+
+impl FromBound for UnixListener {
+    fn from_bound(inner: StdUnixListener, path: BoundPath) -> io::Result<Self> {
+        inner.set_nonblocking(true)?;
+        TokioUnixListener::from_std(inner).map(|inner| UnixListener {
+            inner,
+            path: Some(path),
         })
+    }
+}
+
+// === utility functions ===
+
+fn to_addr(addr: TokioSocketAddr) -> SocketAddr {
+    addr.into()
 }
 
 fn shutdown(socket: &impl AsRawFd, how: Shutdown) -> io::Result<()> {
@@ -632,7 +1003,7 @@ mod tests {
 
     use std::fs::File;
     use std::io::{prelude::*, SeekFrom};
-    use std::os::unix::io::FromRawFd as _;
+    use std::os::unix::io::{AsFd as _, FromRawFd as _};
 
     use tempfile::{tempdir, tempfile};
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -686,6 +1057,77 @@ mod tests {
         assert_eq!(&buf2[..], b"Hello World!\0".as_ref());
     }
 
+    #[tokio::test]
+    async fn unix_stream_send_with_fds_passes_fd() {
+        let mut file1 = tempfile().expect("Can't create temp file.");
+        file1
+            .write_all(b"Hello World!\0")
+            .expect("Can't write to temp file.");
+        file1
+            .seek(SeekFrom::Start(0))
+            .expect("Couldn't seek the file.");
+        let mut buf = [0u8; 5];
+
+        let (sut, other) = UnixStream::pair().expect("Can't create UnixStream's");
+        other
+            .send_with_fds(&[IoSlice::new(b"hello")], &[file1.as_fd()])
+            .await
+            .expect("Can't send with fds");
+        let (count, mut fds) = sut
+            .recv_with_fds(&mut buf)
+            .await
+            .expect("Can't recv with fds");
+
+        assert_eq!(&buf[..count], b"hello");
+        assert_eq!(fds.len(), 1);
+        let mut file2 = File::from(fds.remove(0));
+        let mut buf2 = Vec::new();
+        file2.read_to_end(&mut buf2).expect("Can't read from file");
+        assert_eq!(&buf2[..], b"Hello World!\0".as_ref());
+    }
+
+    #[tokio::test]
+    async fn unix_stream_cancelled_recv_with_fds_loses_nothing() {
+        let file1 = tempfile().expect("Can't create temp file.");
+        let mut buf = [0u8; 1];
+
+        let (sut, other) = UnixStream::pair().expect("Can't create UnixStream's");
+        tokio::select! {
+            biased;
+            _ = sut.recv_with_fds(&mut buf) => panic!("recv unexpectedly completed"),
+            _ = async {} => {}
+        }
+        other
+            .send_with_fds(&[IoSlice::new(b"1")], &[file1.as_fd()])
+            .await
+            .expect("Can't send with fds");
+        let (count, fds) = sut
+            .recv_with_fds(&mut buf)
+            .await
+            .expect("Can't recv with fds");
+
+        assert_eq!(count, 1);
+        assert_eq!(fds.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unix_stream_reads_into_uninitialized_buffer() {
+        let mut buf = Vec::with_capacity(1 << 20);
+
+        let (mut sut, mut other) = UnixStream::pair().expect("Can't create UnixStream's");
+        other
+            .write_all(b"Hello World!".as_ref())
+            .await
+            .expect("Can't write to UnixStream");
+        let count = sut
+            .read_buf(&mut buf)
+            .await
+            .expect("Can't read from UnixStream");
+
+        assert_eq!(count, 12);
+        assert_eq!(&buf[..], b"Hello World!");
+    }
+
     #[tokio::test]
     async fn unix_stream_connects_to_listner() {
         let dir = tempdir().expect("Can't create temp dir");
@@ -710,4 +1152,42 @@ mod tests {
 
         assert_eq!(&buf, b"Hello World!");
     }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn unix_listener_reports_abstract_addr() {
+        use crate::{abstract_addr, AddrKind};
+
+        let name = format!("fd-queue-test-{}", std::process::id());
+        let addr = abstract_addr(&name).expect("Can't create abstract address");
+
+        let mut listener = UnixListener::bind_addr(&addr).expect("Can't bind listener");
+        let client = UnixStream::connect_addr(&addr)
+            .await
+            .expect("Can't connect to listener");
+        let (_server, peer) = listener.accept().await.expect("Can't accept on listener");
+
+        let local = listener.local_addr().expect("Can't get local address");
+        let remote = client.peer_addr().expect("Can't get peer address");
+        assert_eq!(AddrKind::from(&local), AddrKind::Abstract(name.as_bytes()));
+        assert_eq!(AddrKind::from(&remote), AddrKind::Abstract(name.as_bytes()));
+        assert_eq!(AddrKind::from(&peer), AddrKind::Unnamed);
+    }
+
+    #[tokio::test]
+    async fn unix_stream_connect_addr_reports_refused_connection() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("socket");
+        let addr = StdUnixListener::bind(&path)
+            .expect("Can't bind listener")
+            .local_addr()
+            .expect("Can't get local address");
+
+        let result = UnixStream::connect_addr(&addr).await;
+
+        assert_eq!(
+            result.expect_err("Connected to a closed listener").kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
 }
@@ -0,0 +1,664 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Frame based transports that carry [`OwnedFd`] along with their frames.
+//!
+//! This is the fd passing counterpart to `tokio_util::codec::Framed`. An
+//! [`FdFramed`] combines an I/O object that supports fd passing (such as
+//! [`UnixStream`][crate::tokio::UnixStream]) with a codec that implements
+//! [`FdEncoder`] and [`FdDecoder`]. Encoding a frame can attach fds to it and
+//! decoding a frame gets the fds that arrived with its bytes.
+//!
+//! The fds for a frame are sent together with the first byte of that frame,
+//! so they have always arrived by the time that the whole frame has. They can
+//! arrive earlier though: a single read from a stream socket can return the
+//! bytes of several writes and the fds of the last one, so the receiving side
+//! can't tell from where the fds arrived which frame they belong to. Instead
+//! the received fds are kept in the order that they arrived and every frame
+//! has to say how many fds it carries so that the decoder can take that many.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Error, ErrorKind},
+    os::unix::io::{FromRawFd, OwnedFd},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use futures_core::stream::Stream;
+use futures_sink::Sink;
+use futures_util::ready;
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
+use tracing::warn;
+
+use crate::{biqueue::BiQueue, DequeueFd, EnqueueFd, QueueFullError};
+
+const INITIAL_CAPACITY: usize = 8 * 1024;
+const BACKPRESSURE_BOUNDARY: usize = INITIAL_CAPACITY;
+
+/// Encodes items into frames of bytes and attached fds.
+pub trait FdEncoder<Item> {
+    /// The type of encoding errors.
+    type Error: From<io::Error>;
+
+    /// Encodes `item` into the bytes in `dst` and the fds in `fds`.
+    ///
+    /// The fds pushed onto `fds` are sent together with the first byte of
+    /// the frame. A frame that has fds must have at least one byte and at most
+    /// [`FD_QUEUE_SIZE`][crate::tokio::UnixStream::FD_QUEUE_SIZE] fds.
+    fn encode(
+        &mut self,
+        item: Item,
+        dst: &mut BytesMut,
+        fds: &mut Vec<OwnedFd>,
+    ) -> Result<(), Self::Error>;
+}
+
+/// Decodes frames of bytes and attached fds into items.
+pub trait FdDecoder {
+    /// The type of decoded frames.
+    type Item;
+
+    /// The type of decoding errors.
+    type Error: From<io::Error>;
+
+    /// Attempts to decode a frame from the bytes in `src` and the fds in `fds`.
+    ///
+    /// `fds` holds the fds that have arrived and that earlier frames did not
+    /// take, in the order that they arrived. Once all of the bytes of a frame
+    /// are in `src` its fds are in `fds`, though `fds` can also hold fds for
+    /// later frames. The decoder takes the number of fds that the frame says it
+    /// carries through [`RecvFds::take`] or [`RecvFds::pop_front`].
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+        fds: &mut RecvFds,
+    ) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Attempts to decode a frame after the underlying I/O object has reached
+    /// end of file.
+    ///
+    /// The default implementation calls [`decode`][FdDecoder::decode] and
+    /// reports an error if bytes remain once no more frames can be decoded.
+    fn decode_eof(
+        &mut self,
+        src: &mut BytesMut,
+        fds: &mut RecvFds,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src, fds)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "bytes remaining on stream").into()),
+        }
+    }
+}
+
+/// The fds that have arrived for an [`FdDecoder`] but have not yet been taken.
+///
+/// The fds are in the order that they arrived, which is the order of the
+/// frames that they were sent with.
+#[derive(Debug, Default)]
+pub struct RecvFds {
+    fds: VecDeque<OwnedFd>,
+}
+
+/// A unified [`Stream`] and [`Sink`] interface to an underlying I/O object
+/// that passes fds along with the frames encoded and decoded by a codec.
+///
+/// # Examples
+///
+/// ```
+/// # use tempfile::tempfile;
+/// use std::os::unix::io::OwnedFd;
+/// use bytes::{Buf, BufMut, BytesMut};
+/// use fd_queue::tokio::{
+///     codec::{FdDecoder, FdEncoder, FdFramed, RecvFds},
+///     UnixStream,
+/// };
+/// use futures_util::{SinkExt, StreamExt};
+///
+/// // A frame is a single byte holding the number of attached fds.
+/// struct FdCount;
+///
+/// impl FdEncoder<Vec<OwnedFd>> for FdCount {
+///     type Error = std::io::Error;
+///
+///     fn encode(
+///         &mut self,
+///         item: Vec<OwnedFd>,
+///         dst: &mut BytesMut,
+///         fds: &mut Vec<OwnedFd>,
+///     ) -> Result<(), Self::Error> {
+///         dst.put_u8(item.len() as u8);
+///         fds.extend(item);
+///         Ok(())
+///     }
+/// }
+///
+/// impl FdDecoder for FdCount {
+///     type Item = Vec<OwnedFd>;
+///     type Error = std::io::Error;
+///
+///     fn decode(
+///         &mut self,
+///         src: &mut BytesMut,
+///         fds: &mut RecvFds,
+///     ) -> Result<Option<Self::Item>, Self::Error> {
+///         if src.is_empty() {
+///             return Ok(None);
+///         }
+///         let count = src.get_u8();
+///         fds.take(count.into()).map(Some)
+///     }
+/// }
+///
+/// # tokio_test::block_on(async {
+/// let (sock1, sock2) = UnixStream::pair()?;
+/// let mut sender = FdFramed::new(sock1, FdCount);
+/// let mut receiver = FdFramed::new(sock2, FdCount);
+/// # let file = tempfile()?;
+/// // let file: File = ...
+///
+/// sender.send(vec![OwnedFd::from(file)]).await?;
+/// let fds = receiver.next().await.expect("Unexpected end of stream")?;
+///
+/// assert_eq!(fds.len(), 1);
+/// #
+/// # Ok::<(), std::io::Error>(())
+/// # });
+/// ```
+#[pin_project]
+pub struct FdFramed<T, C> {
+    #[pin]
+    io: T,
+    codec: C,
+    read_buf: BytesMut,
+    recv_fds: RecvFds,
+    eof: bool,
+    write_buf: BytesMut,
+    // the position in the byte stream of the start of write_buf
+    write_pos: u64,
+    // the fds for each frame in write_buf tagged with the position of the
+    // first byte of that frame
+    send_fds: VecDeque<(u64, Vec<OwnedFd>)>,
+    // true if the fds at the front of send_fds have been enqueued on io but
+    // not yet written
+    send_fds_enqueued: bool,
+}
+
+#[derive(Debug)]
+struct FrameFdsError {
+    count: usize,
+}
+
+// === impl RecvFds ===
+
+impl RecvFds {
+    /// Returns the number of fds that have arrived and not yet been taken.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Returns `true` if there are no fds that have arrived and not yet been
+    /// taken.
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Removes and returns the earliest fd that arrived.
+    pub fn pop_front(&mut self) -> Option<OwnedFd> {
+        self.fds.pop_front()
+    }
+
+    /// Removes and returns the `count` earliest fds that arrived.
+    ///
+    /// This is an `InvalidData` error if fewer than `count` fds have arrived,
+    /// which means that the peer sent a frame without the fds that it says it
+    /// carries.
+    pub fn take(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        if count > self.fds.len() {
+            warn!(
+                source = "FdFramed",
+                event = "decode",
+                condition = "frame fds missing",
+                count,
+                received = self.fds.len()
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "frame carries more fds than were received",
+            ));
+        }
+
+        Ok(self.fds.drain(..count).collect())
+    }
+
+    fn push(&mut self, fd: OwnedFd) {
+        self.fds.push_back(fd);
+    }
+}
+
+// === impl FdFramed ===
+
+impl<T, C> FdFramed<T, C> {
+    /// Creates a new `FdFramed` over `io` using `codec` to encode and decode
+    /// frames.
+    pub fn new(io: T, codec: C) -> FdFramed<T, C> {
+        FdFramed {
+            io,
+            codec,
+            read_buf: BytesMut::with_capacity(INITIAL_CAPACITY),
+            recv_fds: RecvFds::default(),
+            eof: false,
+            write_buf: BytesMut::with_capacity(INITIAL_CAPACITY),
+            write_pos: 0,
+            send_fds: VecDeque::new(),
+            send_fds_enqueued: false,
+        }
+    }
+
+    /// Returns a reference to the underlying I/O object.
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Returns a mutable reference to the underlying I/O object.
+    ///
+    /// Reading from or writing to the I/O object directly will likely corrupt
+    /// the stream of frames.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Returns a reference to the underlying codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Returns a mutable reference to the underlying codec.
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Returns a reference to the bytes that have been read but not yet decoded.
+    pub fn read_buffer(&self) -> &BytesMut {
+        &self.read_buf
+    }
+
+    /// Returns a mutable reference to the fds that have been received but not
+    /// yet taken by the decoder.
+    pub fn recv_fds_mut(&mut self) -> &mut RecvFds {
+        &mut self.recv_fds
+    }
+
+    /// Consumes the `FdFramed`, returning the underlying I/O object.
+    ///
+    /// Any buffered bytes and fds that have not been decoded or written are
+    /// lost.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T, C> Stream for FdFramed<T, C>
+where
+    T: AsyncRead + DequeueFd + Unpin,
+    C: FdDecoder,
+{
+    type Item = Result<C::Item, C::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            let frame = if *this.eof {
+                this.codec.decode_eof(this.read_buf, this.recv_fds)?
+            } else {
+                this.codec.decode(this.read_buf, this.recv_fds)?
+            };
+
+            if let Some(frame) = frame {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if *this.eof {
+                return Poll::Ready(None);
+            }
+
+            this.read_buf.reserve(1);
+            let count = ready!(poll_read_buf(this.io.as_mut(), cx, this.read_buf))?;
+
+            while let Some(fd) = this.io.dequeue() {
+                // Safety: DequeueFd transfers ownership of the dequeued RawFd
+                // to the caller.
+                this.recv_fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+
+            if count == 0 {
+                *this.eof = true;
+            }
+        }
+    }
+}
+
+impl<T, C, I> Sink<I> for FdFramed<T, C>
+where
+    T: AsyncWrite + EnqueueFd + Unpin,
+    C: FdEncoder<I>,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.write_buf.len() >= BACKPRESSURE_BOUNDARY {
+            self.poll_flush_buf(cx).map_err(Into::into)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        let this = self.project();
+        let start = this.write_buf.len();
+        let mut fds = Vec::new();
+
+        this.codec.encode(item, this.write_buf, &mut fds)?;
+
+        if !fds.is_empty() {
+            if fds.len() > BiQueue::FD_QUEUE_SIZE || this.write_buf.len() == start {
+                this.write_buf.truncate(start);
+                return Err(FrameFdsError::new(fds.len()).into());
+            }
+            this.send_fds
+                .push_back((*this.write_pos + start as u64, fds));
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush_buf(cx))?;
+        ready!(self.project().io.poll_flush(cx))?;
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush_buf(cx))?;
+        ready!(self.project().io.poll_shutdown(cx))?;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, C> FdFramed<T, C>
+where
+    T: AsyncWrite + EnqueueFd + Unpin,
+{
+    fn poll_flush_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+
+        while !this.write_buf.is_empty() {
+            // Each write ends at the start of the next frame with fds so that
+            // those fds are sent with the first byte of their frame.
+            let mut end = this.write_buf.len();
+            let mut has_fds = false;
+            for (i, (pos, fds)) in this.send_fds.iter().enumerate() {
+                let offset = (pos - *this.write_pos) as usize;
+                if offset == 0 && i == 0 {
+                    has_fds = true;
+                    if !*this.send_fds_enqueued {
+                        for fd in fds {
+                            this.io
+                                .enqueue(fd)
+                                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+                        }
+                        *this.send_fds_enqueued = true;
+                    }
+                } else {
+                    end = offset;
+                    break;
+                }
+            }
+
+            let count = ready!(this.io.as_mut().poll_write(cx, &this.write_buf[..end]))?;
+            if count == 0 {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write frame to transport",
+                )));
+            }
+
+            if has_fds {
+                // The kernel has its own references to the fds once they are
+                // sent so our copies can be closed.
+                this.send_fds.pop_front();
+                *this.send_fds_enqueued = false;
+            }
+            this.write_buf.advance(count);
+            *this.write_pos += count as u64;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: fmt::Debug, C: fmt::Debug> fmt::Debug for FdFramed<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FdFramed")
+            .field("io", &self.io)
+            .field("codec", &self.codec)
+            .field("recv_fds", &self.recv_fds)
+            .field("send_fds", &self.send_fds.len())
+            .finish()
+    }
+}
+
+// === impl FrameFdsError ===
+
+impl FrameFdsError {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(count: usize) -> Error {
+        if count > BiQueue::FD_QUEUE_SIZE {
+            Error::new(ErrorKind::Other, QueueFullError::new())
+        } else {
+            Error::new(ErrorKind::InvalidInput, FrameFdsError { count })
+        }
+    }
+}
+
+impl fmt::Display for FrameFdsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "An empty frame can't carry {} file descriptors.",
+            self.count
+        )
+    }
+}
+
+impl std::error::Error for FrameFdsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use bytes::BufMut;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{test_util::hello_file, tokio::UnixStream};
+
+    // A frame is a length byte, an fd count byte, and the payload.
+    struct TestCodec;
+
+    impl FdEncoder<(Vec<u8>, Vec<OwnedFd>)> for TestCodec {
+        type Error = io::Error;
+
+        fn encode(
+            &mut self,
+            (bytes, item_fds): (Vec<u8>, Vec<OwnedFd>),
+            dst: &mut BytesMut,
+            fds: &mut Vec<OwnedFd>,
+        ) -> io::Result<()> {
+            dst.put_u8(bytes.len() as u8);
+            dst.put_u8(item_fds.len() as u8);
+            dst.put_slice(&bytes);
+            fds.extend(item_fds);
+            Ok(())
+        }
+    }
+
+    impl FdDecoder for TestCodec {
+        type Item = (Vec<u8>, Vec<OwnedFd>);
+        type Error = io::Error;
+
+        fn decode(
+            &mut self,
+            src: &mut BytesMut,
+            fds: &mut RecvFds,
+        ) -> io::Result<Option<Self::Item>> {
+            match *src.as_ref() {
+                [len, count, ..] if src.len() >= len as usize + 2 => {
+                    let fds = fds.take(count.into())?;
+                    let frame = src.split_to(len as usize + 2);
+                    Ok(Some((frame[2..].to_vec(), fds)))
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fd_framed_keeps_fds_with_their_frames() {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sender = FdFramed::new(sock1, TestCodec);
+        let mut sut = FdFramed::new(sock2, TestCodec);
+
+        sender
+            .feed((b"one".to_vec(), vec![hello_file().into()]))
+            .await
+            .expect("Can't feed frame");
+        sender
+            .feed((b"two".to_vec(), vec![]))
+            .await
+            .expect("Can't feed frame");
+        sender
+            .feed((
+                b"three".to_vec(),
+                vec![hello_file().into(), hello_file().into()],
+            ))
+            .await
+            .expect("Can't feed frame");
+        sender.flush().await.expect("Can't flush frames");
+
+        let mut counts = Vec::new();
+        for _ in 0..3 {
+            let (bytes, fds) = sut
+                .next()
+                .await
+                .expect("Unexpected end of stream")
+                .expect("Can't decode frame");
+            counts.push((bytes, fds.len()));
+            for fd in fds {
+                let mut buf = String::new();
+                File::from(fd)
+                    .read_to_string(&mut buf)
+                    .expect("Can't read from file");
+                assert_eq!(buf, "Hello World!");
+            }
+        }
+
+        assert_eq!(
+            counts,
+            vec![
+                (b"one".to_vec(), 1),
+                (b"two".to_vec(), 0),
+                (b"three".to_vec(), 2)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fd_framed_keeps_fds_with_frames_from_separate_writes() {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sender = FdFramed::new(sock1, TestCodec);
+        let mut sut = FdFramed::new(sock2, TestCodec);
+
+        sender
+            .send((b"plain".to_vec(), vec![]))
+            .await
+            .expect("Can't send frame");
+        sender
+            .send((b"withfd".to_vec(), vec![hello_file().into()]))
+            .await
+            .expect("Can't send frame");
+
+        let (plain, plain_fds) = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode frame");
+        let (withfd, withfd_fds) = sut
+            .next()
+            .await
+            .expect("Unexpected end of stream")
+            .expect("Can't decode frame");
+
+        assert_eq!((plain, plain_fds.len()), (b"plain".to_vec(), 0));
+        assert_eq!((withfd, withfd_fds.len()), (b"withfd".to_vec(), 1));
+    }
+
+    #[tokio::test]
+    async fn fd_framed_frame_without_its_fds_is_error() {
+        let (mut sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sut = FdFramed::new(sock2, TestCodec);
+
+        sock1
+            .write_all(&[3, 1, b'o', b'n', b'e'])
+            .await
+            .expect("Can't write frame");
+        let result = sut.next().await.expect("Unexpected end of stream");
+
+        assert_eq!(
+            result.expect_err("Frame decoded without its fds").kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn fd_framed_empty_frame_with_fds_is_error() {
+        let (sock1, _sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut sut = FdFramed::new(sock1, EmptyCodec);
+
+        let result = sut.send(vec![hello_file().into()]).await;
+
+        assert!(result.is_err());
+    }
+
+    struct EmptyCodec;
+
+    impl FdEncoder<Vec<OwnedFd>> for EmptyCodec {
+        type Error = io::Error;
+
+        fn encode(
+            &mut self,
+            item: Vec<OwnedFd>,
+            _dst: &mut BytesMut,
+            fds: &mut Vec<OwnedFd>,
+        ) -> io::Result<()> {
+            fds.extend(item);
+            Ok(())
+        }
+    }
+}
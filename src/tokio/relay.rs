@@ -0,0 +1,272 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+use std::{
+    future::poll_fn,
+    io::{Error, ErrorKind},
+    os::unix::io::{AsFd, BorrowedFd, OwnedFd},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{relay::Direction, tokio::UnixStream, EnqueueFd};
+
+const BUF_LEN: usize = 1 << 16;
+
+// One direction of a relay.
+#[derive(Debug)]
+struct Transfer {
+    direction: Direction,
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    fds: Vec<OwnedFd>,
+    // The fds that arrived with the byte just after the ones being written.
+    held: Option<Vec<OwnedFd>>,
+    read_done: bool,
+    finished: bool,
+    total: u64,
+}
+
+/// Forwards bytes and fds between `a` and `b` until both reach end of file.
+///
+/// This is the async version of [`fd_queue::relay()`][crate::relay::relay()].
+/// It returns the number of bytes forwarded from `a` to `b` and from `b` to `a`.
+///
+/// # Examples
+///
+/// ```
+/// use fd_queue::tokio::{relay, UnixStream};
+/// use std::net::Shutdown;
+/// use tokio::io::AsyncWriteExt;
+///
+/// # tokio_test::block_on(async {
+/// let (mut client, mut a) = UnixStream::pair()?;
+/// let (mut b, mut server) = UnixStream::pair()?;
+///
+/// client.write_all(b"hello").await?;
+/// client.shutdown(Shutdown::Write)?;
+/// server.shutdown(Shutdown::Write)?;
+/// let (a_to_b, b_to_a) = relay(&mut a, &mut b).await?;
+/// assert_eq!((a_to_b, b_to_a), (5, 0));
+/// #
+/// # Ok::<(), std::io::Error>(())
+/// # });
+/// ```
+pub async fn relay(a: &mut UnixStream, b: &mut UnixStream) -> io::Result<(u64, u64)> {
+    relay_with_filter(a, b, |_, _| true).await
+}
+
+/// Forwards bytes and fds between `a` and `b`, passing each fd through `filter`.
+///
+/// This is the async version of
+/// [`fd_queue::relay_with_filter()`][crate::relay::relay_with_filter()]. An fd is
+/// forwarded only if `filter` returns `true` for it; otherwise it is closed.
+pub async fn relay_with_filter<F>(
+    a: &mut UnixStream,
+    b: &mut UnixStream,
+    mut filter: F,
+) -> io::Result<(u64, u64)>
+where
+    F: FnMut(Direction, BorrowedFd<'_>) -> bool,
+{
+    let mut a_to_b = Transfer::new(Direction::AToB);
+    let mut b_to_a = Transfer::new(Direction::BToA);
+
+    poll_fn(|cx| {
+        let a_done = a_to_b.poll_transfer(cx, a, b, &mut filter)?.is_ready();
+        let b_done = b_to_a.poll_transfer(cx, b, a, &mut filter)?.is_ready();
+
+        if a_done && b_done {
+            Poll::Ready(Ok((a_to_b.total, b_to_a.total)))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// === impl Transfer ===
+
+impl Transfer {
+    fn new(direction: Direction) -> Transfer {
+        Transfer {
+            direction,
+            buf: vec![0; BUF_LEN].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            fds: Vec::new(),
+            held: None,
+            read_done: false,
+            finished: false,
+            total: 0,
+        }
+    }
+
+    fn poll_transfer<F>(
+        &mut self,
+        cx: &mut Context,
+        src: &mut UnixStream,
+        dst: &mut UnixStream,
+        filter: &mut F,
+    ) -> Poll<io::Result<()>>
+    where
+        F: FnMut(Direction, BorrowedFd<'_>) -> bool,
+    {
+        while !self.finished {
+            if self.pos < self.len {
+                let len =
+                    ready!(Pin::new(&mut *dst).poll_write(cx, &self.buf[self.pos..self.len]))?;
+                if len == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.pos += len;
+                self.total += len as u64;
+                // The fds were sent with the first byte written.
+                self.fds.clear();
+            } else if let Some(fds) = self.held.take() {
+                self.buf[0] = self.buf[self.len];
+                self.pos = 0;
+                self.len = 1;
+
+                for fd in fds {
+                    if filter(self.direction, fd.as_fd()) {
+                        dst.enqueue(&fd)
+                            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+                        self.fds.push(fd);
+                    }
+                }
+            } else if self.read_done {
+                ready!(Pin::new(&mut *dst).poll_shutdown(cx))?;
+                self.finished = true;
+            } else {
+                let (len, fds_waiting) = ready!(src.poll_peek_fds_waiting(cx, &mut self.buf))?;
+                if len == 0 {
+                    self.read_done = true;
+                    continue;
+                }
+                self.pos = 0;
+
+                if !fds_waiting {
+                    let mut buf = ReadBuf::new(&mut self.buf[..len]);
+                    ready!(Pin::new(&mut *src).poll_read(cx, &mut buf))?;
+                    self.len = buf.filled().len();
+                    continue;
+                }
+
+                // A read returns fds together with any bytes queued ahead of
+                // the ones that they were sent with, so while fds are waiting
+                // read a byte at a time until they arrive. The byte that they
+                // arrive with is held back until the bytes before it are sent.
+                let mut count = 0;
+                while count < len {
+                    let mut buf = ReadBuf::new(&mut self.buf[count..count + 1]);
+                    match Pin::new(&mut *src).poll_read(cx, &mut buf)? {
+                        Poll::Ready(()) if buf.filled().is_empty() => break,
+                        Poll::Ready(()) => count += 1,
+                        Poll::Pending if count == 0 => return Poll::Pending,
+                        Poll::Pending => break,
+                    }
+
+                    let fds = src.take_fds();
+                    if !fds.is_empty() {
+                        count -= 1;
+                        self.held = Some(fds);
+                        break;
+                    }
+                }
+                self.len = count;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::os::unix::io::FromRawFd;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{test_util::hello_file, DequeueFd};
+
+    #[tokio::test]
+    async fn relay_forwards_bytes_and_filtered_fds() {
+        let (mut client, mut a) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mut b, mut server) = UnixStream::pair().expect("Can't create UnixStream's");
+        let relay = tokio::spawn(async move {
+            relay_with_filter(&mut a, &mut b, |direction, _| direction == Direction::AToB).await
+        });
+
+        let file = hello_file();
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client.write_all(b"request").await.expect("Can't write");
+        let mut request = [0; 7];
+        server.read_exact(&mut request).await.expect("Can't read");
+        let fd = server.dequeue().expect("No fd forwarded");
+
+        server.enqueue(&file).expect("Can't enqueue fd");
+        server.write_all(b"reply").await.expect("Can't write");
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.expect("Can't read");
+        let vetoed = client.dequeue();
+
+        drop((client, server));
+        let counts = relay.await.expect("Relay panicked").expect("Relay failed");
+
+        // SAFETY: the dequeued fd is owned by the test.
+        let mut forwarded = unsafe { File::from_raw_fd(fd) };
+        let mut buf = String::new();
+        forwarded
+            .read_to_string(&mut buf)
+            .expect("Can't read from file");
+        assert_eq!(&request, b"request");
+        assert_eq!(buf, "Hello World!");
+        assert_eq!(&reply, b"reply");
+        assert!(vetoed.is_none());
+        assert_eq!(counts, (7, 5));
+    }
+
+    #[tokio::test]
+    async fn relay_keeps_fds_with_their_bytes_when_both_are_waiting() {
+        let (mut client, mut a) = UnixStream::pair().expect("Can't create UnixStream's");
+        let (mut b, mut server) = UnixStream::pair().expect("Can't create UnixStream's");
+
+        // Both writes are waiting before the relay starts so that a single read
+        // would return them together.
+        let file = hello_file();
+        client.write_all(b"plain").await.expect("Can't write");
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client.write_all(b"withfd").await.expect("Can't write");
+        drop(client);
+        let relay = tokio::spawn(async move { relay(&mut a, &mut b).await });
+
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.expect("Can't read");
+        let plain_fd = server.dequeue();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.expect("Can't read");
+        let fd = server.dequeue();
+        drop(server);
+        relay.await.expect("Relay panicked").expect("Relay failed");
+
+        assert_eq!(&buf, b"plain");
+        assert!(plain_fd.is_none());
+        assert_eq!(rest, b"withfd");
+        // SAFETY: the dequeued fd is owned by the test.
+        drop(unsafe { File::from_raw_fd(fd.expect("No fd with its bytes")) });
+    }
+}
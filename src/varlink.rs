@@ -0,0 +1,1332 @@
+// Copyright 2022 Steven Bosnick
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE-2.0 or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms
+
+//! Varlink clients and services whose calls and replies can carry fds.
+//!
+//! [Varlink] messages are JSON objects, each terminated by a NUL byte, sent over
+//! a [`UnixStream`]. The fds of a message are sent with its bytes, and each
+//! [`Call`] and [`Reply`] gets back the fds that arrived with the bytes of its
+//! message. As with systemd's varlink services, the parameters of a message
+//! refer to its fds by their index in [`Call::fds`] or [`Reply::fds`], so the
+//! messages themselves are plain varlink messages.
+//!
+//! A [`Client`] makes calls that get a single reply, calls that get a sequence
+//! of replies (`more`), calls that get no reply (`oneway`) and calls that hand
+//! the connection over to another protocol (`upgrade`). A [`Service`] accepts
+//! connections and gives each as a [`Connection`] that yields the calls of its
+//! client in order. A `Connection` answers the calls to the
+//! `org.varlink.service` interface itself, and answers calls to interfaces that
+//! haven't been added to its [`ServiceInfo`] with an `InterfaceNotFound` error.
+//!
+//! [Varlink]: https://varlink.org
+//!
+//! # Examples
+//!
+//! ```
+//! use fd_queue::tokio::{UnixListener, UnixStream};
+//! use fd_queue::varlink::{Call, Client, Reply, Service, ServiceInfo, VarlinkError};
+//! use serde_json::json;
+//! # use tempfile::{tempdir, tempfile};
+//! # tokio_test::block_on(async {
+//! # let dir = tempdir()?;
+//! # let path = dir.path().join("varlink.sock");
+//!
+//! // In the service.
+//! let mut info = ServiceInfo::new("Example", "Logs", "1", "https://example.org");
+//! info.add_interface("org.example.logs", "interface org.example.logs\n...");
+//! let mut service = Service::new(UnixListener::bind(&path)?, info);
+//! tokio::spawn(async move {
+//!     let mut connection = service.accept().await?;
+//!     while let Some(call) = connection.recv().await? {
+//!         match call.method() {
+//!             "org.example.logs.Open" => {
+//! #               let log = tempfile()?;
+//!                 // let log: File = ...
+//!                 connection.reply(Reply::new(json!({})).with_fd(log)).await?;
+//!             }
+//!             method => connection.fail(VarlinkError::method_not_found(method)).await?,
+//!         }
+//!     }
+//!     Ok::<(), std::io::Error>(())
+//! });
+//!
+//! // In the client.
+//! let mut client = Client::connect(&path).await?;
+//! let mut reply = client.call(Call::new("org.example.logs.Open", json!({}))).await?;
+//! let log = reply.take_fds().pop();
+//! # assert!(log.is_some());
+//! # Ok::<(), std::io::Error>(())
+//! # });
+//! ```
+
+use std::{
+    error, fmt,
+    io::{self, Error, ErrorKind},
+    mem,
+    os::unix::io::{AsRawFd, OwnedFd, RawFd},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, BytesMut};
+use futures_util::{ready, SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    tokio::{
+        codec::{FdDecoder, FdEncoder, FdFramed, RecvFds},
+        UnixListener, UnixStream,
+    },
+    DequeueFd, EnqueueFd, QueueFullError,
+};
+
+/// The longest message, not counting its terminating NUL.
+pub const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+const SERVICE_INTERFACE: &str = "org.varlink.service";
+
+const SERVICE_DESCRIPTION: &str = "\
+# The Varlink Service Interface is provided by every varlink service. It
+# describes the service and the interfaces it implements.
+interface org.varlink.service
+
+# Get a list of all the interfaces a service provides and information
+# about the implementation.
+method GetInfo() -> (
+  vendor: string,
+  product: string,
+  version: string,
+  url: string,
+  interfaces: []string
+)
+
+# Get the description of an interface that is implemented by this service.
+method GetInterfaceDescription(interface: string) -> (description: string)
+
+# The requested interface was not found.
+error InterfaceNotFound (interface: string)
+
+# The requested method was not found
+error MethodNotFound (method: string)
+
+# The interface defines the requested method, but the service does not
+# implement it.
+error MethodNotImplemented (method: string)
+
+# One of the passed parameters is invalid.
+error InvalidParameter (parameter: string)
+";
+
+/// A connection to a varlink service.
+#[derive(Debug)]
+pub struct Client {
+    framed: FdFramed<VarlinkStream, VarlinkCodec>,
+    // true while the replies to a `more` call haven't all been received
+    unfinished: bool,
+}
+
+/// The replies to a call made with [`Client::call_more`].
+#[derive(Debug)]
+pub struct Replies<'a> {
+    client: &'a mut Client,
+}
+
+/// A varlink service that accepts connections from clients.
+#[derive(Debug)]
+pub struct Service {
+    listener: UnixListener,
+    info: Arc<ServiceInfo>,
+}
+
+/// The service end of a connection from a varlink client.
+///
+/// Every call that [`recv`][Connection::recv] returns has to be answered (with
+/// [`reply`][Connection::reply], [`fail`][Connection::fail] or
+/// [`upgrade`][Connection::upgrade]) before the next call is received.
+#[derive(Debug)]
+pub struct Connection {
+    framed: FdFramed<VarlinkStream, VarlinkCodec>,
+    info: Arc<ServiceInfo>,
+    // the call that is waiting for an answer
+    pending: Option<Pending>,
+}
+
+/// The description of a service that is returned by
+/// `org.varlink.service.GetInfo`.
+#[derive(Debug, Clone)]
+pub struct ServiceInfo {
+    vendor: String,
+    product: String,
+    version: String,
+    url: String,
+    // the names and descriptions of the service's interfaces
+    interfaces: Vec<(String, String)>,
+}
+
+/// A method call and the fds attached to it.
+#[derive(Debug)]
+pub struct Call {
+    method: String,
+    parameters: Value,
+    fds: Vec<OwnedFd>,
+    more: bool,
+    oneway: bool,
+    upgrade: bool,
+}
+
+/// A reply to a method call and the fds attached to it.
+#[derive(Debug)]
+pub struct Reply {
+    parameters: Value,
+    fds: Vec<OwnedFd>,
+    continues: bool,
+}
+
+/// A connection that has been handed over to another protocol.
+#[derive(Debug)]
+pub struct Upgraded {
+    /// The stream of the connection.
+    pub stream: UnixStream,
+
+    /// The bytes of the other protocol that were read from the stream along
+    /// with the varlink messages.
+    pub buffered: Vec<u8>,
+
+    /// The fds of the other protocol that were received from the stream along
+    /// with the varlink messages, in the order that they arrived.
+    pub fds: Vec<OwnedFd>,
+}
+
+/// An error reply to a method call.
+///
+/// The functions of [`Client`] return these as the inner error of an `io::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarlinkError {
+    name: String,
+    parameters: Value,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    more: bool,
+    upgrade: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CallMessage {
+    method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    parameters: Value,
+    #[serde(default, skip_serializing_if = "is_false")]
+    more: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    oneway: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    upgrade: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReplyMessage {
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    parameters: Value,
+    #[serde(default, skip_serializing_if = "is_false")]
+    continues: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+struct VarlinkCodec;
+
+// The stream of a varlink connection. While fds are waiting it reads a byte at
+// a time, so a read that returns fds returns only the byte that they arrived
+// with.
+#[derive(Debug)]
+struct VarlinkStream(UnixStream);
+
+// === impl Client ===
+
+impl Client {
+    /// Connects to the varlink service listening at `path`.
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Client> {
+        UnixStream::connect(path).await.map(Client::from)
+    }
+
+    /// Makes `call` and waits for its reply.
+    pub async fn call(&mut self, call: Call) -> io::Result<Reply> {
+        self.send(call, false, false, false).await?;
+
+        let reply = self.recv().await?;
+        if reply.continues {
+            return Err(invalid_data("varlink reply continues a single call"));
+        }
+
+        Ok(reply)
+    }
+
+    /// Makes `call`, asking the service for a sequence of replies.
+    ///
+    /// Any replies that are left when the returned [`Replies`] is dropped are
+    /// discarded before the next call.
+    pub async fn call_more(&mut self, call: Call) -> io::Result<Replies<'_>> {
+        self.send(call, true, false, false).await?;
+        self.unfinished = true;
+
+        Ok(Replies { client: self })
+    }
+
+    /// Makes `call` without waiting for, or getting, a reply.
+    pub async fn call_oneway(&mut self, call: Call) -> io::Result<()> {
+        self.send(call, false, true, false).await
+    }
+
+    /// Makes `call`, asking the service to hand the connection over to another
+    /// protocol once it has replied.
+    pub async fn upgrade(mut self, call: Call) -> io::Result<(Reply, Upgraded)> {
+        self.send(call, false, false, true).await?;
+        let reply = self.recv().await?;
+
+        Ok((reply, upgraded(self.framed)))
+    }
+
+    async fn send(
+        &mut self,
+        call: Call,
+        more: bool,
+        oneway: bool,
+        upgrade: bool,
+    ) -> io::Result<()> {
+        self.drain().await?;
+
+        let message = CallMessage {
+            method: call.method,
+            parameters: check_parameters(call.parameters)?,
+            more,
+            oneway,
+            upgrade,
+        };
+        send_message(&mut self.framed, &message, call.fds).await
+    }
+
+    async fn drain(&mut self) -> io::Result<()> {
+        while self.unfinished {
+            match self.recv().await {
+                Ok(reply) => self.unfinished = reply.continues,
+                Err(e) if is_varlink_error(&e) => self.unfinished = false,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> io::Result<Reply> {
+        let (message, fds) = recv_message::<ReplyMessage>(&mut self.framed)
+            .await?
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "varlink service closed the connection",
+                )
+            })?;
+        let parameters = received_parameters(message.parameters)?;
+
+        match message.error {
+            Some(name) => Err(VarlinkError { name, parameters }.into()),
+            None => Ok(Reply {
+                parameters,
+                fds,
+                continues: message.continues,
+            }),
+        }
+    }
+}
+
+impl From<UnixStream> for Client {
+    fn from(stream: UnixStream) -> Client {
+        Client {
+            framed: FdFramed::new(VarlinkStream(stream), VarlinkCodec),
+            unfinished: false,
+        }
+    }
+}
+
+// === impl Replies ===
+
+impl Replies<'_> {
+    /// Receives the next reply.
+    ///
+    /// Returns `None` once the service has sent its last reply. An error reply
+    /// is the last reply.
+    pub async fn recv(&mut self) -> Option<io::Result<Reply>> {
+        if !self.client.unfinished {
+            return None;
+        }
+
+        let result = self.client.recv().await;
+        self.client.unfinished = matches!(&result, Ok(reply) if reply.continues);
+
+        Some(result)
+    }
+}
+
+// === impl Service ===
+
+impl Service {
+    /// Creates a service that accepts connections on `listener` and describes
+    /// itself with `info`.
+    pub fn new(listener: UnixListener, info: ServiceInfo) -> Service {
+        Service {
+            listener,
+            info: Arc::new(info),
+        }
+    }
+
+    /// Accepts the next connection from a client.
+    pub async fn accept(&mut self) -> io::Result<Connection> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(self.connection(stream))
+    }
+
+    /// Serves a client that is connected through `stream`.
+    pub fn connection(&self, stream: UnixStream) -> Connection {
+        Connection {
+            framed: FdFramed::new(VarlinkStream(stream), VarlinkCodec),
+            info: Arc::clone(&self.info),
+            pending: None,
+        }
+    }
+
+    /// Gets the listener that the service accepts connections on.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Gets the description of the service.
+    pub fn info(&self) -> &ServiceInfo {
+        &self.info
+    }
+}
+
+// === impl Connection ===
+
+impl Connection {
+    /// Receives the next call from the client.
+    ///
+    /// Returns `None` once the client closes the connection.
+    pub async fn recv(&mut self) -> io::Result<Option<Call>> {
+        if self.pending.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the previous varlink call hasn't been answered",
+            ));
+        }
+
+        loop {
+            let (message, fds) = match recv_message::<CallMessage>(&mut self.framed).await? {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            let call = Call {
+                method: message.method,
+                parameters: received_parameters(message.parameters)?,
+                fds,
+                more: message.more,
+                oneway: message.oneway,
+                upgrade: message.upgrade,
+            };
+            if !call.oneway {
+                self.pending = Some(Pending {
+                    more: call.more,
+                    upgrade: call.upgrade,
+                });
+            }
+
+            match self.answer(&call) {
+                Some(Ok(reply)) => self.reply(reply).await?,
+                Some(Err(e)) => self.fail(e).await?,
+                None => return Ok(Some(call)),
+            }
+        }
+    }
+
+    /// Answers the current call with its last (or only) reply.
+    ///
+    /// This does nothing if the call is `oneway`.
+    pub async fn reply(&mut self, reply: Reply) -> io::Result<()> {
+        match self.pending.take() {
+            Some(_) => self.send(reply, false, None).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Answers the current call with a reply that will be followed by more
+    /// replies.
+    ///
+    /// This is an error if the client didn't ask for more replies, and does
+    /// nothing if the call is `oneway`.
+    pub async fn reply_more(&mut self, reply: Reply) -> io::Result<()> {
+        match self.pending {
+            Some(Pending { more: true, .. }) => self.send(reply, true, None).await,
+            Some(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "varlink call didn't ask for more replies",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Answers the current call with `error`.
+    ///
+    /// This does nothing if the call is `oneway`.
+    pub async fn fail(&mut self, error: VarlinkError) -> io::Result<()> {
+        match self.pending.take() {
+            Some(_) => {
+                let reply = Reply::new(error.parameters);
+                self.send(reply, false, Some(error.name)).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Answers the current call with `reply` and hands the connection over to
+    /// another protocol.
+    ///
+    /// This is an error if the client didn't ask for an upgrade.
+    pub async fn upgrade(mut self, reply: Reply) -> io::Result<Upgraded> {
+        match self.pending.take() {
+            Some(Pending { upgrade: true, .. }) => self.send(reply, false, None).await?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "varlink call didn't ask for an upgrade",
+                ))
+            }
+        }
+
+        Ok(upgraded(self.framed))
+    }
+
+    async fn send(
+        &mut self,
+        reply: Reply,
+        continues: bool,
+        error: Option<String>,
+    ) -> io::Result<()> {
+        let message = ReplyMessage {
+            parameters: check_parameters(reply.parameters)?,
+            continues,
+            error,
+        };
+        send_message(&mut self.framed, &message, reply.fds).await
+    }
+
+    // Answers the calls that the connection handles itself.
+    fn answer(&self, call: &Call) -> Option<Result<Reply, VarlinkError>> {
+        let interface = call
+            .method
+            .rsplit_once('.')
+            .map_or("", |(interface, _)| interface);
+
+        match call.method.as_str() {
+            "org.varlink.service.GetInfo" => Some(Ok(Reply::new(self.info.to_json()))),
+            "org.varlink.service.GetInterfaceDescription" => {
+                let answer = match call.parameters["interface"].as_str() {
+                    Some(name) => match self.info.description(name) {
+                        Some(description) => Ok(Reply::new(json!({ "description": description }))),
+                        None => Err(VarlinkError::interface_not_found(name)),
+                    },
+                    None => Err(VarlinkError::invalid_parameter("interface")),
+                };
+                Some(answer)
+            }
+            method if interface == SERVICE_INTERFACE => {
+                Some(Err(VarlinkError::method_not_found(method)))
+            }
+            _ if !self.info.has_interface(interface) => {
+                Some(Err(VarlinkError::interface_not_found(interface)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// === impl ServiceInfo ===
+
+impl ServiceInfo {
+    /// Creates the description of a service with no interfaces of its own.
+    pub fn new(
+        vendor: impl Into<String>,
+        product: impl Into<String>,
+        version: impl Into<String>,
+        url: impl Into<String>,
+    ) -> ServiceInfo {
+        ServiceInfo {
+            vendor: vendor.into(),
+            product: product.into(),
+            version: version.into(),
+            url: url.into(),
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// Adds the interface `name`, described by the varlink interface definition
+    /// `description`, to the service.
+    pub fn add_interface(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+    ) -> &mut Self {
+        self.interfaces.push((name.into(), description.into()));
+        self
+    }
+
+    fn has_interface(&self, name: &str) -> bool {
+        self.interfaces
+            .iter()
+            .any(|(interface, _)| interface == name)
+    }
+
+    fn description(&self, name: &str) -> Option<&str> {
+        if name == SERVICE_INTERFACE {
+            return Some(SERVICE_DESCRIPTION);
+        }
+        self.interfaces
+            .iter()
+            .find(|(interface, _)| interface == name)
+            .map(|(_, description)| description.as_str())
+    }
+
+    fn to_json(&self) -> Value {
+        let interfaces: Vec<_> = std::iter::once(SERVICE_INTERFACE)
+            .chain(self.interfaces.iter().map(|(name, _)| name.as_str()))
+            .collect();
+
+        json!({
+            "vendor": self.vendor,
+            "product": self.product,
+            "version": self.version,
+            "url": self.url,
+            "interfaces": interfaces,
+        })
+    }
+}
+
+// === impl Call ===
+
+impl Call {
+    /// Creates a call of `method` with `parameters`, which must be a JSON object.
+    pub fn new(method: impl Into<String>, parameters: Value) -> Call {
+        Call {
+            method: method.into(),
+            parameters,
+            fds: Vec::new(),
+            more: false,
+            oneway: false,
+            upgrade: false,
+        }
+    }
+
+    /// Attaches `fd` to this call.
+    pub fn with_fd(mut self, fd: impl Into<OwnedFd>) -> Call {
+        self.fds.push(fd.into());
+        self
+    }
+
+    /// Gets the fully qualified name of the method.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Gets the parameters.
+    pub fn parameters(&self) -> &Value {
+        &self.parameters
+    }
+
+    /// Gets the fds attached to this call.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Takes the fds attached to this call.
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        mem::take(&mut self.fds)
+    }
+
+    /// Returns `true` if the client asked for a sequence of replies.
+    pub fn is_more(&self) -> bool {
+        self.more
+    }
+
+    /// Returns `true` if the client asked for no reply.
+    pub fn is_oneway(&self) -> bool {
+        self.oneway
+    }
+
+    /// Returns `true` if the client asked to hand the connection over to another
+    /// protocol.
+    pub fn is_upgrade(&self) -> bool {
+        self.upgrade
+    }
+}
+
+// === impl Reply ===
+
+impl Reply {
+    /// Creates a reply with `parameters`, which must be a JSON object.
+    pub fn new(parameters: Value) -> Reply {
+        Reply {
+            parameters,
+            fds: Vec::new(),
+            continues: false,
+        }
+    }
+
+    /// Attaches `fd` to this reply.
+    pub fn with_fd(mut self, fd: impl Into<OwnedFd>) -> Reply {
+        self.fds.push(fd.into());
+        self
+    }
+
+    /// Gets the parameters.
+    pub fn parameters(&self) -> &Value {
+        &self.parameters
+    }
+
+    /// Gets the fds attached to this reply.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Takes the fds attached to this reply.
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        mem::take(&mut self.fds)
+    }
+
+    /// Returns `true` if more replies to the call follow this one.
+    pub fn continues(&self) -> bool {
+        self.continues
+    }
+}
+
+// === impl VarlinkError ===
+
+impl VarlinkError {
+    /// Creates the error `name` with `parameters`, which must be a JSON object.
+    pub fn new(name: impl Into<String>, parameters: Value) -> VarlinkError {
+        VarlinkError {
+            name: name.into(),
+            parameters,
+        }
+    }
+
+    /// Creates an `org.varlink.service.InterfaceNotFound` error.
+    pub fn interface_not_found(interface: &str) -> VarlinkError {
+        VarlinkError::new(
+            "org.varlink.service.InterfaceNotFound",
+            json!({ "interface": interface }),
+        )
+    }
+
+    /// Creates an `org.varlink.service.MethodNotFound` error.
+    pub fn method_not_found(method: &str) -> VarlinkError {
+        VarlinkError::new(
+            "org.varlink.service.MethodNotFound",
+            json!({ "method": method }),
+        )
+    }
+
+    /// Creates an `org.varlink.service.MethodNotImplemented` error.
+    pub fn method_not_implemented(method: &str) -> VarlinkError {
+        VarlinkError::new(
+            "org.varlink.service.MethodNotImplemented",
+            json!({ "method": method }),
+        )
+    }
+
+    /// Creates an `org.varlink.service.InvalidParameter` error.
+    pub fn invalid_parameter(parameter: &str) -> VarlinkError {
+        VarlinkError::new(
+            "org.varlink.service.InvalidParameter",
+            json!({ "parameter": parameter }),
+        )
+    }
+
+    /// Gets the fully qualified name of the error.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the parameters of the error.
+    pub fn parameters(&self) -> &Value {
+        &self.parameters
+    }
+}
+
+impl fmt::Display for VarlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "varlink error {} {}", self.name, self.parameters)
+    }
+}
+
+impl error::Error for VarlinkError {}
+
+impl From<VarlinkError> for Error {
+    fn from(e: VarlinkError) -> Error {
+        Error::new(ErrorKind::Other, e)
+    }
+}
+
+// === impl VarlinkCodec ===
+
+impl FdEncoder<(Vec<u8>, Vec<OwnedFd>)> for VarlinkCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        (message, message_fds): (Vec<u8>, Vec<OwnedFd>),
+        dst: &mut BytesMut,
+        fds: &mut Vec<OwnedFd>,
+    ) -> io::Result<()> {
+        dst.reserve(message.len() + 1);
+        dst.put_slice(&message);
+        dst.put_u8(0);
+        fds.extend(message_fds);
+
+        Ok(())
+    }
+}
+
+impl FdDecoder for VarlinkCodec {
+    type Item = (Value, Vec<OwnedFd>);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut, fds: &mut RecvFds) -> io::Result<Option<Self::Item>> {
+        match src.iter().position(|&b| b == 0) {
+            Some(len) => {
+                let message = src.split_to(len + 1);
+                let message: Value = serde_json::from_slice(&message[..len])
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                // VarlinkStream returns fds only with the byte that they
+                // arrived with and FdFramed decodes before every read, so the
+                // fds here arrived with the bytes of this message.
+                let fds = fds.take(fds.len())?;
+                Ok(Some((message, fds)))
+            }
+            None if src.len() > MAX_MESSAGE_LEN => Err(invalid_data("varlink message too long")),
+            None => Ok(None),
+        }
+    }
+}
+
+// === impl VarlinkStream ===
+
+impl AsyncRead for VarlinkStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let stream = &mut self.get_mut().0;
+
+        let (len, fds_waiting) =
+            ready!(stream.poll_peek_fds_waiting(cx, buf.initialize_unfilled()))?;
+        let len = if fds_waiting { len.min(1) } else { len };
+
+        let mut limited = buf.take(len);
+        ready!(Pin::new(stream).poll_read(cx, &mut limited))?;
+        let count = limited.filled().len();
+        // The peek above initialized the unfilled part of buf.
+        buf.advance(count);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for VarlinkStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+impl EnqueueFd for VarlinkStream {
+    fn enqueue(&mut self, fd: &impl AsRawFd) -> Result<(), QueueFullError> {
+        self.0.enqueue(fd)
+    }
+
+    fn clear_enqueued(&mut self) {
+        self.0.clear_enqueued()
+    }
+}
+
+impl DequeueFd for VarlinkStream {
+    fn dequeue(&mut self) -> Option<RawFd> {
+        self.0.dequeue()
+    }
+}
+
+// === utility functions ===
+
+async fn send_message(
+    framed: &mut FdFramed<VarlinkStream, VarlinkCodec>,
+    message: &impl Serialize,
+    fds: Vec<OwnedFd>,
+) -> io::Result<()> {
+    let bytes = serde_json::to_vec(message).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    if bytes.len() > MAX_MESSAGE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "varlink message too long",
+        ));
+    }
+
+    framed.send((bytes, fds)).await
+}
+
+async fn recv_message<T: DeserializeOwned>(
+    framed: &mut FdFramed<VarlinkStream, VarlinkCodec>,
+) -> io::Result<Option<(T, Vec<OwnedFd>)>> {
+    match framed.next().await {
+        Some(frame) => {
+            let (message, fds) = frame?;
+            let message = serde_json::from_value(message)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            Ok(Some((message, fds)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn upgraded(mut framed: FdFramed<VarlinkStream, VarlinkCodec>) -> Upgraded {
+    let buffered = framed.read_buffer().to_vec();
+    let recv_fds = framed.recv_fds_mut();
+    let fds = recv_fds.take(recv_fds.len()).unwrap_or_default();
+
+    Upgraded {
+        stream: framed.into_inner().0,
+        buffered,
+        fds,
+    }
+}
+
+fn check_parameters(parameters: Value) -> io::Result<Value> {
+    match parameters {
+        Value::Object(ref map) if map.is_empty() => Ok(Value::Null),
+        Value::Object(_) | Value::Null => Ok(parameters),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "varlink parameters must be an object",
+        )),
+    }
+}
+
+fn received_parameters(parameters: Value) -> io::Result<Value> {
+    match parameters {
+        Value::Null => Ok(json!({})),
+        Value::Object(_) => Ok(parameters),
+        _ => Err(invalid_data("varlink parameters must be an object")),
+    }
+}
+
+fn is_varlink_error(e: &Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<VarlinkError>())
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::os::unix::io::FromRawFd;
+
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{test_util::hello_file, EnqueueFd};
+
+    fn info() -> ServiceInfo {
+        let mut info = ServiceInfo::new("fd-queue", "test", "1", "https://example.org");
+        info.add_interface("org.example.test", "interface org.example.test\n");
+        info
+    }
+
+    fn varlink_error(e: io::Error) -> VarlinkError {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<VarlinkError>())
+            .cloned()
+            .expect("Not a varlink error")
+    }
+
+    // Serves the methods of org.example.test until the client disconnects.
+    async fn serve(mut connection: Connection) -> io::Result<()> {
+        while let Some(mut call) = connection.recv().await? {
+            match call.method() {
+                "org.example.test.Echo" => {
+                    let mut reply = Reply::new(call.parameters().clone());
+                    for fd in call.take_fds() {
+                        reply = reply.with_fd(fd);
+                    }
+                    connection.reply(reply).await?;
+                }
+                "org.example.test.Count" => {
+                    for i in 0..3 {
+                        connection.reply_more(Reply::new(json!({ "i": i }))).await?;
+                    }
+                    connection.reply(Reply::new(json!({ "i": 3 }))).await?;
+                }
+                "org.example.test.Upgrade" => {
+                    let mut upgraded = connection.upgrade(Reply::new(json!({}))).await?;
+                    upgraded.stream.write_all(b"raw").await?;
+                    return Ok(());
+                }
+                method => {
+                    connection
+                        .fail(VarlinkError::method_not_found(method))
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect() -> Client {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("varlink.sock")).expect("Can't bind");
+        let service = Service::new(listener, info());
+        tokio::spawn(async move {
+            let _dir = dir;
+            serve(service.connection(sock2)).await
+        });
+
+        Client::from(sock1)
+    }
+
+    #[tokio::test]
+    async fn call_keeps_fds_with_reply() {
+        let dir = tempdir().expect("Can't create temp dir");
+        let path = dir.path().join("varlink.sock");
+        let mut service = Service::new(UnixListener::bind(&path).expect("Can't bind"), info());
+        tokio::spawn(async move {
+            let connection = service.accept().await.expect("Can't accept");
+            serve(connection).await
+        });
+        let mut client = Client::connect(&path).await.expect("Can't connect");
+
+        let call = Call::new("org.example.test.Echo", json!({ "name": "log" }))
+            .with_fd(hello_file())
+            .with_fd(hello_file());
+        let mut reply = client.call(call).await.expect("Can't call");
+
+        assert_eq!(reply.parameters(), &json!({ "name": "log" }));
+        let fds = reply.take_fds();
+        assert_eq!(fds.len(), 2);
+        for fd in fds {
+            let mut buf = String::new();
+            File::from(fd)
+                .read_to_string(&mut buf)
+                .expect("Can't read from file");
+            assert_eq!(buf, "Hello World!");
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_answers_service_interface() {
+        let mut client = connect();
+
+        let info = client
+            .call(Call::new("org.varlink.service.GetInfo", json!({})))
+            .await
+            .expect("Can't get info");
+        let unknown = client
+            .call(Call::new("org.example.other.Method", json!({})))
+            .await
+            .expect_err("Unknown interface didn't fail");
+
+        assert_eq!(
+            info.parameters()["interfaces"],
+            json!(["org.varlink.service", "org.example.test"])
+        );
+        assert_eq!(
+            varlink_error(unknown),
+            VarlinkError::interface_not_found("org.example.other")
+        );
+    }
+
+    #[tokio::test]
+    async fn call_more_gets_every_reply() {
+        let mut client = connect();
+
+        let mut replies = client
+            .call_more(Call::new("org.example.test.Count", json!({})))
+            .await
+            .expect("Can't call");
+        let mut counts = Vec::new();
+        while let Some(reply) = replies.recv().await {
+            counts.push(reply.expect("Error reply").parameters()["i"].clone());
+        }
+        client
+            .call_oneway(Call::new("org.example.test.Echo", json!({})))
+            .await
+            .expect("Can't call oneway");
+        let echo = client
+            .call(Call::new("org.example.test.Echo", json!({ "after": true })))
+            .await
+            .expect("Can't call after oneway");
+
+        assert_eq!(counts, vec![json!(0), json!(1), json!(2), json!(3)]);
+        assert_eq!(echo.parameters(), &json!({ "after": true }));
+    }
+
+    #[tokio::test]
+    async fn dropped_replies_are_discarded() {
+        let mut client = connect();
+
+        {
+            let mut replies = client
+                .call_more(Call::new("org.example.test.Count", json!({})))
+                .await
+                .expect("Can't call");
+            replies
+                .recv()
+                .await
+                .expect("No reply")
+                .expect("Error reply");
+        }
+        let echo = client
+            .call(Call::new("org.example.test.Echo", json!({ "n": 1 })))
+            .await
+            .expect("Can't call");
+
+        assert_eq!(echo.parameters(), &json!({ "n": 1 }));
+    }
+
+    fn pair() -> (Client, Connection) {
+        let (sock1, sock2) = UnixStream::pair().expect("Can't create UnixStream's");
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("varlink.sock")).expect("Can't bind");
+        let service = Service::new(listener, info());
+
+        (Client::from(sock1), service.connection(sock2))
+    }
+
+    #[tokio::test]
+    async fn replies_from_separate_writes_keep_their_fds() {
+        let (mut client, mut connection) = pair();
+
+        let mut replies = client
+            .call_more(Call::new("org.example.test.Open", json!({})))
+            .await
+            .expect("Can't call");
+        connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+        connection
+            .reply_more(Reply::new(json!({ "n": 0 })))
+            .await
+            .expect("Can't reply");
+        connection
+            .reply(Reply::new(json!({ "n": 1 })).with_fd(hello_file()))
+            .await
+            .expect("Can't reply");
+        let mut plain = replies
+            .recv()
+            .await
+            .expect("No reply")
+            .expect("Error reply");
+        let mut withfd = replies
+            .recv()
+            .await
+            .expect("No reply")
+            .expect("Error reply");
+
+        assert_eq!(plain.take_fds().len(), 0);
+        assert_eq!(withfd.parameters(), &json!({ "n": 1 }));
+        assert_eq!(withfd.take_fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn calls_from_separate_writes_keep_their_fds() {
+        let (mut client, mut connection) = pair();
+
+        client
+            .call_oneway(Call::new("org.example.test.Echo", json!({})))
+            .await
+            .expect("Can't call oneway");
+        let call = Call::new("org.example.test.Echo", json!({})).with_fd(hello_file());
+        client
+            .send(call, false, false, false)
+            .await
+            .expect("Can't call");
+        let mut oneway = connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+        let mut withfd = connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+
+        assert_eq!(oneway.take_fds().len(), 0);
+        assert_eq!(withfd.take_fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn upgrade_hands_over_buffered_fds() {
+        let (mut client, mut connection) = pair();
+
+        let call = Call::new("org.example.test.Upgrade", json!({}));
+        client
+            .send(call, false, false, true)
+            .await
+            .expect("Can't call");
+        connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+        let mut server = connection
+            .upgrade(Reply::new(json!({})))
+            .await
+            .expect("Can't upgrade");
+        let file = hello_file();
+        server.stream.enqueue(&file).expect("Can't enqueue fd");
+        server.stream.write_all(b"raw").await.expect("Can't write");
+        drop(server);
+        client.recv().await.expect("Can't receive reply");
+        let mut upgraded = upgraded(client.framed);
+        let mut raw = upgraded.buffered;
+        upgraded
+            .stream
+            .read_to_end(&mut raw)
+            .await
+            .expect("Can't read upgraded stream");
+        let mut fds = upgraded.fds;
+        while let Some(fd) = upgraded.stream.dequeue() {
+            // SAFETY: the dequeued fd is owned by the test.
+            fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        assert_eq!(raw, b"raw");
+        assert_eq!(fds.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connection_finds_fds_of_standard_calls() {
+        let (mut client, sock) = UnixStream::pair().expect("Can't create UnixStream's");
+        let dir = tempdir().expect("Can't create temp dir");
+        let listener = UnixListener::bind(dir.path().join("varlink.sock")).expect("Can't bind");
+        let mut connection = Service::new(listener, info()).connection(sock);
+
+        // Both calls are waiting before the connection reads, so a single read
+        // would return them together.
+        let file = hello_file();
+        client
+            .write_all(b"{\"method\":\"org.example.test.Echo\",\"oneway\":true}\0")
+            .await
+            .expect("Can't write");
+        client.enqueue(&file).expect("Can't enqueue fd");
+        client
+            .write_all(b"{\"method\":\"org.example.test.Echo\",\"parameters\":{\"log\":0}}\0")
+            .await
+            .expect("Can't write");
+        let mut oneway = connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+        let mut withfd = connection
+            .recv()
+            .await
+            .expect("Can't receive call")
+            .expect("No call");
+
+        assert_eq!(oneway.take_fds().len(), 0);
+        assert_eq!(withfd.parameters(), &json!({ "log": 0 }));
+        assert_eq!(withfd.take_fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_sends_and_receives_standard_messages_with_fds() {
+        let (sock, mut service) = UnixStream::pair().expect("Can't create UnixStream's");
+        let mut client = Client::from(sock);
+        let file = hello_file();
+
+        let server = tokio::spawn(async move {
+            let mut call = Vec::new();
+            while call.last() != Some(&0) {
+                let mut byte = [0];
+                service.read_exact(&mut byte).await.expect("Can't read");
+                call.push(byte[0]);
+            }
+            let fd = service.dequeue().expect("No fd with call");
+            // SAFETY: the dequeued fd is owned by the test.
+            drop(unsafe { File::from_raw_fd(fd) });
+            service.enqueue(&file).expect("Can't enqueue fd");
+            service
+                .write_all(b"{\"parameters\":{\"log\":0}}\0")
+                .await
+                .expect("Can't write");
+            call
+        });
+        let call = Call::new("org.example.test.Open", json!({ "log": 0 })).with_fd(hello_file());
+        let mut reply = client.call(call).await.expect("Can't call");
+        let call = server.await.expect("Server panicked");
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&call[..call.len() - 1]).expect("Can't parse call"),
+            json!({ "method": "org.example.test.Open", "parameters": { "log": 0 } })
+        );
+        assert_eq!(reply.parameters(), &json!({ "log": 0 }));
+        assert_eq!(reply.take_fds().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn upgrade_hands_over_stream() {
+        let client = connect();
+
+        let (_, mut upgraded) = client
+            .upgrade(Call::new("org.example.test.Upgrade", json!({})))
+            .await
+            .expect("Can't upgrade");
+        let mut raw = upgraded.buffered;
+        upgraded
+            .stream
+            .read_to_end(&mut raw)
+            .await
+            .expect("Can't read upgraded stream");
+
+        assert_eq!(raw, b"raw");
+    }
+}